@@ -37,6 +37,30 @@ extern "C" {
 
 #pragma mark Consts and enums
 
+//
+// Version of the library this header is from, and of its ABI,
+// which changes whenever applications built against an older
+// header would break. Compare with mtpng_version() and
+// mtpng_abi_version() to check the library loaded at runtime.
+//
+#define MTPNG_VERSION "0.4.0"
+#define MTPNG_ABI_VERSION 1
+
+//
+// Bits of mtpng_capabilities(), for features that depend on how
+// the library was built or the CPU it's running on.
+//
+// MTPNG_CAPABILITY_APNG: animated PNG encoding; not yet supported.
+// MTPNG_CAPABILITY_SIMD: filters use vectorized code on this CPU.
+// MTPNG_CAPABILITY_ZLIB: compression is done by the C zlib library.
+// MTPNG_CAPABILITY_ZLIB_RS: compression is done by zlib-rs, the
+// pure-Rust port of zlib. Exactly one of these two is set.
+//
+#define MTPNG_CAPABILITY_APNG (1u << 0)
+#define MTPNG_CAPABILITY_SIMD (1u << 1)
+#define MTPNG_CAPABILITY_ZLIB (1u << 2)
+#define MTPNG_CAPABILITY_ZLIB_RS (1u << 3)
+
 //
 // Pass to mtpng_threadpool_new() as number of threads to
 // use the default, which is the detected number of logical
@@ -48,9 +72,44 @@ extern "C" {
 // Return type for mtpng functions.
 // Always check the return value, errors are real!
 //
+// Anything other than MTPNG_RESULT_OK is a failure, and
+// mtpng_last_error_message() describes it. More codes may be
+// added, so treat unknown ones as MTPNG_RESULT_ERR.
+//
 typedef enum mtpng_result_t {
     MTPNG_RESULT_OK = 0,
-    MTPNG_RESULT_ERR = 1
+
+    // An internal failure not covered below, such as a worker
+    // thread failing. Earlier versions returned this for all errors.
+    MTPNG_RESULT_ERR = 1,
+
+    // An argument was NULL, out of range, or otherwise invalid,
+    // such as a bad color type and depth, or text keyword.
+    MTPNG_RESULT_INVALID_ARGUMENT = 2,
+
+    // Calls were made out of order, such as writing image data
+    // before the header.
+    MTPNG_RESULT_INVALID_STATE = 3,
+
+    // Image data doesn't match the header's size, such as ending
+    // partway through a row or having too few rows.
+    MTPNG_RESULT_INVALID_DATA = 4,
+
+    // Writing output failed, such as a write or flush callback
+    // reporting failure.
+    MTPNG_RESULT_IO = 5,
+
+    // The encode was cancelled through a cancel token.
+    MTPNG_RESULT_CANCELLED = 6,
+
+    // The encode ran past its deadline.
+    MTPNG_RESULT_TIMED_OUT = 7,
+
+    // mtpng hit an internal bug and panicked. The panic was stopped
+    // from unwinding into the caller, but the objects passed may be
+    // left inconsistent, so only release them afterwards. Libraries
+    // built with panic=abort abort the process instead.
+    MTPNG_RESULT_PANIC = 8
 } mtpng_result;
 
 //
@@ -84,6 +143,7 @@ typedef enum mtpng_strategy_t {
 
 //
 // Compression levels for mtpng_encoder_options_set_compression_level().
+// Any zlib level from 1 to 9 may also be given.
 //
 typedef enum mtpng_compression_level_t {
     MTPNG_COMPRESSION_LEVEL_FAST = 1,
@@ -102,6 +162,33 @@ typedef enum mtpng_color_t {
     MTPNG_COLOR_TRUECOLOR_ALPHA = 6
 } mtpng_color;
 
+//
+// Units for mtpng_encoder_write_physical_size().
+//
+// MTPNG_UNIT_UNKNOWN gives only the pixels' aspect ratio.
+//
+typedef enum mtpng_unit_t {
+    MTPNG_UNIT_UNKNOWN = 0,
+    MTPNG_UNIT_METER = 1
+} mtpng_unit;
+
+//
+// Input pixel layouts for mtpng_encoder_set_pixel_format().
+//
+// MTPNG_PIXEL_FORMAT_NATIVE is the layout PNG uses for the header's
+// color type and depth. The others are 8 bits per channel, and are
+// rearranged into truecolor (BGR, RGBX, BGRX) or truecolor with
+// alpha (BGRA, ARGB) rows; the X bytes are padding, and dropped.
+//
+typedef enum mtpng_pixel_format_t {
+    MTPNG_PIXEL_FORMAT_NATIVE = 0,
+    MTPNG_PIXEL_FORMAT_BGR = 1,
+    MTPNG_PIXEL_FORMAT_BGRA = 2,
+    MTPNG_PIXEL_FORMAT_RGBX = 3,
+    MTPNG_PIXEL_FORMAT_BGRX = 4,
+    MTPNG_PIXEL_FORMAT_ARGB = 5
+} mtpng_pixel_format;
+
 #pragma mark Structs
 
 //
@@ -136,6 +223,40 @@ typedef struct mtpng_header_struct mtpng_header;
 //
 typedef struct mtpng_encoder_struct mtpng_encoder;
 
+//
+// Represents a PNG file encoded into memory, owned by the library
+// until released.
+//
+// The contents are private; you will only ever use pointers.
+//
+typedef struct mtpng_buffer_struct mtpng_buffer;
+
+//
+// Represents a handle for cancelling an encode, which may be used
+// from any thread, and outlive the encoder.
+//
+// The contents are private; you will only ever use pointers.
+//
+typedef struct mtpng_cancel_token_struct mtpng_cancel_token;
+
+//
+// Progress information passed to a progress callback.
+//
+typedef struct mtpng_progress_t {
+    // Image rows taken from input so far.
+    uint32_t rows_consumed;
+
+    // Data chunks filtered, compressed, and passed on for output so far.
+    size_t chunks_completed;
+
+    // Data chunks in the whole image, for working out the fraction done.
+    size_t chunks_total;
+
+    // Bytes written to the output so far. When not in streaming mode,
+    // compressed data is held back until the end.
+    uint64_t bytes_written;
+} mtpng_progress;
+
 #pragma mark Function types
 
 #if 0
@@ -185,6 +306,85 @@ typedef size_t (*mtpng_write_func)(void* user_data,
 //
 typedef bool (*mtpng_flush_func)(void* user_data);
 
+//
+// Progress callback type for mtpng_encoder_set_progress_callback().
+//
+// Called as each data chunk is completed, on the thread that's
+// writing to the encoder, so it may update a progress bar but
+// shouldn't block for long. The progress data is only valid during
+// the call.
+//
+typedef void (*mtpng_progress_func)(void* user_data,
+                                    const mtpng_progress* p_progress);
+
+//
+// Allocation callback types for mtpng_allocator, which work like
+// the C library's malloc(), realloc(), and free() with the
+// allocator's user data passed first.
+//
+// Return NULL if the memory can't be allocated; the encode will
+// fail with MTPNG_RESULT_IO.
+//
+// They're called from the thread pool's threads as well as the
+// calling thread, so must be thread-safe.
+//
+typedef void* (*mtpng_malloc_func)(void* user_data,
+                                   size_t size);
+
+typedef void* (*mtpng_realloc_func)(void* user_data,
+                                    void* p,
+                                    size_t size);
+
+typedef void (*mtpng_free_func)(void* user_data,
+                                void* p);
+
+//
+// Allocator for mtpng_encoder_options_set_allocator().
+// All three functions must be provided.
+//
+typedef struct mtpng_allocator_t {
+    mtpng_malloc_func malloc_func;
+    mtpng_realloc_func realloc_func;
+    mtpng_free_func free_func;
+    void* user_data;
+} mtpng_allocator;
+
+#pragma mark Version
+
+//
+// Returns the library's version, such as "0.4.0", as a static string.
+//
+extern const char*
+mtpng_version(void);
+
+//
+// Returns the library's ABI version. If it isn't MTPNG_ABI_VERSION,
+// the library loaded doesn't match the header the application was
+// built with, and shouldn't be used.
+//
+extern uint32_t
+mtpng_abi_version(void);
+
+//
+// Returns a bitmask of MTPNG_CAPABILITY_* flags for the features
+// available, to check at load time before relying on them.
+//
+extern uint32_t
+mtpng_capabilities(void);
+
+#pragma mark Errors
+
+//
+// Returns a message describing the most recent failure on the
+// calling thread, for logging or showing to users, or NULL if
+// there hasn't been one.
+//
+// The string is UTF-8, owned by the library, and valid until the
+// next failing call on the same thread.
+//
+extern const char*
+mtpng_last_error_message(void);
+
 #pragma mark ThreadPool
 
 //
@@ -199,9 +399,9 @@ typedef bool (*mtpng_flush_func)(void* user_data);
 // If you do not create a thread pool, a default global one will
 // be created when you first create an encoder.
 //
-// A thread pool may be used with multiple encoders, but caller
-// is responsible for ensuring that the pool lives longer than
-// all the encoders using it.
+// A thread pool may be shared by any number of options and
+// encoders, including encoders running concurrently on different
+// threads; create one at startup to avoid the global pool.
 //
 // Check the return value for errors.
 //
@@ -210,14 +410,26 @@ mtpng_threadpool_new(mtpng_threadpool** pp_pool,
                      size_t threads);
 
 //
-// Releases the pool's memory and clears the pointer.
+// Gets the number of worker threads in the pool, which is the
+// detected number of logical processors if it was created with
+// MTPNG_THREADS_DEFAULT.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_threadpool_get_threads(mtpng_threadpool* p_pool,
+                             size_t* p_threads);
+
+//
+// Releases the pool's memory and clears the pointer, waiting for
+// its worker threads to exit before returning.
 //
 // On input, *pp_pool must be a valid instance pointer.
 // On output, *pp_pool will be NULL on success or remain unchanged
 // in case of failure.
 //
-// Caller's responsibility to ensure that no encoders are using
-// the pool.
+// Fails with MTPNG_RESULT_INVALID_STATE if any options or encoders
+// are still using the pool; release or finish them first.
 //
 // Check the return value for errors.
 //
@@ -258,9 +470,9 @@ mtpng_encoder_options_release(mtpng_encoder_options** pp_options);
 // Set the thread pool instance to queue work on.
 //
 // p_pool may be NULL, in which case a default global thread pool
-// will be used. If a thread pool is provided, it is the caller's
-// responsibility to keep the thread pool alive until all encoders
-// using it have been released.
+// will be used. If a thread pool is provided, it can't be released
+// until these options and all encoders created from them have been
+// released or finished.
 //
 // Check the return values for errors.
 //
@@ -268,6 +480,26 @@ extern mtpng_result
 mtpng_encoder_options_set_thread_pool(mtpng_encoder_options* p_options,
                                       mtpng_threadpool* p_pool);
 
+//
+// Set the allocator for the large working buffers of encoders
+// created with these options -- copied input rows, filtered and
+// compressed chunks, and compressed data waiting to be written --
+// and for the output memory of mtpng_encoder_new_buffer(), which
+// holds the whole PNG file. The allocator is copied, and each
+// buffer is freed through the allocator it came from, so this may
+// be changed at any time.
+//
+// p_allocator may be NULL to go back to the system allocator.
+// zlib's compression state and output staging, and scratch rows
+// for filtering, always come from the system allocator; they're a
+// fixed size for each running job.
+//
+// Check the return values for errors.
+//
+extern mtpng_result
+mtpng_encoder_options_set_allocator(mtpng_encoder_options* p_options,
+                                    const mtpng_allocator* p_allocator);
+
 
 //
 // Override the default PNG filter mode selection.
@@ -403,6 +635,10 @@ mtpng_header_set_color(mtpng_header* p_header,
 // p_options may be NULL, in which case default options will
 // be used including a global threadpool.
 //
+// mtpng never opens files itself, so the application opens its
+// output however its platform needs, such as with _wfopen() for
+// non-ASCII paths on Windows.
+//
 // Check the return values for errors.
 //
 extern mtpng_result
@@ -412,6 +648,26 @@ mtpng_encoder_new(mtpng_encoder** pp_encoder,
                   void* const user_data,
                   mtpng_encoder_options* p_options);
 
+//
+// Create a new PNG encoder instance which writes into a growable
+// memory buffer, instead of through callbacks. Copies the options
+// data.
+//
+// Finish it with mtpng_encoder_finish_buffer() to get the output.
+//
+// On input, *pp_encoder must be NULL.
+// On output, *pp_encoder will be an instance pointer on success,
+// or remain unchanged in case of failure.
+//
+// p_options may be NULL, in which case default options will
+// be used including a global threadpool.
+//
+// Check the return values for errors.
+//
+extern mtpng_result
+mtpng_encoder_new_buffer(mtpng_encoder** pp_encoder,
+                         mtpng_encoder_options* p_options);
+
 //
 // Releases the encoder's memory and clears the pointer.
 //
@@ -420,6 +676,10 @@ mtpng_encoder_new(mtpng_encoder** pp_encoder,
 // at the end of encoding will consume the instance and
 // release its memory.
 //
+// If all of the image data was written, the file is completed
+// as by mtpng_encoder_finish(), but without reporting errors.
+// Otherwise any outstanding work is cancelled.
+//
 // On input, *pp_encoder must be a valid instance pointer.
 // On output, *pp_encoder will be NULL on success, or remain
 // unchanged in case of failure.
@@ -434,6 +694,37 @@ mtpng_encoder_new(mtpng_encoder** pp_encoder,
 extern mtpng_result
 mtpng_encoder_release(mtpng_encoder** pp_encoder);
 
+//
+// Set a function to be called with progress information as each
+// data chunk is completed, replacing any set before.
+//
+// progress_func must not be NULL. user_data is passed to it, and
+// may be any value such as a private object pointer or NULL.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_set_progress_callback(mtpng_encoder* p_encoder,
+                                    mtpng_progress_func progress_func,
+                                    void* const user_data);
+
+//
+// Get a token which can be used to cancel the encoder's work,
+// such as from a GUI thread when the user clicks cancel.
+//
+// On input, *pp_token must be NULL.
+// On output, *pp_token will be a token instance on success, or
+// remain unchanged in case of failure.
+//
+// Free with mtpng_cancel_token_release(), which may be done
+// before or after the encoder is finished or released.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_get_cancel_token(mtpng_encoder* p_encoder,
+                               mtpng_cancel_token** pp_token);
+
 //
 // Signal that we're done setting up, and start writing
 // header data to the output.
@@ -453,6 +744,9 @@ mtpng_encoder_write_header(mtpng_encoder* p_encoder,
 //
 // See https://www.w3.org/TR/PNG/#11PLTE for the data format.
 //
+// Indexed-color palettes may have at most 2^depth entries, and
+// greyscale images can't have a palette.
+//
 // Must be called after mtpng_encoder_write_header() and before
 // mtpng_encoder_write_image() or mtpng_encoder_write_image_data().
 //
@@ -469,6 +763,10 @@ mtpng_encoder_write_palette(mtpng_encoder* p_encoder,
 //
 // See https://www.w3.org/TR/PNG/#11tRNS for the data format.
 //
+// Indexed images may have up to one entry per palette entry; the
+// color's samples must fit in the bit depth; and color types with
+// alpha can't have transparency.
+//
 // Must be called after mtpng_encoder_write_palette() for indexed
 // images, or mtpng_encoder_write_header() for others; and before
 // mtpng_encoder_write_image() or mtpng_encoder_write_image_data().
@@ -482,8 +780,11 @@ mtpng_encoder_write_transparency(mtpng_encoder* p_encoder,
 
 //
 // Write a custom ancillary chunk to the output stream.
-// The tag must be a 4-byte string. The data should be provided
-// in the appropriate format for the tag.
+// The tag must be a 4-byte string of ASCII letters. The data should
+// be provided in the appropriate format for the tag.
+//
+// Critical chunks, whose tags start with an uppercase letter, can't
+// be written this way, and the third letter must be uppercase.
 //
 // Check the return value for errors.
 //
@@ -493,6 +794,94 @@ mtpng_encoder_write_chunk(mtpng_encoder* p_encoder,
                           const uint8_t* p_bytes,
                           size_t len);
 
+//
+// Write a text chunk with the given keyword and text, both UTF-8
+// strings. Latin-1 text is written in a tEXt chunk, which all
+// decoders can read, and other text in an iTXt chunk.
+//
+// Keywords must be 1-79 printable Latin-1 characters without
+// leading, trailing, or consecutive spaces, such as "Title",
+// "Author", "Description", "Copyright", or "Software".
+//
+// Must be called after mtpng_encoder_write_header(), and either
+// before any image data or after all of it.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_text(mtpng_encoder* p_encoder,
+                         const char* p_keyword,
+                         const char* p_text);
+
+//
+// Write a compressed text chunk, as a zTXt chunk for Latin-1 text
+// or a compressed iTXt chunk otherwise. Requirements are the same
+// as for mtpng_encoder_write_text().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_compressed_text(mtpng_encoder* p_encoder,
+                                    const char* p_keyword,
+                                    const char* p_text);
+
+//
+// Write an ICC color profile, compressed in an iCCP chunk. The
+// name follows the same rules as text keywords, and the profile
+// must be a whole ICC profile, such as read from an .icc file.
+//
+// Must be called after mtpng_encoder_write_header() and before
+// mtpng_encoder_write_palette() or any image data. Don't also
+// write an sRGB chunk.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_icc_profile(mtpng_encoder* p_encoder,
+                                const char* p_name,
+                                const uint8_t* p_bytes,
+                                size_t len);
+
+//
+// Write a pHYs chunk giving the pixels per unit horizontally and
+// vertically, such as 11811 per meter for 300 dpi. With
+// MTPNG_UNIT_UNKNOWN, they give only the pixels' aspect ratio.
+//
+// Values must be from 1 to 2^31 - 1.
+//
+// Must be called after mtpng_encoder_write_header() and before
+// any image data.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_physical_size(mtpng_encoder* p_encoder,
+                                  uint32_t x,
+                                  uint32_t y,
+                                  mtpng_unit unit);
+
+//
+// Write a tIME chunk with the time of the image's last
+// modification, in UTC. Month and day count from 1, and second
+// may be 60 for a leap second.
+//
+// For reproducible output, take the time from SOURCE_DATE_EPOCH
+// when it's set rather than the clock.
+//
+// Must be called after mtpng_encoder_write_header() and before
+// any image data.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_time(mtpng_encoder* p_encoder,
+                         uint16_t year,
+                         uint8_t month,
+                         uint8_t day,
+                         uint8_t hour,
+                         uint8_t minute,
+                         uint8_t second);
+
 //
 // Load one or more rows of input data into the encoder, to be
 // filtered and compressed as data is provided.
@@ -501,7 +890,8 @@ mtpng_encoder_write_chunk(mtpng_encoder* p_encoder,
 // mtpng_encoder_finish().
 //
 // Image data must be pre-packed in the correct bit depth and
-// channel order. If not all rows are provided before calling
+// channel order, unless another layout has been set with the
+// functions below. If not all rows are provided before calling
 // mtpng_encoder_finish(), failure will result.
 //
 // Check the return value for errors.
@@ -511,6 +901,46 @@ mtpng_encoder_write_image_rows(mtpng_encoder* p_encoder,
                                const uint8_t* p_bytes,
                                size_t len);
 
+//
+// Set the pixel layout of rows passed to
+// mtpng_encoder_write_image_rows(), such as for Windows DIBs or
+// GPU readbacks in BGRA order. Defaults to MTPNG_PIXEL_FORMAT_NATIVE.
+//
+// Formats other than native must match an 8-bit truecolor header,
+// with alpha if the format has it, or writing rows will fail with
+// MTPNG_RESULT_INVALID_ARGUMENT.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_set_pixel_format(mtpng_encoder* p_encoder,
+                               mtpng_pixel_format format);
+
+//
+// Set the distance in bytes between the starts of rows passed to
+// mtpng_encoder_write_image_rows(), for rows padded to alignment.
+// The padding is skipped, and may be left off the last row.
+//
+// 0, the default, means rows are packed with no padding.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_set_row_stride(mtpng_encoder* p_encoder,
+                             size_t stride);
+
+//
+// Set whether the rows in each call to mtpng_encoder_write_image_rows()
+// are stored bottom-up, starting with the lowest row in memory, as in
+// Windows DIBs and OpenGL readbacks. Rows must still be written from
+// the top of the image down, so pass the whole image in one call.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_set_bottom_up(mtpng_encoder* p_encoder,
+                            bool bottom_up);
+
 //
 // Wait for any outstanding work blocks, flush output,
 // release the encoder instance and clear the pointer.
@@ -528,11 +958,89 @@ mtpng_encoder_write_image_rows(mtpng_encoder* p_encoder,
 // If using a threadpool, must be called before releasing
 // the threadpool!
 //
+// Encoders from mtpng_encoder_new_buffer() must be finished
+// with mtpng_encoder_finish_buffer() instead.
+//
 // Check the return value for errors.
 //
 extern mtpng_result
 mtpng_encoder_finish(mtpng_encoder** pp_encoder);
 
+//
+// Finish an encoder from mtpng_encoder_new_buffer() as with
+// mtpng_encoder_finish(), and hand over the encoded file.
+//
+// On input, *pp_encoder must be a valid instance pointer, and
+// *pp_buffer must be NULL.
+// On output, *pp_encoder will be NULL, and *pp_buffer will be
+// a buffer instance on success. On failure, *pp_buffer remains
+// NULL; *pp_encoder is NULL if it had been consumed, or remains
+// unchanged if the arguments were invalid.
+//
+// Free the buffer with mtpng_buffer_release().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_finish_buffer(mtpng_encoder** pp_encoder,
+                            mtpng_buffer** pp_buffer);
+
+#pragma mark Buffer
+
+//
+// Get the encoded bytes from a buffer, such as to send in an
+// HTTP response or copy into an asset package.
+//
+// On output, *pp_bytes points to the data, which remains valid
+// until the buffer is released, and *p_len is its length.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_buffer_get_data(mtpng_buffer* p_buffer,
+                      const uint8_t** pp_bytes,
+                      size_t* p_len);
+
+//
+// Releases the buffer's memory and clears the pointer.
+//
+// On input, *pp_buffer must be a valid instance pointer.
+// On output, *pp_buffer will be NULL on success or remain unchanged
+// in case of failure.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_buffer_release(mtpng_buffer** pp_buffer);
+
+#pragma mark Cancel token
+
+//
+// Cancel the encode the token came from. This is safe to call
+// from any thread, at any time while the token is alive.
+//
+// Running work stops at its next check and queued work is
+// dropped. The encoder returns errors from all further calls
+// without writing anything more, so release it with
+// mtpng_encoder_release() on the thread that was using it.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_cancel_token_cancel(mtpng_cancel_token* p_token);
+
+//
+// Releases the token's memory and clears the pointer.
+//
+// On input, *pp_token must be a valid instance pointer.
+// On output, *pp_token will be NULL on success or remain unchanged
+// in case of failure.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_cancel_token_release(mtpng_cancel_token** pp_token);
+
 #pragma mark footer
 
 #ifdef __cplusplus
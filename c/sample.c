@@ -51,7 +51,7 @@ static bool flush_func(void* user_data)
 do { \
     mtpng_result _ret = (ret); \
     if (_ret != MTPNG_RESULT_OK) { \
-        fprintf(stderr, "Error: %d\n", (int)(_ret)); \
+        fprintf(stderr, "Error %d: %s\n", (int)(_ret), mtpng_last_error_message()); \
         goto cleanup; \
     }\
 } while (0)
@@ -59,6 +59,11 @@ do { \
 int main(int argc, char **argv) {
     size_t const threads = MTPNG_THREADS_DEFAULT;
 
+    if (mtpng_abi_version() != MTPNG_ABI_VERSION) {
+        fprintf(stderr, "Error: mtpng %s doesn't match the header\n", mtpng_version());
+        return 1;
+    }
+
     uint32_t const width = 1024;
     uint32_t const height = 768;
 
@@ -106,6 +111,12 @@ int main(int argc, char **argv) {
     TRY(mtpng_header_set_color(header, MTPNG_COLOR_TRUECOLOR, 8));
     TRY(mtpng_encoder_write_header(encoder, header));
 
+    //
+    // Add some metadata
+    //
+    TRY(mtpng_encoder_write_text(encoder, "Software", "mtpng C sample"));
+    TRY(mtpng_encoder_write_physical_size(encoder, 3780, 3780, MTPNG_UNIT_METER));
+
     //
     // Write the data!
     //
@@ -0,0 +1,165 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// Mtpng.java - Java bindings for Android and the JVM
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+package org.mtpng;
+
+import java.nio.ByteBuffer;
+import java.util.Objects;
+
+/**
+ * Encodes PNG files with mtpng, loading the native library built
+ * with the "java" feature as libmtpng.
+ *
+ * On Android, pass a Bitmap's pixels from getPixels() to encodeArgb();
+ * on the JVM, a BufferedImage's from getRGB().
+ */
+public final class Mtpng {
+    static {
+        System.loadLibrary("mtpng");
+    }
+
+    private Mtpng() {
+    }
+
+    // Color types, as in the PNG header.
+    public static final int COLOR_GREYSCALE = 0;
+    public static final int COLOR_TRUECOLOR = 2;
+    public static final int COLOR_GREYSCALE_ALPHA = 4;
+    public static final int COLOR_TRUECOLOR_ALPHA = 6;
+
+    /**
+     * Encoder options, with the same names as the command-line tool.
+     * Anything left unset uses the default.
+     */
+    public static final class Options {
+        private int level;
+        private String levelName;
+        private String filter;
+        private String strategy;
+        private int chunkSize;
+        private int threads;
+        private boolean streaming;
+
+        /** Compression level from 1 to 9. */
+        public Options level(int level) {
+            this.level = level;
+            this.levelName = null;
+            return this;
+        }
+
+        /** Compression level "fast", "default", or "high". */
+        public Options level(String level) {
+            this.levelName = level;
+            return this;
+        }
+
+        /** "adaptive", "none", "sub", "up", "average", or "paeth". */
+        public Options filter(String filter) {
+            this.filter = filter;
+            return this;
+        }
+
+        /** "auto", "default", "filtered", "huffman", "rle", or "fixed". */
+        public Options strategy(String strategy) {
+            this.strategy = strategy;
+            return this;
+        }
+
+        /** Bytes of image data per chunk of work; at least 32768. */
+        public Options chunkSize(int chunkSize) {
+            this.chunkSize = chunkSize;
+            return this;
+        }
+
+        /** Most threads of the shared pool for one encode to use. */
+        public Options threads(int threads) {
+            this.threads = threads;
+            return this;
+        }
+
+        public Options streaming(boolean streaming) {
+            this.streaming = streaming;
+            return this;
+        }
+    }
+
+    private static final Options DEFAULTS = new Options();
+
+    /**
+     * Encodes the remaining bytes of a buffer, as packed rows of the
+     * given color type and bit depth, to PNG file data.
+     *
+     * Direct buffers are read in place; others are copied first.
+     *
+     * @throws IllegalArgumentException if the image or options are invalid
+     */
+    public static byte[] encode(ByteBuffer pixels, int width, int height,
+                                int colorType, int depth, Options options) {
+        Objects.requireNonNull(pixels, "pixels");
+        if (!pixels.isDirect()) {
+            ByteBuffer direct = ByteBuffer.allocateDirect(pixels.remaining());
+            direct.put(pixels.duplicate());
+            direct.flip();
+            pixels = direct;
+        }
+        if (options == null) {
+            options = DEFAULTS;
+        }
+        return nativeEncode(pixels, pixels.position(), pixels.remaining(),
+                            width, height, colorType, depth,
+                            options.level, options.levelName,
+                            options.filter, options.strategy,
+                            options.chunkSize, options.threads, options.streaming);
+    }
+
+    /**
+     * Encodes pixels packed as 0xAARRGGBB ints, without premultiplied
+     * alpha, to PNG file data. The image is saved as RGB if every
+     * pixel is opaque, or RGBA if not.
+     *
+     * @throws IllegalArgumentException if the image or options are invalid
+     */
+    public static byte[] encodeArgb(int[] argb, int width, int height, Options options) {
+        Objects.requireNonNull(argb, "argb");
+        if (options == null) {
+            options = DEFAULTS;
+        }
+        return nativeEncodeArgb(argb, width, height,
+                                options.level, options.levelName,
+                                options.filter, options.strategy,
+                                options.chunkSize, options.threads, options.streaming);
+    }
+
+    private static native byte[] nativeEncode(ByteBuffer pixels, int offset, int length,
+                                              int width, int height, int colorType, int depth,
+                                              int level, String levelName,
+                                              String filter, String strategy,
+                                              int chunkSize, int threads, boolean streaming);
+
+    private static native byte[] nativeEncodeArgb(int[] argb, int width, int height,
+                                                  int level, String levelName,
+                                                  String filter, String strategy,
+                                                  int chunkSize, int threads, boolean streaming);
+}
@@ -0,0 +1,388 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// async_encoder.rs - encoder wrapper for async output
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Async wrappers around the encoder, which write output to an async
+//! sink and wait for the worker threads without blocking the runtime:
+//! AsyncEncoder for Tokio with the `tokio` feature, and FuturesEncoder
+//! for the futures crate's AsyncWrite with the `futures` feature.
+//!
+//! The filtering and compression still run on the thread pool set
+//! in the Options; only the waiting and output are async.
+
+#[cfg(feature="futures")]
+use std::future::poll_fn;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+#[cfg(feature="tokio")]
+use tokio::io::AsyncWriteExt;
+
+use super::Header;
+use super::Result;
+use super::encoder::{Encoder, Options};
+use super::utils::*;
+
+//
+// Set by the jobs as they finish, and waited on by the async side.
+// A signal that comes in while nobody is waiting is remembered, so
+// there's no race between checking for results and starting to wait.
+//
+#[derive(Default)]
+struct Signal {
+    state: Mutex<SignalState>,
+}
+
+#[derive(Default)]
+struct SignalState {
+    signaled: bool,
+    waker: Option<Waker>,
+}
+
+impl Signal {
+    fn notify(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.signaled = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wait(&self) -> SignalWait<'_> {
+        SignalWait {
+            signal: self,
+        }
+    }
+}
+
+struct SignalWait<'s> {
+    signal: &'s Signal,
+}
+
+impl<'s> Future for SignalWait<'s> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.signal.state.lock().unwrap();
+        if state.signaled {
+            state.signaled = false;
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+//
+// The runtime-independent part: a regular encoder writing into
+// a buffer, which the wrappers drain into their async output.
+//
+struct AsyncCore<'a> {
+    encoder: Encoder<'a, Vec<u8>>,
+    signal: Arc<Signal>,
+}
+
+impl<'a> AsyncCore<'a> {
+    fn new(options: &Options<'a>) -> AsyncCore<'a> {
+        let signal = Arc::new(Signal::default());
+        let mut encoder = Encoder::new(Vec::new(), options);
+        let wake_signal = Arc::clone(&signal);
+        encoder.set_wake(Arc::new(move || wake_signal.notify()));
+        AsyncCore {
+            encoder,
+            signal,
+        }
+    }
+
+    //
+    // Take whatever output is ready to go.
+    //
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(self.encoder.output_mut())
+    }
+
+    //
+    // Give the buffer back to reuse, once it's been written out.
+    //
+    fn return_output(&mut self, mut buffer: Vec<u8>) {
+        buffer.clear();
+        let output = self.encoder.output_mut();
+        if output.is_empty() {
+            *output = buffer;
+        }
+    }
+
+    //
+    // Wait until another row can be written without blocking.
+    //
+    async fn wait_for_room(&mut self) -> Result<()> {
+        loop {
+            self.encoder.poll()?;
+            if !self.encoder.row_would_block() {
+                return Ok(());
+            }
+            self.signal.wait().await;
+        }
+    }
+
+    //
+    // Wait until all the rows written so far are compressed.
+    //
+    async fn wait_for_flush(&mut self) -> Result<()> {
+        loop {
+            self.encoder.poll()?;
+            if self.encoder.is_flushed() {
+                return Ok(());
+            }
+            self.signal.wait().await;
+        }
+    }
+}
+
+//
+// Both async encoders are the same apart from the output trait, so
+// they're stamped out from this, given functions to write and flush.
+//
+macro_rules! async_encoder {
+    ($(#[$attr:meta])* $name:ident, $write:path, $write_all:path, $flush:path) => {
+        $(#[$attr])*
+        pub struct $name<'a, W: $write + Unpin> {
+            core: AsyncCore<'a>,
+            output: W,
+        }
+
+        impl<'a, W: $write + Unpin> $name<'a, W> {
+            /// Creates a new encoder instance with the given async
+            /// output sink and options.
+            pub fn new(write: W, options: &Options<'a>) -> $name<'a, W> {
+                $name {
+                    core: AsyncCore::new(options),
+                    output: write,
+                }
+            }
+
+            async fn write_output(&mut self) -> Result<()> {
+                let buffer = self.core.take_output();
+                if !buffer.is_empty() {
+                    $write_all(&mut self.output, &buffer).await?;
+                }
+                self.core.return_output(buffer);
+                Ok(())
+            }
+
+            /// Write the PNG signature and header chunk.
+            pub async fn write_header(&mut self, header: &Header) -> Result<()> {
+                self.core.encoder.write_header(header)?;
+                self.write_output().await
+            }
+
+            /// Write an indexed-color palette as a PLTE chunk.
+            pub async fn write_palette(&mut self, palette: &[u8]) -> Result<()> {
+                self.core.encoder.write_palette(palette)?;
+                self.write_output().await
+            }
+
+            /// Write a transparency info chunk.
+            pub async fn write_transparency(&mut self, data: &[u8]) -> Result<()> {
+                self.core.encoder.write_transparency(data)?;
+                self.write_output().await
+            }
+
+            /// Write a custom ancillary chunk to the output stream.
+            pub async fn write_chunk(&mut self, tag: &[u8], data: &[u8]) -> Result<()> {
+                self.core.encoder.write_chunk(tag, data)?;
+                self.write_output().await
+            }
+
+            /// Load one or more rows of input data into the encoder's buffers,
+            /// waiting for room if the threads are busy.
+            pub async fn write_image_rows(&mut self, buf: &[u8]) -> Result<()> {
+                let stride = self.core.encoder.stride();
+                if stride == 0 || buf.len() % stride != 0 {
+                    // Let the encoder report the error.
+                    return self.core.encoder.write_image_rows(buf);
+                }
+                for row in buf.chunks(stride) {
+                    self.core.wait_for_room().await?;
+                    self.core.encoder.write_image_rows(row)?;
+                    self.write_output().await?;
+                }
+                Ok(())
+            }
+
+            /// Wait for all in-progress data to be compressed, and flush
+            /// it to output.
+            pub async fn flush(&mut self) -> Result<()> {
+                self.core.wait_for_flush().await?;
+                self.write_output().await?;
+                Ok($flush(&mut self.output).await?)
+            }
+
+            /// Finish the image, flush output, and return the output sink.
+            pub async fn finish(mut self) -> Result<W> {
+                self.core.wait_for_flush().await?;
+                let buffer = self.core.encoder.finish()?;
+                $write_all(&mut self.output, &buffer).await?;
+                $flush(&mut self.output).await?;
+                Ok(self.output)
+            }
+        }
+    }
+}
+
+#[cfg(feature="tokio")]
+async fn tokio_write_all<W: tokio::io::AsyncWrite + Unpin>(output: &mut W, buf: &[u8]) -> IoResult {
+    output.write_all(buf).await
+}
+
+#[cfg(feature="tokio")]
+async fn tokio_flush<W: tokio::io::AsyncWrite + Unpin>(output: &mut W) -> IoResult {
+    output.flush().await
+}
+
+#[cfg(feature="tokio")]
+async_encoder! {
+    /// An encoder writing to a Tokio AsyncWrite output, whose methods
+    /// return futures instead of blocking while waiting on the thread pool
+    /// or the output.
+    ///
+    /// Methods have the same requirements as their Encoder counterparts.
+    AsyncEncoder, tokio::io::AsyncWrite, tokio_write_all, tokio_flush
+}
+
+#[cfg(feature="futures")]
+async fn futures_write_all<W: futures_io::AsyncWrite + Unpin>(output: &mut W, mut buf: &[u8]) -> IoResult {
+    while !buf.is_empty() {
+        let n = poll_fn(|cx| Pin::new(&mut *output).poll_write(cx, buf)).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer"));
+        }
+        buf = &buf[n ..];
+    }
+    Ok(())
+}
+
+#[cfg(feature="futures")]
+async fn futures_flush<W: futures_io::AsyncWrite + Unpin>(output: &mut W) -> IoResult {
+    poll_fn(|cx| Pin::new(&mut *output).poll_flush(cx)).await
+}
+
+#[cfg(feature="futures")]
+async_encoder! {
+    /// An encoder writing to a futures AsyncWrite output, for async-std
+    /// and other non-Tokio runtimes. Works the same as AsyncEncoder.
+    FuturesEncoder, futures_io::AsyncWrite, futures_write_all, futures_flush
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+
+    use super::super::{ColorType, Header};
+    use super::super::encoder::{Encoder, Options};
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // Minimal executor, so the tests don't need a runtime.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    fn test_image() -> (Header, Vec<u8>) {
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let data = (0 .. 640 * 480 * 3).map(|i| (i % 251) as u8).collect();
+        (header, data)
+    }
+
+    fn sync_encode(options: &Options) -> Vec<u8> {
+        let (header, data) = test_image();
+        let mut encoder = Encoder::new(Vec::new(), options);
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows(&data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[cfg(feature="tokio")]
+    #[test]
+    fn tokio_matches_sync() {
+        use super::AsyncEncoder;
+
+
+        let mut options = Options::new();
+        options.set_chunk_size(65536).unwrap();
+        options.set_max_inflight_chunks(Some(2)).unwrap();
+
+        let (header, data) = test_image();
+        let output = block_on(async {
+            let mut encoder = AsyncEncoder::new(Vec::<u8>::new(), &options);
+            encoder.write_header(&header).await?;
+            encoder.write_image_rows(&data).await?;
+            encoder.finish().await
+        }).unwrap();
+        assert!(output == sync_encode(&options));
+    }
+
+    #[cfg(feature="futures")]
+    #[test]
+    fn futures_matches_sync() {
+        use super::FuturesEncoder;
+
+        let mut options = Options::new();
+        options.set_chunk_size(65536).unwrap();
+        options.set_streaming(true).unwrap();
+
+        let (header, data) = test_image();
+        let output = block_on(async {
+            let mut encoder = FuturesEncoder::new(Vec::<u8>::new(), &options);
+            encoder.write_header(&header).await?;
+            encoder.write_image_rows(&data).await?;
+            encoder.flush().await?;
+            encoder.finish().await
+        }).unwrap();
+        assert!(output == sync_encode(&options));
+    }
+}
@@ -23,10 +23,28 @@
 // THE SOFTWARE.
 //
 
+use std::cmp;
 use std::convert::TryFrom;
-use std::fs::File;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::fs::{File, FileTimes, OpenOptions};
 use std::io;
-use std::io::{Error, ErrorKind, Write};
+use std::io::{Error, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+//
+// WASI and the like have no threads to spawn, so the CLI works
+// through its files and trials on the main thread there.
+//
+const NO_THREADS: bool = cfg!(all(target_arch="wasm32", not(target_feature="atomics")));
 
 // CLI options
 extern crate clap;
@@ -35,8 +53,23 @@ use clap::{Arg, ArgMatches, Command};
 // For reading an existing file
 extern crate png;
 
+// For reading other formats
+#[cfg(feature="image")]
+extern crate image;
+
+// For batch mode on shells that don't expand wildcards
+extern crate glob;
+
+// For default settings from ~/.config/mtpng.toml
+extern crate toml;
+
+// For keeping decoded pixels off the heap
+extern crate memmap2;
+use memmap2::{Mmap, MmapMut};
+
 extern crate rayon;
 use rayon::{ThreadPool, ThreadPoolBuilder};
+use rayon::prelude::*;
 
 // For timing!
 extern crate time;
@@ -45,14 +78,146 @@ use time::OffsetDateTime;
 // Hey that's us!
 extern crate mtpng;
 use mtpng::{ColorType, CompressionLevel, Header};
+use mtpng::Mode;
 use mtpng::Mode::{Adaptive, Fixed};
-use mtpng::encoder::{Encoder, Options};
+use mtpng::encoder::{Encoder, Options, Stats};
+use mtpng::validate::{validate, Finding, Severity};
 use mtpng::Strategy;
 use mtpng::Filter;
 
 pub fn err(payload: &str) -> Error
 {
-    Error::new(ErrorKind::Other, payload)
+    Error::other(payload)
+}
+
+//
+// An invalid or unsupported option.
+//
+fn usage(payload: &str) -> Error
+{
+    Error::new(io::ErrorKind::InvalidInput, payload)
+}
+
+//
+// An input file that can't be read as an image.
+//
+fn bad_input(payload: &str) -> Error
+{
+    Error::new(io::ErrorKind::InvalidData, payload)
+}
+
+//
+// Kinds of failure, with exit codes for scripts to tell them apart.
+// Usage errors from clap also exit with 2. diff exits with 5 when
+// the images differ.
+//
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Failure {
+    Internal = 1,
+    Usage = 2,
+    Input = 3,
+    Io = 4,
+    Differ = 5,
+}
+
+impl Failure {
+    fn of(e: &Error) -> Failure {
+        if let Some(reported) = e.get_ref().and_then(|inner| inner.downcast_ref::<Reported>()) {
+            return reported.0;
+        }
+        if let Some(e) = e.get_ref().and_then(|inner| inner.downcast_ref::<mtpng::Error>()) {
+            return match e {
+                mtpng::Error::Io(e) => Failure::of(e),
+                mtpng::Error::InvalidOptions(_) | mtpng::Error::InvalidText(_) => Failure::Usage,
+                mtpng::Error::WorkerFailed(_) | mtpng::Error::VerificationFailed(_) |
+                mtpng::Error::Cancelled | mtpng::Error::TimedOut => Failure::Internal,
+                _ => Failure::Input,
+            };
+        }
+        match e.kind() {
+            io::ErrorKind::InvalidInput => Failure::Usage,
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => Failure::Input,
+            io::ErrorKind::Other => Failure::Internal,
+            _ => Failure::Io,
+        }
+    }
+
+    //
+    // The exit code for a batch is that of its failures if they're
+    // all alike, or internal if they're mixed.
+    //
+    fn merge(failure: Option<Failure>, next: Failure) -> Failure {
+        match failure {
+            Some(kind) if kind != next => Failure::Internal,
+            _ => next,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Failure::Internal => "internal",
+            Failure::Usage => "usage",
+            Failure::Input => "input",
+            Failure::Io => "io",
+            Failure::Differ => "differ",
+        }
+    }
+}
+
+//
+// Failures that have already been reported, leaving only their
+// exit code and perhaps a summary.
+//
+#[derive(Debug)]
+struct Reported(Failure, String);
+
+impl fmt::Display for Reported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.1)
+    }
+}
+
+impl std::error::Error for Reported {}
+
+impl fmt::Debug for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+//
+// Report a failure on stderr, as a tab-separated line of kind, file,
+// and message with --quiet, and as JSON on stdout with --json.
+//
+fn report_failure(args: &ArgMatches,
+                  progress: &ProgressBar,
+                  input: Option<&Path>,
+                  output: Option<&Path>,
+                  e: &Error)
+{
+    let failure = Failure::of(e);
+    let message = e.to_string();
+    if let (true, Some(input)) = (args.is_present("json"), input) {
+        let output = output.map_or(String::new(), |output| {
+            format!(",\"output\":{}", json_string(&output.to_string_lossy()))
+        });
+        // Best effort, as stdout may be what failed.
+        writeln!(io::stdout(), "{{\"input\":{}{},\"error\":{{\"kind\":\"{}\",\"code\":{},\"message\":{}}}}}",
+                 json_string(&input.to_string_lossy()),
+                 output,
+                 failure.name(),
+                 failure as i32,
+                 json_string(&message)).ok();
+    }
+    if args.is_present("quiet") {
+        let file = input.map_or("-".into(), |input| input.to_string_lossy());
+        let clean = |s: &str| s.replace(['\t', '\n'], " ");
+        eprintln!("error\t{}\t{}\t{}", failure.name(), clean(&file), clean(&message));
+    } else if let Some(input) = input {
+        progress.println(&format!("Error: {}: {}", input.display(), message));
+    } else {
+        progress.println(&format!("Error: {}", message));
+    }
 }
 
 fn expand(src: &[u8]) -> io::Result<Vec<u8>>
@@ -62,209 +227,3625 @@ fn expand(src: &[u8]) -> io::Result<Vec<u8>>
     Ok(v)
 }
 
+//
+// Decoded pixel data, either on the heap or in a memory-mapped
+// temporary file which the OS can page out under memory pressure.
+//
+enum PixelBuffer {
+    Heap(Vec<u8>),
+    Mapped(TempMap),
+}
+
+impl AsRef<[u8]> for PixelBuffer {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            PixelBuffer::Heap(data) => data,
+            PixelBuffer::Mapped(map) => map.as_ref(),
+        }
+    }
+}
+
+//
+// Shareable handle to a pixel buffer for the encoder.
+//
+#[derive(Clone)]
+struct SharedPixels(Arc<PixelBuffer>);
+
+impl AsRef<[u8]> for SharedPixels {
+    fn as_ref(&self) -> &[u8] {
+        (*self.0).as_ref()
+    }
+}
+
+//
+// Read-only memory map of a temporary file, deleted when done.
+//
+struct TempMap {
+    path: PathBuf,
+    map: Option<Mmap>,
+}
+
+impl TempMap {
+    //
+    // Create a temporary file of the given size and fill it in
+    // through a writable mapping before freezing it.
+    //
+    fn new<F>(len: usize, fill: F) -> io::Result<TempMap>
+        where F: FnOnce(&mut [u8]) -> io::Result<()>
+    {
+        // Several files may be decoded at once in batch mode.
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("mtpng-{}-{}.raw", process_id(), count));
+        let file = OpenOptions::new().read(true)
+                                     .write(true)
+                                     .create_new(true)
+                                     .open(&path)?;
+        let mut temp = TempMap {
+            path,
+            map: None,
+        };
+        file.set_len(len as u64)?;
+
+        // Safety: the file was just created exclusively by us,
+        // so nobody else should be modifying it.
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        fill(&mut map)?;
+        temp.map = Some(map.make_read_only()?);
+        Ok(temp)
+    }
+}
+
+impl AsRef<[u8]> for TempMap {
+    fn as_ref(&self) -> &[u8] {
+        match self.map {
+            Some(ref map) => map,
+            None => &[],
+        }
+    }
+}
+
+impl Drop for TempMap {
+    fn drop(&mut self) {
+        // Unmap first, as Windows won't delete a mapped file.
+        self.map = None;
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+//
+// Our process ID, to keep temporary file names apart. WASI has no
+// process IDs, and panics asking, so it's zero there; each module
+// instance is sandboxed to the directories it's given anyway.
+//
+fn process_id() -> u32 {
+    if cfg!(target_os="wasi") {
+        0
+    } else {
+        process::id()
+    }
+}
+
 struct Image {
     header: Header,
-    data: Vec<u8>,
+    data: SharedPixels,
     palette: Option<Vec<u8>>,
     transparency: Option<Vec<u8>>,
+    // Ancillary chunks from a PNG source, for --keep-metadata.
+    chunks: Vec<([u8; 4], Vec<u8>)>,
+}
+
+//
+// Open a file for reading, or stdin for "-".
+//
+fn open_input(filename: &Path) -> io::Result<Box<dyn Read>>
+{
+    if filename == Path::new("-") {
+        Ok(Box::new(io::stdin()))
+    } else {
+        Ok(Box::new(File::open(filename)?))
+    }
+}
+
+//
+// Where an image is written: stdout, a file, or with --in-place a
+// replacement for the original.
+//
+enum Output {
+    Stdout(io::BufWriter<io::Stdout>),
+    File(File),
+    Replace(Replacement),
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout(out) => out.write(buf),
+            Output::File(file) => file.write(buf),
+            Output::Replace(replacement) => replacement.file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(out) => out.flush(),
+            Output::File(file) => file.flush(),
+            Output::Replace(replacement) => replacement.file.flush(),
+        }
+    }
+}
+
+impl Output {
+    //
+    // Flush the output once it's complete, putting any replacement
+    // in place of the original.
+    //
+    fn finish(mut self) -> io::Result<()> {
+        self.flush()?;
+        match self {
+            Output::Replace(replacement) => replacement.commit(),
+            _ => Ok(()),
+        }
+    }
+}
+
+//
+// A temporary file in the same directory as the one it replaces, which
+// is renamed over the original only once complete, so the original is
+// never left half-written. It takes the original's permissions, and
+// with --preserve-times its access and modification times. The file is
+// deleted if dropped before then.
+//
+struct Replacement {
+    file: File,
+    temp: PathBuf,
+    target: PathBuf,
+    preserve_times: bool,
+    done: bool,
+}
+
+impl Replacement {
+    fn new(target: &Path, preserve_times: bool) -> io::Result<Replacement> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let name = target.file_name().unwrap_or_default().to_string_lossy();
+        let temp = target.with_file_name(format!(".{}.mtpng-{}-{}.tmp", name, process_id(), count));
+        let file = OpenOptions::new().write(true)
+                                     .create_new(true)
+                                     .open(&temp)?;
+        Ok(Replacement {
+            file,
+            temp,
+            target: target.to_path_buf(),
+            preserve_times,
+            done: false,
+        })
+    }
+
+    fn commit(mut self) -> io::Result<()> {
+        let original = fs::metadata(&self.target)?;
+        // WASI has no permissions to carry over.
+        if !cfg!(target_os="wasi") {
+            self.file.set_permissions(original.permissions())?;
+        }
+        if self.preserve_times {
+            self.file.set_times(FileTimes::new().set_accessed(original.accessed()?)
+                                                .set_modified(original.modified()?))?;
+        }
+        self.file.sync_all()?;
+        fs::rename(&self.temp, &self.target)?;
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl Drop for Replacement {
+    fn drop(&mut self) {
+        if !self.done {
+            fs::remove_file(&self.temp).ok();
+        }
+    }
+}
+
+//
+// Create a file for writing, or use stdout for "-". An existing file
+// is only overwritten with --force.
+//
+fn create_output(args: &ArgMatches, filename: &Path) -> io::Result<Output>
+{
+    let in_context = |e: Error| match e.kind() {
+        io::ErrorKind::AlreadyExists => already_exists(filename),
+        _ => Error::new(e.kind(), format!("{}: {}", filename.display(), e)),
+    };
+    if filename == Path::new("-") {
+        Ok(Output::Stdout(io::BufWriter::new(io::stdout())))
+    } else if args.is_present("in-place") {
+        let replacement = Replacement::new(filename, args.is_present("preserve-times"))
+            .map_err(in_context)?;
+        Ok(Output::Replace(replacement))
+    } else {
+        let file = OpenOptions::new().write(true)
+                                     .create(true)
+                                     .truncate(true)
+                                     .create_new(!args.is_present("force"))
+                                     .open(filename)
+                                     .map_err(in_context)?;
+        Ok(Output::File(file))
+    }
+}
+
+fn already_exists(filename: &Path) -> Error
+{
+    Error::new(io::ErrorKind::AlreadyExists,
+               format!("output {} already exists; use --force to overwrite it", filename.display()))
+}
+
+//
+// Check before converting that the output won't overwrite an existing
+// file without --force, and isn't the input, which only --in-place
+// may replace safely.
+//
+fn check_output(args: &ArgMatches, job: &Job) -> io::Result<()>
+{
+    if job.output == Path::new("-") || args.is_present("in-place") ||
+       fs::symlink_metadata(&job.output).is_err() {
+        return Ok(());
+    }
+    if let (Ok(input), Ok(output)) = (fs::canonicalize(&job.input), fs::canonicalize(&job.output)) {
+        if input == output && job.input != Path::new("-") {
+            return Err(usage("output is the same file as the input; use --in-place to replace it"));
+        }
+    }
+    if !args.is_present("force") {
+        return Err(already_exists(&job.output));
+    }
+    Ok(())
+}
+
+//
+// Decoded pixels go on the heap, or in a temporary file with --mmap.
+//
+fn pixel_buffer<F>(len: usize, mmap: bool, fill: F) -> io::Result<PixelBuffer>
+    where F: FnOnce(&mut [u8]) -> io::Result<()>
+{
+    if mmap {
+        Ok(PixelBuffer::Mapped(TempMap::new(len, fill)?))
+    } else {
+        let mut data = vec![0u8; len];
+        fill(&mut data)?;
+        Ok(PixelBuffer::Heap(data))
+    }
 }
 
-fn read_png(filename: &str)
+const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+//
+// Read an image file, which may be in formats other than PNG when
+// built with the image feature. Formats are told apart by their
+// contents, so this works on stdin too.
+//
+fn read_image(filename: &Path, mmap: bool)
     -> io::Result<Image>
 {
-    use png::Decoder;
-    use png::Transformations;
+    decode_image(filename, open_input(filename)?, mmap)
+}
 
-    let mut decoder = Decoder::new(File::open(filename)?);
-    decoder.set_transformations(Transformations::IDENTITY);
+fn decode_image<R: Read>(filename: &Path, mut input: R, mmap: bool)
+    -> io::Result<Image>
+{
+    let mut magic = Vec::new();
+    (&mut input).take(PNG_SIGNATURE.len() as u64).read_to_end(&mut magic)?;
+    let is_png = magic == PNG_SIGNATURE;
+    let is_pnm = magic.len() >= 2 && magic[0] == b'P' && (b'1' ..= b'7').contains(&magic[1]);
+    let input = io::Cursor::new(magic).chain(input);
+    if is_png {
+        read_png(input, mmap)
+    } else if is_pnm {
+        read_pnm(input, mmap)
+    } else {
+        read_other(filename, input, mmap)
+    }
+}
 
-    let mut reader = decoder.read_info()?;
-    let info = reader.info();
+//
+// Read a netpbm image: PBM, PGM, or PPM in plain (P1-P3) or raw
+// (P4-P6) form, or PAM (P7). Samples are scaled from the maxval to
+// the full range of 8 or 16 bits, except that PBM bitmaps map to
+// 1-bit greyscale with black and white swapped. Only the first image
+// of a multi-image stream is read.
+//
+// http://netpbm.sourceforge.net/doc/pnm.html
+//
+fn read_pnm<R: Read>(mut input: R, mmap: bool)
+    -> io::Result<Image>
+{
+    let mut data = Vec::new();
+    input.read_to_end(&mut data)?;
+    let format = data[1];
+    let mut pnm = PnmReader {
+        data: &data,
+        pos: 2,
+    };
 
-    let mut header = Header::new();
-    header.set_size(info.width, info.height)?;
-    header.set_color(ColorType::try_from(info.color_type as u8)?,
-                     info.bit_depth as u8)?;
+    let (width, height, channels, maxval) = if format == b'7' {
+        pnm.pam_header()?
+    } else {
+        let width = pnm.number()?;
+        let height = pnm.number()?;
+        let maxval = match format {
+            b'1' | b'4' => 1,
+            _ => pnm.number()?,
+        };
+        let channels = match format {
+            b'3' | b'6' => 3,
+            _ => 1,
+        };
+        // Raw rasters start after a single whitespace character.
+        if (b'4' ..= b'6').contains(&format) {
+            pnm.bytes(1)?;
+        }
+        (width, height, channels, maxval)
+    };
+    if maxval == 0 || maxval > 65535 {
+        return Err(bad_input("Invalid PNM maxval"));
+    }
 
-    let palette = match info.palette {
-        Some(ref cow) => Some(expand(&cow[..])?),
-        None => None,
+    let plain = (b'1' ..= b'3').contains(&format);
+    let bitmap = format == b'1' || format == b'4';
+    let color_type = match channels {
+        1 => ColorType::Greyscale,
+        2 => ColorType::GreyscaleAlpha,
+        3 => ColorType::Truecolor,
+        4 => ColorType::TruecolorAlpha,
+        _ => return Err(bad_input("PAM depth must be 1 to 4")),
     };
-    let transparency = match info.trns {
-        Some(ref cow) => Some(expand(&cow[..])?),
-        None => None,
+    let depth = if bitmap {
+        1
+    } else if maxval < 256 {
+        8
+    } else {
+        16
     };
 
-    let mut data = vec![0u8; reader.output_buffer_size()];
-    reader.next_frame(&mut data)?;
+    let mut header = Header::new();
+    header.set_size(width, height)?;
+    header.set_color(color_type, depth)?;
+    let stride = header.stride();
+    let len = stride.checked_mul(height as usize).ok_or_else(|| bad_input("Image is too large"))?;
+
+    let data = pixel_buffer(len, mmap, |buf| {
+        if bitmap {
+            for row in buf.chunks_mut(stride) {
+                if plain {
+                    for x in 0 .. width as usize {
+                        if pnm.bit()? == 0 {
+                            row[x / 8] |= 0x80 >> (x % 8);
+                        }
+                    }
+                } else {
+                    for (dest, src) in row.iter_mut().zip(pnm.bytes(stride)?) {
+                        *dest = !src;
+                    }
+                }
+            }
+        } else {
+            let full = if depth == 8 { 255 } else { 65535 };
+            for dest in buf.chunks_mut(usize::from(depth / 8)) {
+                let value = if plain {
+                    pnm.number()?
+                } else if depth == 8 {
+                    u32::from(pnm.bytes(1)?[0])
+                } else {
+                    let bytes = pnm.bytes(2)?;
+                    u32::from(bytes[0]) << 8 | u32::from(bytes[1])
+                };
+                if value > maxval {
+                    return Err(bad_input("PNM sample is above maxval"));
+                }
+                let scaled = (value * full + maxval / 2) / maxval;
+                if depth == 8 {
+                    dest[0] = scaled as u8;
+                } else {
+                    dest.copy_from_slice(&(scaled as u16).to_be_bytes());
+                }
+            }
+        }
+        Ok(())
+    })?;
 
     Ok(Image {
         header,
-        data,
-        palette,
-        transparency
+        data: SharedPixels(Arc::new(data)),
+        palette: None,
+        transparency: None,
+        chunks: Vec::new(),
     })
 }
 
-fn write_png(pool: &ThreadPool,
-             args: &ArgMatches,
-             filename: &str,
-             image: &Image)
-   -> io::Result<()>
-{
-    let writer = File::create(filename)?;
-    let mut options = Options::new();
-
-    // Encoding options
-    options.set_thread_pool(pool)?;
+struct PnmReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
 
-    match args.value_of("chunk-size") {
-        None    => {},
-        Some(s) => {
-            let n = s.parse::<usize>().map_err(|_e| err("Invalid chunk size"))?;
-            options.set_chunk_size(n)?;
-        },
+impl<'a> PnmReader<'a> {
+    //
+    // Skip whitespace and comments, which run from # to the end of the line.
+    //
+    fn skip_space(&mut self) {
+        while let Some(&c) = self.data.get(self.pos) {
+            if c == b'#' {
+                while self.pos < self.data.len() && self.data[self.pos] != b'\n' {
+                    self.pos += 1;
+                }
+            } else if c.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
     }
 
-    match args.value_of("filter") {
-        None             => {},
-        Some("adaptive") => options.set_filter_mode(Adaptive)?,
-        Some("none")     => options.set_filter_mode(Fixed(Filter::None))?,
-        Some("up")       => options.set_filter_mode(Fixed(Filter::Up))?,
-        Some("sub")      => options.set_filter_mode(Fixed(Filter::Sub))?,
-        Some("average")  => options.set_filter_mode(Fixed(Filter::Average))?,
-        Some("paeth")    => options.set_filter_mode(Fixed(Filter::Paeth))?,
-        _                => return Err(err("Unsupported filter type")),
+    fn number(&mut self) -> io::Result<u32> {
+        self.skip_space();
+        let start = self.pos;
+        while self.data.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.data[start .. self.pos]).unwrap()
+            .parse::<u32>()
+            .map_err(|_e| bad_input("Invalid PNM file"))
     }
 
-    match args.value_of("level") {
-        None            => {},
-        Some("default") => options.set_compression_level(CompressionLevel::Default)?,
-        Some("1")       => options.set_compression_level(CompressionLevel::Fast)?,
-        Some("9")       => options.set_compression_level(CompressionLevel::High)?,
-        _               => return Err(err("Unsupported compression level (try default, 1, or 9)")),
+    //
+    // Plain PBM pixels are single digits, which needn't be separated.
+    //
+    fn bit(&mut self) -> io::Result<u8> {
+        self.skip_space();
+        match self.data.get(self.pos) {
+            Some(&c) if c == b'0' || c == b'1' => {
+                self.pos += 1;
+                Ok(c - b'0')
+            },
+            _ => Err(bad_input("Invalid PNM file")),
+        }
     }
 
-    match args.value_of("strategy") {
-        None             => {},
-        Some("auto")     => options.set_strategy_mode(Adaptive)?,
-        Some("default")  => options.set_strategy_mode(Fixed(Strategy::Default))?,
-        Some("filtered") => options.set_strategy_mode(Fixed(Strategy::Filtered))?,
-        Some("huffman")  => options.set_strategy_mode(Fixed(Strategy::HuffmanOnly))?,
-        Some("rle")      => options.set_strategy_mode(Fixed(Strategy::Rle))?,
-        Some("fixed")    => options.set_strategy_mode(Fixed(Strategy::Fixed))?,
-        _                => return Err(err("Invalid compression strategy mode"))?,
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(bad_input("PNM file is truncated"));
+        }
+        self.pos += len;
+        Ok(&self.data[self.pos - len .. self.pos])
     }
 
-    match args.value_of("streaming") {
-        None        => {},
-        Some("yes") => options.set_streaming(true)?,
-        Some("no")  => options.set_streaming(false)?,
-        _           => return Err(err("Invalid streaming mode, try yes or no."))
+    //
+    // PAM headers are lines of keywords and values, up to ENDHDR.
+    // Returns the width, height, depth, and maxval.
+    //
+    fn pam_header(&mut self) -> io::Result<(u32, u32, u32, u32)> {
+        let (mut width, mut height, mut depth, mut maxval) = (None, None, None, None);
+        loop {
+            let end = self.data[self.pos ..].iter().position(|&c| c == b'\n')
+                .ok_or_else(|| bad_input("PAM header is truncated"))?;
+            let line = String::from_utf8_lossy(&self.data[self.pos .. self.pos + end]).into_owned();
+            self.pos += end + 1;
+
+            let mut words = line.split_whitespace();
+            let field = match words.next() {
+                Some("ENDHDR") => break,
+                Some("WIDTH") => &mut width,
+                Some("HEIGHT") => &mut height,
+                Some("DEPTH") => &mut depth,
+                Some("MAXVAL") => &mut maxval,
+                _ => continue,
+            };
+            let value = words.next().and_then(|word| word.parse::<u32>().ok());
+            *field = Some(value.ok_or_else(|| bad_input("Invalid PAM header"))?);
+        }
+        match (width, height, depth, maxval) {
+            (Some(width), Some(height), Some(depth), Some(maxval)) => Ok((width, height, depth, maxval)),
+            _ => Err(bad_input("PAM header is missing a field")),
+        }
     }
+}
 
-    let mut encoder = Encoder::new(writer, &options);
+#[cfg(not(feature="image"))]
+fn read_other<R: Read>(_filename: &Path, _input: R, _mmap: bool)
+    -> io::Result<Image>
+{
+    Err(bad_input("Input must be a PNG file; build with the image feature for other formats"))
+}
 
-    // Image data
-    encoder.write_header(&image.header)?;
-    match &image.palette {
-        Some(v) => encoder.write_palette(v)?,
-        None => {},
-    }
-    match &image.transparency {
-        Some(v) => encoder.write_transparency(v)?,
-        None => {},
+//
+// Decode JPEG, GIF, WebP, TIFF, BMP, or TGA input with the image
+// crate, which handles bottom-up rows and BGR channel order. TGA
+// files have no signature, so are recognized by their extension.
+// 16-bit samples are stored big-endian in PNG, and any other
+// sample types are converted to 16-bit RGBA.
+//
+#[cfg(feature="image")]
+fn read_other<R: Read>(filename: &Path, mut input: R, mmap: bool)
+    -> io::Result<Image>
+{
+    use image::DynamicImage;
+
+    fn big_endian(samples: &[u16]) -> Vec<u8> {
+        samples.iter().flat_map(|sample| sample.to_be_bytes()).collect()
     }
-    encoder.write_image_rows(&image.data)?;
-    encoder.finish()?;
 
-    Ok(())
-}
+    let mut data = Vec::new();
+    input.read_to_end(&mut data)?;
+    let decoded = match image::ImageFormat::from_path(filename) {
+        Ok(image::ImageFormat::Tga) => image::load_from_memory_with_format(&data, image::ImageFormat::Tga),
+        _ => image::load_from_memory(&data),
+    }.map_err(|e| match e {
+        image::ImageError::IoError(e) => e,
+        e => bad_input(&e.to_string()),
+    })?;
 
-fn doit(args: ArgMatches) -> io::Result<()> {
-    let threads = match args.value_of("threads") {
-        None    => 0, // Means default
-        Some(s) => {
-            s.parse::<usize>().map_err(|_e| err("invalid threads"))?
-        },
+    let mut header = Header::new();
+    header.set_size(decoded.width(), decoded.height())?;
+    let (color_type, depth, samples) = match decoded {
+        DynamicImage::ImageLuma8(image) => (ColorType::Greyscale, 8, image.into_raw()),
+        DynamicImage::ImageLumaA8(image) => (ColorType::GreyscaleAlpha, 8, image.into_raw()),
+        DynamicImage::ImageRgb8(image) => (ColorType::Truecolor, 8, image.into_raw()),
+        DynamicImage::ImageRgba8(image) => (ColorType::TruecolorAlpha, 8, image.into_raw()),
+        DynamicImage::ImageLuma16(image) => (ColorType::Greyscale, 16, big_endian(&image)),
+        DynamicImage::ImageLumaA16(image) => (ColorType::GreyscaleAlpha, 16, big_endian(&image)),
+        DynamicImage::ImageRgb16(image) => (ColorType::Truecolor, 16, big_endian(&image)),
+        DynamicImage::ImageRgba16(image) => (ColorType::TruecolorAlpha, 16, big_endian(&image)),
+        other => (ColorType::TruecolorAlpha, 16, big_endian(&other.to_rgba16())),
     };
+    header.set_color(color_type, depth)?;
 
-    let pool = ThreadPoolBuilder::new().num_threads(threads)
-                                       .build()
-                                       .map_err(|e| err(&e.to_string()))?;
-    eprintln!("Using {} threads", pool.current_num_threads());
+    let data = if mmap {
+        pixel_buffer(samples.len(), true, |buf| {
+            buf.copy_from_slice(&samples);
+            Ok(())
+        })?
+    } else {
+        PixelBuffer::Heap(samples)
+    };
 
-    let reps = match args.value_of("repeat") {
-        Some(s) => {
-            s.parse::<usize>().map_err(|_e| err("invalid repeat"))?
-        },
-        None => 1,
+    Ok(Image {
+        header,
+        data: SharedPixels(Arc::new(data)),
+        palette: None,
+        transparency: None,
+        chunks: Vec::new(),
+    })
+}
+
+//
+// Layout of raw pixel input, from --size and --format.
+//
+struct RawFormat {
+    width: u32,
+    height: u32,
+    color_type: ColorType,
+    depth: u8,
+    // Blue first, as from many GPUs and Windows APIs.
+    bgr: bool,
+    // 16-bit samples least significant byte first.
+    little_endian: bool,
+}
+
+fn raw_format(args: &ArgMatches) -> io::Result<Option<RawFormat>>
+{
+    if !args.is_present("raw") {
+        return Ok(None);
+    }
+
+    let size = args.value_of("size").ok_or_else(|| usage("--raw needs --size"))?;
+    let (width, height) = size.split_once('x')
+        .and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)))
+        .ok_or_else(|| usage("Invalid size, try WIDTHxHEIGHT"))?;
+
+    let format = args.value_of("format").ok_or_else(|| usage("--raw needs --format"))?;
+    let (color_type, depth, bgr, little_endian) = match format {
+        "gray8"     => (ColorType::Greyscale, 8, false, false),
+        "gray16be"  => (ColorType::Greyscale, 16, false, false),
+        "gray16le"  => (ColorType::Greyscale, 16, false, true),
+        "graya8"    => (ColorType::GreyscaleAlpha, 8, false, false),
+        "graya16be" => (ColorType::GreyscaleAlpha, 16, false, false),
+        "graya16le" => (ColorType::GreyscaleAlpha, 16, false, true),
+        "rgb8"      => (ColorType::Truecolor, 8, false, false),
+        "bgr8"      => (ColorType::Truecolor, 8, true, false),
+        "rgb16be"   => (ColorType::Truecolor, 16, false, false),
+        "rgb16le"   => (ColorType::Truecolor, 16, false, true),
+        "rgba8"     => (ColorType::TruecolorAlpha, 8, false, false),
+        "bgra8"     => (ColorType::TruecolorAlpha, 8, true, false),
+        "rgba16be"  => (ColorType::TruecolorAlpha, 16, false, false),
+        "rgba16le"  => (ColorType::TruecolorAlpha, 16, false, true),
+        _           => return Err(usage("Unsupported raw format")),
     };
 
-    // input and output are guaranteed to be present
-    let infile = args.value_of("input").unwrap();
-    let outfile = args.value_of("output").unwrap();
+    Ok(Some(RawFormat {
+        width,
+        height,
+        color_type,
+        depth,
+        bgr,
+        little_endian,
+    }))
+}
 
-    println!("{} -> {}", infile, outfile);
-    let image = read_png(infile)?;
+//
+// Read raw pixels with no header, packed rows from top to bottom,
+// converting to PNG's RGB order and big-endian samples.
+//
+fn read_raw(filename: &Path, format: &RawFormat, mmap: bool)
+    -> io::Result<Image>
+{
+    let mut header = Header::new();
+    header.set_size(format.width, format.height)?;
+    header.set_color(format.color_type, format.depth)?;
+    let len = header.stride().checked_mul(format.height as usize)
+        .ok_or_else(|| bad_input("Image is too large"))?;
 
-    for _i in 0 .. reps {
-        let start_time = OffsetDateTime::now_utc();
-        write_png(&pool, &args, outfile, &image)?;
-        let delta = OffsetDateTime::now_utc() - start_time;
+    let mut input = open_input(filename)?;
+    let data = pixel_buffer(len, mmap, |buf| {
+        input.read_exact(buf).map_err(|_e| bad_input("Raw input is shorter than --size and --format need"))?;
+        if format.little_endian {
+            for sample in buf.chunks_mut(2) {
+                sample.swap(0, 1);
+            }
+        }
+        if format.bgr {
+            // Only 8-bit formats come in this order.
+            for pixel in buf.chunks_mut(header.bytes_per_pixel()) {
+                pixel.swap(0, 2);
+            }
+        }
+        Ok(())
+    })?;
 
-        println!("Done in {} ms", (delta.as_seconds_f64() * 1000.0).round());
-    }
+    Ok(Image {
+        header,
+        data: SharedPixels(Arc::new(data)),
+        palette: None,
+        transparency: None,
+        chunks: Vec::new(),
+    })
+}
 
-    Ok(())
+// Ancillary chunks that stay valid when the image is re-encoded.
+const KNOWN_CHUNKS: [&[u8; 4]; 20] = [
+    b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB", b"cICP", b"mDCV", b"cLLI",
+    b"bKGD", b"hIST", b"pHYs", b"sPLT", b"eXIf", b"tEXt", b"zTXt", b"iTXt",
+    b"tIME", b"oFFs", b"pCAL", b"sCAL",
+];
+
+// Those that must come before PLTE.
+const BEFORE_PALETTE: [&[u8; 4]; 8] = [
+    b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB", b"cICP", b"mDCV", b"cLLI",
+];
+
+// Those kept by --strip safe, which affect how the image looks
+// but shouldn't say anything about where it came from.
+const DISPLAY_CHUNKS: [&[u8; 4]; 15] = [
+    b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB", b"cICP", b"mDCV", b"cLLI",
+    b"bKGD", b"hIST", b"pHYs", b"sPLT", b"oFFs", b"pCAL", b"sCAL",
+];
+
+//
+// Whether to copy a chunk, given --keep-metadata and --strip. Any
+// --strip other than all implies copying what it doesn't remove.
+//
+fn keep_chunk(args: &ArgMatches, tag: &[u8; 4]) -> bool
+{
+    match strip_policy(args) {
+        None => args.is_present("keep-metadata"),
+        Some("all") => false,
+        Some("safe") => DISPLAY_CHUNKS.contains(&tag),
+        Some(list) => !list.split(',').any(|name| name.as_bytes() == tag),
+    }
 }
 
-pub fn main() {
-    let matches = Command::new("mtpng parallel PNG encoder")
-        .version("0.4.0")
-        .author("Brion Vibber <brion@pobox.com>")
-        .about("Re-encodes PNG images using multiple CPU cores to exercise the mtpng library.")
-        .arg(Arg::new("chunk-size")
+//
+// The --strip policy, if any. A default from the config file or
+// environment gives way to --keep-metadata on the command line.
+//
+fn strip_policy(args: &ArgMatches) -> Option<&str>
+{
+    if args.is_present("keep-metadata") && args.occurrences_of("strip") == 0 {
+        None
+    } else {
+        args.value_of("strip")
+    }
+}
+
+fn check_strip(value: &str) -> Result<(), String>
+{
+    let valid = value == "all" || value == "safe" || value.split(',').all(|name| {
+        name.len() == 4 && name.bytes().all(|c| c.is_ascii_alphabetic())
+    });
+    if valid {
+        Ok(())
+    } else {
+        Err("try all, safe, or a list of chunk names such as eXIf,tEXt".to_string())
+    }
+}
+
+//
+// Find the ancillary chunks that can be copied to the output: the
+// known ones above, as the pixels are unchanged, and unknown ones
+// marked safe to copy by a lowercase fourth letter. tRNS is copied
+// separately.
+//
+// https://www.w3.org/TR/PNG/#14Ordering
+//
+fn copyable_chunks(data: &[u8]) -> Vec<([u8; 4], Vec<u8>)>
+{
+    let mut chunks = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    while data.len() - pos >= 12 {
+        let len = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let mut tag = [0u8; 4];
+        tag.copy_from_slice(&data[pos + 4 .. pos + 8]);
+        if &tag == b"IEND" || data.len() - pos - 12 < len {
+            break;
+        }
+        if is_copyable(&tag) {
+            chunks.push((tag, data[pos + 8 .. pos + 8 + len].to_vec()));
+        }
+        pos += 12 + len;
+    }
+    chunks
+}
+
+fn is_copyable(tag: &[u8; 4]) -> bool
+{
+    let ancillary = tag[0].is_ascii_lowercase() && tag[2].is_ascii_uppercase();
+    let copyable = KNOWN_CHUNKS.contains(&tag) || tag[3].is_ascii_lowercase();
+    ancillary && copyable && tag != b"tRNS"
+}
+
+//
+// Find the chunks to copy from a PNG file without reading it all in,
+// by seeking past the others, for --low-memory.
+//
+fn scan_chunks(filename: &Path) -> io::Result<Vec<([u8; 4], Vec<u8>)>>
+{
+    let mut file = io::BufReader::new(File::open(filename)?);
+    let mut chunks = Vec::new();
+    file.seek(SeekFrom::Start(PNG_SIGNATURE.len() as u64))?;
+    loop {
+        let mut head = [0u8; 8];
+        if file.read_exact(&mut head).is_err() {
+            break;
+        }
+        let len = u32::from_be_bytes([head[0], head[1], head[2], head[3]]);
+        let tag = [head[4], head[5], head[6], head[7]];
+        if &tag == b"IEND" {
+            break;
+        }
+        if is_copyable(&tag) {
+            let mut data = vec![0u8; len as usize];
+            file.read_exact(&mut data)?;
+            chunks.push((tag, data));
+            file.seek_relative(4)?;
+        } else {
+            file.seek_relative(i64::from(len) + 4)?;
+        }
+    }
+    Ok(chunks)
+}
+
+fn decoding_error(e: png::DecodingError) -> Error
+{
+    match e {
+        png::DecodingError::IoError(e) => e,
+        e => bad_input(&e.to_string()),
+    }
+}
+
+fn read_png<R: Read>(mut input: R, mmap: bool)
+    -> io::Result<Image>
+{
+    use png::Decoder;
+    use png::Transformations;
+
+    // Compressed data is small next to the pixels, so keep it
+    // in memory to find the chunks to copy.
+    let mut file = Vec::new();
+    input.read_to_end(&mut file)?;
+    let chunks = copyable_chunks(&file);
+
+    let mut decoder = Decoder::new(io::Cursor::new(&file));
+    decoder.set_transformations(Transformations::IDENTITY);
+
+    let mut reader = decoder.read_info().map_err(decoding_error)?;
+    let info = reader.info();
+
+    let mut header = Header::new();
+    header.set_size(info.width, info.height)?;
+    header.set_color(ColorType::try_from(info.color_type as u8)?,
+                     info.bit_depth as u8)?;
+
+    let palette = match info.palette {
+        Some(ref cow) => Some(expand(&cow[..])?),
+        None => None,
+    };
+    let transparency = match info.trns {
+        Some(ref cow) => Some(expand(&cow[..])?),
+        None => None,
+    };
+
+    let data = pixel_buffer(reader.output_buffer_size(), mmap, |buf| {
+        reader.next_frame(buf).map_err(decoding_error)?;
+        Ok(())
+    })?;
+
+    Ok(Image {
+        header,
+        data: SharedPixels(Arc::new(data)),
+        palette,
+        transparency,
+        chunks,
+    })
+}
+
+//
+// Build a pHYs chunk from --dpi or --ppm, either one number or
+// separate horizontal and vertical ones as in 300x600. PNG stores
+// pixels per meter, so dots per inch are converted and rounded.
+//
+fn physical_size(args: &ArgMatches) -> io::Result<Option<Vec<u8>>>
+{
+    let (value, per_meter) = match (args.value_of("dpi"), args.value_of("ppm")) {
+        (Some(dpi), _) => (dpi, 1.0 / 0.0254),
+        (None, Some(ppm)) => (ppm, 1.0),
+        (None, None) => return Ok(None),
+    };
+    let parse = |s: &str| {
+        s.parse::<f64>().ok()
+            .map(|n| (n * per_meter).round())
+            .filter(|&n| n >= 1.0 && n <= f64::from(i32::MAX))
+            .map(|n| n as u32)
+            .ok_or_else(|| usage("Invalid resolution, try 300 or 300x600"))
+    };
+    let (x, y) = match value.split_once('x') {
+        Some((x, y)) => (parse(x)?, parse(y)?),
+        None => (parse(value)?, parse(value)?),
+    };
+
+    let mut data = Vec::with_capacity(9);
+    data.extend_from_slice(&x.to_be_bytes());
+    data.extend_from_slice(&y.to_be_bytes());
+    data.push(1); // Unit is the meter
+    Ok(Some(data))
+}
+
+//
+// Build a tIME chunk for --timestamp, from SOURCE_DATE_EPOCH if it's
+// set so builds that embed the output can be reproduced, or else the
+// current time.
+//
+// https://reproducible-builds.org/specs/source-date-epoch/
+//
+fn modification_time(args: &ArgMatches) -> io::Result<Option<Vec<u8>>>
+{
+    if !args.is_present("timestamp") {
+        return Ok(None);
+    }
+    let time = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse::<i64>().ok()
+            .and_then(|seconds| OffsetDateTime::from_unix_timestamp(seconds).ok())
+            .filter(|time| (0 ..= 0xffff).contains(&time.year()))
+            .ok_or_else(|| usage("Invalid SOURCE_DATE_EPOCH, which should be seconds since 1970"))?,
+        Err(_) => OffsetDateTime::now_utc(),
+    };
+
+    let mut data = Vec::with_capacity(7);
+    data.extend_from_slice(&(time.year() as u16).to_be_bytes());
+    data.extend_from_slice(&[u8::from(time.month()), time.day(), time.hour(), time.minute(), time.second()]);
+    Ok(Some(data))
+}
+
+//
+// Build color space chunks from --srgb or --gamma. sRGB is written
+// with the gAMA and cHRM values the spec recommends alongside it, for
+// decoders that don't know sRGB. A gamma may be given as stored in
+// PNG, such as 0.45455, or as the display exponent, such as 2.2.
+//
+// https://www.w3.org/TR/PNG/#11sRGB
+//
+fn color_space(args: &ArgMatches) -> io::Result<Vec<([u8; 4], Vec<u8>)>>
+{
+    let mut chunks = Vec::new();
+    if let Some(intent) = args.value_of("srgb") {
+        let intent = match intent {
+            "perceptual" => 0,
+            "relative"   => 1,
+            "saturation" => 2,
+            "absolute"   => 3,
+            _            => return Err(usage("Invalid rendering intent, try perceptual, relative, saturation, or absolute")),
+        };
+        chunks.push((*b"sRGB", vec![intent]));
+        chunks.push((*b"gAMA", 45455u32.to_be_bytes().to_vec()));
+        let chromaticities: [u32; 8] = [31270, 32900, 64000, 33000, 30000, 60000, 15000, 6000];
+        chunks.push((*b"cHRM", chromaticities.iter().flat_map(|v| v.to_be_bytes()).collect()));
+    } else if let Some(gamma) = args.value_of("gamma") {
+        let gamma = gamma.parse::<f64>().ok()
+            .filter(|&g| g > 0.0)
+            .map(|g| if g > 1.0 { 1.0 / g } else { g })
+            .map(|g| (g * 100000.0).round() as u32)
+            .filter(|&g| g > 0)
+            .ok_or_else(|| usage("Invalid gamma, try 2.2 or 0.45455"))?;
+        chunks.push((*b"gAMA", gamma.to_be_bytes().to_vec()));
+    }
+    Ok(chunks)
+}
+
+//
+// Write the image to a file, showing progress through its chunks
+// unless it's one of a batch.
+//
+fn write_png<'a>(pool: &ThreadPool,
+                 args: &'a ArgMatches,
+                 filename: &Path,
+                 image: &Image,
+                 progress: &ProgressBar,
+                 batch: bool)
+   -> io::Result<(Stats, Settings<'a>)>
+{
+    let options = encoder_options(pool, args, args.value_of("level"))?;
+    if let Some(s) = args.value_of("optimize") {
+        let trials = s.parse::<usize>().ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| usage("Invalid optimize trials"))?;
+        return optimize(pool, args, filename, image, &options, trials, progress);
+    }
+
+    let writer = create_output(args, filename)?;
+    let progress = if batch { None } else { Some(progress) };
+    let (writer, stats) = encode(args, image, &options, writer, progress)?;
+    writer.finish()?;
+    Ok((stats, Settings::given(args)))
+}
+
+//
+// Convert a PNG file a batch of rows at a time, for --low-memory, so
+// only those rows and the chunks being compressed are held in memory
+// rather than the whole image. Output is written in streaming mode, a
+// chunk at a time. Returns the image with no pixel data, for reports.
+//
+fn stream_png(pool: &ThreadPool,
+              args: &ArgMatches,
+              job: &Job,
+              progress: &ProgressBar,
+              batch: bool)
+   -> io::Result<(Image, Stats)>
+{
+    use png::Decoder;
+    use png::Transformations;
+
+    if args.is_present("optimize") || args.is_present("color-type") || args.is_present("bit-depth") ||
+       args.is_present("colors") || args.is_present("background") || args.is_present("raw") {
+        return Err(usage("Cannot use --low-memory with --optimize, --raw, or color conversion"));
+    }
+    let keep_metadata = args.is_present("keep-metadata") ||
+                        strip_policy(args).is_some_and(|strip| strip != "all");
+    let chunks = match (job.input == Path::new("-"), keep_metadata) {
+        (true, true) => return Err(usage("Cannot copy metadata from stdin with --low-memory")),
+        (false, true) => scan_chunks(&job.input)?,
+        (_, false) => Vec::new(),
+    };
+
+    let mut input = io::BufReader::new(open_input(&job.input)?);
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic).map_err(|_| bad_input("Input must be a PNG file for --low-memory"))?;
+    if magic != PNG_SIGNATURE {
+        return Err(bad_input("Input must be a PNG file for --low-memory"));
+    }
+    let mut decoder = Decoder::new(io::Cursor::new(magic).chain(input));
+    decoder.set_transformations(Transformations::IDENTITY);
+    let mut reader = decoder.read_info().map_err(decoding_error)?;
+    let info = reader.info();
+    if info.interlaced {
+        return Err(usage("Cannot use --low-memory with interlaced input"));
+    }
+
+    let mut header = Header::new();
+    header.set_size(info.width, info.height)?;
+    header.set_color(ColorType::try_from(info.color_type as u8)?,
+                     info.bit_depth as u8)?;
+    let image = Image {
+        header,
+        data: SharedPixels(Arc::new(PixelBuffer::Heap(Vec::new()))),
+        palette: info.palette.as_ref().map(|cow| cow.to_vec()),
+        transparency: info.trns.as_ref().map(|cow| cow.to_vec()),
+        chunks,
+    };
+
+    let mut options = encoder_options(pool, args, args.value_of("level"))?;
+    options.set_streaming(true)?;
+    options.set_max_inflight_chunks(Some(pool.current_num_threads() * 2))?;
+
+    // Rows are passed on a few megabytes at a time.
+    let stride = header.stride();
+    let rows = cmp::max(1, (4 << 20) / stride);
+    let writer = create_output(args, &job.output)?;
+    let progress = if batch { None } else { Some(progress) };
+    let (writer, stats) = encode_rows(args, &image, &options, writer, progress, |encoder| {
+        let mut buf = Vec::with_capacity(rows * stride);
+        while let Some(row) = reader.next_row().map_err(decoding_error)? {
+            buf.extend_from_slice(row.data());
+            if buf.len() == rows * stride {
+                encoder.write_image_rows(&buf)?;
+                buf.clear();
+            }
+        }
+        if !buf.is_empty() {
+            encoder.write_image_rows(&buf)?;
+        }
+        Ok(())
+    })?;
+    writer.finish()?;
+    Ok((image, stats))
+}
+
+//
+// Names of the filter, strategy, and level used, for reports.
+//
+#[derive(Copy, Clone)]
+struct Settings<'a> {
+    filter: &'a str,
+    strategy: &'a str,
+    level: &'a str,
+}
+
+impl<'a> Settings<'a> {
+    fn given(args: &'a ArgMatches) -> Self {
+        Settings {
+            filter: args.value_of("filter").unwrap_or("adaptive"),
+            strategy: args.value_of("strategy").unwrap_or("auto"),
+            level: args.value_of("level").unwrap_or("default"),
+        }
+    }
+}
+
+impl fmt::Display for Settings<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "filter {}, strategy {}, level {}", self.filter, self.strategy, self.level)
+    }
+}
+
+fn compression_level(s: &str) -> io::Result<CompressionLevel>
+{
+    match s {
+        "default" => Ok(CompressionLevel::Default),
+        _ => s.parse::<u8>().ok()
+            .and_then(|n| CompressionLevel::try_from(n).ok())
+            .ok_or_else(|| usage("Unsupported compression level (try default, or 1-9)")),
+    }
+}
+
+//
+// Encoder options from the command line, with the level given
+// separately as bench can try several.
+//
+fn encoder_options<'a>(pool: &'a ThreadPool, args: &ArgMatches, level: Option<&str>)
+    -> io::Result<Options<'a>>
+{
+    let mut options = Options::new();
+
+    // Encoding options
+    options.set_thread_pool(pool)?;
+
+    match args.value_of("chunk-size") {
+        None    => {},
+        Some(s) => {
+            let n = s.parse::<usize>().map_err(|_e| usage("Invalid chunk size"))?;
+            options.set_chunk_size(n)?;
+        },
+    }
+
+    match args.value_of("filter") {
+        None             => {},
+        Some("adaptive") => options.set_filter_mode(Adaptive)?,
+        Some("none")     => options.set_filter_mode(Fixed(Filter::None))?,
+        Some("up")       => options.set_filter_mode(Fixed(Filter::Up))?,
+        Some("sub")      => options.set_filter_mode(Fixed(Filter::Sub))?,
+        Some("average")  => options.set_filter_mode(Fixed(Filter::Average))?,
+        Some("paeth")    => options.set_filter_mode(Fixed(Filter::Paeth))?,
+        _                => return Err(usage("Unsupported filter type")),
+    }
+
+    if let Some(s) = level {
+        options.set_compression_level(compression_level(s)?)?;
+    }
+
+    match args.value_of("strategy") {
+        None             => {},
+        Some("auto")     => options.set_strategy_mode(Adaptive)?,
+        Some("default")  => options.set_strategy_mode(Fixed(Strategy::Default))?,
+        Some("filtered") => options.set_strategy_mode(Fixed(Strategy::Filtered))?,
+        Some("huffman")  => options.set_strategy_mode(Fixed(Strategy::HuffmanOnly))?,
+        Some("rle")      => options.set_strategy_mode(Fixed(Strategy::Rle))?,
+        Some("fixed")    => options.set_strategy_mode(Fixed(Strategy::Fixed))?,
+        _                => return Err(usage("Invalid compression strategy mode"))?,
+    }
+
+    match args.value_of("streaming") {
+        None        => {},
+        Some("yes") => options.set_streaming(true)?,
+        Some("no")  => options.set_streaming(false)?,
+        _           => return Err(usage("Invalid streaming mode, try yes or no."))
+    }
+
+    Ok(options)
+}
+
+//
+// Encode the image with its chunks to the writer, returning it.
+//
+fn encode<W: Write>(args: &ArgMatches,
+                    image: &Image,
+                    options: &Options,
+                    writer: W,
+                    progress: Option<&ProgressBar>)
+   -> io::Result<(W, Stats)>
+{
+    encode_rows(args, image, options, writer, progress, |encoder| {
+        Ok(encoder.write_image_rows_shared(image.data.clone())?)
+    })
+}
+
+//
+// Encode the image's chunks, with the image data written by the
+// given function, as it's read in bit by bit with --low-memory.
+//
+fn encode_rows<W, F>(args: &ArgMatches,
+                     image: &Image,
+                     options: &Options,
+                     writer: W,
+                     progress: Option<&ProgressBar>,
+                     write_rows: F)
+   -> io::Result<(W, Stats)>
+    where W: Write,
+          F: FnOnce(&mut Encoder<W>) -> io::Result<()>
+{
+    let physical = physical_size(args)?;
+    let modified = modification_time(args)?;
+    let profile = match args.value_of("icc") {
+        Some(filename) => Some(fs::read(filename).map_err(|e| Error::new(e.kind(), format!("{}: {}", filename, e)))?),
+        None => None,
+    };
+
+    let color_space = color_space(args)?;
+
+    // Chunks given on the command line replace copied ones, along with
+    // any others giving the color space in another way.
+    let mut replaced: Vec<&[u8; 4]> = color_space.iter().map(|(tag, _)| tag).collect();
+    if physical.is_some() {
+        replaced.push(b"pHYs");
+    }
+    if modified.is_some() {
+        replaced.push(b"tIME");
+    }
+    if profile.is_some() || !color_space.is_empty() {
+        replaced.extend(&[b"iCCP", b"sRGB"]);
+    }
+
+    let mut encoder = Encoder::new(writer, options);
+    if let Some(progress) = progress {
+        encoder.set_progress_callback(move |p| {
+            progress.update(p.chunks_completed, p.chunks_total, "chunks");
+        });
+    }
+
+    // Image data
+    // Copied chunks all go before the image data, keeping their
+    // order, except that some must come before the palette.
+    let (before_palette, after_palette): (Vec<_>, Vec<_>) = image.chunks.iter()
+        .filter(|(tag, _)| keep_chunk(args, tag))
+        .filter(|(tag, _)| !replaced.contains(&tag))
+        .partition(|(tag, _)| BEFORE_PALETTE.contains(&tag));
+
+    encoder.write_header(&image.header)?;
+    if let Some(profile) = &profile {
+        encoder.write_icc_profile("ICC profile", profile)?;
+    }
+    for (tag, data) in &color_space {
+        encoder.write_chunk(tag, data)?;
+    }
+    for (tag, data) in before_palette {
+        encoder.write_chunk(tag, data)?;
+    }
+    if let Some(v) = &image.palette {
+        encoder.write_palette(v)?;
+    }
+    if let Some(v) = &image.transparency {
+        encoder.write_transparency(v)?;
+    }
+    for (tag, data) in after_palette {
+        encoder.write_chunk(tag, data)?;
+    }
+    if let Some(data) = &physical {
+        encoder.write_chunk(b"pHYs", data)?;
+    }
+    if let Some(data) = &modified {
+        encoder.write_chunk(b"tIME", data)?;
+    }
+    if let Some(texts) = args.values_of("text") {
+        for text in texts {
+            let (keyword, value) = text.split_once('=')
+                .ok_or_else(|| usage("Invalid text, try Keyword=value"))?;
+            encoder.write_text(keyword, value)?;
+        }
+    }
+    if let Some(comment) = args.value_of("comment") {
+        encoder.write_text("Comment", comment)?;
+    }
+    write_rows(&mut encoder)?;
+    let result = encoder.finish_with_stats()?;
+    if let Some(progress) = progress {
+        progress.clear();
+    }
+    Ok(result)
+}
+
+const TRIAL_FILTERS: [(&str, Mode<Filter>); 6] = [
+    ("adaptive", Adaptive),
+    ("none", Fixed(Filter::None)),
+    ("sub", Fixed(Filter::Sub)),
+    ("up", Fixed(Filter::Up)),
+    ("average", Fixed(Filter::Average)),
+    ("paeth", Fixed(Filter::Paeth)),
+];
+
+const TRIAL_STRATEGIES: [(&str, Mode<Strategy>); 5] = [
+    ("auto", Adaptive),
+    ("default", Fixed(Strategy::Default)),
+    ("filtered", Fixed(Strategy::Filtered)),
+    ("rle", Fixed(Strategy::Rle)),
+    ("huffman", Fixed(Strategy::HuffmanOnly)),
+];
+
+//
+// Try the settings given, then up to trials - 1 others at level 9,
+// keeping the smallest output. Each filter is tried with automatic
+// strategy first, as the filter usually matters more. Trials run at
+// once on threads of their own, sharing the pool as in batch mode.
+//
+fn optimize<'a>(pool: &ThreadPool,
+                args: &'a ArgMatches,
+                filename: &Path,
+                image: &Image,
+                options: &Options,
+                trials: usize,
+                progress: &ProgressBar)
+   -> io::Result<(Stats, Settings<'a>)>
+{
+    let mut settings = vec![(Settings::given(args), *options)];
+    for (strategy_name, strategy) in &TRIAL_STRATEGIES {
+        for (filter_name, filter) in &TRIAL_FILTERS {
+            let mut trial = *options;
+            trial.set_compression_level(CompressionLevel::High)?;
+            trial.set_filter_mode(*filter)?;
+            trial.set_strategy_mode(*strategy)?;
+            let names = Settings {
+                filter: filter_name,
+                strategy: strategy_name,
+                level: "9",
+            };
+            settings.push((names, trial));
+        }
+    }
+    settings.truncate(trials);
+
+    let next = AtomicUsize::new(0);
+    let threads = cmp::min(settings.len(), pool.current_num_threads());
+    let mut results: Vec<(usize, (Vec<u8>, Stats))> = on_threads(threads, || {
+        let mut results = Vec::new();
+        loop {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            match settings.get(i) {
+                Some((_, options)) => results.push((i, encode(args, image, options, Vec::new(), None)?)),
+                None => break,
+            }
+        }
+        Ok(results)
+    }).into_iter()
+        .map(|result| result.unwrap_or_else(|_| Err(err("Trial failed"))))
+        .collect::<io::Result<Vec<_>>>()?
+        .into_iter().flatten().collect();
+
+    results.sort_by_key(|(i, (data, _))| (data.len(), *i));
+    let given = results.iter().find(|(i, _)| *i == 0).map_or(0, |(_, (data, _))| data.len());
+    let (best, (data, stats)) = results.swap_remove(0);
+    if filename != Path::new("-") && !args.is_present("quiet") {
+        let saved = 100.0 * (given - data.len()) as f64 / given as f64;
+        let name = if best == 0 { "given settings".to_string() } else { settings[best].0.to_string() };
+        progress.println(&format!("Best of {} trials: {}, {} bytes, {:.1}% smaller than given settings",
+                                  settings.len(), name, data.len(), saved));
+    }
+
+    let mut writer = create_output(args, filename)?;
+    writer.write_all(&data)?;
+    writer.finish()?;
+    Ok((stats, settings[best].0))
+}
+
+//
+// Read sample i of a row at any bit depth.
+//
+fn sample(row: &[u8], i: usize, depth: u8) -> u16
+{
+    match depth {
+        16 => u16::from_be_bytes([row[i * 2], row[i * 2 + 1]]),
+        8 => u16::from(row[i]),
+        _ => {
+            let per_byte = 8 / depth as usize;
+            let shift = 8 - depth as usize * (i % per_byte + 1);
+            u16::from(row[i / per_byte] >> shift) & ((1 << depth) - 1)
+        }
+    }
+}
+
+//
+// Expand any image to 8-bit RGBA for quantizing, applying the
+// palette and tRNS.
+//
+fn rgba8(image: &Image) -> Vec<u8>
+{
+    let header = &image.header;
+    let width = header.width() as usize;
+    let height = header.height() as usize;
+    let depth = header.depth();
+    let channels = header.color_type().channels();
+    let max = (1u32 << depth) - 1;
+    let scale = |v: u16| ((u32::from(v) * 255 + max / 2) / max) as u8;
+
+    let palette = image.palette.as_deref().unwrap_or(&[]);
+    let trns = image.transparency.as_deref().unwrap_or(&[]);
+    let key = |i: usize| trns.get(i * 2 .. i * 2 + 2).map(|v| u16::from_be_bytes([v[0], v[1]]));
+
+    let mut out = Vec::with_capacity(width * height * 4);
+    for row in image.data.as_ref().chunks(header.stride()).take(height) {
+        for x in 0 .. width {
+            let s = |c: usize| sample(row, x * channels + c, depth);
+            let rgba = match header.color_type() {
+                ColorType::Greyscale => {
+                    let v = s(0);
+                    let alpha = if key(0) == Some(v) { 0 } else { 255 };
+                    [scale(v), scale(v), scale(v), alpha]
+                },
+                ColorType::GreyscaleAlpha => {
+                    let v = scale(s(0));
+                    [v, v, v, scale(s(1))]
+                },
+                ColorType::Truecolor => {
+                    let (r, g, b) = (s(0), s(1), s(2));
+                    let keyed = key(0) == Some(r) && key(1) == Some(g) && key(2) == Some(b);
+                    [scale(r), scale(g), scale(b), if keyed { 0 } else { 255 }]
+                },
+                ColorType::TruecolorAlpha => {
+                    [scale(s(0)), scale(s(1)), scale(s(2)), scale(s(3))]
+                },
+                ColorType::IndexedColor => {
+                    let i = s(0) as usize;
+                    let rgb = palette.get(i * 3 .. i * 3 + 3).unwrap_or(&[0, 0, 0]);
+                    [rgb[0], rgb[1], rgb[2], trns.get(i).copied().unwrap_or(255)]
+                },
+            };
+            out.extend_from_slice(&rgba);
+        }
+    }
+    out
+}
+
+//
+// Pick up to the given number of colors by median cut: the box of
+// colors with the widest spread on any channel is split at its median
+// until there are enough boxes, and each box's average is a color.
+// Images with no more colors than that come out exact.
+//
+fn median_cut(pixels: &[u8], colors: usize) -> Vec<[u8; 4]>
+{
+    // A million or so samples is plenty to place the colors.
+    let step = cmp::max(1, pixels.len() / 4 / (1 << 20));
+    let mut samples: Vec<[u8; 4]> = pixels.chunks(4)
+        .step_by(step)
+        .map(|p| [p[0], p[1], p[2], p[3]])
+        .collect();
+
+    let spread = |colors: &[[u8; 4]]| {
+        (0 .. 4).map(|c| {
+            let min = colors.iter().map(|p| p[c]).min().unwrap_or(0);
+            let max = colors.iter().map(|p| p[c]).max().unwrap_or(0);
+            (max - min, c)
+        }).max().unwrap_or((0, 0))
+    };
+
+    let mut boxes = vec![(0, samples.len(), spread(&samples))];
+    while boxes.len() < colors {
+        let widest = boxes.iter()
+            .enumerate()
+            .max_by_key(|(_, &(_, _, spread))| spread)
+            .map(|(i, &b)| (i, b));
+        let (i, (start, end, (range, channel))) = match widest {
+            Some(widest) => widest,
+            None => break,
+        };
+        if range == 0 {
+            break;
+        }
+        samples[start .. end].sort_unstable_by_key(|p| p[channel]);
+        // Split between different values, so no color is in both halves.
+        let box_ = &samples[start .. end];
+        let median = box_[box_.len() / 2][channel];
+        let mut mid = start + box_.partition_point(|p| p[channel] < median);
+        if mid == start {
+            mid += box_.partition_point(|p| p[channel] <= median);
+        }
+        boxes[i] = (start, mid, spread(&samples[start .. mid]));
+        boxes.push((mid, end, spread(&samples[mid .. end])));
+    }
+
+    let mut palette: Vec<[u8; 4]> = boxes.iter().filter(|&&(start, end, _)| end > start).map(|&(start, end, _)| {
+        let mut sum = [0usize; 4];
+        for p in &samples[start .. end] {
+            for c in 0 .. 4 {
+                sum[c] += usize::from(p[c]);
+            }
+        }
+        let n = end - start;
+        let mut color = [0u8; 4];
+        for c in 0 .. 4 {
+            color[c] = ((sum[c] + n / 2) / n) as u8;
+        }
+        color
+    }).collect();
+
+    // Translucent colors go first, so tRNS can leave off the opaque ones.
+    palette.sort_by_key(|color| color[3] == 255);
+    palette
+}
+
+//
+// Finds the nearest palette color to each pixel, remembering recent
+// answers, as most images repeat the same colors a lot.
+//
+struct Nearest<'a> {
+    palette: &'a [[u8; 4]],
+    cache: Vec<Option<([u8; 4], usize)>>,
+}
+
+impl<'a> Nearest<'a> {
+    fn new(palette: &'a [[u8; 4]]) -> Self {
+        Nearest {
+            palette,
+            cache: vec![None; 4096],
+        }
+    }
+
+    fn index(&mut self, color: [u8; 4]) -> usize {
+        let hash = u32::from_le_bytes(color).wrapping_mul(0x9e37_79b1) >> 20;
+        let palette = self.palette;
+        let slot = &mut self.cache[hash as usize];
+        match *slot {
+            Some((cached, index)) if cached == color => index,
+            _ => {
+                let distance = |entry: &[u8; 4]| -> i32 {
+                    (0 .. 4).map(|c| {
+                        let d = i32::from(color[c]) - i32::from(entry[c]);
+                        d * d
+                    }).sum()
+                };
+                let index = (0 .. palette.len())
+                    .min_by_key(|&i| distance(&palette[i]))
+                    .unwrap_or(0);
+                *slot = Some((color, index));
+                index
+            }
+        }
+    }
+}
+
+fn put_index(row: &mut [u8], x: usize, depth: u8, index: usize)
+{
+    let per_byte = 8 / depth as usize;
+    let shift = 8 - depth as usize * (x % per_byte + 1);
+    row[x / per_byte] |= (index as u8) << shift;
+}
+
+//
+// Reduce an image to an indexed-color one with at most the given
+// number of colors, for --colors. With dithering, each pixel's error
+// from its nearest color is spread over the pixels to the right and
+// below (Floyd-Steinberg), which has to go in order; without it the
+// rows are mapped in parallel.
+//
+fn quantize(pool: &ThreadPool, image: &Image, colors: usize, dither: bool)
+    -> io::Result<Image>
+{
+    let width = image.header.width() as usize;
+    let height = image.header.height() as usize;
+    let pixels = rgba8(image);
+    let palette = median_cut(&pixels, colors);
+    let depth = match palette.len() {
+        0 ..= 2 => 1,
+        3 ..= 4 => 2,
+        5 ..= 16 => 4,
+        _ => 8,
+    };
+
+    let mut header = Header::new();
+    header.set_size(image.header.width(), image.header.height())?;
+    header.set_color(ColorType::IndexedColor, depth)?;
+    let stride = header.stride();
+    let mut data = vec![0u8; stride * height];
+
+    if dither {
+        let mut nearest = Nearest::new(&palette);
+        let mut error = vec![[0i32; 4]; width + 2];
+        let mut next = vec![[0i32; 4]; width + 2];
+        for (row, src) in data.chunks_mut(stride).zip(pixels.chunks(width * 4)) {
+            for (x, pixel) in src.chunks(4).enumerate() {
+                let mut color = [0u8; 4];
+                for c in 0 .. 4 {
+                    color[c] = (i32::from(pixel[c]) + error[x + 1][c] / 16).clamp(0, 255) as u8;
+                }
+                let index = nearest.index(color);
+                put_index(row, x, depth, index);
+                for c in 0 .. 4 {
+                    let e = i32::from(color[c]) - i32::from(palette[index][c]);
+                    error[x + 2][c] += e * 7;
+                    next[x][c] += e * 3;
+                    next[x + 1][c] += e * 5;
+                    next[x + 2][c] += e;
+                }
+            }
+            error = std::mem::replace(&mut next, vec![[0i32; 4]; width + 2]);
+        }
+    } else {
+        pool.install(|| {
+            data.par_chunks_mut(stride).zip(pixels.par_chunks(width * 4)).for_each_init(|| {
+                Nearest::new(&palette)
+            }, |nearest, (row, src)| {
+                for (x, pixel) in src.chunks(4).enumerate() {
+                    put_index(row, x, depth, nearest.index([pixel[0], pixel[1], pixel[2], pixel[3]]));
+                }
+            });
+        });
+    }
+
+    let translucent = palette.iter().take_while(|color| color[3] < 255).count();
+    Ok(Image {
+        header,
+        data: SharedPixels(Arc::new(PixelBuffer::Heap(data))),
+        palette: Some(palette.iter().flat_map(|color| color[.. 3].to_vec()).collect()),
+        transparency: if translucent > 0 {
+            Some(palette[.. translucent].iter().map(|color| color[3]).collect())
+        } else {
+            None
+        },
+        chunks: reformatted_chunks(image, ColorType::IndexedColor),
+    })
+}
+
+//
+// Copied chunks, less those in terms of the old color type or samples.
+// An ICC profile is for either gray or color images, so it goes too
+// if the new color type is the other kind.
+//
+fn reformatted_chunks(image: &Image, color_type: ColorType) -> Vec<([u8; 4], Vec<u8>)>
+{
+    let gray = |color_type| matches!(color_type, ColorType::Greyscale | ColorType::GreyscaleAlpha);
+    let crossed = gray(image.header.color_type()) != gray(color_type);
+    image.chunks.iter()
+        .filter(|(tag, _)| !matches!(tag, b"bKGD" | b"sBIT" | b"hIST"))
+        .filter(|(tag, _)| !(crossed && tag == b"iCCP"))
+        .cloned()
+        .collect()
+}
+
+//
+// Parse a --background color, as #rrggbb or #rgb.
+//
+fn background_color(value: &str) -> io::Result<[u8; 3]>
+{
+    let digits = value.strip_prefix('#').unwrap_or(value);
+    let channel = |i: usize, len: usize| {
+        digits.get(i * len .. (i + 1) * len)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .map(|v| if len == 1 { v * 17 } else { v })
+    };
+    let len = match digits.len() {
+        6 => 2,
+        3 => 1,
+        _ => 0,
+    };
+    match (len, channel(0, len), channel(1, len), channel(2, len)) {
+        (1 ..= 2, Some(r), Some(g), Some(b)) => Ok([r, g, b]),
+        _ => Err(usage("Invalid background color, try #rrggbb such as #ffffff")),
+    }
+}
+
+//
+// Composite a transparent image over the --background color, making
+// it opaque for viewers that don't handle alpha. Opaque images are
+// left as they are.
+//
+fn flatten(args: &ArgMatches, image: Image) -> io::Result<Image>
+{
+    let background = match args.value_of("background") {
+        Some(value) => background_color(value)?,
+        None => return Ok(image),
+    };
+    let alpha = matches!(image.header.color_type(), ColorType::GreyscaleAlpha | ColorType::TruecolorAlpha);
+    if !alpha && image.transparency.is_none() {
+        return Ok(image);
+    }
+    let (header, data) = mtpng::convert::flatten(&image.header,
+                                                 image.data.as_ref(),
+                                                 image.palette.as_deref(),
+                                                 image.transparency.as_deref(),
+                                                 background)?;
+    let chunks = reformatted_chunks(&image, header.color_type());
+    Ok(Image {
+        header,
+        data: SharedPixels(Arc::new(PixelBuffer::Heap(data))),
+        palette: None,
+        transparency: None,
+        chunks,
+    })
+}
+
+//
+// Change the image's color type or depth for --color-type, --bit-depth,
+// or --colors, after flattening it over any --background. Indexed
+// color goes through the quantizer, with as many colors as the depth
+// allows, and the library converts to the others. The depth is kept
+// if the new color type allows it, or is 8.
+//
+fn reformat(pool: &ThreadPool, args: &ArgMatches, image: Image) -> io::Result<Image>
+{
+    let image = flatten(args, image)?;
+    let color_type = match args.value_of("color-type") {
+        None if args.is_present("colors") => ColorType::IndexedColor,
+        None          => image.header.color_type(),
+        Some("gray")  => ColorType::Greyscale,
+        Some("graya") => ColorType::GreyscaleAlpha,
+        Some("rgb")   => ColorType::Truecolor,
+        Some("rgba")  => ColorType::TruecolorAlpha,
+        Some("indexed") => ColorType::IndexedColor,
+        _             => return Err(usage("Invalid color type, try gray, graya, rgb, rgba, or indexed")),
+    };
+    let depth = match args.value_of("bit-depth") {
+        Some(s) => {
+            let depth = s.parse::<u8>().ok()
+                .filter(|&depth| color_type.is_depth_valid(depth))
+                .ok_or_else(|| usage("Invalid bit depth for the color type"))?;
+            Some(depth)
+        },
+        None => None,
+    };
+
+    if let ColorType::IndexedColor = color_type {
+        if depth.is_none() && !args.is_present("color-type") && !args.is_present("colors") {
+            if args.is_present("dither") {
+                return Err(usage("--dither needs --colors or indexed color"));
+            }
+            return Ok(image);
+        }
+        let colors = match args.value_of("colors") {
+            Some(s) => {
+                s.parse::<usize>().ok()
+                    .filter(|n| (2 ..= 256).contains(n))
+                    .ok_or_else(|| usage("Invalid colors, try 2-256"))?
+            },
+            None => 256,
+        };
+        let max = 1 << depth.unwrap_or(8);
+        return quantize(pool, &image, cmp::min(colors, max), args.is_present("dither"));
+    }
+    if args.is_present("colors") {
+        return Err(usage("--colors makes an indexed image, so can't be used with another color type"));
+    }
+    if args.is_present("dither") {
+        return Err(usage("--dither needs --colors or indexed color"));
+    }
+    if depth.is_none() && !args.is_present("color-type") {
+        return Ok(image);
+    }
+
+    let depth = depth.unwrap_or_else(|| {
+        if color_type.is_depth_valid(image.header.depth()) {
+            image.header.depth()
+        } else {
+            8
+        }
+    });
+    let (header, data) = mtpng::convert::convert(&image.header,
+                                                 image.data.as_ref(),
+                                                 image.palette.as_deref(),
+                                                 image.transparency.as_deref(),
+                                                 color_type,
+                                                 depth)?;
+    let chunks = reformatted_chunks(&image, header.color_type());
+    Ok(Image {
+        header,
+        data: SharedPixels(Arc::new(PixelBuffer::Heap(data))),
+        palette: None,
+        transparency: None,
+        chunks,
+    })
+}
+
+//
+// Quote a string for JSON.
+//
+fn json_string(value: &str) -> String
+{
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+//
+// Color types by the names --color-type takes.
+//
+fn color_type_name(color_type: ColorType) -> &'static str
+{
+    match color_type {
+        ColorType::Greyscale      => "gray",
+        ColorType::GreyscaleAlpha => "graya",
+        ColorType::Truecolor      => "rgb",
+        ColorType::TruecolorAlpha => "rgba",
+        ColorType::IndexedColor   => "indexed",
+    }
+}
+
+//
+// One line of JSON on a finished file, for --json. Times are in
+// milliseconds; those for filtering, compression, and checksums are
+// summed over all threads, so may add up to more than the encode.
+//
+fn json_report(job: &Job,
+               image: &Image,
+               input_bytes: Option<u64>,
+               read_time: Duration,
+               stats: &Stats,
+               settings: &Settings,
+               threads: usize)
+   -> String
+{
+    let ms = |time: Duration| format!("{:.3}", time.as_secs_f64() * 1000.0);
+    let or_null = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+    let ratio = input_bytes.filter(|&bytes| bytes > 0)
+        .map(|bytes| format!("{:.4}", stats.bytes_written as f64 / bytes as f64));
+    let [none, sub, up, average, paeth] = stats.filter_rows;
+
+    format!(concat!("{{\"input\":{},\"output\":{},\"input_bytes\":{},\"output_bytes\":{},\"ratio\":{},",
+                    "\"width\":{},\"height\":{},\"color_type\":\"{}\",\"bit_depth\":{},",
+                    "\"raw_bytes\":{},\"idat_bytes\":{},\"chunks\":{},",
+                    "\"time_ms\":{{\"read\":{},\"encode\":{},\"filter\":{},\"deflate\":{},",
+                    "\"checksum\":{},\"write\":{},\"wait\":{}}},",
+                    "\"filter_rows\":{{\"none\":{},\"sub\":{},\"up\":{},\"average\":{},\"paeth\":{}}},",
+                    "\"settings\":{{\"filter\":{},\"strategy\":{},\"level\":{},\"threads\":{}}}}}"),
+            json_string(&job.input.to_string_lossy()),
+            json_string(&job.output.to_string_lossy()),
+            or_null(input_bytes.map(|bytes| bytes.to_string())),
+            stats.bytes_written,
+            or_null(ratio),
+            image.header.width(),
+            image.header.height(),
+            color_type_name(image.header.color_type()),
+            image.header.depth(),
+            stats.uncompressed_bytes,
+            stats.compressed_bytes,
+            stats.chunks,
+            ms(read_time),
+            ms(stats.wall_time),
+            ms(stats.filter_time),
+            ms(stats.deflate_time),
+            ms(stats.checksum_time),
+            ms(stats.write_time),
+            ms(stats.wait_time),
+            none, sub, up, average, paeth,
+            json_string(settings.filter),
+            json_string(settings.strategy),
+            json_string(settings.level),
+            threads)
+}
+
+//
+// Progress bar on stderr, for large files and batches. It's only drawn
+// on a terminal, and not for the first half second, so quick runs and
+// logs stay clean. Other messages go through it, to clear the bar first.
+//
+struct ProgressBar {
+    enabled: bool,
+    start: Instant,
+    // When the bar was last drawn, if it's showing.
+    drawn: Mutex<Option<Instant>>,
+}
+
+impl ProgressBar {
+    fn new(args: &ArgMatches) -> Self {
+        ProgressBar {
+            enabled: !args.is_present("quiet") && io::stderr().is_terminal(),
+            start: Instant::now(),
+            drawn: Mutex::new(None),
+        }
+    }
+
+    fn update(&self, done: usize, total: usize, unit: &str) {
+        let elapsed = self.start.elapsed();
+        if !self.enabled || total == 0 || elapsed < Duration::from_millis(500) {
+            return;
+        }
+        let mut drawn = self.drawn.lock().unwrap_or_else(|e| e.into_inner());
+        if drawn.is_some_and(|time| time.elapsed() < Duration::from_millis(100)) && done < total {
+            return;
+        }
+
+        const WIDTH: usize = 30;
+        let filled = WIDTH * done / total;
+        let eta = if done > 0 {
+            let left = elapsed.as_secs_f64() * (total - done) as f64 / done as f64;
+            format!(", {:.0}s left", left.ceil())
+        } else {
+            String::new()
+        };
+        eprint!("\r\x1b[K[{}{}] {}% {}/{} {}{}",
+                "=".repeat(filled), " ".repeat(WIDTH - filled),
+                100 * done / total, done, total, unit, eta);
+        *drawn = Some(Instant::now());
+    }
+
+    fn println(&self, line: &str) {
+        let mut drawn = self.drawn.lock().unwrap_or_else(|e| e.into_inner());
+        if drawn.take().is_some() {
+            eprint!("\r\x1b[K");
+        }
+        eprintln!("{}", line);
+    }
+
+    fn clear(&self) {
+        let mut drawn = self.drawn.lock().unwrap_or_else(|e| e.into_inner());
+        if drawn.take().is_some() {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+//
+// An input file and where to write its output.
+//
+struct Job {
+    input: PathBuf,
+    output: PathBuf,
+}
+
+//
+// Work out what to convert: either a single input and output, or any
+// number of inputs, with --out-dir and --suffix choosing where each
+// output goes.
+//
+fn jobs(args: &ArgMatches) -> io::Result<Vec<Job>>
+{
+    let files: Vec<&str> = args.values_of("files").unwrap().collect();
+    let out_dir = args.value_of("out-dir").map(Path::new);
+    let suffix = args.value_of("suffix");
+
+    if out_dir.is_none() && suffix.is_none() && !args.is_present("in-place") {
+        if files.len() != 2 {
+            return Err(usage("Give an input and output filename, or use --out-dir, --suffix, or --in-place with several inputs"));
+        }
+        return Ok(vec![Job {
+            input: PathBuf::from(files[0]),
+            output: PathBuf::from(files[1]),
+        }]);
+    }
+
+    let mut jobs = Vec::new();
+    for pattern in files {
+        if pattern == "-" {
+            return Err(usage("Cannot use stdin with --out-dir, --suffix, or --in-place"));
+        }
+        for (root, input) in expand_input(pattern, args.is_present("recursive"))? {
+            let mut output = match out_dir {
+                Some(dir) => dir.join(input.strip_prefix(&root).unwrap()),
+                None => input.clone(),
+            };
+            if let Some(suffix) = suffix {
+                let stem = output.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+                output.set_file_name(format!("{}{}.png", stem, suffix));
+            }
+            jobs.push(Job {
+                input,
+                output,
+            });
+        }
+    }
+    Ok(jobs)
+}
+
+//
+// Expand a filename, glob pattern, or directory into the input files,
+// each with the directory their output path is relative to.
+//
+fn expand_input(pattern: &str, recursive: bool) -> io::Result<Vec<(PathBuf, PathBuf)>>
+{
+    let path = PathBuf::from(pattern);
+    let paths = if path.exists() {
+        vec![path]
+    } else {
+        let matches = glob::glob(pattern).map_err(|e| usage(&e.to_string()))?;
+        let paths = matches.collect::<Result<Vec<_>, _>>().map_err(io::Error::from)?;
+        if paths.is_empty() {
+            return Err(Error::new(io::ErrorKind::NotFound, format!("No files match {}", pattern)));
+        }
+        paths
+    };
+
+    let mut inputs = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            if !recursive {
+                return Err(usage(&format!("{} is a directory; use --recursive", path.display())));
+            }
+            let mut files = Vec::new();
+            find_images(&path, &mut files)?;
+            inputs.extend(files.into_iter().map(|file| (path.clone(), file)));
+        } else {
+            let root = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            inputs.push((root, path));
+        }
+    }
+    Ok(inputs)
+}
+
+//
+// Find the images under a directory, in a stable order.
+//
+fn find_images(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()>
+{
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            find_images(&path, files)?;
+        } else if is_image(&path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn is_image(path: &Path) -> bool
+{
+    let extensions: &[&str] = if cfg!(feature="image") {
+        &["png", "pbm", "pgm", "ppm", "pnm", "pam", "jpg", "jpeg", "gif", "webp", "tif", "tiff", "bmp", "tga"]
+    } else {
+        &["png", "pbm", "pgm", "ppm", "pnm", "pam"]
+    };
+    match path.extension() {
+        Some(ext) => extensions.iter().any(|known| ext.eq_ignore_ascii_case(known)),
+        None => false,
+    }
+}
+
+fn convert(pool: &ThreadPool,
+           args: &ArgMatches,
+           job: &Job,
+           progress: &ProgressBar,
+           batch: bool)
+   -> io::Result<()>
+{
+    //
+    // Diagnostics all go to stderr, but the timing chatter is left
+    // out when the image goes to stdout, as in a pipeline, or with
+    // --quiet.
+    //
+    let to_stdout = job.output == Path::new("-");
+    if to_stdout && args.is_present("json") {
+        return Err(usage("Cannot print JSON with the image going to stdout"));
+    }
+    let verbose = !to_stdout && !args.is_present("quiet");
+    check_output(args, job)?;
+
+    if verbose {
+        progress.println(&format!("{} -> {}", job.input.display(), job.output.display()));
+    }
+    let input_bytes = if job.input == Path::new("-") {
+        None
+    } else {
+        fs::metadata(&job.input).ok().map(|metadata| metadata.len())
+    };
+    if let Some(dir) = job.output.parent() {
+        if args.is_present("out-dir") {
+            fs::create_dir_all(dir)?;
+        }
+    }
+
+    // With --low-memory reading is done along with encoding, and timed
+    // with it.
+    let (image, read_time, stats, settings, delta) = if args.is_present("low-memory") {
+        let start_time = OffsetDateTime::now_utc();
+        let (image, stats) = stream_png(pool, args, job, progress, batch)?;
+        let delta = OffsetDateTime::now_utc() - start_time;
+        (image, Duration::ZERO, stats, Settings::given(args), delta)
+    } else {
+        let read_start = Instant::now();
+        let image = match raw_format(args)? {
+            Some(format) => read_raw(&job.input, &format, args.is_present("mmap"))?,
+            None => read_image(&job.input, args.is_present("mmap"))?,
+        };
+        let image = reformat(pool, args, image)?;
+        let read_time = read_start.elapsed();
+
+        let start_time = OffsetDateTime::now_utc();
+        let (stats, settings) = write_png(pool, args, &job.output, &image, progress, batch)?;
+        let delta = OffsetDateTime::now_utc() - start_time;
+        (image, read_time, stats, settings, delta)
+    };
+
+    if args.is_present("json") {
+        writeln!(io::stdout(), "{}", json_report(job, &image, input_bytes, read_time, &stats, &settings,
+                                                 pool.current_num_threads()))?;
+    }
+
+    let ms = (delta.as_seconds_f64() * 1000.0).round();
+    if verbose && batch {
+        // Lines from other files may come in between.
+        progress.println(&format!("Done {} in {} ms", job.output.display(), ms));
+    } else if verbose {
+        progress.println(&format!("Done in {} ms", ms));
+    }
+
+    Ok(())
+}
+
+//
+// Build a pool of the given number of threads, or 0 for the default.
+// Without threads, it's just the calling thread, which is all the
+// encoder has then anyway as it runs inline; that can only be done
+// once, as a thread can't be taken back from a pool.
+//
+fn build_pool(threads: usize) -> io::Result<ThreadPool> {
+    let builder = if NO_THREADS {
+        ThreadPoolBuilder::new().num_threads(1).use_current_thread()
+    } else {
+        ThreadPoolBuilder::new().num_threads(threads)
+    };
+    builder.build().map_err(|e| err(&e.to_string()))
+}
+
+//
+// Run work on this many threads of our own, returning how each went.
+// Without threads, it's run once on this one, so the work should keep
+// going until there's none left rather than doing a share of it.
+//
+fn on_threads<T, F>(threads: usize, work: F) -> Vec<thread::Result<T>>
+    where F: Fn() -> T + Sync,
+          T: Send
+{
+    if NO_THREADS {
+        return vec![Ok(work())];
+    }
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0 .. threads).map(|_| scope.spawn(&work)).collect();
+        handles.into_iter().map(|handle| handle.join()).collect()
+    })
+}
+
+fn doit(args: &ArgMatches, progress: &ProgressBar) -> io::Result<()> {
+    let threads = match args.value_of("threads") {
+        None    => 0, // Means default
+        Some(s) => {
+            s.parse::<usize>().map_err(|_e| usage("invalid threads"))?
+        },
+    };
+
+    // There's no temporary directory or memory mapping under WASI.
+    if cfg!(target_os="wasi") && args.is_present("mmap") {
+        return Err(usage("--mmap is not available on WASI"));
+    }
+
+    let jobs = jobs(args)?;
+
+    let pool = build_pool(threads)?;
+    if jobs.iter().all(|job| job.output != Path::new("-")) && !args.is_present("quiet") {
+        eprintln!("Using {} threads", pool.current_num_threads());
+    }
+
+    if let [job] = &jobs[..] {
+        return convert(&pool, args, job, progress, false).map_err(|e| {
+            report_failure(args, progress, Some(&job.input), Some(&job.output), &e);
+            Error::other(Reported(Failure::of(&e), String::new()))
+        });
+    }
+
+    //
+    // Files are taken in turn by this many threads of their own, which
+    // share the pool for encoding. Small images are encoded on the
+    // thread that reads them, so this keeps cores busy on many small
+    // files. The encoders wait on the pool, so they can't run on it.
+    //
+    let threads = match args.value_of("jobs") {
+        Some(s) => {
+            let n = s.parse::<usize>().map_err(|_e| usage("invalid jobs"))?;
+            if n == 0 {
+                return Err(usage("invalid jobs"));
+            }
+            n
+        },
+        None => pool.current_num_threads(),
+    };
+
+    // Keep going past failures, so one bad file doesn't stop a batch.
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    // The exit code is that of the failures if they're all alike.
+    let failure = Mutex::new(None);
+    let results = on_threads(cmp::min(threads, jobs.len()), || {
+        while let Some(job) = jobs.get(next.fetch_add(1, Ordering::Relaxed)) {
+            if let Err(e) = convert(&pool, args, job, progress, true) {
+                report_failure(args, progress, Some(&job.input), Some(&job.output), &e);
+                failed.fetch_add(1, Ordering::Relaxed);
+                let mut failure = failure.lock().unwrap_or_else(|e| e.into_inner());
+                *failure = Some(Failure::merge(*failure, Failure::of(&e)));
+            }
+            let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+            progress.update(done, jobs.len(), "files");
+        }
+    });
+    for result in results {
+        if let Err(panic) = result {
+            panic::resume_unwind(panic);
+        }
+    }
+    progress.clear();
+    let failed = failed.into_inner();
+    if let Some(failure) = failure.into_inner().unwrap_or_else(|e| e.into_inner()) {
+        let summary = format!("{} of {} files failed", failed, jobs.len());
+        return Err(Error::other(Reported(failure, summary)));
+    }
+
+    Ok(())
+}
+
+//
+// Check a file's structure, and if that's sound and --decode is given,
+// decompress and unfilter the image data too, checking the zlib
+// checksum that decoders usually skip. Returns the structural findings
+// and any decoding error.
+//
+fn verify_file(filename: &Path, decode: bool) -> io::Result<(Vec<Finding>, Option<String>)>
+{
+    let mut data = Vec::new();
+    open_input(filename)?.read_to_end(&mut data)?;
+    let findings = validate(&data);
+    if !decode || findings.iter().any(|finding| finding.severity == Severity::Error) {
+        return Ok((findings, None));
+    }
+
+    let mut options = png::DecodeOptions::default();
+    options.set_ignore_adler32(false);
+    let mut decoder = png::Decoder::new_with_options(io::Cursor::new(&data), options);
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let result = decoder.read_info().and_then(|mut reader| {
+        let mut buf = vec![0u8; reader.output_buffer_size()];
+        reader.next_frame(&mut buf)?;
+        reader.finish()
+    });
+    Ok((findings, result.err().map(|e| e.to_string())))
+}
+
+//
+// Verify files, printing what's wrong with each, or OK. Warnings are
+// shown but only errors count as corruption, giving the input exit
+// code; files that can't be read give the I/O one.
+//
+fn verify(args: &ArgMatches, progress: &ProgressBar) -> io::Result<()>
+{
+    let files: Vec<&Path> = args.values_of("files").unwrap().map(Path::new).collect();
+    let json = args.is_present("json");
+    let quiet = args.is_present("quiet");
+
+    let mut out = io::stdout().lock();
+    let mut failed = 0;
+    let mut failure = None;
+    for (i, &file) in files.iter().enumerate() {
+        let (findings, decoding) = match verify_file(file, args.is_present("decode")) {
+            Ok(result) => result,
+            Err(e) => {
+                report_failure(args, progress, Some(file), None, &e);
+                failed += 1;
+                failure = Some(Failure::merge(failure, Failure::of(&e)));
+                continue;
+            },
+        };
+        let valid = decoding.is_none() &&
+                    findings.iter().all(|finding| finding.severity != Severity::Error);
+
+        let mut problems: Vec<(Option<&Finding>, String)> = findings.iter()
+            .map(|finding| (Some(finding), finding.to_string()))
+            .collect();
+        if let Some(ref e) = decoding {
+            problems.push((None, format!("error decoding: {}", e)));
+        }
+
+        progress.clear();
+        if json {
+            let findings: Vec<String> = findings.iter().map(|finding| {
+                format!("{{\"offset\":{},\"chunk\":{},\"severity\":\"{}\",\"message\":{}}}",
+                        finding.offset,
+                        finding.chunk.map_or("null".into(),
+                                             |tag| json_string(&String::from_utf8_lossy(&tag))),
+                        if finding.severity == Severity::Error { "error" } else { "warning" },
+                        json_string(finding.message))
+            }).collect();
+            let decoding = decoding.as_ref().map_or("null".into(), |e| json_string(e));
+            writeln!(out, "{{\"input\":{},\"valid\":{},\"findings\":[{}],\"decode_error\":{}}}",
+                     json_string(&file.to_string_lossy()), valid, findings.join(","), decoding)?;
+        } else if quiet {
+            let clean = |s: &str| s.replace(['\t', '\n'], " ");
+            for (finding, message) in &problems {
+                if finding.map_or(true, |finding| finding.severity == Severity::Error) {
+                    eprintln!("error\t{}\t{}\t{}", Failure::Input.name(),
+                              clean(&file.to_string_lossy()), clean(message));
+                }
+            }
+        } else if problems.is_empty() {
+            writeln!(out, "{}: OK", file.display())?;
+        } else {
+            for (_, message) in &problems {
+                writeln!(out, "{}: {}", file.display(), message)?;
+            }
+        }
+
+        if !valid {
+            failed += 1;
+            failure = Some(Failure::merge(failure, Failure::Input));
+        }
+        progress.update(i + 1, files.len(), "files");
+    }
+    progress.clear();
+
+    match failure {
+        Some(failure) => {
+            let summary = if files.len() > 1 {
+                format!("{} of {} files failed", failed, files.len())
+            } else {
+                String::new()
+            };
+            Err(Error::other(Reported(failure, summary)))
+        },
+        None => Ok(()),
+    }
+}
+
+//
+// A chunk's contents in words, for the metadata chunks info shows:
+// the keyword and text of text chunks, or the value of others.
+//
+fn chunk_value(tag: &[u8; 4], data: &[u8]) -> Option<(Option<String>, String)>
+{
+    let u32_at = |i: usize| {
+        data.get(i .. i + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    let value = match tag {
+        b"tEXt" | b"zTXt" | b"iTXt" => {
+            return Some(match mtpng::read_text_chunk(tag, data) {
+                Ok((keyword, text)) => (Some(keyword), text),
+                Err(e) => (None, format!("unreadable: {}", e)),
+            });
+        },
+        b"gAMA" => format!("{}", f64::from(u32_at(0)?) / 100000.0),
+        b"sRGB" => match data.first()? {
+            0 => "perceptual",
+            1 => "relative colorimetric",
+            2 => "saturation",
+            3 => "absolute colorimetric",
+            _ => "unknown intent",
+        }.to_string(),
+        b"cHRM" => {
+            let values = (0 .. 8).map(|i| u32_at(i * 4).map(|v| f64::from(v) / 100000.0))
+                                 .collect::<Option<Vec<f64>>>()?;
+            format!("white {},{} red {},{} green {},{} blue {},{}",
+                    values[0], values[1], values[2], values[3],
+                    values[4], values[5], values[6], values[7])
+        },
+        b"cICP" if data.len() == 4 => {
+            format!("primaries {}, transfer {}, matrix {}, {} range",
+                    data[0], data[1], data[2], if data[3] == 1 { "full" } else { "narrow" })
+        },
+        b"iCCP" => {
+            let end = data.iter().position(|&b| b == 0)?;
+            format!("{} ({} bytes compressed)",
+                    String::from_utf8_lossy(&data[.. end]), data.len().saturating_sub(end + 2))
+        },
+        b"pHYs" => {
+            let (x, y) = (u32_at(0)?, u32_at(4)?);
+            match data.get(8)? {
+                1 => format!("{}x{} pixels per meter ({:.0}x{:.0} dpi)",
+                             x, y, f64::from(x) * 0.0254, f64::from(y) * 0.0254),
+                _ => format!("aspect ratio {}:{}", x, y),
+            }
+        },
+        b"tIME" if data.len() == 7 => {
+            format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+                    u16::from_be_bytes([data[0], data[1]]),
+                    data[2], data[3], data[4], data[5], data[6])
+        },
+        b"eXIf" => format!("{} bytes", data.len()),
+        _ => return None,
+    };
+    Some((None, value))
+}
+
+// A chunk's tag, offset in the file, and contents.
+type Chunk<'a> = ([u8; 4], usize, &'a [u8]);
+
+//
+// List a PNG file's chunks, with their offsets and contents, up to
+// IEND or the first that's cut off. Also returns where that stopped.
+// Data without the PNG signature has no chunks.
+//
+fn png_chunks(data: &[u8]) -> (Vec<Chunk<'_>>, usize)
+{
+    let mut chunks = Vec::new();
+    if !data.starts_with(&PNG_SIGNATURE) {
+        return (chunks, 0);
+    }
+    let mut pos = PNG_SIGNATURE.len();
+    while pos + 12 <= data.len() {
+        let len = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let mut tag = [0u8; 4];
+        tag.copy_from_slice(&data[pos + 4 .. pos + 8]);
+        let next = match (pos + 12).checked_add(len) {
+            Some(next) if next <= data.len() => next,
+            _ => break,
+        };
+        chunks.push((tag, pos, &data[pos + 8 .. pos + 8 + len]));
+        pos = next;
+        if &tag == b"IEND" {
+            break;
+        }
+    }
+    (chunks, pos)
+}
+
+//
+// Print what's in a PNG file without decoding it: the header, each
+// chunk's size and offset, and the values of metadata chunks. Runs of
+// IDAT chunks are summed up, except in JSON.
+//
+fn info_file(args: &ArgMatches, filename: &Path, out: &mut dyn Write) -> io::Result<()>
+{
+    let mut data = Vec::new();
+    open_input(filename)?.read_to_end(&mut data)?;
+    if !data.starts_with(&PNG_SIGNATURE) {
+        return Err(bad_input("not a PNG file"));
+    }
+
+    let (chunks, end) = png_chunks(&data);
+    let truncated = chunks.last().map_or(true, |(tag, _, _)| tag != b"IEND");
+
+    let ihdr = match chunks.first() {
+        Some((tag, _, body)) if tag == b"IHDR" && body.len() == 13 => *body,
+        _ => return Err(bad_input("missing IHDR chunk")),
+    };
+    let width = u32::from_be_bytes([ihdr[0], ihdr[1], ihdr[2], ihdr[3]]);
+    let height = u32::from_be_bytes([ihdr[4], ihdr[5], ihdr[6], ihdr[7]]);
+    let depth = ihdr[8];
+    let color_type = ColorType::try_from(ihdr[9]).map_or("unknown", color_type_name);
+    let interlaced = ihdr[12] != 0;
+    let metadata: Vec<_> = chunks.iter()
+        .filter_map(|(tag, _, body)| chunk_value(tag, body).map(|(key, value)| (tag, key, value)))
+        .collect();
+
+    let name = filename.to_string_lossy();
+    if args.is_present("json") {
+        let chunks: Vec<String> = chunks.iter().map(|(tag, offset, body)| {
+            format!("{{\"tag\":{},\"offset\":{},\"length\":{}}}",
+                    json_string(&String::from_utf8_lossy(tag)), offset, body.len())
+        }).collect();
+        let metadata: Vec<String> = metadata.iter().map(|(tag, key, value)| {
+            format!("{{\"tag\":{},\"keyword\":{},\"value\":{}}}",
+                    json_string(&String::from_utf8_lossy(&tag[..])),
+                    key.as_ref().map_or("null".into(), |key| json_string(key)),
+                    json_string(value))
+        }).collect();
+        writeln!(out, concat!("{{\"input\":{},\"bytes\":{},\"width\":{},\"height\":{},",
+                              "\"color_type\":\"{}\",\"bit_depth\":{},\"interlaced\":{},",
+                              "\"truncated\":{},\"chunks\":[{}],\"metadata\":[{}]}}"),
+                 json_string(&name), data.len(), width, height, color_type, depth,
+                 interlaced, truncated, chunks.join(","), metadata.join(","))?;
+        return Ok(());
+    }
+
+    writeln!(out, "{}: {}x{} {}, {}-bit, {}, {} bytes",
+             name, width, height, color_type, depth,
+             if interlaced { "interlaced" } else { "not interlaced" },
+             data.len())?;
+    let mut i = 0;
+    while i < chunks.len() {
+        let (tag, offset, body) = chunks[i];
+        let run = chunks[i ..].iter().take_while(|(next, _, _)| &tag == b"IDAT" && next == &tag).count();
+        if run > 1 {
+            let total: usize = chunks[i .. i + run].iter().map(|(_, _, body)| body.len()).sum();
+            writeln!(out, "  {} {:>10} bytes at {}, in {} chunks",
+                     String::from_utf8_lossy(&tag), total, offset, run)?;
+            i += run;
+        } else {
+            writeln!(out, "  {} {:>10} bytes at {}", String::from_utf8_lossy(&tag), body.len(), offset)?;
+            i += 1;
+        }
+    }
+    if truncated {
+        writeln!(out, "  truncated at byte {}", end)?;
+    }
+    for (tag, key, value) in &metadata {
+        let value = value.replace('\n', "\\n");
+        match key {
+            Some(key) => writeln!(out, "  {} {}: {}", String::from_utf8_lossy(&tag[..]), key, value)?,
+            None => writeln!(out, "  {} {}", String::from_utf8_lossy(&tag[..]), value)?,
+        }
+    }
+    Ok(())
+}
+
+//
+// Print information on each file, carrying on past ones that can't
+// be read.
+//
+fn info(args: &ArgMatches, progress: &ProgressBar) -> io::Result<()>
+{
+    let files: Vec<&Path> = args.values_of("files").unwrap().map(Path::new).collect();
+    let mut out = io::stdout().lock();
+    let mut failed = 0;
+    let mut failure = None;
+    for &file in &files {
+        if let Err(e) = info_file(args, file, &mut out) {
+            if e.kind() == io::ErrorKind::BrokenPipe {
+                return Err(e);
+            }
+            report_failure(args, progress, Some(file), None, &e);
+            failed += 1;
+            failure = Some(Failure::merge(failure, Failure::of(&e)));
+        }
+    }
+
+    match failure {
+        Some(failure) => {
+            let summary = if files.len() > 1 {
+                format!("{} of {} files failed", failed, files.len())
+            } else {
+                String::new()
+            };
+            Err(Error::other(Reported(failure, summary)))
+        },
+        None => Ok(()),
+    }
+}
+
+//
+// How two images' pixels compare: the number of pixels that differ,
+// the largest difference in any channel, and where the first is.
+//
+struct PixelDiff {
+    differing: u64,
+    max_difference: u16,
+    first: Option<(u32, u32)>,
+}
+
+//
+// Compare two images' pixels, as stored if their formats match, or
+// else as RGBA at the greater of their depths, so a file re-encoded
+// with a different color type still compares equal if it looks the
+// same. Both must be the same size.
+//
+fn diff_pixels(a: &Image, b: &Image) -> io::Result<PixelDiff>
+{
+    let same_format = a.header.color_type() as u8 == b.header.color_type() as u8 &&
+                      a.header.depth() == b.header.depth() &&
+                      a.palette == b.palette &&
+                      a.transparency == b.transparency;
+    let (header, data_a, data_b) = if same_format {
+        (a.header, a.data.as_ref().to_vec(), b.data.as_ref().to_vec())
+    } else {
+        let depth = if cmp::max(a.header.depth(), b.header.depth()) > 8 { 16 } else { 8 };
+        let rgba = |image: &Image| {
+            mtpng::convert::convert(&image.header, image.data.as_ref(),
+                                    image.palette.as_deref(), image.transparency.as_deref(),
+                                    ColorType::TruecolorAlpha, depth)
+        };
+        let (header, data_a) = rgba(a)?;
+        let (_, data_b) = rgba(b)?;
+        (header, data_a, data_b)
+    };
+
+    let width = header.width() as usize;
+    let depth = header.depth();
+    let channels = header.color_type().channels();
+    let mut diff = PixelDiff { differing: 0, max_difference: 0, first: None };
+    let stride = header.stride();
+    for (y, (row_a, row_b)) in data_a.chunks(stride).zip(data_b.chunks(stride)).enumerate() {
+        if row_a == row_b {
+            continue;
+        }
+        for x in 0 .. width {
+            let mut differs = false;
+            for c in 0 .. channels {
+                let i = x * channels + c;
+                let (va, vb) = (sample(row_a, i, depth), sample(row_b, i, depth));
+                if va != vb {
+                    differs = true;
+                    diff.max_difference = cmp::max(diff.max_difference, va.abs_diff(vb));
+                }
+            }
+            if differs {
+                diff.differing += 1;
+                diff.first.get_or_insert((x as u32, y as u32));
+            }
+        }
+    }
+    Ok(diff)
+}
+
+//
+// Compare two PNG files' chunks and pixels. Chunks are matched up by
+// tag, with image data compared by its pixels rather than its bytes.
+// The exit code is that for differing images if the pixels differ,
+// or with --strict if any other chunks do.
+//
+fn diff(args: &ArgMatches, progress: &ProgressBar) -> io::Result<()>
+{
+    let files = [Path::new(args.value_of("a").unwrap()), Path::new(args.value_of("b").unwrap())];
+    let mut data = [Vec::new(), Vec::new()];
+    let mut images = Vec::new();
+    for (file, data) in files.iter().zip(data.iter_mut()) {
+        let read = open_input(file).and_then(|mut input| input.read_to_end(data))
+            .and_then(|_| decode_image(file, io::Cursor::new(&data[..]), false));
+        match read {
+            Ok(image) => images.push(image),
+            Err(e) => {
+                report_failure(args, progress, Some(file), None, &e);
+                return Err(Error::other(Reported(Failure::of(&e), String::new())));
+            },
+        }
+    }
+    let names = [files[0].to_string_lossy(), files[1].to_string_lossy()];
+
+    // Each tag in the order first seen, with those only in the second
+    // file placed after the one before them there, and how its chunks
+    // compare.
+    let is_png = |data: &[u8]| data.starts_with(&PNG_SIGNATURE);
+    let mut chunks = [Vec::new(), Vec::new()];
+    let mut tags: Vec<[u8; 4]> = Vec::new();
+    if is_png(&data[0]) && is_png(&data[1]) {
+        chunks = [png_chunks(&data[0]).0, png_chunks(&data[1]).0];
+        for (tag, _, _) in &chunks[0] {
+            if !tags.contains(tag) {
+                tags.push(*tag);
+            }
+        }
+        let mut at = 0;
+        for (tag, _, _) in &chunks[1] {
+            match tags.iter().position(|t| t == tag) {
+                Some(i) => at = cmp::max(at, i + 1),
+                None => {
+                    tags.insert(at, *tag);
+                    at += 1;
+                },
+            }
+        }
+    }
+    let mut chunks_differ = false;
+    let compared: Vec<([u8; 4], &str, usize, usize)> = tags.iter().map(|tag| {
+        fn bodies<'a>(chunks: &[Chunk<'a>], tag: &[u8; 4]) -> Vec<&'a [u8]> {
+            chunks.iter().filter(|(t, _, _)| t == tag).map(|&(_, _, body)| body).collect()
+        }
+        let (a, b) = (bodies(&chunks[0], tag), bodies(&chunks[1], tag));
+        let bytes = |bodies: &[&[u8]]| bodies.iter().map(|body| body.len()).sum::<usize>();
+        let status = if b.is_empty() {
+            "only_a"
+        } else if a.is_empty() {
+            "only_b"
+        } else if a == b {
+            "same"
+        } else {
+            "differs"
+        };
+        // Image data may be compressed differently for the same
+        // pixels, so it's judged by comparing those instead.
+        if status != "same" && tag != b"IDAT" {
+            chunks_differ = true;
+        }
+        (*tag, status, bytes(&a), bytes(&b))
+    }).collect();
+
+    let (a, b) = (&images[0], &images[1]);
+    let size_a = (a.header.width(), a.header.height());
+    let size_b = (b.header.width(), b.header.height());
+    let pixels = if size_a == size_b { Some(diff_pixels(a, b)?) } else { None };
+    let identical = pixels.as_ref().is_some_and(|pixels| pixels.differing == 0);
+
+    let mut out = io::stdout().lock();
+    if args.is_present("json") {
+        let chunks: Vec<String> = compared.iter().map(|(tag, status, bytes_a, bytes_b)| {
+            format!("{{\"tag\":{},\"status\":\"{}\",\"a_bytes\":{},\"b_bytes\":{}}}",
+                    json_string(&String::from_utf8_lossy(tag)), status, bytes_a, bytes_b)
+        }).collect();
+        let pixels = match pixels {
+            Some(ref pixels) => format!("{{\"differing\":{},\"max_difference\":{},\"first\":{}}}",
+                                        pixels.differing, pixels.max_difference,
+                                        pixels.first.map_or("null".into(),
+                                                            |(x, y)| format!("[{},{}]", x, y))),
+            None => "null".to_string(),
+        };
+        writeln!(out, "{{\"a\":{},\"b\":{},\"identical\":{},\"chunks\":[{}],\"pixels\":{}}}",
+                 json_string(&names[0]), json_string(&names[1]), identical,
+                 chunks.join(","), pixels)?;
+    } else if !args.is_present("quiet") {
+        for (tag, status, bytes_a, bytes_b) in &compared {
+            let note = if tag == b"IDAT" && identical { " (pixels identical)" } else { "" };
+            let tag = String::from_utf8_lossy(tag);
+            match *status {
+                "only_a" => writeln!(out, "  {} only in {}", tag, names[0])?,
+                "only_b" => writeln!(out, "  {} only in {}", tag, names[1])?,
+                "differs" if bytes_a != bytes_b => {
+                    writeln!(out, "  {} differs{}, {} -> {} bytes", tag, note, bytes_a, bytes_b)?;
+                },
+                "differs" => writeln!(out, "  {} differs{}", tag, note)?,
+                _ if bytes_a != bytes_b => writeln!(out, "  {} {} -> {} bytes", tag, bytes_a, bytes_b)?,
+                _ => writeln!(out, "  {} same", tag)?,
+            }
+        }
+        match pixels {
+            None => writeln!(out, "Sizes differ: {}x{} vs {}x{}", size_a.0, size_a.1, size_b.0, size_b.1)?,
+            Some(PixelDiff { differing: 0, .. }) => writeln!(out, "Pixels are identical")?,
+            Some(PixelDiff { differing, max_difference, first }) => {
+                let (x, y) = first.unwrap_or_default();
+                writeln!(out, "Pixels differ: {} of {} by up to {}, first at {},{}",
+                         differing, u64::from(size_a.0) * u64::from(size_a.1),
+                         max_difference, x, y)?;
+            },
+        }
+    }
+
+    if !identical || (chunks_differ && args.is_present("strict")) {
+        return Err(Error::other(Reported(Failure::Differ, String::new())));
+    }
+    Ok(())
+}
+
+//
+// Options for how images are encoded, shared by bench.
+//
+fn encoding_args<'a>() -> [Arg<'a>; 4]
+{
+    [
+        Arg::new("chunk-size")
             .long("chunk-size")
             .value_name("bytes")
             .help("Divide image into chunks of at least this given size.")
-            .takes_value(true))
-        .arg(Arg::new("filter")
+            .takes_value(true),
+        Arg::new("filter")
             .long("filter")
             .value_name("filter")
-            .help("Set a fixed filter: one of none, sub, up, average, or paeth."))
-        .arg(Arg::new("level")
-            .long("level")
-            .value_name("level")
-            .help("Set deflate compression level, from 1-9."))
-        .arg(Arg::new("strategy")
+            .help("Set a fixed filter: one of none, sub, up, average, or paeth."),
+        Arg::new("strategy")
             .long("strategy")
             .value_name("strategy")
-            .help("Deflate strategy: one of filtered, huffman, rle, or fixed."))
-        .arg(Arg::new("streaming")
+            .help("Deflate strategy: one of filtered, huffman, rle, or fixed."),
+        Arg::new("streaming")
             .long("streaming")
             .value_name("streaming")
-            .help("Use streaming output mode; trades off file size for lower latency and memory usage"))
+            .help("Use streaming output mode; trades off file size for lower latency and memory usage"),
+    ]
+}
+
+//
+// Parse a comma-separated list of values for bench to try in turn.
+//
+fn sweep<'a, T, F>(args: &'a ArgMatches, name: &str, parse: F) -> io::Result<Vec<Option<T>>>
+    where F: Fn(&'a str) -> io::Result<T>
+{
+    match args.value_of(name) {
+        None => Ok(vec![None]),
+        Some(list) => list.split(',').map(|s| parse(s.trim()).map(Some)).collect(),
+    }
+}
+
+//
+// Timings of one encoder and its settings, in milliseconds, sorted.
+//
+struct Timings {
+    encoder: &'static str,
+    threads: usize,
+    level: String,
+    times: Vec<f64>,
+    bytes: u64,
+}
+
+impl Timings {
+    fn median(&self) -> f64 {
+        self.times[self.times.len() / 2]
+    }
+
+    fn p95(&self) -> f64 {
+        self.times[(self.times.len() * 95).div_ceil(100) - 1]
+    }
+}
+
+//
+// Run an encode some times untimed to warm up, then time more runs.
+// The encode returns the size of its output.
+//
+fn time_runs<F>(warmup: usize, runs: usize, mut tick: impl FnMut(), mut encode: F)
+    -> io::Result<(Vec<f64>, u64)>
+    where F: FnMut() -> io::Result<u64>
+{
+    let mut times = Vec::with_capacity(runs);
+    let mut bytes = 0;
+    for run in 0 .. warmup + runs {
+        let start = Instant::now();
+        bytes = encode()?;
+        if run >= warmup {
+            times.push(start.elapsed().as_secs_f64() * 1000.0);
+        }
+        tick();
+    }
+    times.sort_by(f64::total_cmp);
+    Ok((times, bytes))
+}
+
+//
+// Encode with the png crate, for --compare. It only uses one thread.
+//
+fn png_crate_encode(image: &Image, compression: png::Compression) -> io::Result<u64>
+{
+    let header = &image.header;
+    let mut output = Vec::new();
+    let mut encoder = png::Encoder::new(&mut output, header.width(), header.height());
+    encoder.set_color(png::ColorType::from_u8(header.color_type() as u8).unwrap());
+    encoder.set_depth(png::BitDepth::from_u8(header.depth()).unwrap());
+    encoder.set_compression(compression);
+    if let Some(v) = &image.palette {
+        encoder.set_palette(&v[..]);
+    }
+    if let Some(v) = &image.transparency {
+        encoder.set_trns(&v[..]);
+    }
+    let encoding_error = |e: png::EncodingError| err(&e.to_string());
+    let mut writer = encoder.write_header().map_err(encoding_error)?;
+    writer.write_image_data(image.data.as_ref()).map_err(encoding_error)?;
+    writer.finish().map_err(encoding_error)?;
+    Ok(output.len() as u64)
+}
+
+//
+// Time encoding an image in memory, with no file I/O, after some
+// warm-up runs. Each combination of the thread counts and levels
+// given is run in turn, and reported with the fastest, median, and
+// 95th percentile times, and the speed of the median in megabytes
+// of raw image data per second. With --compare, the png crate is
+// timed too at each of its compression settings.
+//
+fn bench(args: &ArgMatches, progress: &ProgressBar) -> io::Result<()>
+{
+    let count = |name: &str, default: usize| match args.value_of(name) {
+        Some(s) => s.parse::<usize>().map_err(|_e| usage(&format!("invalid {}", name))),
+        None => Ok(default),
+    };
+    let runs = count("runs", 10)?;
+    let warmup = count("warmup", 2)?;
+    if runs == 0 {
+        return Err(usage("invalid runs"));
+    }
+    let thread_counts = sweep(args, "threads", |s| {
+        s.parse::<usize>().ok().filter(|&n| n > 0).ok_or_else(|| usage("invalid threads"))
+    })?;
+    // The main thread can only join one pool, so there's nothing to sweep.
+    if NO_THREADS && thread_counts.len() > 1 {
+        return Err(usage("only one thread count can be benchmarked without threads"));
+    }
+    let levels = sweep(args, "level", |s| compression_level(s).map(|_| s))?;
+    let compared: &[(&str, png::Compression)] = if args.is_present("compare") {
+        &[("fast", png::Compression::Fast),
+          ("default", png::Compression::Default),
+          ("best", png::Compression::Best)]
+    } else {
+        &[]
+    };
+
+    let input = Path::new(args.value_of("file").unwrap());
+    let image = read_image(input, false).map_err(|e| {
+        report_failure(args, progress, Some(input), None, &e);
+        Error::other(Reported(Failure::of(&e), String::new()))
+    })?;
+    let raw_bytes = image.data.as_ref().len();
+    let json = args.is_present("json");
+    let mut out = io::stdout().lock();
+    if !json {
+        writeln!(out, "{}: {}x{} {}, {}-bit, {} runs after {} warm-up",
+                 input.display(), image.header.width(), image.header.height(),
+                 color_type_name(image.header.color_type()), image.header.depth(),
+                 runs, warmup)?;
+        writeln!(out, "{:<9} {:>7} {:>7} {:>10} {:>10} {:>10} {:>8} {:>10}",
+                 "encoder", "threads", "level", "min ms", "median ms", "p95 ms", "MB/s", "bytes")?;
+    }
+    let mut print = |timings: &Timings| -> io::Result<()> {
+        let speed = raw_bytes as f64 / 1000.0 / timings.median();
+        progress.clear();
+        if json {
+            writeln!(out, concat!("{{\"input\":{},\"encoder\":\"{}\",\"threads\":{},\"level\":{},\"runs\":{},",
+                                  "\"min_ms\":{:.3},\"median_ms\":{:.3},\"p95_ms\":{:.3},",
+                                  "\"mb_per_s\":{:.1},\"raw_bytes\":{},\"output_bytes\":{}}}"),
+                     json_string(&input.to_string_lossy()), timings.encoder, timings.threads,
+                     json_string(&timings.level), runs, timings.times[0], timings.median(),
+                     timings.p95(), speed, raw_bytes, timings.bytes)?;
+        } else {
+            writeln!(out, "{:<9} {:>7} {:>7} {:>10.1} {:>10.1} {:>10.1} {:>8.1} {:>10}",
+                     timings.encoder, timings.threads, timings.level, timings.times[0],
+                     timings.median(), timings.p95(), speed, timings.bytes)?;
+        }
+        Ok(())
+    };
+
+    let total = (thread_counts.len() * levels.len() + compared.len()) * (warmup + runs);
+    let done = AtomicUsize::new(0);
+    let tick = || progress.update(done.fetch_add(1, Ordering::Relaxed) + 1, total, "runs");
+    let mut results = Vec::new();
+    for &threads in &thread_counts {
+        let pool = build_pool(threads.unwrap_or(0))?;
+        for &level in &levels {
+            let options = encoder_options(&pool, args, level)?;
+            let (times, bytes) = time_runs(warmup, runs, tick, || {
+                let mut encoder = Encoder::new(io::sink(), &options);
+                encoder.write_header(&image.header)?;
+                if let Some(v) = &image.palette {
+                    encoder.write_palette(v)?;
+                }
+                if let Some(v) = &image.transparency {
+                    encoder.write_transparency(v)?;
+                }
+                encoder.write_image_rows_shared(image.data.clone())?;
+                Ok(encoder.finish_with_stats()?.1.bytes_written)
+            })?;
+            let timings = Timings {
+                encoder: "mtpng",
+                threads: pool.current_num_threads(),
+                level: level.unwrap_or("default").to_string(),
+                times,
+                bytes,
+            };
+            print(&timings)?;
+            results.push(timings);
+        }
+    }
+    for &(name, compression) in compared {
+        let (times, bytes) = time_runs(warmup, runs, tick, || png_crate_encode(&image, compression))?;
+        let timings = Timings { encoder: "png", threads: 1, level: name.to_string(), times, bytes };
+        print(&timings)?;
+        results.push(timings);
+    }
+    progress.clear();
+
+    // Sum up the first settings against the png crate's default.
+    if let (false, [first, ..]) = (json, &results[..]) {
+        if let Some(png) = results.iter().find(|t| t.encoder == "png" && t.level == "default") {
+            let change = first.bytes as f64 / png.bytes as f64 - 1.0;
+            writeln!(out, "mtpng with {} thread{} at level {} is {:.1}x as fast as the png crate's default, \
+                      with output {:.1}% {}",
+                     first.threads, if first.threads == 1 { "" } else { "s" }, first.level,
+                     png.median() / first.median(), change.abs() * 100.0,
+                     if change > 0.0 { "larger" } else { "smaller" })?;
+        }
+    }
+
+    Ok(())
+}
+
+//
+// Default settings for options not given on the command line, from
+// environment variables such as MTPNG_LEVEL or else the config file,
+// which holds the same settings in lowercase:
+//
+//   threads = 4
+//   level = 9
+//   filter = "paeth"
+//   strip = "safe"
+//
+// The file is $MTPNG_CONFIG if set, or mtpng.toml in $XDG_CONFIG_HOME
+// or ~/.config. They're checked up front so a bad one is reported as
+// such, rather than as though it were given on the command line.
+//
+struct Defaults(Vec<(&'static str, String)>);
+
+const DEFAULTS: [&str; 4] = ["threads", "level", "filter", "strip"];
+
+impl Defaults {
+    fn load() -> io::Result<Defaults> {
+        let config_dir = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+        let (path, required) = match (env::var_os("MTPNG_CONFIG"), config_dir) {
+            (Some(path), _) => (PathBuf::from(path), true),
+            (None, Some(dir)) => (dir.join("mtpng.toml"), false),
+            (None, None) => return Defaults::from_env(Vec::new(), |var| env::var_os(var)),
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => String::new(),
+            Err(e) => return Err(Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+        };
+        let settings = Defaults::parse(&path.display().to_string(), &text)?;
+        Defaults::from_env(settings, |var| env::var_os(var))
+    }
+
+    //
+    // Read the settings in a config file's text, with its path for errors.
+    //
+    fn parse(source: &str, text: &str) -> io::Result<Vec<(&'static str, String)>> {
+        let table = text.parse::<toml::Value>()
+            .map_err(|e| usage(&format!("{}: {}", source, e)))?;
+        let mut settings = Vec::new();
+        for (key, value) in table.as_table().into_iter().flatten() {
+            let name = DEFAULTS.iter().find(|&&name| name == key).ok_or_else(|| {
+                usage(&format!("{}: unknown setting {}; try {}", source, key, DEFAULTS.join(", ")))
+            })?;
+            let value = match value {
+                toml::Value::String(s) => s.clone(),
+                toml::Value::Integer(n) => n.to_string(),
+                _ => return Err(usage(&format!("{}: {} should be a string or number", source, key))),
+            };
+            Defaults::check(source, name, &value)?;
+            settings.push((*name, value));
+        }
+        Ok(settings)
+    }
+
+    //
+    // Override the settings with MTPNG_* variables, as found by lookup.
+    //
+    fn from_env<F>(mut settings: Vec<(&'static str, String)>, lookup: F) -> io::Result<Defaults>
+        where F: Fn(&str) -> Option<OsString>
+    {
+        for name in &DEFAULTS {
+            let var = format!("MTPNG_{}", name.to_uppercase());
+            if let Some(value) = lookup(&var) {
+                let value = value.into_string().map_err(|_e| usage(&format!("{}: not valid UTF-8", var)))?;
+                Defaults::check(&var, name, &value)?;
+                settings.retain(|(setting, _)| setting != name);
+                settings.push((name, value));
+            }
+        }
+        Ok(Defaults(settings))
+    }
+
+    fn check(source: &str, name: &str, value: &str) -> io::Result<()> {
+        let valid = match name {
+            "threads" => value.parse::<usize>().is_ok(),
+            "level" => compression_level(value).is_ok(),
+            "filter" => ["adaptive", "none", "sub", "up", "average", "paeth"].contains(&value),
+            _ => check_strip(value).is_ok(),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(usage(&format!("{}: invalid {} {}", source, name, value)))
+        }
+    }
+
+    //
+    // Set the defaults on those of the command's options they apply to.
+    //
+    fn apply<'a>(&'a self, command: Command<'a>) -> Command<'a> {
+        self.0.iter().fold(command, |command, (name, value)| {
+            if command.get_arguments().any(|arg| arg.get_id() == *name) {
+                command.mut_arg(*name, |arg| arg.default_value(value))
+            } else {
+                command
+            }
+        })
+    }
+}
+
+//
+// The command line, with the defaults from the config file and
+// environment applied.
+//
+fn command(defaults: &Defaults) -> Command<'_> {
+    defaults.apply(Command::new("mtpng parallel PNG encoder")
+        .version("0.4.0")
+        .author("Brion Vibber <brion@pobox.com>")
+        .about("Re-encodes PNG images using multiple CPU cores to exercise the mtpng library.")
+        .args(encoding_args())
+        .arg(Arg::new("level")
+            .long("level")
+            .value_name("level")
+            .help("Set deflate compression level, from 1-9, or default (6)."))
         .arg(Arg::new("threads")
             .long("threads")
             .value_name("threads")
             .help("Override default number of threads."))
-        .arg(Arg::new("repeat")
-            .long("repeat")
+        .arg(Arg::new("jobs")
+            .long("jobs")
+            .short('j')
             .value_name("n")
-            .help("Run conversion n times, as load benchmarking helper."))
-        .arg(Arg::new("input")
-            .help("Input filename, must be another PNG.")
-            .required(true)
-            .index(1))
-        .arg(Arg::new("output")
-            .help("Output filename.")
+            .help("Convert up to n files at once in batch mode; defaults to the number of threads."))
+        .arg(Arg::new("optimize")
+            .long("optimize")
+            .short('O')
+            .value_name("trials")
+            .help("Try up to this many combinations of filter, strategy, and level, keeping the smallest output; 31 tries them all."))
+        .arg(Arg::new("quiet")
+            .long("quiet")
+            .short('q')
+            .global(true)
+            .help("Print only errors, with no progress bar or timing."))
+        .arg(Arg::new("json")
+            .long("json")
+            .global(true)
+            .help("Print a line of JSON to stdout for each file, with sizes, timings, and the settings used."))
+        .arg(Arg::new("keep-metadata")
+            .long("keep-metadata")
+            .help("Copy ancillary chunks from PNG input, such as text, color space, physical size, and EXIF data."))
+        .arg(Arg::new("strip")
+            .long("strip")
+            .value_name("chunks")
+            .validator(check_strip)
+            .help("Copy ancillary chunks from PNG input except these: all, safe to keep only those affecting display such as color space, or a comma-separated list of chunk names."))
+        .arg(Arg::new("text")
+            .long("text")
+            .value_name("keyword=value")
+            .multiple_occurrences(true)
+            .help("Add a text chunk, such as Author=name; may be repeated. Standard keywords include Title, Author, Description, Copyright, Software, and Source."))
+        .arg(Arg::new("comment")
+            .long("comment")
+            .value_name("text")
+            .help("Add a text chunk with the Comment keyword."))
+        .arg(Arg::new("color-type")
+            .long("color-type")
+            .value_name("type")
+            .help("Convert to this color type: gray, graya, rgb, rgba, or indexed, which reduces to as many colors as the bit depth allows."))
+        .arg(Arg::new("bit-depth")
+            .long("bit-depth")
+            .value_name("depth")
+            .help("Convert to this bit depth: 1, 2, 4, 8, or 16, as allowed by the color type."))
+        .arg(Arg::new("colors")
+            .long("colors")
+            .value_name("n")
+            .help("Reduce to an indexed-color image with at most n colors, from 2-256, for smaller files."))
+        .arg(Arg::new("dither")
+            .long("dither")
+            .help("Dither when reducing to indexed color, for smoother gradients at the cost of some noise and size."))
+        .arg(Arg::new("background")
+            .long("background")
+            .value_name("color")
+            .help("Composite transparent images over this color, such as '#ffffff', making them opaque."))
+        .arg(Arg::new("icc")
+            .long("icc")
+            .value_name("file")
+            .help("Embed the ICC color profile from this file, replacing any copied from the input."))
+        .arg(Arg::new("srgb")
+            .long("srgb")
+            .value_name("intent")
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("perceptual")
+            .conflicts_with_all(&["icc", "gamma"])
+            .help("Mark the output as sRGB, with a rendering intent of perceptual (the default), relative, saturation, or absolute, as in --srgb=relative."))
+        .arg(Arg::new("gamma")
+            .long("gamma")
+            .value_name("gamma")
+            .help("Write a gamma value, either as the display exponent such as 2.2 or as stored such as 0.45455."))
+        .arg(Arg::new("dpi")
+            .long("dpi")
+            .value_name("dpi")
+            .conflicts_with("ppm")
+            .help("Set the physical resolution in dots per inch, such as 300 or 300x600, replacing any copied from the input."))
+        .arg(Arg::new("ppm")
+            .long("ppm")
+            .value_name("ppm")
+            .help("Set the physical resolution in pixels per meter, such as 11811 or 11811x23622, replacing any copied from the input."))
+        .arg(Arg::new("timestamp")
+            .long("timestamp")
+            .help("Write the time of conversion in a tIME chunk, replacing any copied from the input; SOURCE_DATE_EPOCH is used instead if set, for reproducible builds."))
+        .arg(Arg::new("raw")
+            .long("raw")
+            .requires_all(&["size", "format"])
+            .help("Read input as raw pixels with no header, as given by --size and --format."))
+        .arg(Arg::new("size")
+            .long("size")
+            .value_name("WxH")
+            .help("Width and height of raw input, such as 1920x1080."))
+        .arg(Arg::new("format")
+            .long("format")
+            .value_name("format")
+            .help("Pixel format of raw input: one of gray8, graya8, rgb8, bgr8, rgba8, bgra8, or gray16, graya16, rgb16, rgba16 with a be or le suffix for byte order."))
+        .arg(Arg::new("mmap")
+            .long("mmap")
+            .help("Decode input into a memory-mapped temporary file instead of the heap, to reduce peak memory use on very large images."))
+        .arg(Arg::new("low-memory")
+            .long("low-memory")
+            .conflicts_with("mmap")
+            .help("Convert PNG input a few rows at a time instead of reading the whole image in, for images too large for memory. Output is written in streaming mode."))
+        .arg(Arg::new("out-dir")
+            .long("out-dir")
+            .value_name("dir")
+            .help("Convert any number of inputs, writing outputs to this directory."))
+        .arg(Arg::new("suffix")
+            .long("suffix")
+            .value_name("suffix")
+            .allow_hyphen_values(true)
+            .help("Convert any number of inputs, adding this to each output's file name, as in foo.png -> foo<suffix>.png."))
+        .arg(Arg::new("force")
+            .long("force")
+            .short('f')
+            .help("Overwrite output files that already exist."))
+        .arg(Arg::new("in-place")
+            .long("in-place")
+            .short('i')
+            .conflicts_with_all(&["out-dir", "suffix"])
+            .help("Convert any number of inputs, replacing each with its output only once that's complete."))
+        .arg(Arg::new("preserve-times")
+            .long("preserve-times")
+            .requires("in-place")
+            .help("Keep the access and modification times of files replaced by --in-place."))
+        .arg(Arg::new("recursive")
+            .long("recursive")
+            .short('r')
+            .help("Convert the PNG files in input directories and their subdirectories, keeping the tree under --out-dir."))
+        .arg(Arg::new("files")
+            .value_name("files")
+            .help("Input and output filenames; use - for stdin or stdout. Input must be PNG or PNM (PBM, PGM, PPM, PAM), or with the image feature JPEG, GIF, WebP, TIFF, BMP, or TGA. With --out-dir, --suffix, or --in-place, any number of input files, directories, or wildcard patterns.")
             .required(true)
-            .index(2))
-        .get_matches();
+            .multiple_values(true))
+        .subcommand_negates_reqs(true)
+        .subcommand(Command::new("verify")
+            .about("Checks PNG files for corruption, exiting with status 3 if any are corrupt.")
+            .arg(Arg::new("decode")
+                .long("decode")
+                .help("Decompress the image data too, checking its zlib checksum, not just the chunk structure and CRCs."))
+            .arg(Arg::new("files")
+                .value_name("files")
+                .help("PNG files to check; use - for stdin.")
+                .required(true)
+                .multiple_values(true)))
+        .subcommand(defaults.apply(Command::new("bench")
+            .about("Times encoding an image in memory, reporting the fastest, median, and 95th percentile times and throughput.")
+            .args(encoding_args())
+            .arg(Arg::new("level")
+                .long("level")
+                .value_name("levels")
+                .help("Compression level, or a comma-separated list to try each, such as 1,6,9."))
+            .arg(Arg::new("threads")
+                .long("threads")
+                .value_name("threads")
+                .help("Number of threads, or a comma-separated list to try each, such as 1,2,4,8."))
+            .arg(Arg::new("compare")
+                .long("compare")
+                .help("Also time the png crate at its fast, default, and best compression, to compare."))
+            .arg(Arg::new("runs")
+                .long("runs")
+                .value_name("n")
+                .help("Number of timed runs of each combination; defaults to 10."))
+            .arg(Arg::new("warmup")
+                .long("warmup")
+                .value_name("n")
+                .help("Number of untimed runs first, to warm up caches and threads; defaults to 2."))
+            .arg(Arg::new("file")
+                .value_name("file")
+                .help("Image to encode; use - for stdin.")
+                .required(true))))
+        .subcommand(Command::new("diff")
+            .about("Compares two images' chunks and pixels, exiting with status 5 if the pixels differ.")
+            .arg(Arg::new("strict")
+                .long("strict")
+                .help("Also count chunks other than image data that differ, or are in only one file."))
+            .arg(Arg::new("a")
+                .value_name("a")
+                .help("First image; use - for stdin.")
+                .required(true))
+            .arg(Arg::new("b")
+                .value_name("b")
+                .help("Second image.")
+                .required(true)))
+        .subcommand(Command::new("info")
+            .about("Prints the size, format, chunks, and metadata of PNG files, without decoding them.")
+            .arg(Arg::new("files")
+                .value_name("files")
+                .help("PNG files to describe; use - for stdin.")
+                .required(true)
+                .multiple_values(true))))
+}
+
+pub fn main() {
+    let defaults = Defaults::load().unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(Failure::of(&e) as i32);
+    });
+    let matches = command(&defaults).get_matches();
+
+    // Global options are found in the subcommand's matches.
+    let args = matches.subcommand().map_or(&matches, |(_, args)| args);
+    let progress = ProgressBar::new(args);
+    let result = match matches.subcommand() {
+        Some(("verify", args)) => verify(args, &progress),
+        Some(("info", args)) => info(args, &progress),
+        Some(("diff", args)) => diff(args, &progress),
+        Some(("bench", args)) => bench(args, &progress),
+        _ => doit(&matches, &progress),
+    };
+    if let Err(e) = result {
+        // Output piped into head and the like stops early; that's fine.
+        if e.kind() == io::ErrorKind::BrokenPipe {
+            process::exit(0);
+        }
+        let failure = Failure::of(&e);
+        match e.get_ref().and_then(|inner| inner.downcast_ref::<Reported>()) {
+            Some(Reported(_, summary)) => {
+                if !summary.is_empty() && !args.is_present("quiet") {
+                    eprintln!("Error: {}", summary);
+                }
+            },
+            None => report_failure(args, &progress, None, None, &e),
+        }
+        process::exit(failure as i32);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::io;
+
+    use mtpng::{ColorType, CompressionLevel};
+
+    use rayon::{ThreadPool, ThreadPoolBuilder};
+
+    use super::{background_color, check_strip, command, compression_level, png_chunks, raw_format, read_pnm};
+    use super::{median_cut, quantize, reformat, rgba8};
+    use super::{Defaults, Failure, Image, Nearest, PNG_SIGNATURE};
+
+    fn pnm(data: &[u8]) -> Image {
+        read_pnm(data, false).unwrap()
+    }
+
+    fn pnm_error(data: &[u8]) -> io::ErrorKind {
+        read_pnm(data, false).err().unwrap().kind()
+    }
+
+    fn args(defaults: &Defaults, args: &[&str]) -> clap::ArgMatches {
+        command(defaults).try_get_matches_from(args).unwrap()
+    }
+
+    fn pool() -> ThreadPool {
+        ThreadPoolBuilder::new().num_threads(2).build().unwrap()
+    }
+
+    #[test]
+    fn pnm_plain() {
+        // Plain PBM: black is 1, PNG's grey 0. Digits needn't be spaced.
+        let image = pnm(b"P1\n# comment\n3 2\n1 0 1\n010");
+        assert!(matches!(image.header.color_type(), ColorType::Greyscale));
+        assert_eq!(image.header.depth(), 1);
+        assert_eq!(image.data.as_ref(), [0b01000000, 0b10100000]);
+
+        // Plain PGM, scaled up from maxval 15.
+        let image = pnm(b"P2 2 1 15 0 15");
+        assert_eq!(image.header.depth(), 8);
+        assert_eq!(image.data.as_ref(), [0, 255]);
+
+        // Plain PPM with a maxval over 255 goes to 16 bits.
+        let image = pnm(b"P3 1 1 1000 1000 500 0");
+        assert!(matches!(image.header.color_type(), ColorType::Truecolor));
+        assert_eq!(image.header.depth(), 16);
+        assert_eq!(image.data.as_ref(), [255, 255, 128, 0, 0, 0]);
+    }
+
+    #[test]
+    fn pnm_raw() {
+        let image = pnm(b"P4 9 1\n\xff\x00");
+        assert_eq!(image.data.as_ref(), [0x00, 0xff]);
+
+        let image = pnm(b"P5 2 1 255\n\x10\x20");
+        assert_eq!(image.data.as_ref(), [0x10, 0x20]);
+
+        let image = pnm(b"P6 1 1 65535\n\x01\x02\x03\x04\x05\x06");
+        assert_eq!(image.header.depth(), 16);
+        assert_eq!(image.data.as_ref(), [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn pam() {
+        let image = pnm(b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n\
+                          \x01\x02\x03\x04\x05\x06\x07\x08");
+        assert!(matches!(image.header.color_type(), ColorType::TruecolorAlpha));
+        assert_eq!(image.header.width(), 2);
+        assert_eq!(image.data.as_ref(), [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let image = pnm(b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 2\nMAXVAL 1\nENDHDR\n\x01\x00");
+        assert!(matches!(image.header.color_type(), ColorType::GreyscaleAlpha));
+        assert_eq!(image.data.as_ref(), [255, 0]);
+    }
+
+    #[test]
+    fn pnm_errors() {
+        assert_eq!(pnm_error(b"P5 2 2 255\n\x00\x00\x00"), io::ErrorKind::InvalidData);
+        assert_eq!(pnm_error(b"P2 1 1 0 0"), io::ErrorKind::InvalidData);
+        assert_eq!(pnm_error(b"P2 1 1 15 16"), io::ErrorKind::InvalidData);
+        assert_eq!(pnm_error(b"P2 x 1 15 0"), io::ErrorKind::InvalidData);
+        assert_eq!(pnm_error(b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 5\nMAXVAL 255\nENDHDR\n"), io::ErrorKind::InvalidData);
+        assert_eq!(pnm_error(b"P7\nWIDTH 1\nHEIGHT 1\nENDHDR\n"), io::ErrorKind::InvalidData);
+        assert_eq!(pnm_error(b"P7\nWIDTH 1\n"), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn raw_geometry() {
+        let defaults = Defaults(Vec::new());
+        let matches = args(&defaults, &["mtpng", "--raw", "--size", "640x480", "--format", "bgra8", "in", "out"]);
+        let format = raw_format(&matches).unwrap().unwrap();
+        assert_eq!((format.width, format.height), (640, 480));
+        assert!(matches!(format.color_type, ColorType::TruecolorAlpha));
+        assert_eq!(format.depth, 8);
+        assert!(format.bgr && !format.little_endian);
+
+        let matches = args(&defaults, &["mtpng", "--raw", "--size", "1x1", "--format", "gray16le", "in", "out"]);
+        let format = raw_format(&matches).unwrap().unwrap();
+        assert_eq!(format.depth, 16);
+        assert!(format.little_endian && !format.bgr);
+
+        for (size, format) in &[("640", "rgb8"), ("640x", "rgb8"), ("axb", "rgb8"), ("2x2", "rgb9")] {
+            let matches = args(&defaults, &["mtpng", "--raw", "--size", size, "--format", format, "in", "out"]);
+            let e = raw_format(&matches).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+
+        let matches = args(&defaults, &["mtpng", "in", "out"]);
+        assert!(raw_format(&matches).unwrap().is_none());
+    }
+
+    #[test]
+    fn option_values() {
+        assert!(matches!(compression_level("default").unwrap(), CompressionLevel::Default));
+        assert!(matches!(compression_level("9").unwrap(), CompressionLevel::High));
+        assert!(compression_level("0").is_err());
+        assert!(compression_level("fast").is_err());
+
+        assert!(check_strip("all").is_ok());
+        assert!(check_strip("safe").is_ok());
+        assert!(check_strip("eXIf,tEXt").is_ok());
+        assert!(check_strip("eXIf,text1").is_err());
+
+        assert_eq!(background_color("#ffffff").unwrap(), [255, 255, 255]);
+        assert_eq!(background_color("102030").unwrap(), [0x10, 0x20, 0x30]);
+        assert_eq!(background_color("#f80").unwrap(), [0xff, 0x88, 0x00]);
+        assert!(background_color("#ff").is_err());
+        assert!(background_color("#gggggg").is_err());
+    }
+
+    #[test]
+    fn defaults_merge() {
+        let settings = Defaults::parse("mtpng.toml", "level = 9\nthreads = 4\nstrip = \"safe\"\n").unwrap();
+        assert_eq!(settings, [("level", "9".to_string()),
+                              ("strip", "safe".to_string()),
+                              ("threads", "4".to_string())]);
+
+        // Variables override the file.
+        let env = |var: &str| match var {
+            "MTPNG_LEVEL" => Some(OsString::from("1")),
+            "MTPNG_FILTER" => Some(OsString::from("paeth")),
+            _ => None,
+        };
+        let defaults = Defaults::from_env(settings, env).unwrap();
+        let matches = args(&defaults, &["mtpng", "in", "out"]);
+        assert_eq!(matches.value_of("level"), Some("1"));
+        assert_eq!(matches.value_of("filter"), Some("paeth"));
+        assert_eq!(matches.value_of("threads"), Some("4"));
+        assert_eq!(matches.value_of("strip"), Some("safe"));
+
+        // And the command line overrides both, in subcommands too.
+        let matches = args(&defaults, &["mtpng", "--level", "6", "in", "out"]);
+        assert_eq!(matches.value_of("level"), Some("6"));
+        let matches = args(&defaults, &["mtpng", "bench", "in"]);
+        let (_, bench) = matches.subcommand().unwrap();
+        assert_eq!(bench.value_of("level"), Some("1"));
+    }
+
+    #[test]
+    fn defaults_errors() {
+        let kind = |e: io::Error| e.kind();
+        assert_eq!(Defaults::parse("f", "level = 10").map_err(kind).err(), Some(io::ErrorKind::InvalidInput));
+        assert_eq!(Defaults::parse("f", "colour = 1").map_err(kind).err(), Some(io::ErrorKind::InvalidInput));
+        assert_eq!(Defaults::parse("f", "level = [9]").map_err(kind).err(), Some(io::ErrorKind::InvalidInput));
+        assert_eq!(Defaults::parse("f", "level = ").map_err(kind).err(), Some(io::ErrorKind::InvalidInput));
+
+        let env = |var: &str| if var == "MTPNG_THREADS" { Some(OsString::from("many")) } else { None };
+        assert!(Defaults::from_env(Vec::new(), env).is_err());
+    }
+
+    #[test]
+    fn failure_kinds() {
+        let of = |kind: io::ErrorKind| Failure::of(&io::Error::new(kind, "x")) as i32;
+        assert_eq!(of(io::ErrorKind::InvalidInput), 2);
+        assert_eq!(of(io::ErrorKind::InvalidData), 3);
+        assert_eq!(of(io::ErrorKind::UnexpectedEof), 3);
+        assert_eq!(of(io::ErrorKind::NotFound), 4);
+        assert_eq!(of(io::ErrorKind::Other), 1);
+
+        let e: io::Error = mtpng::Error::InvalidOptions("x").into();
+        assert_eq!(Failure::of(&e), Failure::Usage);
+        let e: io::Error = mtpng::Error::InvalidHeader("x").into();
+        assert_eq!(Failure::of(&e), Failure::Input);
+
+        assert_eq!(Failure::merge(None, Failure::Io), Failure::Io);
+        assert_eq!(Failure::merge(Some(Failure::Io), Failure::Io), Failure::Io);
+        assert_eq!(Failure::merge(Some(Failure::Io), Failure::Input), Failure::Internal);
+    }
+
+    #[test]
+    fn chunk_listing() {
+        let mut data = PNG_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data.extend_from_slice(b"teSt");
+        data.extend_from_slice(&[42, 0, 0, 0, 0]);
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(b"IEND");
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(b"trailing");
+
+        let (chunks, end) = png_chunks(&data);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0], (*b"teSt", 8, &[42u8][..]));
+        assert_eq!(&chunks[1].0, b"IEND");
+        assert_eq!(end, data.len() - 8);
+
+        // A chunk that's cut off stops the listing.
+        let (chunks, end) = png_chunks(&data[.. 20]);
+        assert!(chunks.is_empty());
+        assert_eq!(end, 8);
+
+        // As does a length that runs past the end, however large.
+        let mut huge = PNG_SIGNATURE.to_vec();
+        huge.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        huge.extend_from_slice(b"teSt");
+        huge.extend_from_slice(&[0; 8]);
+        assert_eq!(png_chunks(&huge), (vec![], 8));
+
+        // Short or non-PNG input has no chunks.
+        for short in &[&data[.. 3], &data[.. 8], &data[.. 12], &b"P1\n1 1\n0\n"[..]] {
+            let (chunks, _) = png_chunks(short);
+            assert!(chunks.is_empty());
+        }
+        assert_eq!(png_chunks(b"P1 1 0"), (vec![], 0));
+    }
+
+    #[test]
+    fn median_cut_exact() {
+        // No more colors than asked for: each comes out as it is.
+        let pixels = [10, 20, 30, 255, 200, 100, 0, 255, 10, 20, 30, 255, 0, 0, 0, 0, 200, 100, 0, 255];
+        let mut palette = median_cut(&pixels, 4);
+        palette.sort_unstable();
+        assert_eq!(palette, [[0, 0, 0, 0], [10, 20, 30, 255], [200, 100, 0, 255]]);
+
+        // One color, however many are asked for.
+        assert_eq!(median_cut(&[1, 2, 3, 4, 1, 2, 3, 4], 256), [[1, 2, 3, 4]]);
 
-    match doit(matches) {
-        Ok(()) => {},
-        Err(e) => eprintln!("Error: {}", e),
+        // Fewer colors are averaged.
+        assert_eq!(median_cut(&[0, 0, 0, 255, 0, 0, 2, 255], 1), [[0, 0, 1, 255]]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn nearest_color() {
+        let palette = [[0, 0, 0, 0], [0, 0, 0, 255], [255, 255, 255, 255]];
+        let mut nearest = Nearest::new(&palette);
+        assert_eq!(nearest.index([10, 10, 10, 255]), 1);
+        assert_eq!(nearest.index([200, 200, 200, 255]), 2);
+        assert_eq!(nearest.index([10, 10, 10, 20]), 0);
+        // Cached answers are the same.
+        assert_eq!(nearest.index([200, 200, 200, 255]), 2);
+        assert_eq!(nearest.index([10, 10, 10, 255]), 1);
+    }
+
+    #[test]
+    fn quantize_depth() {
+        let pool = pool();
+        for &(colors, depth) in &[(2, 1), (3, 2), (4, 2), (5, 4), (16, 4), (17, 8)] {
+            let mut data = format!("P5 {} 1 255\n", colors).into_bytes();
+            data.extend((0 .. colors).map(|i| (i * 15) as u8));
+            let image = pnm(&data);
+            let indexed = quantize(&pool, &image, 256, false).unwrap();
+            assert!(matches!(indexed.header.color_type(), ColorType::IndexedColor));
+            assert_eq!(indexed.header.depth(), depth, "{} colors", colors);
+            assert_eq!(indexed.palette.as_ref().unwrap().len(), colors * 3);
+            assert!(indexed.transparency.is_none());
+            // Few enough colors to be exact.
+            assert_eq!(rgba8(&indexed), rgba8(&image));
+        }
+    }
+
+    #[test]
+    fn quantize_transparency() {
+        let pool = pool();
+        let image = pnm(b"P7\nWIDTH 4\nHEIGHT 1\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n\
+                          \xff\x00\x00\xff\x00\xff\x00\x80\x00\x00\xff\xff\x00\x00\x00\x00");
+        let indexed = quantize(&pool, &image, 4, false).unwrap();
+        assert_eq!(indexed.header.depth(), 2);
+
+        // The translucent colors come first, and tRNS has only those.
+        let transparency = indexed.transparency.as_ref().unwrap();
+        let mut translucent = transparency.clone();
+        translucent.sort_unstable();
+        assert_eq!(translucent, [0x00, 0x80]);
+        let palette = indexed.palette.as_ref().unwrap();
+        assert_eq!(palette.len(), 12);
+        let mut opaque = vec![palette[6 .. 9].to_vec(), palette[9 .. 12].to_vec()];
+        opaque.sort_unstable();
+        assert_eq!(opaque, [[0x00, 0x00, 0xff], [0xff, 0x00, 0x00]]);
+        assert_eq!(rgba8(&indexed), rgba8(&image));
+    }
+
+    #[test]
+    fn quantize_dither() {
+        let pool = pool();
+        let mut data = b"P5 16 2 255\n".to_vec();
+        data.extend((0 .. 32).map(|i| (i % 16 * 17) as u8));
+        let image = pnm(&data);
+        let dithered = quantize(&pool, &image, 2, true).unwrap();
+        assert_eq!(dithered.palette.as_deref(), Some(&[60, 60, 60, 196, 196, 196][..]));
+        assert_eq!(dithered.data.as_ref(), [0x01, 0x7f, 0x01, 0x7f]);
+        let again = quantize(&pool, &image, 2, true).unwrap();
+        assert_eq!(dithered.palette, again.palette);
+        assert_eq!(dithered.data.as_ref(), again.data.as_ref());
+
+        // Error is carried along the gradient, so it differs from the plain mapping.
+        let plain = quantize(&pool, &image, 2, false).unwrap();
+        assert_eq!(dithered.palette, plain.palette);
+        assert_eq!(plain.data.as_ref(), [0x00, 0xff, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn reformat_chunks() {
+        let pool = pool();
+        let defaults = Defaults(Vec::new());
+        let tagged = |data: &[u8]| {
+            let mut image = pnm(data);
+            image.chunks = vec![(*b"iCCP", vec![1]), (*b"gAMA", vec![2]), (*b"bKGD", vec![3])];
+            image
+        };
+        let tags = |image: Image, options: &[&str]| {
+            let mut line = vec!["mtpng"];
+            line.extend_from_slice(options);
+            line.extend_from_slice(&["in", "out"]);
+            let image = reformat(&pool, &args(&defaults, &line), image).unwrap();
+            image.chunks.iter().map(|(tag, _)| *tag).collect::<Vec<_>>()
+        };
+        let gray = b"P5 2 1 255\n\x00\xff";
+        let rgb = b"P6 2 1 255\n\x00\x00\x00\xff\x00\x00";
+
+        // Unchanged images keep all their chunks.
+        assert_eq!(tags(tagged(gray), &[]), [*b"iCCP", *b"gAMA", *b"bKGD"]);
+
+        // The profile stays within gray or color types...
+        assert_eq!(tags(tagged(gray), &["--color-type", "graya"]), [*b"iCCP", *b"gAMA"]);
+        assert_eq!(tags(tagged(rgb), &["--color-type", "rgba"]), [*b"iCCP", *b"gAMA"]);
+        assert_eq!(tags(tagged(rgb), &["--colors", "2"]), [*b"iCCP", *b"gAMA"]);
+
+        // ...and goes when crossing between them.
+        assert_eq!(tags(tagged(gray), &["--color-type", "rgb"]), [*b"gAMA"]);
+        assert_eq!(tags(tagged(gray), &["--colors", "2"]), [*b"gAMA"]);
+        assert_eq!(tags(tagged(rgb), &["--color-type", "gray"]), [*b"gAMA"]);
+    }
+}
@@ -0,0 +1,133 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// bindings.rs - options shared by the language bindings
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//
+// The language bindings take options by the same names as
+// the command-line tool.
+//
+
+use std::convert::TryFrom;
+
+use super::ColorType;
+use super::CompressionLevel;
+use super::Header;
+use super::Mode::{Adaptive, Fixed};
+use super::Strategy;
+use super::{Error, Result};
+use super::encoder::Options;
+use super::filter::Filter;
+
+// A compression level as 1-9, or a name.
+pub(crate) enum Level<'a> {
+    Number(i64),
+    Name(&'a str),
+}
+
+fn compression_level(level: Level) -> Result<CompressionLevel> {
+    match level {
+        Level::Number(n) => u8::try_from(n).ok()
+            .and_then(|n| CompressionLevel::try_from(n).ok())
+            .ok_or(Error::InvalidOptions("Compression level must be from 1 to 9")),
+        Level::Name("fast")    => Ok(CompressionLevel::Fast),
+        Level::Name("default") => Ok(CompressionLevel::Default),
+        Level::Name("high")    => Ok(CompressionLevel::High),
+        Level::Name(_)         => Err(Error::InvalidOptions("Unsupported compression level (try fast, default, high, or 1-9)")),
+    }
+}
+
+pub(crate) fn options(level: Option<Level>,
+                      filter: Option<&str>,
+                      strategy: Option<&str>,
+                      chunk_size: Option<usize>,
+                      threads: Option<usize>,
+                      streaming: bool)
+    -> Result<Options<'static>>
+{
+    let mut options = Options::new();
+    if let Some(level) = level {
+        options.set_compression_level(compression_level(level)?)?;
+    }
+    match filter {
+        None             => {},
+        Some("adaptive") => options.set_filter_mode(Adaptive)?,
+        Some("none")     => options.set_filter_mode(Fixed(Filter::None))?,
+        Some("up")       => options.set_filter_mode(Fixed(Filter::Up))?,
+        Some("sub")      => options.set_filter_mode(Fixed(Filter::Sub))?,
+        Some("average")  => options.set_filter_mode(Fixed(Filter::Average))?,
+        Some("paeth")    => options.set_filter_mode(Fixed(Filter::Paeth))?,
+        _                => return Err(Error::InvalidOptions("Unsupported filter type")),
+    }
+    match strategy {
+        None             => {},
+        Some("auto")     => options.set_strategy_mode(Adaptive)?,
+        Some("default")  => options.set_strategy_mode(Fixed(Strategy::Default))?,
+        Some("filtered") => options.set_strategy_mode(Fixed(Strategy::Filtered))?,
+        Some("huffman")  => options.set_strategy_mode(Fixed(Strategy::HuffmanOnly))?,
+        Some("rle")      => options.set_strategy_mode(Fixed(Strategy::Rle))?,
+        Some("fixed")    => options.set_strategy_mode(Fixed(Strategy::Fixed))?,
+        _                => return Err(Error::InvalidOptions("Invalid compression strategy mode")),
+    }
+    if let Some(chunk_size) = chunk_size {
+        options.set_chunk_size(chunk_size)?;
+    }
+    options.set_max_threads(threads)?;
+    options.set_streaming(streaming)?;
+    Ok(options)
+}
+
+// A color type by name, defaulting to RGBA; by the number of
+// channels in an array's shape; or as already worked out. Each
+// binding only uses some of these.
+#[allow(dead_code)]
+pub(crate) enum Color<'a> {
+    Name(Option<&'a str>),
+    Channels(usize),
+    Type(ColorType),
+}
+
+fn color_type(color: Color) -> Result<ColorType> {
+    match color {
+        Color::Name(Some("gray"))  | Color::Channels(1) => Ok(ColorType::Greyscale),
+        Color::Name(Some("graya")) | Color::Channels(2) => Ok(ColorType::GreyscaleAlpha),
+        Color::Name(Some("rgb"))   | Color::Channels(3) => Ok(ColorType::Truecolor),
+        Color::Name(Some("rgba")) | Color::Name(None) | Color::Channels(4) => Ok(ColorType::TruecolorAlpha),
+        Color::Name(_)     => Err(Error::InvalidHeader("Invalid color type, try gray, graya, rgb, or rgba")),
+        Color::Channels(_) => Err(Error::InvalidHeader("Image arrays must have 1 to 4 channels")),
+        Color::Type(color_type) => Ok(color_type),
+    }
+}
+
+pub(crate) fn header<N, D>(width: N, height: N, color: Color, depth: D) -> Result<Header>
+    where u32: TryFrom<N>, u8: TryFrom<D>
+{
+    let width = u32::try_from(width).map_err(|_| Error::InvalidHeader("Invalid width"))?;
+    let height = u32::try_from(height).map_err(|_| Error::InvalidHeader("Invalid height"))?;
+    let depth = u8::try_from(depth).map_err(|_| Error::InvalidHeader("Invalid bit depth for the color type"))?;
+
+    let mut header = Header::new();
+    header.set_size(width, height)?;
+    header.set_color(color_type(color)?, depth)?;
+    Ok(header)
+}
@@ -0,0 +1,321 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// buffer.rs - working buffers from a pluggable allocator
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use std::alloc;
+use std::alloc::Layout;
+use std::cmp;
+
+use std::io;
+use std::io::Write;
+
+use std::ops::{Deref, DerefMut};
+
+use std::ptr;
+use std::slice;
+
+use std::sync::Arc;
+
+/// Memory for the encoder's large working buffers, for hosts that
+/// track or limit what a codec allocates. Pass one to
+/// Encoder::set_allocator().
+///
+/// Copied input rows, filtered and compressed chunk data, and compressed
+/// output waiting its turn to be written all come from the allocator.
+/// zlib's compression state and output staging buffer, and scratch
+/// rows for filtering, don't; they're a fixed size per running job.
+///
+/// # Safety
+///
+/// alloc() and realloc() must return either null or memory that's valid
+/// for reads and writes of the requested size until it's freed. Jobs run
+/// on the thread pool, so any thread may allocate or free.
+pub unsafe trait Allocator: Send + Sync {
+    /// Allocate size bytes, which is never 0, or return null on failure.
+    fn alloc(&self, size: usize) -> *mut u8;
+
+    /// Resize an allocation to size bytes, keeping its contents, or
+    /// return null on failure and leave the old one as it was.
+    ///
+    /// # Safety
+    ///
+    /// ptr must be a live allocation of old_size bytes from this allocator.
+    unsafe fn realloc(&self, ptr: *mut u8, old_size: usize, size: usize) -> *mut u8;
+
+    /// Free an allocation.
+    ///
+    /// # Safety
+    ///
+    /// ptr must be a live allocation of size bytes from this allocator,
+    /// and isn't used again.
+    unsafe fn free(&self, ptr: *mut u8, size: usize);
+}
+
+//
+// Rust's global allocator, used unless the encoder is given another.
+//
+struct System;
+
+unsafe impl Allocator for System {
+    fn alloc(&self, size: usize) -> *mut u8 {
+        match Layout::from_size_align(size, 1) {
+            Ok(layout) => unsafe { alloc::alloc(layout) },
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, old_size: usize, size: usize) -> *mut u8 {
+        if Layout::from_size_align(size, 1).is_err() {
+            return ptr::null_mut();
+        }
+        alloc::realloc(ptr, Layout::from_size_align_unchecked(old_size, 1), size)
+    }
+
+    unsafe fn free(&self, ptr: *mut u8, size: usize) {
+        alloc::dealloc(ptr, Layout::from_size_align_unchecked(size, 1))
+    }
+}
+
+//
+// Which allocator buffers come from, cheap to clone into jobs.
+// The default is the system allocator.
+//
+#[derive(Clone, Default)]
+pub(crate) struct Heap(Option<Arc<dyn Allocator>>);
+
+impl Heap {
+    pub(crate) fn new(allocator: Arc<dyn Allocator>) -> Heap {
+        Heap(Some(allocator))
+    }
+
+    fn allocator(&self) -> &dyn Allocator {
+        match self.0 {
+            Some(ref allocator) => &**allocator,
+            None => &System,
+        }
+    }
+
+    // An empty buffer, which allocates on first use.
+    pub(crate) fn buffer(&self) -> Buffer {
+        Buffer {
+            data: ptr::null_mut(),
+            len: 0,
+            capacity: 0,
+            heap: self.clone(),
+        }
+    }
+}
+
+//
+// A growable byte buffer, like Vec<u8>, whose memory comes from
+// the heap it was created with and goes back there when dropped.
+// Running out of memory is an error rather than an abort.
+//
+pub(crate) struct Buffer {
+    data: *mut u8,
+    len: usize,
+    capacity: usize,
+    heap: Heap,
+}
+
+//
+// The memory is owned by the buffer and only written through
+// &mut self, and allocators have to work from any thread.
+//
+unsafe impl Send for Buffer {}
+unsafe impl Sync for Buffer {}
+
+impl Buffer {
+    // Make room for at least additional more bytes, growing
+    // geometrically as Vec does to keep reallocs rare.
+    pub(crate) fn reserve(&mut self, additional: usize) -> io::Result<()> {
+        let needed = self.needed(additional)?;
+        if needed > self.capacity {
+            self.grow(cmp::max(needed, self.capacity.saturating_mul(2)))?;
+        }
+        Ok(())
+    }
+
+    // Make room for exactly additional more bytes.
+    pub(crate) fn reserve_exact(&mut self, additional: usize) -> io::Result<()> {
+        let needed = self.needed(additional)?;
+        if needed > self.capacity {
+            self.grow(needed)?;
+        }
+        Ok(())
+    }
+
+    fn needed(&self, additional: usize) -> io::Result<usize> {
+        self.len.checked_add(additional)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::OutOfMemory, "Buffer too large"))
+    }
+
+    fn grow(&mut self, capacity: usize) -> io::Result<()> {
+        let allocator = self.heap.allocator();
+        let data = if self.data.is_null() {
+            allocator.alloc(capacity)
+        } else {
+            unsafe {
+                allocator.realloc(self.data, self.capacity, capacity)
+            }
+        };
+        if data.is_null() {
+            return Err(io::Error::new(io::ErrorKind::OutOfMemory, "Allocator returned null"));
+        }
+        self.data = data;
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    pub(crate) fn extend_from_slice(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if !bytes.is_empty() {
+            self.reserve(bytes.len())?;
+            unsafe {
+                ptr::copy_nonoverlapping(bytes.as_ptr(), self.data.add(self.len), bytes.len());
+            }
+            self.len += bytes.len();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    // Remove the first n bytes, moving the rest down.
+    pub(crate) fn drain_front(&mut self, n: usize) {
+        assert!(n <= self.len);
+        if n > 0 {
+            unsafe {
+                ptr::copy(self.data.add(n), self.data, self.len - n);
+            }
+            self.len -= n;
+        }
+    }
+}
+
+impl Default for Buffer {
+    fn default() -> Buffer {
+        Heap::default().buffer()
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.data.is_null() {
+            &[]
+        } else {
+            unsafe {
+                slice::from_raw_parts(self.data, self.len)
+            }
+        }
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        if self.data.is_null() {
+            &mut []
+        } else {
+            unsafe {
+                slice::from_raw_parts_mut(self.data, self.len)
+            }
+        }
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.extend_from_slice(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if !self.data.is_null() {
+            unsafe {
+                self.heap.allocator().free(self.data, self.capacity);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Counts live allocations, passing them on to the system allocator.
+    #[derive(Default)]
+    struct Counting {
+        live: AtomicUsize,
+    }
+
+    unsafe impl Allocator for Counting {
+        fn alloc(&self, size: usize) -> *mut u8 {
+            self.live.fetch_add(1, Ordering::SeqCst);
+            System.alloc(size)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, old_size: usize, size: usize) -> *mut u8 {
+            System.realloc(ptr, old_size, size)
+        }
+
+        unsafe fn free(&self, ptr: *mut u8, size: usize) {
+            self.live.fetch_sub(1, Ordering::SeqCst);
+            System.free(ptr, size)
+        }
+    }
+
+    #[test]
+    fn grow_and_drain() {
+        let counting = Arc::new(Counting::default());
+        let mut buffer = Heap::new(counting.clone()).buffer();
+        assert!(buffer.is_empty());
+        assert_eq!(counting.live.load(Ordering::SeqCst), 0);
+
+        for i in 0 .. 1000u32 {
+            buffer.write_all(&i.to_be_bytes()).unwrap();
+        }
+        assert_eq!(buffer.len(), 4000);
+        assert_eq!(&buffer[4 .. 8], &[0, 0, 0, 1]);
+        assert_eq!(counting.live.load(Ordering::SeqCst), 1);
+
+        buffer.drain_front(3996);
+        assert_eq!(&buffer[..], &999u32.to_be_bytes());
+        buffer.clear();
+        assert!(buffer.is_empty());
+
+        drop(buffer);
+        assert_eq!(counting.live.load(Ordering::SeqCst), 0);
+    }
+}
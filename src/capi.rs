@@ -23,6 +23,10 @@
 // THE SOFTWARE.
 //
 
+// The functions' safety requirements on their pointers are documented
+// for C callers in c/mtpng.h.
+#![allow(clippy::missing_safety_doc)]
+
 use rayon::ThreadPool;
 use rayon::ThreadPoolBuilder;
 
@@ -31,9 +35,17 @@ use std::convert::TryFrom;
 use std::io;
 use std::io::Write;
 
+use std::panic;
+use std::panic::AssertUnwindSafe;
+
 use std::ptr;
 
-use std::ffi::CStr;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
 
 use libc::{c_void, c_int, size_t};
@@ -43,30 +55,174 @@ use super::Strategy;
 use super::CompressionLevel;
 use super::Mode::{Adaptive, Fixed};
 use super::Header;
+use super::{Error, Result};
 
 use super::encoder::Encoder;
 use super::encoder::Options;
+use super::encoder::{CancelToken, Progress};
 
 use super::filter::Filter;
 
+use super::buffer;
+use super::buffer::{Buffer, Heap};
+
 use super::utils::invalid_input;
 use super::utils::other;
 
 #[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum CResult {
     Ok = 0,
     Err = 1,
+    InvalidArgument = 2,
+    InvalidState = 3,
+    InvalidData = 4,
+    Io = 5,
+    Cancelled = 6,
+    TimedOut = 7,
+    Panic = 8,
 }
 
-impl From<Result<(),io::Error>> for CResult {
-    fn from(result: Result<(),io::Error>) -> CResult {
+impl From<Result<()>> for CResult {
+    fn from(result: Result<()>) -> CResult {
         match result {
             Ok(()) => CResult::Ok,
-            Err(_) => CResult::Err,
+            Err(e) => {
+                let code = CResult::of(&e);
+                set_last_error(&e);
+                code
+            },
+        }
+    }
+}
+
+impl CResult {
+    fn of(e: &Error) -> CResult {
+        match e {
+            Error::InvalidHeader(_) | Error::ImageTooLarge { .. } | Error::InvalidOptions(_) |
+            Error::InvalidPalette(_) | Error::PaletteTooLarge { .. } | Error::InvalidTransparency(_) |
+            Error::InvalidText(_) | Error::InvalidChunk(_) => CResult::InvalidArgument,
+            Error::InvalidState(_) => CResult::InvalidState,
+            Error::RowDataMismatch(_) | Error::PartialRow { .. } |
+            Error::RowCountMismatch { .. } => CResult::InvalidData,
+            Error::Cancelled => CResult::Cancelled,
+            Error::TimedOut => CResult::TimedOut,
+            Error::Io(e) if e.kind() == io::ErrorKind::InvalidInput => CResult::InvalidArgument,
+            Error::Io(_) => CResult::Io,
+            Error::WorkerFailed(_) | Error::VerificationFailed(_) => CResult::Err,
         }
     }
 }
 
+//
+// Bumped whenever a change to the C API would break applications
+// built against an older mtpng.h; must match MTPNG_ABI_VERSION.
+//
+const ABI_VERSION: u32 = 1;
+
+// Bits for mtpng_capabilities(), as MTPNG_CAPABILITY_* in mtpng.h.
+// Bit 0 is for APNG, which isn't supported yet.
+const CAPABILITY_SIMD: u32 = 1 << 1;
+const CAPABILITY_ZLIB: u32 = 1 << 2;
+const CAPABILITY_ZLIB_RS: u32 = 1 << 3;
+
+#[no_mangle]
+pub extern "C"
+fn mtpng_version() -> *const c_char
+{
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub extern "C"
+fn mtpng_abi_version() -> u32
+{
+    ABI_VERSION
+}
+
+#[no_mangle]
+pub extern "C"
+fn mtpng_capabilities() -> u32
+{
+    // The deflate backend, chosen as in lib.rs.
+    let mut capabilities = if cfg!(any(feature="zlib-rs", target_arch="wasm32")) {
+        CAPABILITY_ZLIB_RS
+    } else {
+        CAPABILITY_ZLIB
+    };
+    if simd() {
+        capabilities |= CAPABILITY_SIMD;
+    }
+    capabilities
+}
+
+//
+// Whether the filters get vectorized code paths on this CPU, as they
+// do with SSE4.1 or better on x86, and NEON on 64-bit ARM.
+//
+fn simd() -> bool
+{
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        is_x86_feature_detected!("sse4.1") || (cfg!(target_arch = "x86") && is_x86_feature_detected!("sse2"))
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        true
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+//
+// Keep the error's message for mtpng_last_error_message(), until
+// the next failure on this thread.
+//
+fn set_last_error(e: &Error) {
+    set_last_error_message(&e.to_string());
+}
+
+fn set_last_error_message(message: &str) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+//
+// Run the body of an entry point, catching any panic so it doesn't
+// unwind into the C caller, which is undefined behavior. The objects
+// involved may be left inconsistent, so the caller should only
+// release them afterwards.
+//
+fn catch_panic<F>(body: F) -> CResult
+    where F: FnOnce() -> Result<()>
+{
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(result) => CResult::from(result),
+        Err(payload) => {
+            let message = payload.downcast_ref::<&str>().copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            set_last_error_message(&format!("mtpng panicked: {}", message));
+            CResult::Panic
+        },
+    }
+}
+
+#[no_mangle]
+pub extern "C"
+fn mtpng_last_error_message() -> *const c_char
+{
+    LAST_ERROR.with(|last| {
+        last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
 /*
 pub type CReadFunc = unsafe extern "C"
     fn(*const c_void, *mut u8, size_t) -> size_t;
@@ -78,6 +234,42 @@ pub type CWriteFunc = unsafe extern "C"
 pub type CFlushFunc = unsafe extern "C"
     fn(*const c_void) -> bool;
 
+#[repr(C)]
+pub struct CProgress {
+    rows_consumed: u32,
+    chunks_completed: size_t,
+    chunks_total: size_t,
+    bytes_written: u64,
+}
+
+pub type CProgressFunc = unsafe extern "C"
+    fn(*const c_void, *const CProgress);
+
+pub type CMallocFunc = unsafe extern "C"
+    fn(*mut c_void, size_t) -> *mut c_void;
+
+pub type CReallocFunc = unsafe extern "C"
+    fn(*mut c_void, *mut c_void, size_t) -> *mut c_void;
+
+pub type CFreeFunc = unsafe extern "C"
+    fn(*mut c_void, *mut c_void);
+
+#[repr(C)]
+pub struct CAllocator {
+    malloc_func: Option<CMallocFunc>,
+    realloc_func: Option<CReallocFunc>,
+    free_func: Option<CFreeFunc>,
+    user_data: *mut c_void,
+}
+
+//
+// The C caller's user data for the progress callback, which is only
+// ever called on the thread writing to the encoder.
+//
+struct CUserData(*const c_void);
+
+unsafe impl Send for CUserData {}
+
 /*
 
 //
@@ -132,9 +324,9 @@ impl CWriter {
     -> CWriter
     {
         CWriter {
-            write_func: write_func,
-            flush_func: flush_func,
-            user_data: user_data,
+            write_func,
+            flush_func,
+            user_data,
         }
     }
 }
@@ -143,7 +335,7 @@ impl Write for CWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let ret = unsafe {
             (self.write_func)(self.user_data,
-                                   &buf[0],
+                                   buf.as_ptr(),
                                    buf.len())
         };
         if ret == buf.len() {
@@ -165,13 +357,306 @@ impl Write for CWriter {
     }
 }
 
-// Cheat on the lifetimes?
-type CEncoder = Encoder<'static, CWriter>;
+//
+// An allocator from mtpng_encoder_options_set_allocator(), checked
+// to have all its functions.
+//
+struct Allocator {
+    malloc_func: CMallocFunc,
+    realloc_func: CReallocFunc,
+    free_func: CFreeFunc,
+    user_data: *mut c_void,
+}
+
+//
+// The encoder's jobs take their buffers on the thread pool, so the
+// C caller's allocator is documented as having to be thread-safe.
+//
+unsafe impl Send for Allocator {}
+unsafe impl Sync for Allocator {}
+
+impl Allocator {
+    fn from_c(allocator: &CAllocator) -> Result<Allocator> {
+        match (allocator.malloc_func, allocator.realloc_func, allocator.free_func) {
+            (Some(malloc_func), Some(realloc_func), Some(free_func)) => Ok(Allocator {
+                malloc_func,
+                realloc_func,
+                free_func,
+                user_data: allocator.user_data,
+            }),
+            _ => Err(invalid_input("allocator functions must not be null").into()),
+        }
+    }
+}
 
-pub type PThreadPool = *mut ThreadPool;
-pub type PEncoderOptions = *mut Options<'static>;
+unsafe impl buffer::Allocator for Allocator {
+    fn alloc(&self, size: usize) -> *mut u8 {
+        unsafe {
+            (self.malloc_func)(self.user_data, size) as *mut u8
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, _old_size: usize, size: usize) -> *mut u8 {
+        (self.realloc_func)(self.user_data, ptr as *mut c_void, size) as *mut u8
+    }
+
+    unsafe fn free(&self, ptr: *mut u8, _size: usize) {
+        (self.free_func)(self.user_data, ptr as *mut c_void)
+    }
+}
+
+//
+// Buffered output, which comes from the same allocator as the
+// encoder's working buffers so it can be handed over to C.
+//
+pub struct CBuffer(Buffer);
+
+impl Write for CBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+//
+// Output for an encoder: either the C callbacks, or a growable
+// buffer that's handed over by mtpng_encoder_finish_buffer().
+//
+pub enum COutput {
+    Callbacks(CWriter),
+    Buffer(CBuffer),
+}
+
+impl Write for COutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            COutput::Callbacks(writer) => writer.write(buf),
+            COutput::Buffer(buffer) => buffer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            COutput::Callbacks(writer) => writer.flush(),
+            COutput::Buffer(_) => Ok(()),
+        }
+    }
+}
+
+//
+// A thread pool shared between C encoders, which counts the options
+// and encoders using it so it can't be released out from under them,
+// and waits for its worker threads to exit when it is released.
+//
+pub struct CThreadPool {
+    pool: ThreadPool,
+    threads: usize,
+    users: AtomicUsize,
+    exited: Arc<(Mutex<usize>, Condvar)>,
+}
+
+// One options or encoder instance's hold on a CThreadPool.
+struct PoolUse(*const CThreadPool);
+
+unsafe impl Send for PoolUse {}
+
+impl PoolUse {
+    unsafe fn new(p_pool: *const CThreadPool) -> PoolUse {
+        (*p_pool).users.fetch_add(1, Ordering::SeqCst);
+        PoolUse(p_pool)
+    }
+
+    // Cheat on the lifetimes; the use count keeps the pool alive.
+    fn pool(&self) -> &'static ThreadPool {
+        unsafe {
+            &(*self.0).pool
+        }
+    }
+}
+
+impl Clone for PoolUse {
+    fn clone(&self) -> PoolUse {
+        unsafe {
+            PoolUse::new(self.0)
+        }
+    }
+}
+
+impl Drop for PoolUse {
+    fn drop(&mut self) {
+        unsafe {
+            (*self.0).users.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+pub struct COptions {
+    options: Options<'static>,
+    pool: Option<PoolUse>,
+    allocator: Option<Arc<dyn buffer::Allocator>>,
+}
+
+impl Deref for COptions {
+    type Target = Options<'static>;
+
+    fn deref(&self) -> &Options<'static> {
+        &self.options
+    }
+}
+
+impl DerefMut for COptions {
+    fn deref_mut(&mut self) -> &mut Options<'static> {
+        &mut self.options
+    }
+}
+
+//
+// Pixel layouts from mtpng_encoder_set_pixel_format() other than
+// PNG's own, which are rearranged a row at a time as they're written.
+//
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PixelFormat {
+    Native,
+    Bgr,
+    Bgra,
+    Rgbx,
+    Bgrx,
+    Argb,
+}
+
+impl PixelFormat {
+    fn from_c(format: c_int) -> Result<PixelFormat> {
+        match format {
+            0 => Ok(PixelFormat::Native),
+            1 => Ok(PixelFormat::Bgr),
+            2 => Ok(PixelFormat::Bgra),
+            3 => Ok(PixelFormat::Rgbx),
+            4 => Ok(PixelFormat::Bgrx),
+            5 => Ok(PixelFormat::Argb),
+            _ => Err(invalid_input("Invalid pixel format").into()),
+        }
+    }
+
+    // Bytes per input pixel, and which of them go in each PNG channel.
+    fn swizzle(self) -> Option<(usize, &'static [usize])> {
+        match self {
+            PixelFormat::Native => None,
+            PixelFormat::Bgr => Some((3, &[2, 1, 0])),
+            PixelFormat::Bgra => Some((4, &[2, 1, 0, 3])),
+            PixelFormat::Rgbx => Some((4, &[0, 1, 2])),
+            PixelFormat::Bgrx => Some((4, &[2, 1, 0])),
+            PixelFormat::Argb => Some((4, &[1, 2, 3, 0])),
+        }
+    }
+}
+
+pub struct CEncoder {
+    encoder: Encoder<'static, COutput>,
+
+    // Held for as long as the encoder may queue work on the pool.
+    pool: Option<PoolUse>,
+
+    // Layout of the rows passed to mtpng_encoder_write_image_rows().
+    header: Option<Header>,
+    format: PixelFormat,
+    row_stride: usize,
+    bottom_up: bool,
+    row: Vec<u8>,
+}
+
+impl CEncoder {
+    fn new(encoder: Encoder<'static, COutput>, pool: Option<PoolUse>) -> CEncoder {
+        CEncoder {
+            encoder,
+            pool,
+            header: None,
+            format: PixelFormat::Native,
+            row_stride: 0,
+            bottom_up: false,
+            row: Vec::new(),
+        }
+    }
+
+    fn write_rows(&mut self, buf: &[u8]) -> Result<()> {
+        let header = match self.header {
+            Some(header) if self.format != PixelFormat::Native ||
+                            self.row_stride != 0 ||
+                            self.bottom_up => header,
+            _ => return self.encoder.write_image_rows(buf),
+        };
+
+        let swizzle = self.format.swizzle();
+        let row_len = match swizzle {
+            Some((bpp, channels)) => {
+                if header.depth() != 8 || header.color_type().channels() != channels.len() {
+                    return Err(invalid_input("Pixel format doesn't match the header's color type and depth").into());
+                }
+                header.width() as usize * bpp
+            },
+            None => header.stride(),
+        };
+        let stride = if self.row_stride == 0 {
+            row_len
+        } else if self.row_stride < row_len {
+            return Err(invalid_input("Row stride is shorter than a row").into());
+        } else {
+            self.row_stride
+        };
+
+        // Every row may be padded out to the stride, or the last one's
+        // padding may be left off; anything else ends in a partial row.
+        let rows = if buf.len() % stride == 0 {
+            buf.len() / stride
+        } else if buf.len() >= row_len && (buf.len() - row_len) % stride == 0 {
+            (buf.len() - row_len) / stride + 1
+        } else {
+            return Err(Error::PartialRow {
+                bytes: buf.len() % stride,
+                stride,
+            });
+        };
+
+        for i in 0 .. rows {
+            let n = if self.bottom_up { rows - 1 - i } else { i };
+            let src = &buf[n * stride .. n * stride + row_len];
+            match swizzle {
+                Some((bpp, channels)) => {
+                    self.row.clear();
+                    for pixel in src.chunks_exact(bpp) {
+                        self.row.extend(channels.iter().map(|&c| pixel[c]));
+                    }
+                    self.encoder.write_image_rows(&self.row)?;
+                },
+                None => self.encoder.write_image_rows(src)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Deref for CEncoder {
+    type Target = Encoder<'static, COutput>;
+
+    fn deref(&self) -> &Encoder<'static, COutput> {
+        &self.encoder
+    }
+}
+
+impl DerefMut for CEncoder {
+    fn deref_mut(&mut self) -> &mut Encoder<'static, COutput> {
+        &mut self.encoder
+    }
+}
+
+pub type PThreadPool = *mut CThreadPool;
+pub type PEncoderOptions = *mut COptions;
 pub type PEncoder = *mut CEncoder;
 pub type PHeader = *mut Header;
+pub type PBuffer = *mut CBuffer;
+pub type PCancelToken = *mut CancelToken;
 
 
 #[no_mangle]
@@ -179,19 +664,49 @@ pub unsafe extern "C"
 fn mtpng_threadpool_new(pp_pool: *mut PThreadPool, threads: size_t)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if pp_pool.is_null() {
-            return Err(invalid_input("pp_pool must not be null"));
+            return Err(invalid_input("pp_pool must not be null").into());
         }
         if !(*pp_pool).is_null() {
-            return Err(invalid_input("*pp_pool must be null"))
+            return Err(invalid_input("*pp_pool must be null").into())
         }
+        let exited = Arc::new((Mutex::new(0), Condvar::new()));
+        let on_exit = Arc::clone(&exited);
         let pool = ThreadPoolBuilder::new().num_threads(threads)
+                                            .exit_handler(move |_| {
+                                                let (count, cvar) = &*on_exit;
+                                                *count.lock().unwrap() += 1;
+                                                cvar.notify_all();
+                                            })
                                             .build()
                                             .map_err(|err| other(&err.to_string()))?;
-        *pp_pool = Box::into_raw(Box::new(pool));
+        *pp_pool = Box::into_raw(Box::new(CThreadPool {
+            threads: pool.current_num_threads(),
+            pool,
+            users: AtomicUsize::new(0),
+            exited,
+        }));
         Ok(())
-    }())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_threadpool_get_threads(p_pool: PThreadPool,
+                                p_threads: *mut size_t)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if p_pool.is_null() {
+            return Err(invalid_input("p_pool must not be null").into());
+        }
+        if p_threads.is_null() {
+            return Err(invalid_input("p_threads must not be null").into());
+        }
+        *p_threads = (*p_pool).threads;
+        Ok(())
+    })
 }
 
 #[no_mangle]
@@ -199,17 +714,29 @@ pub unsafe extern "C"
 fn mtpng_threadpool_release(pp_pool: *mut PThreadPool)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if pp_pool.is_null() {
-            return Err(invalid_input("pp_pool must not be null"));
+            return Err(invalid_input("pp_pool must not be null").into());
         }
         if (*pp_pool).is_null() {
-            return Err(invalid_input("*pp_pool must not be null"));
+            return Err(invalid_input("*pp_pool must not be null").into());
+        }
+        if (**pp_pool).users.load(Ordering::SeqCst) > 0 {
+            return Err(Error::InvalidState("Thread pool is still in use by options or encoders."));
         }
-        drop(Box::from_raw(*pp_pool));
+        let CThreadPool { pool, threads, exited, .. } = *Box::from_raw(*pp_pool);
         *pp_pool = ptr::null_mut();
+
+        // Rayon shuts down its threads in the background once the
+        // pool is dropped; wait for them so the release is complete.
+        drop(pool);
+        let (count, cvar) = &*exited;
+        let mut count = count.lock().unwrap();
+        while *count < threads {
+            count = cvar.wait(count).unwrap();
+        }
         Ok(())
-    }())
+    })
 }
 
 
@@ -218,16 +745,20 @@ pub unsafe extern "C"
 fn mtpng_encoder_options_new(pp_options: *mut PEncoderOptions)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if pp_options.is_null() {
-            return Err(invalid_input("pp_options must not be null"));
+            return Err(invalid_input("pp_options must not be null").into());
         }
         if !(*pp_options).is_null() {
-            return Err(invalid_input("*pp_options must be null"))
+            return Err(invalid_input("*pp_options must be null").into())
         }
-        *pp_options = Box::into_raw(Box::new(Options::new()));
+        *pp_options = Box::into_raw(Box::new(COptions {
+            options: Options::new(),
+            pool: None,
+            allocator: None,
+        }));
         Ok(())
-    }())
+    })
 }
 
 #[no_mangle]
@@ -235,17 +766,17 @@ pub unsafe extern "C"
 fn mtpng_encoder_options_release(pp_options: *mut PEncoderOptions)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if pp_options.is_null() {
-            return Err(invalid_input("pp_header must not be null"));
+            return Err(invalid_input("pp_header must not be null").into());
         }
         if (*pp_options).is_null() {
-            return Err(invalid_input("*pp_header must not be null"));
+            return Err(invalid_input("*pp_header must not be null").into());
         }
         drop(Box::from_raw(*pp_options));
         *pp_options = ptr::null_mut();
         Ok(())
-    }())
+    })
 }
 
 
@@ -255,12 +786,40 @@ fn mtpng_encoder_options_set_thread_pool(p_options: PEncoderOptions,
                                          p_pool: PThreadPool)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
+        if p_options.is_null() {
+            return Err(invalid_input("p_options must not be null").into());
+        }
+        if p_pool.is_null() {
+            (*p_options).clear_thread_pool();
+            (*p_options).pool = None;
+        } else {
+            let pool = PoolUse::new(p_pool);
+            (*p_options).set_thread_pool(pool.pool())?;
+            (*p_options).pool = Some(pool);
+        }
+        Ok(())
+    })
+}
+
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_options_set_allocator(p_options: PEncoderOptions,
+                                       p_allocator: *const CAllocator)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
         if p_options.is_null() {
-            return Err(invalid_input("p_options must not be null"));
+            return Err(invalid_input("p_options must not be null").into());
         }
-        (*p_options).set_thread_pool(&*p_pool)
-    }())
+        (*p_options).allocator = if p_allocator.is_null() {
+            None
+        } else {
+            Some(Arc::new(Allocator::from_c(&*p_allocator)?))
+        };
+        Ok(())
+    })
 }
 
 
@@ -270,12 +829,12 @@ fn mtpng_encoder_options_set_filter(p_options: PEncoderOptions,
                                     filter_mode: c_int)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if p_options.is_null() {
-            return Err(invalid_input("p_options must not be null"));
+            return Err(invalid_input("p_options must not be null").into());
         }
-        if filter_mode > u8::max_value() as c_int {
-            return Err(invalid_input("Invalid filter mode"));
+        if filter_mode > u8::MAX as c_int {
+            return Err(invalid_input("Invalid filter mode").into());
         }
         let mode = if filter_mode < 0 {
             Adaptive
@@ -283,7 +842,7 @@ fn mtpng_encoder_options_set_filter(p_options: PEncoderOptions,
             Fixed(Filter::try_from(filter_mode as u8)?)
         };
         (*p_options).set_filter_mode(mode)
-    }())
+    })
 }
 
 #[no_mangle]
@@ -292,12 +851,12 @@ fn mtpng_encoder_options_set_strategy(p_options: PEncoderOptions,
                                       strategy_mode: c_int)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if p_options.is_null() {
-            return Err(invalid_input("p_options must not be null"));
+            return Err(invalid_input("p_options must not be null").into());
         }
-        if strategy_mode > u8::max_value() as c_int {
-            return Err(invalid_input("Invalid strategy mode"));
+        if strategy_mode > u8::MAX as c_int {
+            return Err(invalid_input("Invalid strategy mode").into());
         }
         let mode = if strategy_mode < 0 {
             Adaptive
@@ -305,7 +864,7 @@ fn mtpng_encoder_options_set_strategy(p_options: PEncoderOptions,
             Fixed(Strategy::try_from(strategy_mode as u8)?)
         };
         (*p_options).set_strategy_mode(mode)
-    }())
+    })
 }
 
 #[no_mangle]
@@ -314,16 +873,16 @@ fn mtpng_encoder_options_set_compression_level(p_options: PEncoderOptions,
                                                compression_level: c_int)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if p_options.is_null() {
-            return Err(invalid_input("p_options must not be null"));
+            return Err(invalid_input("p_options must not be null").into());
         }
-        if compression_level < 0 || compression_level > 9 {
-            return Err(invalid_input("Invalid compression level"));
+        if !(0 ..= 9).contains(&compression_level) {
+            return Err(invalid_input("Invalid compression level").into());
         }
         let level = CompressionLevel::try_from(compression_level as u8)?;
         (*p_options).set_compression_level(level)
-    }())
+    })
 }
 
 #[no_mangle]
@@ -332,12 +891,12 @@ fn mtpng_encoder_options_set_chunk_size(p_options: PEncoderOptions,
                                         chunk_size: size_t)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if p_options.is_null() {
-            return Err(invalid_input("p_encoder must not be null"));
+            return Err(invalid_input("p_encoder must not be null").into());
         }
         (*p_options).set_chunk_size(chunk_size)
-    }())
+    })
 }
 
 
@@ -346,16 +905,16 @@ pub unsafe extern "C"
 fn mtpng_header_new(pp_header: *mut PHeader)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if pp_header.is_null() {
-            return Err(invalid_input("pp_header must not be null"));
+            return Err(invalid_input("pp_header must not be null").into());
         }
         if !(*pp_header).is_null() {
-            return Err(invalid_input("*pp_header must be null"))
+            return Err(invalid_input("*pp_header must be null").into())
         }
         *pp_header = Box::into_raw(Box::new(Header::new()));
         Ok(())
-    }())
+    })
 }
 
 #[no_mangle]
@@ -363,17 +922,17 @@ pub unsafe extern "C"
 fn mtpng_header_release(pp_header: *mut PHeader)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if pp_header.is_null() {
-            return Err(invalid_input("pp_header must not be null"));
+            return Err(invalid_input("pp_header must not be null").into());
         }
         if (*pp_header).is_null() {
-            return Err(invalid_input("*pp_header must not be null"));
+            return Err(invalid_input("*pp_header must not be null").into());
         }
         drop(Box::from_raw(*pp_header));
         *pp_header = ptr::null_mut();
         Ok(())
-    }())
+    })
 }
 
 #[no_mangle]
@@ -383,12 +942,12 @@ fn mtpng_header_set_size(p_header: PHeader,
                          height: u32)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if p_header.is_null() {
-            return Err(invalid_input("p_encoder must not be null"));
+            return Err(invalid_input("p_encoder must not be null").into());
         }
         (*p_header).set_size(width, height)
-    }())
+    })
 }
 
 #[no_mangle]
@@ -398,16 +957,16 @@ fn mtpng_header_set_color(p_header: PHeader,
                                    depth: u8)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if p_header.is_null() {
-            return Err(invalid_input("p_header must not be null"));
+            return Err(invalid_input("p_header must not be null").into());
         }
-        if color_type < 0 || color_type > u8::max_value() as c_int {
-            return Err(invalid_input("Invalid color type"));
+        if color_type < 0 || color_type > u8::MAX as c_int {
+            return Err(invalid_input("Invalid color type").into());
         }
         let color = ColorType::try_from(color_type as u8)?;
         (*p_header).set_color(color, depth)
-    }())
+    })
 }
 
 
@@ -421,27 +980,56 @@ fn mtpng_encoder_new(pp_encoder: *mut PEncoder,
                      p_options: PEncoderOptions)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if pp_encoder.is_null() {
-            return Err(invalid_input("pp_encoder must not be null"));
+            return Err(invalid_input("pp_encoder must not be null").into());
         }
         if !(*pp_encoder).is_null() {
-            return Err(invalid_input("*pp_encoder must be null"));
+            return Err(invalid_input("*pp_encoder must be null").into());
         }
         let writer = match (write_func, flush_func) {
             (Some(wf), Some(ff)) => CWriter::new(wf, ff, user_data),
-            _ => return Err(invalid_input("write_func and flush_func must not be null"))
+            _ => return Err(invalid_input("write_func and flush_func must not be null").into())
         };
-        let default = Options::<'static>::new();
-        let options = if p_options.is_null() {
-            &default
-        } else {
-            &*p_options
+        *pp_encoder = new_encoder(COutput::Callbacks(writer), p_options)?;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_new_buffer(pp_encoder: *mut PEncoder,
+                            p_options: PEncoderOptions)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if pp_encoder.is_null() {
+            return Err(invalid_input("pp_encoder must not be null").into());
+        }
+        if !(*pp_encoder).is_null() {
+            return Err(invalid_input("*pp_encoder must be null").into());
+        }
+        let heap = match p_options.as_ref().and_then(|options| options.allocator.clone()) {
+            Some(allocator) => Heap::new(allocator),
+            None => Heap::default(),
         };
-        let encoder = Encoder::new(writer, options);
-        *pp_encoder = Box::into_raw(Box::new(encoder));
+        *pp_encoder = new_encoder(COutput::Buffer(CBuffer(heap.buffer())), p_options)?;
         Ok(())
-    }())
+    })
+}
+
+unsafe fn new_encoder(output: COutput, p_options: PEncoderOptions) -> Result<PEncoder>
+{
+    let encoder = if p_options.is_null() {
+        CEncoder::new(Encoder::new(output, &Options::new()), None)
+    } else {
+        let mut encoder = Encoder::new(output, &(*p_options).options);
+        if let Some(ref allocator) = (*p_options).allocator {
+            encoder.set_allocator(Arc::clone(allocator))?;
+        }
+        CEncoder::new(encoder, (*p_options).pool.clone())
+    };
+    Ok(Box::into_raw(Box::new(encoder)))
 }
 
 #[no_mangle]
@@ -449,36 +1037,119 @@ pub unsafe extern "C"
 fn mtpng_encoder_release(pp_encoder: *mut PEncoder)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if pp_encoder.is_null() {
-            return Err(invalid_input("pp_encoder must not be null"))
+            return Err(invalid_input("pp_encoder must not be null").into())
         }
         if (*pp_encoder).is_null() {
-            return Err(invalid_input("*pp_encoder must not be null"))
+            return Err(invalid_input("*pp_encoder must not be null").into())
         }
         drop(Box::from_raw(*pp_encoder));
         *pp_encoder = ptr::null_mut();
         Ok(())
-    }())
+    })
 }
 
 
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_set_progress_callback(p_encoder: PEncoder,
+                                       progress_func: Option<CProgressFunc>,
+                                       user_data: *mut c_void)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null").into());
+        }
+        let progress_func = match progress_func {
+            Some(func) => func,
+            None => return Err(invalid_input("progress_func must not be null").into()),
+        };
+        let user_data = CUserData(user_data);
+        (*p_encoder).set_progress_callback(move |progress: Progress| {
+            let progress = CProgress {
+                rows_consumed: progress.rows_consumed,
+                chunks_completed: progress.chunks_completed,
+                chunks_total: progress.chunks_total,
+                bytes_written: progress.bytes_written,
+            };
+            progress_func(user_data.0, &progress);
+        });
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_get_cancel_token(p_encoder: PEncoder,
+                                  pp_token: *mut PCancelToken)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null").into());
+        }
+        if pp_token.is_null() {
+            return Err(invalid_input("pp_token must not be null").into());
+        }
+        if !(*pp_token).is_null() {
+            return Err(invalid_input("*pp_token must be null").into());
+        }
+        *pp_token = Box::into_raw(Box::new((*p_encoder).cancel_token()));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_cancel_token_cancel(p_token: PCancelToken)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if p_token.is_null() {
+            return Err(invalid_input("p_token must not be null").into());
+        }
+        (*p_token).cancel();
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_cancel_token_release(pp_token: *mut PCancelToken)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if pp_token.is_null() {
+            return Err(invalid_input("pp_token must not be null").into());
+        }
+        if (*pp_token).is_null() {
+            return Err(invalid_input("*pp_token must not be null").into());
+        }
+        drop(Box::from_raw(*pp_token));
+        *pp_token = ptr::null_mut();
+        Ok(())
+    })
+}
+
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_write_header(p_encoder: PEncoder,
                               p_header: PHeader)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if p_encoder.is_null() {
-            return Err(invalid_input("p_encoder must not be null"));
+            return Err(invalid_input("p_encoder must not be null").into());
         }
         if p_header.is_null() {
-            return Err(invalid_input("p_header must not be null"));
+            return Err(invalid_input("p_header must not be null").into());
         }
         (*p_encoder).write_header(&*p_header)?;
+        (*p_encoder).header = Some(*p_header);
         Ok(())
-    }())
+    })
 }
 
 #[no_mangle]
@@ -488,16 +1159,16 @@ fn mtpng_encoder_write_palette(p_encoder: PEncoder,
                                len: size_t)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if p_encoder.is_null() {
-            return Err(invalid_input("p_encoder must not be null"));
+            return Err(invalid_input("p_encoder must not be null").into());
         }
         if p_bytes.is_null() {
-            return Err(invalid_input("p_bytes must not be null"));
+            return Err(invalid_input("p_bytes must not be null").into());
         }
         let slice = ::std::slice::from_raw_parts(p_bytes, len);
         (*p_encoder).write_palette(slice)
-    }())
+    })
 }
 
 #[no_mangle]
@@ -507,16 +1178,16 @@ fn mtpng_encoder_write_transparency(p_encoder: PEncoder,
                                     len: size_t)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if p_encoder.is_null() {
-            return Err(invalid_input("p_encoder must not be null"));
+            return Err(invalid_input("p_encoder must not be null").into());
         }
         if p_bytes.is_null() {
-            return Err(invalid_input("p_bytes must not be null"));
+            return Err(invalid_input("p_bytes must not be null").into());
         }
         let slice = ::std::slice::from_raw_parts(p_bytes, len);
         (*p_encoder).write_transparency(slice)
-    }())
+    })
 }
 
 #[no_mangle]
@@ -527,20 +1198,138 @@ fn mtpng_encoder_write_chunk(p_encoder: PEncoder,
                              len: size_t)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if p_encoder.is_null() {
-            return Err(invalid_input("p_encoder must not be null"));
+            return Err(invalid_input("p_encoder must not be null").into());
         }
         if p_tag.is_null() {
-            return Err(invalid_input("p_tag must not be null"));
+            return Err(invalid_input("p_tag must not be null").into());
         }
         if p_bytes.is_null() {
-            return Err(invalid_input("p_bytes must not be null"));
+            return Err(invalid_input("p_bytes must not be null").into());
         }
         let tag = CStr::from_ptr(p_tag).to_bytes();
         let slice = ::std::slice::from_raw_parts(p_bytes, len);
         (*p_encoder).write_chunk(tag, slice)
-    }())
+    })
+}
+
+//
+// Read a C string argument as UTF-8.
+//
+unsafe fn c_str<'a>(p_str: *const c_char, name: &str) -> Result<&'a str>
+{
+    if p_str.is_null() {
+        return Err(invalid_input(&format!("{} must not be null", name)).into());
+    }
+    CStr::from_ptr(p_str).to_str()
+        .map_err(|_| invalid_input(&format!("{} must be UTF-8", name)).into())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_text(p_encoder: PEncoder,
+                            p_keyword: *const c_char,
+                            p_text: *const c_char)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null").into());
+        }
+        let keyword = c_str(p_keyword, "p_keyword")?;
+        let text = c_str(p_text, "p_text")?;
+        (*p_encoder).write_text(keyword, text)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_compressed_text(p_encoder: PEncoder,
+                                       p_keyword: *const c_char,
+                                       p_text: *const c_char)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null").into());
+        }
+        let keyword = c_str(p_keyword, "p_keyword")?;
+        let text = c_str(p_text, "p_text")?;
+        (*p_encoder).write_compressed_text(keyword, text)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_icc_profile(p_encoder: PEncoder,
+                                   p_name: *const c_char,
+                                   p_bytes: *const u8,
+                                   len: size_t)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null").into());
+        }
+        if p_bytes.is_null() {
+            return Err(invalid_input("p_bytes must not be null").into());
+        }
+        let name = c_str(p_name, "p_name")?;
+        let slice = ::std::slice::from_raw_parts(p_bytes, len);
+        (*p_encoder).write_icc_profile(name, slice)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_physical_size(p_encoder: PEncoder,
+                                     x: u32,
+                                     y: u32,
+                                     unit: c_int)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null").into());
+        }
+        if x == 0 || y == 0 || x > i32::MAX as u32 || y > i32::MAX as u32 {
+            return Err(invalid_input("Invalid physical size").into());
+        }
+        if unit != 0 && unit != 1 {
+            return Err(invalid_input("Invalid physical size unit").into());
+        }
+        let mut data = Vec::with_capacity(9);
+        data.extend_from_slice(&x.to_be_bytes());
+        data.extend_from_slice(&y.to_be_bytes());
+        data.push(unit as u8);
+        (*p_encoder).write_chunk(b"pHYs", &data)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_time(p_encoder: PEncoder,
+                            year: u16,
+                            month: u8,
+                            day: u8,
+                            hour: u8,
+                            minute: u8,
+                            second: u8)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null").into());
+        }
+        if !(1 ..= 12).contains(&month) || !(1 ..= 31).contains(&day) ||
+           hour > 23 || minute > 59 || second > 60 {
+            return Err(invalid_input("Invalid time").into());
+        }
+        let [year_high, year_low] = year.to_be_bytes();
+        let data = [year_high, year_low, month, day, hour, minute, second];
+        (*p_encoder).write_chunk(b"tIME", &data)
+    })
 }
 
 #[no_mangle]
@@ -550,16 +1339,61 @@ fn mtpng_encoder_write_image_rows(p_encoder: PEncoder,
                                   len: size_t)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if p_encoder.is_null() {
-            return Err(invalid_input("p_encoder must not be null"));
+            return Err(invalid_input("p_encoder must not be null").into());
         }
         if p_bytes.is_null() {
-            return Err(invalid_input("p_bytes must not be null"));
+            return Err(invalid_input("p_bytes must not be null").into());
         }
         let slice = ::std::slice::from_raw_parts(p_bytes, len);
-        (*p_encoder).write_image_rows(slice)
-    }())
+        (*p_encoder).write_rows(slice)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_set_pixel_format(p_encoder: PEncoder,
+                                  format: c_int)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null").into());
+        }
+        (*p_encoder).format = PixelFormat::from_c(format)?;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_set_row_stride(p_encoder: PEncoder,
+                                stride: size_t)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null").into());
+        }
+        (*p_encoder).row_stride = stride;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_set_bottom_up(p_encoder: PEncoder,
+                               bottom_up: bool)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null").into());
+        }
+        (*p_encoder).bottom_up = bottom_up;
+        Ok(())
+    })
 }
 
 #[no_mangle]
@@ -567,20 +1401,299 @@ pub unsafe extern "C"
 fn mtpng_encoder_finish(pp_encoder: *mut PEncoder)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    catch_panic(|| -> Result<()> {
         if pp_encoder.is_null() {
-            return Err(invalid_input("pp_encoder must not be null"));
+            return Err(invalid_input("pp_encoder must not be null").into());
         }
         if (*pp_encoder).is_null() {
-            return Err(invalid_input("*pp_encoder must not be null"));
+            return Err(invalid_input("*pp_encoder must not be null").into());
+        }
+
+        if let COutput::Buffer(_) = (**pp_encoder).output_mut() {
+            return Err(invalid_input("Use mtpng_encoder_finish_buffer() for a buffer encoder").into());
         }
 
         // Take ownership back from C...
-        let b_encoder = Box::from_raw(*pp_encoder);
+        let CEncoder { encoder, pool, .. } = *Box::from_raw(*pp_encoder);
         *pp_encoder = ptr::null_mut();
 
-        // And finish it out.
-        b_encoder.finish()?;
+        // And finish it out, before letting go of the pool.
+        encoder.finish()?;
+        drop(pool);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_finish_buffer(pp_encoder: *mut PEncoder,
+                               pp_buffer: *mut PBuffer)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if pp_encoder.is_null() {
+            return Err(invalid_input("pp_encoder must not be null").into());
+        }
+        if (*pp_encoder).is_null() {
+            return Err(invalid_input("*pp_encoder must not be null").into());
+        }
+        if pp_buffer.is_null() {
+            return Err(invalid_input("pp_buffer must not be null").into());
+        }
+        if !(*pp_buffer).is_null() {
+            return Err(invalid_input("*pp_buffer must be null").into());
+        }
+        if let COutput::Callbacks(_) = (**pp_encoder).output_mut() {
+            return Err(invalid_input("Use mtpng_encoder_finish() for a callback encoder").into());
+        }
+
+        let CEncoder { encoder, pool, .. } = *Box::from_raw(*pp_encoder);
+        *pp_encoder = ptr::null_mut();
+
+        match encoder.finish()? {
+            COutput::Buffer(buffer) => *pp_buffer = Box::into_raw(Box::new(buffer)),
+            COutput::Callbacks(_) => unreachable!(),
+        }
+        drop(pool);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_buffer_get_data(p_buffer: PBuffer,
+                         pp_bytes: *mut *const u8,
+                         p_len: *mut size_t)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if p_buffer.is_null() {
+            return Err(invalid_input("p_buffer must not be null").into());
+        }
+        if pp_bytes.is_null() || p_len.is_null() {
+            return Err(invalid_input("pp_bytes and p_len must not be null").into());
+        }
+        let buffer = &(*p_buffer).0;
+        *pp_bytes = buffer.as_ptr();
+        *p_len = buffer.len();
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_buffer_release(pp_buffer: *mut PBuffer)
+-> CResult
+{
+    catch_panic(|| -> Result<()> {
+        if pp_buffer.is_null() {
+            return Err(invalid_input("pp_buffer must not be null").into());
+        }
+        if (*pp_buffer).is_null() {
+            return Err(invalid_input("*pp_buffer must not be null").into());
+        }
+        drop(Box::from_raw(*pp_buffer));
+        *pp_buffer = ptr::null_mut();
         Ok(())
-    }())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::ptr;
+
+    use super::*;
+
+    #[test]
+    fn header_versions() {
+        // The C header's copies have to be kept in step by hand.
+        let header = include_str!("../c/mtpng.h");
+        let version = unsafe { CStr::from_ptr(mtpng_version()) }.to_str().unwrap();
+        assert!(header.contains(&format!("#define MTPNG_VERSION \"{}\"\n", version)));
+        assert!(header.contains(&format!("#define MTPNG_ABI_VERSION {}\n", mtpng_abi_version())));
+    }
+
+    #[test]
+    fn capabilities() {
+        let deflate = mtpng_capabilities() & (CAPABILITY_ZLIB | CAPABILITY_ZLIB_RS);
+        if cfg!(any(feature="zlib-rs", target_arch="wasm32")) {
+            assert_eq!(deflate, CAPABILITY_ZLIB_RS);
+        } else {
+            assert_eq!(deflate, CAPABILITY_ZLIB);
+        }
+    }
+
+    #[test]
+    fn panics() {
+        let result = catch_panic(|| panic!("oh no"));
+        assert_eq!(result, CResult::Panic);
+        let message = unsafe { CStr::from_ptr(mtpng_last_error_message()) };
+        assert_eq!(message.to_str().unwrap(), "mtpng panicked: oh no");
+    }
+
+    #[test]
+    fn shared_pool() {
+        unsafe {
+            let mut pool: PThreadPool = ptr::null_mut();
+            assert_eq!(mtpng_threadpool_new(&mut pool, 2), CResult::Ok);
+            let mut threads: size_t = 0;
+            assert_eq!(mtpng_threadpool_get_threads(pool, &mut threads), CResult::Ok);
+            assert_eq!(threads, 2);
+
+            let mut options: PEncoderOptions = ptr::null_mut();
+            assert_eq!(mtpng_encoder_options_new(&mut options), CResult::Ok);
+            assert_eq!(mtpng_encoder_options_set_thread_pool(options, pool), CResult::Ok);
+
+            let mut encoders: [PEncoder; 2] = [ptr::null_mut(); 2];
+            for encoder in encoders.iter_mut() {
+                assert_eq!(mtpng_encoder_new_buffer(encoder, options), CResult::Ok);
+            }
+            assert_eq!(mtpng_encoder_options_release(&mut options), CResult::Ok);
+            assert_eq!(mtpng_threadpool_release(&mut pool), CResult::InvalidState);
+            assert!(!pool.is_null());
+
+            let mut header: PHeader = ptr::null_mut();
+            assert_eq!(mtpng_header_new(&mut header), CResult::Ok);
+            assert_eq!(mtpng_header_set_size(header, 4, 4), CResult::Ok);
+            assert_eq!(mtpng_header_set_color(header, 2, 8), CResult::Ok);
+            for encoder in encoders.iter_mut() {
+                let mut buffer: PBuffer = ptr::null_mut();
+                assert_eq!(mtpng_encoder_write_header(*encoder, header), CResult::Ok);
+                assert_eq!(mtpng_encoder_write_image_rows(*encoder, [0u8; 48].as_ptr(), 48), CResult::Ok);
+                assert_eq!(mtpng_encoder_finish_buffer(encoder, &mut buffer), CResult::Ok);
+                assert_eq!(mtpng_buffer_release(&mut buffer), CResult::Ok);
+            }
+            assert_eq!(mtpng_header_release(&mut header), CResult::Ok);
+
+            assert_eq!(mtpng_threadpool_release(&mut pool), CResult::Ok);
+            assert!(pool.is_null());
+        }
+    }
+
+    // Counts live and total allocations in the user data.
+    #[derive(Default)]
+    struct Counts {
+        live: AtomicUsize,
+        total: AtomicUsize,
+    }
+
+    unsafe extern "C" fn counting_malloc(user_data: *mut c_void, size: size_t) -> *mut c_void {
+        let counts = &*(user_data as *const Counts);
+        counts.live.fetch_add(1, Ordering::SeqCst);
+        counts.total.fetch_add(1, Ordering::SeqCst);
+        libc::malloc(size)
+    }
+
+    unsafe extern "C" fn counting_realloc(_user_data: *mut c_void, p: *mut c_void, size: size_t) -> *mut c_void {
+        libc::realloc(p, size)
+    }
+
+    unsafe extern "C" fn counting_free(user_data: *mut c_void, p: *mut c_void) {
+        (*(user_data as *const Counts)).live.fetch_sub(1, Ordering::SeqCst);
+        libc::free(p)
+    }
+
+    #[test]
+    fn custom_allocator() {
+        let counts = Counts::default();
+        unsafe {
+            let mut options: PEncoderOptions = ptr::null_mut();
+            assert_eq!(mtpng_encoder_options_new(&mut options), CResult::Ok);
+            let incomplete = CAllocator {
+                malloc_func: Some(counting_malloc),
+                realloc_func: None,
+                free_func: Some(counting_free),
+                user_data: &counts as *const Counts as *mut c_void,
+            };
+            assert_eq!(mtpng_encoder_options_set_allocator(options, &incomplete), CResult::InvalidArgument);
+            let allocator = CAllocator {
+                realloc_func: Some(counting_realloc),
+                ..incomplete
+            };
+            assert_eq!(mtpng_encoder_options_set_allocator(options, &allocator), CResult::Ok);
+
+            let mut encoder: PEncoder = ptr::null_mut();
+            assert_eq!(mtpng_encoder_new_buffer(&mut encoder, options), CResult::Ok);
+            assert_eq!(mtpng_encoder_options_release(&mut options), CResult::Ok);
+
+            let mut header: PHeader = ptr::null_mut();
+            assert_eq!(mtpng_header_new(&mut header), CResult::Ok);
+            assert_eq!(mtpng_header_set_size(header, 4, 4), CResult::Ok);
+            assert_eq!(mtpng_encoder_write_header(encoder, header), CResult::Ok);
+            assert_eq!(mtpng_header_release(&mut header), CResult::Ok);
+            assert_eq!(mtpng_encoder_write_image_rows(encoder, [0u8; 64].as_ptr(), 64), CResult::Ok);
+
+            let mut buffer: PBuffer = ptr::null_mut();
+            assert_eq!(mtpng_encoder_finish_buffer(&mut encoder, &mut buffer), CResult::Ok);
+
+            // The working buffers came from the allocator too, and
+            // only the output is left.
+            assert!(counts.total.load(Ordering::SeqCst) > 1);
+            assert_eq!(counts.live.load(Ordering::SeqCst), 1);
+
+            let mut bytes: *const u8 = ptr::null();
+            let mut len: size_t = 0;
+            assert_eq!(mtpng_buffer_get_data(buffer, &mut bytes, &mut len), CResult::Ok);
+            assert_eq!(std::slice::from_raw_parts(bytes, 8), b"\x89PNG\r\n\x1a\n");
+
+            assert_eq!(mtpng_buffer_release(&mut buffer), CResult::Ok);
+            assert_eq!(counts.live.load(Ordering::SeqCst), 0);
+        }
+    }
+
+    // Encode an 8-bit 2x2 image of the given color type, with the given layout setup.
+    unsafe fn encode_2x2(color_type: c_int, setup: impl Fn(PEncoder), rows: &[u8]) -> Vec<u8> {
+        let mut encoder: PEncoder = ptr::null_mut();
+        assert_eq!(mtpng_encoder_new_buffer(&mut encoder, ptr::null_mut()), CResult::Ok);
+        setup(encoder);
+
+        let mut header: PHeader = ptr::null_mut();
+        assert_eq!(mtpng_header_new(&mut header), CResult::Ok);
+        assert_eq!(mtpng_header_set_size(header, 2, 2), CResult::Ok);
+        assert_eq!(mtpng_header_set_color(header, color_type, 8), CResult::Ok);
+        assert_eq!(mtpng_encoder_write_header(encoder, header), CResult::Ok);
+        assert_eq!(mtpng_header_release(&mut header), CResult::Ok);
+        assert_eq!(mtpng_encoder_write_image_rows(encoder, rows.as_ptr(), rows.len()), CResult::Ok);
+
+        let mut buffer: PBuffer = ptr::null_mut();
+        assert_eq!(mtpng_encoder_finish_buffer(&mut encoder, &mut buffer), CResult::Ok);
+        let data = (*buffer).0.to_vec();
+        assert_eq!(mtpng_buffer_release(&mut buffer), CResult::Ok);
+        data
+    }
+
+    #[test]
+    fn row_layout() {
+        unsafe {
+            let native = encode_2x2(2, |_| {}, &[1, 2, 3, 4, 5, 6,
+                                                 7, 8, 9, 10, 11, 12]);
+
+            // Bottom-up BGRX rows padded to 12 bytes, the last row unpadded.
+            let dib = encode_2x2(2, |encoder| {
+                assert_eq!(mtpng_encoder_set_pixel_format(encoder, 4), CResult::Ok);
+                assert_eq!(mtpng_encoder_set_row_stride(encoder, 12), CResult::Ok);
+                assert_eq!(mtpng_encoder_set_bottom_up(encoder, true), CResult::Ok);
+            }, &[9, 8, 7, 0, 12, 11, 10, 0, 99, 99, 99, 99,
+                 3, 2, 1, 0, 6, 5, 4, 0]);
+            assert_eq!(native, dib);
+
+            let native = encode_2x2(6, |_| {}, &[1, 2, 3, 4, 5, 6, 7, 8,
+                                                 9, 10, 11, 12, 13, 14, 15, 16]);
+
+            // Bottom-up BGRA rows padded to 12 bytes, the last row included.
+            let dib = encode_2x2(6, |encoder| {
+                assert_eq!(mtpng_encoder_set_pixel_format(encoder, 2), CResult::Ok);
+                assert_eq!(mtpng_encoder_set_row_stride(encoder, 12), CResult::Ok);
+                assert_eq!(mtpng_encoder_set_bottom_up(encoder, true), CResult::Ok);
+            }, &[11, 10, 9, 12, 15, 14, 13, 16, 99, 99, 99, 99,
+                 3, 2, 1, 4, 7, 6, 5, 8, 99, 99, 99, 99]);
+            assert_eq!(native, dib);
+
+            let mut encoder: PEncoder = ptr::null_mut();
+            assert_eq!(mtpng_encoder_new_buffer(&mut encoder, ptr::null_mut()), CResult::Ok);
+            assert_eq!(mtpng_encoder_set_pixel_format(encoder, 6), CResult::InvalidArgument);
+            assert_eq!(mtpng_encoder_release(&mut encoder), CResult::Ok);
+        }
+    }
 }
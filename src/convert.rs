@@ -0,0 +1,327 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// convert.rs - conversion of pixel data between color types and depths
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Conversion of pixel data between color types and bit depths.
+//!
+//! Any image can be converted to greyscale or truecolor, with or
+//! without alpha, at any depth those allow. Indexed images are looked
+//! up in their palette, and tRNS is applied as alpha. Transparent
+//! images can also be flattened over a background color.
+//!
+//! ```
+//! # fn main() -> mtpng::Result<()> {
+//! use mtpng::ColorType;
+//!
+//! let header = mtpng::Header::builder()
+//!     .size(2, 1)
+//!     .color_type(ColorType::TruecolorAlpha)
+//!     .depth(8)
+//!     .build()?;
+//! let pixels = [255, 0, 0, 255, 255, 255, 255, 0];
+//! let (header, grey) = mtpng::convert::convert(&header, &pixels, None, None,
+//!                                              ColorType::Greyscale, 8)?;
+//! assert_eq!(header.stride(), 2);
+//! assert_eq!(grey, [54, 255]);
+//! # Ok(())
+//! # }
+//! ```
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{ColorType, Header};
+use super::{Error, Result};
+
+/// Convert an image's pixel data to another color type and bit depth,
+/// returning the new header and data.
+///
+/// Indexed images need their palette, and may have a tRNS chunk's
+/// alpha values; greyscale and truecolor images may have a tRNS
+/// transparent color, making matching pixels transparent. Color is
+/// converted to grey by its luminance, with the Rec. 709 weights
+/// libpng uses, and alpha is dropped if the new type has none.
+///
+/// Samples are scaled to the new depth, rounding to the nearest value.
+/// Converting to indexed color needs a palette to be chosen, so it's
+/// not done here.
+pub fn convert(header: &Header,
+               data: &[u8],
+               palette: Option<&[u8]>,
+               transparency: Option<&[u8]>,
+               color_type: ColorType,
+               depth: u8)
+    -> Result<(Header, Vec<u8>)>
+{
+    if let ColorType::IndexedColor = color_type {
+        return Err(Error::InvalidHeader("Cannot convert to indexed color without choosing a palette."));
+    }
+    let mut out_header = *header;
+    out_header.set_color(color_type, depth)?;
+
+    let width = header.width() as usize;
+    let height = header.height() as usize;
+    let stride = header.stride();
+    if data.len() != stride * height {
+        return Err(Error::RowDataMismatch("Image data doesn't match the header's size."));
+    }
+    let palette = match (header.color_type(), palette) {
+        (ColorType::IndexedColor, None) => {
+            return Err(Error::InvalidPalette("Indexed images need their palette to convert."));
+        },
+        (_, palette) => palette.unwrap_or(&[]),
+    };
+    let transparency = transparency.unwrap_or(&[]);
+
+    let out_stride = out_header.stride();
+    let mut out = vec![0u8; out_stride * height];
+    for (row, out_row) in data.chunks(stride).zip(out.chunks_mut(out_stride)) {
+        for x in 0 .. width {
+            let [r, g, b, a] = read_pixel(header, row, x, palette, transparency);
+            let samples = match color_type {
+                ColorType::Greyscale => [luma(r, g, b), 0, 0, 0],
+                ColorType::GreyscaleAlpha => [luma(r, g, b), a, 0, 0],
+                ColorType::Truecolor => [r, g, b, 0],
+                _ => [r, g, b, a],
+            };
+            let channels = color_type.channels();
+            for (c, &sample) in samples[.. channels].iter().enumerate() {
+                write_sample(out_row, x * channels + c, depth, sample);
+            }
+        }
+    }
+    Ok((out_header, out))
+}
+
+/// Composite an image over a solid background color, given as 8-bit
+/// RGB, returning the header and data of an opaque image.
+///
+/// Transparency comes from the alpha channel or tRNS chunk, as for
+/// `convert`. Greyscale images stay greyscale over a grey background,
+/// and anything else becomes truecolor; the result is 16 bits deep if
+/// the image was, or else 8.
+pub fn flatten(header: &Header,
+               data: &[u8],
+               palette: Option<&[u8]>,
+               transparency: Option<&[u8]>,
+               background: [u8; 3])
+    -> Result<(Header, Vec<u8>)>
+{
+    let [r, g, b] = background;
+    let grey = r == g && g == b;
+    let color_type = match header.color_type() {
+        ColorType::Greyscale | ColorType::GreyscaleAlpha if grey => ColorType::Greyscale,
+        _ => ColorType::Truecolor,
+    };
+    let depth = if header.depth() == 16 { 16 } else { 8 };
+    let (mut out_header, mut out) = convert(header, data, palette, transparency,
+                                           ColorType::TruecolorAlpha, 16)?;
+
+    // Blend each pixel in place, then pack the opaque samples down.
+    let background = background.map(|v| u32::from(v) * 257);
+    let channels = color_type.channels();
+    let mut packed = 0;
+    for pixel in 0 .. out.len() / 8 {
+        let sample = |c: usize| u32::from(u16::from_be_bytes([out[pixel * 8 + c * 2], out[pixel * 8 + c * 2 + 1]]));
+        let alpha = sample(3);
+        let blend = |c: usize| ((sample(c) * alpha + background[c] * (65535 - alpha) + 32767) / 65535) as u16;
+        let rgb = [blend(0), blend(1), blend(2)];
+        let samples = match color_type {
+            ColorType::Greyscale => [luma(rgb[0], rgb[1], rgb[2]), 0, 0],
+            _ => rgb,
+        };
+        for &value in &samples[.. channels] {
+            write_sample(&mut out, packed, depth, value);
+            packed += 1;
+        }
+    }
+
+    out_header.set_color(color_type, depth)?;
+    out.truncate(out_header.stride() * out_header.height() as usize);
+    Ok((out_header, out))
+}
+
+//
+// Read a pixel as 16-bit RGBA.
+//
+fn read_pixel(header: &Header, row: &[u8], x: usize, palette: &[u8], transparency: &[u8]) -> [u16; 4] {
+    let depth = header.depth();
+    let channels = header.color_type().channels();
+    let sample = |c: usize| read_sample(row, x * channels + c, depth);
+    let scale = |v: u16| scale(v, depth, 16);
+
+    // A transparent color is compared before scaling, at the image's depth.
+    let key = |values: &[u16]| {
+        values.iter().enumerate().all(|(i, &v)| {
+            transparency.get(i * 2 .. i * 2 + 2) == Some(&v.to_be_bytes()[..])
+        })
+    };
+
+    match header.color_type() {
+        ColorType::Greyscale => {
+            let v = sample(0);
+            let alpha = if key(&[v]) { 0 } else { 65535 };
+            [scale(v), scale(v), scale(v), alpha]
+        },
+        ColorType::GreyscaleAlpha => {
+            let v = scale(sample(0));
+            [v, v, v, scale(sample(1))]
+        },
+        ColorType::Truecolor => {
+            let (r, g, b) = (sample(0), sample(1), sample(2));
+            let alpha = if key(&[r, g, b]) { 0 } else { 65535 };
+            [scale(r), scale(g), scale(b), alpha]
+        },
+        ColorType::TruecolorAlpha => {
+            [scale(sample(0)), scale(sample(1)), scale(sample(2)), scale(sample(3))]
+        },
+        ColorType::IndexedColor => {
+            let i = sample(0) as usize;
+            let rgb = palette.get(i * 3 .. i * 3 + 3).unwrap_or(&[0, 0, 0]);
+            let alpha = transparency.get(i).copied().unwrap_or(255);
+            [rgb[0], rgb[1], rgb[2], alpha].map(|v| u16::from(v) * 257)
+        },
+    }
+}
+
+fn luma(r: u16, g: u16, b: u16) -> u16 {
+    ((6968 * u32::from(r) + 23434 * u32::from(g) + 2366 * u32::from(b) + 16384) >> 15) as u16
+}
+
+fn scale(value: u16, from: u8, to: u8) -> u16 {
+    let from_max = (1u32 << from) - 1;
+    let to_max = (1u32 << to) - 1;
+    ((u32::from(value) * to_max + from_max / 2) / from_max) as u16
+}
+
+fn read_sample(row: &[u8], i: usize, depth: u8) -> u16 {
+    match depth {
+        16 => u16::from_be_bytes([row[i * 2], row[i * 2 + 1]]),
+        8 => u16::from(row[i]),
+        _ => {
+            let per_byte = 8 / depth as usize;
+            let shift = 8 - depth as usize * (i % per_byte + 1);
+            u16::from(row[i / per_byte] >> shift) & ((1 << depth) - 1)
+        }
+    }
+}
+
+//
+// Write a 16-bit sample at the given depth. Rows start zeroed,
+// so packed samples are just or'd in.
+//
+fn write_sample(row: &mut [u8], i: usize, depth: u8, value: u16) {
+    let value = scale(value, 16, depth);
+    match depth {
+        16 => row[i * 2 .. i * 2 + 2].copy_from_slice(&value.to_be_bytes()),
+        8 => row[i] = value as u8,
+        _ => {
+            let per_byte = 8 / depth as usize;
+            let shift = 8 - depth as usize * (i % per_byte + 1);
+            row[i / per_byte] |= (value as u8) << shift;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{convert, flatten};
+    use super::super::{ColorType, Error, Header};
+
+    fn header(width: u32, color_type: ColorType, depth: u8) -> Header {
+        Header::builder().size(width, 1).color_type(color_type).depth(depth).build().unwrap()
+    }
+
+    #[test]
+    fn conversions() {
+        // Grey to 16-bit truecolor with alpha, keyed out by tRNS.
+        let grey = header(4, ColorType::Greyscale, 2);
+        let (_, data) = convert(&grey, &[0b00011011], None, Some(&[0, 1]),
+                                ColorType::TruecolorAlpha, 16).unwrap();
+        assert_eq!(data.len(), 4 * 8);
+        assert_eq!(&data[.. 8], &[0, 0, 0, 0, 0, 0, 255, 255]);
+        assert_eq!(&data[8 .. 16], &[0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0, 0]);
+        assert_eq!(&data[24 ..], &[255; 8]);
+
+        // Indexed to grey with alpha, and down to 1-bit grey.
+        let indexed = header(3, ColorType::IndexedColor, 8);
+        let palette = [255, 255, 255, 0, 0, 0, 255, 0, 0];
+        let (_, data) = convert(&indexed, &[0, 1, 2], Some(&palette), Some(&[128]),
+                                ColorType::GreyscaleAlpha, 8).unwrap();
+        assert_eq!(data, [255, 128, 0, 255, 54, 255]);
+        let (out_header, data) = convert(&indexed, &[0, 1, 2], Some(&palette), None,
+                                     ColorType::Greyscale, 1).unwrap();
+        assert_eq!(out_header.depth(), 1);
+        assert_eq!(data, [0b10000000]);
+
+        // 16-bit to 8-bit rounds.
+        let rgb = header(1, ColorType::Truecolor, 16);
+        let (_, data) = convert(&rgb, &[0x12, 0x93, 0x12, 0x92, 255, 255], None, None,
+                                ColorType::Truecolor, 8).unwrap();
+        assert_eq!(data, [0x13, 0x12, 255]);
+    }
+
+    #[test]
+    fn flattening() {
+        // Half-transparent red over blue, and fully transparent.
+        let rgba = header(2, ColorType::TruecolorAlpha, 8);
+        let (out_header, data) = flatten(&rgba, &[255, 0, 0, 128, 9, 9, 9, 0], None, None,
+                                         [0, 0, 255]).unwrap();
+        assert!(matches!(out_header.color_type(), ColorType::Truecolor));
+        assert_eq!(data, [128, 0, 127, 0, 0, 255]);
+
+        // Grey keyed out by tRNS stays grey over grey, at 8 bits.
+        let grey = header(2, ColorType::Greyscale, 1);
+        let (out_header, data) = flatten(&grey, &[0b01000000], None, Some(&[0, 1]),
+                                         [64, 64, 64]).unwrap();
+        assert!(matches!(out_header.color_type(), ColorType::Greyscale));
+        assert_eq!(out_header.depth(), 8);
+        assert_eq!(data, [0, 64]);
+
+        // Indexed becomes truecolor, and 16 bits stay 16.
+        let indexed = header(1, ColorType::IndexedColor, 8);
+        let (_, data) = flatten(&indexed, &[0], Some(&[10, 20, 30]), Some(&[0]),
+                                [1, 2, 3]).unwrap();
+        assert_eq!(data, [1, 2, 3]);
+        let grey_alpha = header(1, ColorType::GreyscaleAlpha, 16);
+        let (out_header, data) = flatten(&grey_alpha, &[0x12, 0x34, 255, 255], None, None,
+                                         [255, 0, 0]).unwrap();
+        assert!(matches!(out_header.color_type(), ColorType::Truecolor));
+        assert_eq!(data, [0x12, 0x34, 0x12, 0x34, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn invalid_conversions() {
+        let rgb = header(2, ColorType::Truecolor, 8);
+        let indexed = header(2, ColorType::IndexedColor, 8);
+        assert!(matches!(convert(&rgb, &[0; 6], None, None, ColorType::IndexedColor, 8),
+                         Err(Error::InvalidHeader(_))));
+        assert!(matches!(convert(&rgb, &[0; 6], None, None, ColorType::Truecolor, 4),
+                         Err(Error::InvalidHeader(_))));
+        assert!(matches!(convert(&rgb, &[0; 5], None, None, ColorType::Truecolor, 8),
+                         Err(Error::RowDataMismatch(_))));
+        assert!(matches!(convert(&indexed, &[0; 2], None, None, ColorType::Truecolor, 8),
+                         Err(Error::InvalidPalette(_))));
+    }
+}
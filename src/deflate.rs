@@ -23,36 +23,143 @@
 // THE SOFTWARE.
 //
 
-use std::io;
-use std::io::Write;
+#[cfg(any(feature="zlib-rs", target_arch="wasm32"))]
+use alloc::alloc::Layout;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
 
-use std::mem;
+use core::mem;
 
-use std::ptr;
+use core::ptr;
 
-use std::convert::TryFrom;
+use core::convert::TryFrom;
 
-use std::os::raw::*;
+use core::ffi::*;
 
 use ::libz_sys::*;
 
+use super::Error;
 use super::utils::*;
 
+#[cfg(feature="std")]
 pub fn adler32(sum: u32, bytes: &[u8]) -> u32 {
     unsafe {
         ::libz_sys::adler32(c_ulong::from(sum), &bytes[0], bytes.len() as c_uint) as u32
     }
 }
 
+#[cfg(feature="std")]
 pub fn adler32_initial() -> u32 {
     unsafe {
         ::libz_sys::adler32(0, ptr::null(), 0) as u32
     }
 }
 
+// Safe functions in libz-rs-sys, as used on wasm32 or with zlib-rs.
+#[cfg(feature="std")]
+#[allow(unused_unsafe)]
 pub fn adler32_combine(sum_a: u32, sum_b: u32, len_b: usize) -> u32 {
     unsafe {
-        ::libz_sys::adler32_combine(c_ulong::from(sum_a), c_ulong::from(sum_b), len_b as c_long) as u32
+        ::libz_sys::adler32_combine(c_ulong::from(sum_a), c_ulong::from(sum_b), len_b as _) as u32
+    }
+}
+
+pub fn crc32(sum: u32, bytes: &[u8]) -> u32 {
+    unsafe {
+        ::libz_sys::crc32(c_ulong::from(sum), bytes.as_ptr(), bytes.len() as c_uint) as u32
+    }
+}
+
+pub fn crc32_initial() -> u32 {
+    unsafe {
+        ::libz_sys::crc32(0, ptr::null(), 0) as u32
+    }
+}
+
+// Safe functions in libz-rs-sys, as used on wasm32 or with zlib-rs.
+#[cfg(feature="std")]
+#[allow(unused_unsafe)]
+pub fn crc32_combine(sum_a: u32, sum_b: u32, len_b: usize) -> u32 {
+    unsafe {
+        ::libz_sys::crc32_combine(c_ulong::from(sum_a), c_ulong::from(sum_b), len_b as _) as u32
+    }
+}
+
+//
+// Compressed output is handed to the Write sink in pieces
+// of at most this many bytes.
+//
+pub const OUTPUT_BUFFER_SIZE: usize = 128 * 1024;
+
+//
+// Approximate memory used by a compressor at the default settings
+// of 32 KiB window and memory level 8, plus the output buffer.
+//
+// https://zlib.net/zlib_tech.html
+//
+#[cfg(feature="std")]
+pub const WORKING_MEMORY: usize = (1 << (15 + 2)) + (1 << (8 + 9)) + OUTPUT_BUFFER_SIZE;
+
+//
+// Upper bound on compressed size for the given input length,
+// including the zlib header and checksum. Same as zlib's compressBound().
+//
+#[cfg(feature="std")]
+pub fn deflate_bound(len: usize) -> usize {
+    len + (len >> 12) + (len >> 14) + (len >> 25) + 13
+}
+
+//
+// A zeroed stream, which zlib fills in with its default allocator.
+// zlib-rs has no allocator of its own without std, so it's given
+// one on top of alloc.
+//
+fn new_stream() -> Box<z_stream> {
+    let stream = unsafe {
+        let maybe = mem::MaybeUninit::<z_stream>::zeroed();
+        maybe.assume_init()
+    };
+    #[cfg(any(feature="zlib-rs", target_arch="wasm32"))]
+    let stream = z_stream {
+        zalloc: Some(zalloc),
+        zfree: Some(zfree),
+        ..stream
+    };
+    Box::new(stream)
+}
+
+//
+// zfree only gets the pointer back, so each block starts with its
+// size. zlib-rs aligns what it needs within the block itself.
+//
+#[cfg(any(feature="zlib-rs", target_arch="wasm32"))]
+const ZALLOC_HEADER: usize = 16;
+
+#[cfg(any(feature="zlib-rs", target_arch="wasm32"))]
+unsafe extern "C" fn zalloc(_opaque: *mut c_void, items: c_uint, size: c_uint) -> *mut c_void {
+    let layout = (items as usize).checked_mul(size as usize)
+        .and_then(|size| size.checked_add(ZALLOC_HEADER))
+        .and_then(|size| Layout::from_size_align(size, ZALLOC_HEADER).ok());
+    match layout {
+        Some(layout) => {
+            let block = alloc::alloc::alloc(layout);
+            if block.is_null() {
+                return ptr::null_mut();
+            }
+            (block as *mut usize).write(layout.size());
+            block.add(ZALLOC_HEADER) as *mut c_void
+        },
+        None => ptr::null_mut(),
+    }
+}
+
+#[cfg(any(feature="zlib-rs", target_arch="wasm32"))]
+unsafe extern "C" fn zfree(_opaque: *mut c_void, address: *mut c_void) {
+    if !address.is_null() {
+        let block = (address as *mut u8).sub(ZALLOC_HEADER);
+        let size = (block as *mut usize).read();
+        alloc::alloc::dealloc(block, Layout::from_size_align_unchecked(size, ZALLOC_HEADER));
     }
 }
 
@@ -64,8 +171,14 @@ pub struct Options {
     strategy: c_int,
 }
 
+/// Deflate compression strategy, as in zlib.
+///
+/// More strategies may be added in future versions; matches on it
+/// need a wildcard arm.
 #[repr(i32)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum Strategy {
     Default = Z_DEFAULT_STRATEGY,
     Filtered = Z_FILTERED,
@@ -75,7 +188,7 @@ pub enum Strategy {
 }
 
 impl TryFrom<u8> for Strategy {
-    type Error = io::Error;
+    type Error = Error;
 
     fn try_from(val: u8) -> Result<Self, Self::Error> {
         match val {
@@ -84,7 +197,7 @@ impl TryFrom<u8> for Strategy {
             2 => Ok(Strategy::HuffmanOnly),
             3 => Ok(Strategy::Rle),
             4 => Ok(Strategy::Fixed),
-            _ => Err(invalid_input("Invalid strategy constant")),
+            _ => Err(Error::InvalidOptions("Invalid strategy constant")),
         }
     }
 }
@@ -111,6 +224,7 @@ impl Options {
     // Default is 15 (32 KiB)
     // Set negative value for raw stream (no header/checksum)
     //
+    #[cfg(feature="std")]
     pub fn set_window_bits(&mut self, bits: i32) {
         self.window_bits = bits as c_int;
     }
@@ -120,12 +234,15 @@ impl Options {
     }
 }
 
+// Names follow zlib's Z_*_FLUSH constants.
+#[allow(clippy::enum_variant_names)]
 #[derive(Copy, Clone)]
 pub enum Flush {
-    // Only SyncFlush and Finish are used internally.
+    // Only NoFlush, SyncFlush and Finish are used internally.
 
-    //NoFlush = Z_NO_FLUSH as isize,
+    NoFlush = Z_NO_FLUSH as isize,
     //PartialFlush = Z_PARTIAL_FLUSH as isize,
+    #[cfg(feature="std")]
     SyncFlush = Z_SYNC_FLUSH as isize,
     //FullFlush = Z_FULL_FLUSH as isize,
     Finish = Z_FINISH as isize,
@@ -137,6 +254,11 @@ pub struct Deflate<W: Write> {
     initialized: bool,
     finished: bool,
     stream: Box<z_stream>,
+
+    // Output is accumulated here across NoFlush calls, so the
+    // Write sink sees the same pieces however the input is split.
+    buffer: Vec<u8>,
+    pending: usize,
 }
 
 impl<W: Write> Deflate<W> {
@@ -146,10 +268,9 @@ impl<W: Write> Deflate<W> {
             options,
             initialized: false,
             finished: false,
-            stream: Box::new(unsafe {
-                let maybe = mem::MaybeUninit::<z_stream>::zeroed();
-                maybe.assume_init()
-            }),
+            stream: new_stream(),
+            buffer: Vec::new(),
+            pending: 0,
         }
     }
 
@@ -180,6 +301,7 @@ impl<W: Write> Deflate<W> {
         }
     }
 
+    #[cfg(feature="std")]
     pub fn set_dictionary(&mut self, dict: &[u8]) -> IoResult {
         self.init()?;
         let ret = unsafe {
@@ -196,40 +318,38 @@ impl<W: Write> Deflate<W> {
 
     fn deflate(&mut self, data: &[u8], flush: Flush) -> IoResult {
         self.init()?;
-        let mut buffer = [0u8; 128 * 1024];
+        if self.buffer.is_empty() {
+            self.buffer = vec![0u8; OUTPUT_BUFFER_SIZE];
+        }
+        let buffer = &mut self.buffer;
         let stream = &mut *self.stream;
-        stream.next_in = &data[0] as *const u8 as *mut u8;
+        stream.next_in = data.as_ptr() as *mut u8;
         stream.avail_in = data.len() as c_uint;
         loop {
-            stream.next_out = &mut buffer[0] as *mut u8;
-            stream.avail_out = buffer.len() as c_uint;
+            stream.next_out = &mut buffer[self.pending] as *mut u8;
+            stream.avail_out = (buffer.len() - self.pending) as c_uint;
             let ret = unsafe {
                 deflate(stream, flush as c_int)
             };
             match ret {
                 Z_OK | Z_STREAM_END => {
-                    let end = buffer.len() - stream.avail_out as usize;
-                    self.output.write_all(&buffer[0 .. end])?;
-                    match ret {
-                        Z_OK => {
-                            if stream.avail_out == 0 {
-                                // Must call again; more output available.
-                                continue;
-                            } else {
-                                return Ok(());
-                            }
-                        },
-                        Z_STREAM_END => {
-                            self.finished = true;
-                            if stream.avail_out == 0 {
-                                // Must call again; more output available.
-                                continue;
-                            } else {
-                                return Ok(());
-                            }
-                        },
-                        _ => unreachable!(),
+                    if ret == Z_STREAM_END {
+                        self.finished = true;
+                    }
+                    self.pending = buffer.len() - stream.avail_out as usize;
+                    if stream.avail_out == 0 {
+                        // Must call again; more output available.
+                        self.output.write_all(&buffer[..])?;
+                        self.pending = 0;
+                        continue;
                     }
+                    if let Flush::NoFlush = flush {
+                        // Hold on to partial output until the next call.
+                    } else {
+                        self.output.write_all(&buffer[0 .. self.pending])?;
+                        self.pending = 0;
+                    }
+                    return Ok(());
                 },
                 Z_STREAM_ERROR => return Err(invalid_input("Inconsistent stream state")),
                 Z_BUF_ERROR => return Err(other("No progress possible")),
@@ -246,7 +366,7 @@ impl<W: Write> Deflate<W> {
     //
     // Deallocate the zlib state and return the writer.
     //
-    pub fn finish(mut self) -> io::Result<W> {
+    pub fn finish(mut self) -> IoResult<W> {
         if self.initialized {
             let ret = unsafe {
                 deflateEnd(&mut *self.stream)
@@ -263,3 +383,107 @@ impl<W: Write> Deflate<W> {
         }
     }
 }
+
+//
+// Decompresses a zlib stream, for checking the encoder's own output.
+//
+pub struct Inflate {
+    initialized: bool,
+    finished: bool,
+    stream: Box<z_stream>,
+}
+
+impl Inflate {
+    pub fn new() -> Inflate {
+        Inflate {
+            initialized: false,
+            finished: false,
+            stream: new_stream(),
+        }
+    }
+
+    fn init(&mut self) -> IoResult {
+        if self.initialized {
+            Ok(())
+        } else {
+            let ret = unsafe {
+                inflateInit_(&mut *self.stream,
+                             zlibVersion(),
+                             mem::size_of::<z_stream>() as c_int)
+            };
+            match ret {
+                Z_OK => {
+                    self.initialized = true;
+                    Ok(())
+                },
+                Z_MEM_ERROR => Err(other("Out of memory")),
+                Z_VERSION_ERROR => Err(invalid_input("Incompatible version of zlib")),
+                _ => Err(other("Unexpected error")),
+            }
+        }
+    }
+
+    //
+    // Decompress more of the stream, appending the output.
+    // Data past the end of the stream is an error.
+    //
+    pub fn write(&mut self, data: &[u8], output: &mut Vec<u8>) -> IoResult {
+        self.init()?;
+        let mut buffer = vec![0u8; OUTPUT_BUFFER_SIZE];
+        let stream = &mut *self.stream;
+        stream.next_in = data.as_ptr() as *mut u8;
+        stream.avail_in = data.len() as c_uint;
+        loop {
+            stream.next_out = buffer.as_mut_ptr();
+            stream.avail_out = buffer.len() as c_uint;
+            let ret = unsafe {
+                inflate(stream, Z_NO_FLUSH)
+            };
+            match ret {
+                Z_OK | Z_STREAM_END => {
+                    if ret == Z_STREAM_END {
+                        self.finished = true;
+                    }
+                    output.extend_from_slice(&buffer[.. buffer.len() - stream.avail_out as usize]);
+                },
+                // No more input, and no output left to drain.
+                Z_BUF_ERROR => return Ok(()),
+                Z_DATA_ERROR | Z_NEED_DICT => return Err(invalid_input("Corrupt stream")),
+                Z_MEM_ERROR => return Err(other("Out of memory")),
+                _ => return Err(other("Unexpected error")),
+            }
+            if self.finished && stream.avail_in > 0 {
+                return Err(invalid_input("Data after end of stream"));
+            }
+            // A full buffer may mean there's more output to come.
+            if self.finished || (stream.avail_in == 0 && stream.avail_out > 0) {
+                return Ok(());
+            }
+        }
+    }
+
+    //
+    // Whether the end of the stream has been reached,
+    // with its checksum verified.
+    //
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+//
+// The zlib state is owned by the Inflate and only used through
+// &mut self, and zlib streams don't depend on the thread they were
+// created on, so it can move with the encoder between threads.
+//
+unsafe impl Send for Inflate {}
+
+impl Drop for Inflate {
+    fn drop(&mut self) {
+        if self.initialized {
+            unsafe {
+                inflateEnd(&mut *self.stream);
+            }
+        }
+    }
+}
@@ -23,16 +23,27 @@
 // THE SOFTWARE.
 //
 
-use rayon::ThreadPool;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 
-use std::collections::VecDeque;
+use crossbeam_channel;
+use crossbeam_channel::{Sender, Receiver};
+
+use std::cmp;
+use std::convert::TryFrom;
+use std::collections::{BinaryHeap, VecDeque};
 
 use std::io;
-use std::io::Write;
+use std::io::{Seek, SeekFrom, Write};
+
+use std::ops::{Deref, DerefMut};
+
+use std::panic;
+
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use std::sync::Arc;
-use std::sync::mpsc;
-use std::sync::mpsc::{Sender, Receiver};
+use std::thread;
+use std::time::Duration;
 
 use super::ColorType;
 use super::CompressionLevel;
@@ -41,27 +52,116 @@ use super::Header;
 use super::Mode;
 use super::Mode::{Adaptive, Fixed};
 
+use super::buffer::{Allocator, Buffer, Heap};
 use super::filter::AdaptiveFilter;
 use super::filter::Filter;
 use super::writer::Writer;
+use super::workers::WorkerPool;
 
 use super::deflate;
 use super::deflate::Deflate;
 use super::deflate::Flush;
+use super::text;
+use super::verify::Verifier;
+use super::validate::ChunkOrder;
 
-use super::utils::*;
+use super::{Error, Result};
+use super::utils::write_be32;
+use super::utils::Instant;
 
 
 /// Options setup struct for the PNG encoder.
 /// May be modified and reused.
+///
+/// With the `serde` feature, options can be kept in config files.
+/// They're validated as by the setters when deserialized. The thread
+/// pool isn't included, and is the global pool when deserialized.
 #[derive(Copy, Clone)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature="serde", serde(try_from="OptionsFields"))]
 pub struct Options<'a> {
     chunk_size: usize,
     compression_level: CompressionLevel,
     strategy_mode: Mode<Strategy>,
     filter_mode: Mode<Filter>,
     streaming: bool,
-    thread_pool: Option<&'a ThreadPool>,
+    idat_size: Option<usize>,
+    max_inflight_chunks: Option<usize>,
+    serial_threshold: usize,
+    max_threads: Option<usize>,
+    deadline: Option<Duration>,
+    deadline_action: DeadlineAction,
+    max_pixels: Option<u64>,
+    verify: Verify,
+    validation: Validation,
+    #[cfg_attr(feature="serde", serde(skip))]
+    thread_pool: Pool<'a>,
+}
+
+/// What to do when an encode runs past its deadline.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DeadlineAction {
+    /// Cancel the encode and return a TimedOut error.
+    Fail,
+
+    /// Compress the remaining data at the Fast level, so it
+    /// still finishes as soon as possible.
+    Downgrade,
+}
+
+/// How much of the output to check as it's written, for Options::set_verify().
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Verify {
+    /// No checks.
+    Off,
+
+    /// Check the chunk structure and CRCs, and that the IHDR
+    /// chunk matches the header.
+    Chunks,
+
+    /// Also decompress and unfilter the image data, and check that
+    /// the decoded rows match a checksum of the input rows.
+    Pixels,
+}
+
+/// How strictly to check ancillary chunks, for Options::set_validation().
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Validation {
+    /// Allow anything decoders can read, though it may be unusual.
+    Permissive,
+
+    /// Also refuse chunks that break the spec's ordering rules, that
+    /// are too large for libpng's defaults, or whose position relative
+    /// to the image data isn't known.
+    Strict,
+}
+
+/// Common trade-offs between speed and file size, for Options::preset().
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Preset {
+    /// Fast compression and a single cheap filter.
+    Fastest,
+
+    /// The defaults, which are similar to libpng's.
+    Balanced,
+
+    /// High compression, and larger chunks which lose less
+    /// compression at the boundaries.
+    Smallest,
+}
+
+//
+// Where to run the filter and deflate jobs.
+//
+#[derive(Copy, Clone)]
+enum Pool<'a> {
+    Global,
+    Rayon(&'a ThreadPool),
+    Workers(&'a WorkerPool),
 }
 
 impl<'a> Options<'a> {
@@ -71,6 +171,15 @@ impl<'a> Options<'a> {
     /// * strategy_mode: Adaptive
     /// * filter_mode: Adaptive
     /// * streaming: off
+    /// * idat_size: none
+    /// * max_inflight_chunks: unlimited
+    /// * serial_threshold: 0 (images of one chunk only)
+    /// * max_threads: unlimited
+    /// * deadline: none
+    /// * deadline_action: Fail
+    /// * max_pixels: unlimited
+    /// * verify: Off
+    /// * validation: Permissive
     /// * thread_pool: global default
     ///
     /// The compression, strategy, and filtering use the same
@@ -100,27 +209,109 @@ impl<'a> Options<'a> {
             //
             streaming: false,
 
+            //
+            // By default IDAT chunks follow the data chunks in streaming
+            // mode, or are merged into one big IDAT otherwise.
+            //
+            idat_size: None,
+
+            //
+            // Input is only held back when all the threads are busy.
+            //
+            max_inflight_chunks: None,
+
+            //
+            // Images that fit in a single chunk are always encoded on
+            // the calling thread, as there's nothing to parallelize.
+            //
+            serial_threshold: 0,
+
+            //
+            // Use as much of the pool as is free.
+            //
+            max_threads: None,
+
+            //
+            // No time limit.
+            //
+            deadline: None,
+            deadline_action: DeadlineAction::Fail,
+
+            //
+            // Any image the platform can address is allowed.
+            //
+            max_pixels: None,
+
+            //
+            // Output isn't checked.
+            //
+            verify: Verify::Off,
+
+            //
+            // Custom chunks are only checked for valid tags.
+            //
+            validation: Validation::Permissive,
+
             //
             // Use the global thread pool.
             //
-            thread_pool: None,
+            thread_pool: Pool::Global,
+        }
+    }
+
+    /// Create a new Options struct using one of the preset trade-offs
+    /// between speed and size. Other options have the defaults.
+    pub fn preset(preset: Preset) -> Options<'a> {
+        let mut options = Options::new();
+        match preset {
+            Preset::Fastest => {
+                options.compression_level = CompressionLevel::Fast;
+                options.filter_mode = Fixed(Filter::Sub);
+            },
+            Preset::Balanced => {},
+            Preset::Smallest => {
+                options.compression_level = CompressionLevel::High;
+                options.chunk_size = 1024 * 1024;
+            },
+        }
+        options
+    }
+
+    /// Start building an Options struct with chained calls,
+    /// beginning from the defaults.
+    pub fn builder() -> OptionsBuilder<'a> {
+        OptionsBuilder {
+            options: Options::new(),
+            error: None,
         }
     }
 
     /// Use a custom Rayon ThreadPool instance instead of the global pool.
-    pub fn set_thread_pool(&mut self, thread_pool: &'a ThreadPool) -> IoResult {
-        self.thread_pool = Some(thread_pool);
+    pub fn set_thread_pool(&mut self, thread_pool: &'a ThreadPool) -> Result<()> {
+        self.thread_pool = Pool::Rayon(thread_pool);
+        Ok(())
+    }
+
+    /// Use a pool of plain std::thread workers instead of Rayon.
+    pub fn set_worker_pool(&mut self, worker_pool: &'a WorkerPool) -> Result<()> {
+        self.thread_pool = Pool::Workers(worker_pool);
         Ok(())
     }
 
+    // Go back to the global pool, for the C API.
+    #[cfg(feature="capi")]
+    pub(crate) fn clear_thread_pool(&mut self) {
+        self.thread_pool = Pool::Global;
+    }
+
     /// Set the size in bytes of chunks used for distributing data to threads.
     /// The actual chunk size used will be a multiple of row lengths approximating
     /// the requested size.
     ///
     /// Chunk size must be at least 32 KiB.
-    pub fn set_chunk_size(&mut self, chunk_size: usize) -> IoResult {
+    pub fn set_chunk_size(&mut self, chunk_size: usize) -> Result<()> {
         if chunk_size < 32768 {
-            Err(invalid_input("chunk size must be at least 32768"))
+            Err(Error::InvalidOptions("chunk size must be at least 32768"))
         } else {
             self.chunk_size = chunk_size;
             Ok(())
@@ -128,9 +319,14 @@ impl<'a> Options<'a> {
     }
 
     /// Set the deflate compression level.
-    /// Currently supported are Fast (equivalent to gzip -1),
-    /// Default (gzip -6), and High (gzip -9).
-    pub fn set_compression_level(&mut self, level: CompressionLevel) -> IoResult {
+    /// Supported are Fast (equivalent to gzip -1), Default (gzip -6),
+    /// High (gzip -9), and any Level from 1 to 9 in between.
+    pub fn set_compression_level(&mut self, level: CompressionLevel) -> Result<()> {
+        if let CompressionLevel::Level(n) = level {
+            if !(1 ..= 9).contains(&n) {
+                return Err(Error::InvalidOptions("Compression level must be from 1 to 9"));
+            }
+        }
         self.compression_level = level;
         Ok(())
     }
@@ -143,7 +339,7 @@ impl<'a> Options<'a> {
     /// which often does well but can pick poorly on some images.
     /// Fixed<*> may be used to override the mode for the whole image,
     /// which sometimes produces better results than the heuristic.
-    pub fn set_filter_mode(&mut self, filter_mode: Mode<Filter>) -> IoResult {
+    pub fn set_filter_mode(&mut self, filter_mode: Mode<Filter>) -> Result<()> {
         self.filter_mode = filter_mode;
         Ok(())
     }
@@ -151,7 +347,7 @@ impl<'a> Options<'a> {
     /// Set the deflate compression strategy. By default it will use Adaptive,
     /// which picks Default for Fixed<None> or Filtered for other filter types.
     /// This matches libpng's logic as well.
-    pub fn set_strategy_mode(&mut self, strategy_mode: Mode<Strategy>) -> IoResult {
+    pub fn set_strategy_mode(&mut self, strategy_mode: Mode<Strategy>) -> Result<()> {
         self.strategy_mode = strategy_mode;
         Ok(())
     }
@@ -160,936 +356,4234 @@ impl<'a> Options<'a> {
     /// around each compressed data chunk. This allows for streaming a large file
     /// over a network etc during compression, at a cost of a few more bytes at
     /// chunk boundaries.
-    pub fn set_streaming(&mut self, streaming: bool) -> IoResult {
+    pub fn set_streaming(&mut self, streaming: bool) -> Result<()> {
         self.streaming = streaming;
         Ok(())
     }
-}
 
-impl<'a> Default for Options<'a> {
-    fn default() -> Self {
-        Self::new()
+    /// Set a fixed size in bytes for the compressed "IDAT" PNG chunks
+    /// written to output, instead of following the input-sized data chunks
+    /// (in streaming mode) or emitting a single IDAT (otherwise).
+    ///
+    /// Every IDAT except the last will be exactly this size, which gives
+    /// predictable chunk boundaries for things like range-request serving.
+    /// In streaming mode output is held back until a full IDAT is ready.
+    ///
+    /// Pass None to return to the default behavior.
+    pub fn set_idat_size(&mut self, idat_size: Option<usize>) -> Result<()> {
+        match idat_size {
+            Some(0) => Err(Error::InvalidOptions("IDAT size must be at least 1")),
+            Some(n) if n > i32::MAX as usize => Err(Error::InvalidOptions("IDAT size cannot exceed 2 GiB - 1 byte")),
+            _ => {
+                self.idat_size = idat_size;
+                Ok(())
+            }
+        }
     }
-}
 
-// Accumulates a set of pixels, then gets sent off as input
-// to the deflate jobs.
-struct PixelChunk {
-    header: Header,
+    /// Limit the number of data chunks that may be held in memory between
+    /// being read from input and written to output. When the limit is hit,
+    /// writing more image rows will block until earlier chunks have been
+    /// compressed and written out.
+    ///
+    /// This bounds memory usage for very large images, at the cost of
+    /// parallelism if set lower than the number of threads.
+    ///
+    /// Pass None to return to the default behavior.
+    pub fn set_max_inflight_chunks(&mut self, max_inflight_chunks: Option<usize>) -> Result<()> {
+        match max_inflight_chunks {
+            Some(0) => Err(Error::InvalidOptions("Max in-flight chunks must be at least 1")),
+            _ => {
+                self.max_inflight_chunks = max_inflight_chunks;
+                Ok(())
+            }
+        }
+    }
 
-    index: usize,
-    start_row: usize,
-    end_row: usize,
-    is_start: bool,
-    is_end: bool,
+    /// Set a size in bytes of image data at or below which the image is
+    /// filtered and compressed on the calling thread instead of being sent
+    /// to the thread pool. This avoids the cross-thread round trips, which
+    /// can dominate the time taken for small images like icons and thumbnails.
+    ///
+    /// Images that fit in a single chunk are always encoded this way.
+    /// The output is the same either way.
+    pub fn set_serial_threshold(&mut self, serial_threshold: usize) -> Result<()> {
+        self.serial_threshold = serial_threshold;
+        Ok(())
+    }
 
-    stride: usize,
+    /// Limit how many of the pool's threads a single encode may occupy
+    /// at once, so one image doesn't monopolize a pool shared between
+    /// many encoders.
+    ///
+    /// Pass None to return to the default behavior.
+    pub fn set_max_threads(&mut self, max_threads: Option<usize>) -> Result<()> {
+        match max_threads {
+            Some(0) => Err(Error::InvalidOptions("Max threads must be at least 1")),
+            _ => {
+                self.max_threads = max_threads;
+                Ok(())
+            }
+        }
+    }
 
-    // Rows of pixel data, each with stride bytes per row
-    rows: Vec<Vec<u8>>,
-}
+    /// Set a time limit for encoding, counted from when the header is
+    /// written. What happens when it runs over is set with
+    /// set_deadline_action().
+    ///
+    /// Pass None to return to the default behavior.
+    ///
+    /// There's no clock to check against on wasm32-unknown-unknown,
+    /// so deadlines are an InvalidOptions error there.
+    pub fn set_deadline(&mut self, deadline: Option<Duration>) -> Result<()> {
+        if cfg!(all(target_arch="wasm32", target_os="unknown")) && deadline.is_some() {
+            return Err(Error::InvalidOptions("Deadlines need a clock, which this target lacks"));
+        }
+        self.deadline = deadline;
+        Ok(())
+    }
 
-impl PixelChunk {
-    fn new(header: Header, index: usize, start_row: usize, end_row: usize) -> PixelChunk {
-        assert!(start_row <= end_row);
+    /// Set what to do when an encode runs past its deadline: either fail
+    /// with an error, or finish up at a faster compression level.
+    pub fn set_deadline_action(&mut self, deadline_action: DeadlineAction) -> Result<()> {
+        self.deadline_action = deadline_action;
+        Ok(())
+    }
 
-        let height = header.height as usize;
-        assert!(end_row <= height);
+    /// Limit the number of pixels in images encoded with these options,
+    /// so dimensions from untrusted input can't lead to huge allocations.
+    /// Encoder::write_header() returns Error::ImageTooLarge for larger images.
+    ///
+    /// Pass None to return to the default behavior.
+    pub fn set_max_pixels(&mut self, max_pixels: Option<u64>) -> Result<()> {
+        match max_pixels {
+            Some(0) => Err(Error::InvalidOptions("Max pixels must be at least 1")),
+            _ => {
+                self.max_pixels = max_pixels;
+                Ok(())
+            }
+        }
+    }
 
-        PixelChunk {
-            header,
+    /// Check the output as it's written, by parsing it back separately
+    /// from the code that produced it. Failures are returned as
+    /// Error::VerificationFailed, by the call that wrote the bad data
+    /// or by finish() for anything missing at the end.
+    ///
+    /// This is for archival and other uses that want a checked
+    /// guarantee for each file, at some cost in time; Verify::Pixels
+    /// costs about as much as decoding the image. On seekable output,
+    /// the IDAT chunk is buffered instead of written in place.
+    pub fn set_verify(&mut self, verify: Verify) -> Result<()> {
+        self.verify = verify;
+        Ok(())
+    }
 
-            index,
-            start_row,
-            end_row,
-            is_start: start_row == 0,
-            is_end: end_row == height,
+    /// Choose how strictly to check text and custom chunks before
+    /// writing them.
+    ///
+    /// Validation::Permissive, the default, allows anything decoders
+    /// can read. Validation::Strict also returns Error::InvalidChunk for:
+    /// * chunks out of the order the spec requires, such as gAMA after
+    ///   PLTE, or repeats of chunks allowed only once
+    /// * chunks larger than the 8 MB libpng accepts by default
+    /// * any chunk after image data has started, as it may be written
+    ///   before or after the IDAT chunks depending on buffering
+    /// * any chunk before the header
+    ///
+    /// The validate module reports the same problems in existing files.
+    pub fn set_validation(&mut self, validation: Validation) -> Result<()> {
+        self.validation = validation;
+        Ok(())
+    }
+
+    fn threads(&self) -> usize {
+        match self.thread_pool {
+            Pool::Global => ::rayon::current_num_threads(),
+            Pool::Rayon(pool) => pool.current_num_threads(),
+            Pool::Workers(pool) => pool.current_num_threads(),
+        }
+    }
 
-            stride: header.stride(),
+    //
+    // Maximum number of filter and deflate jobs to have running at once.
+    //
+    fn max_jobs(&self) -> usize {
+        // Keep the threads busy by queueing a couple extra jobs
+        // But not so busy that we don't interleave types
+        let jobs = self.threads() + 2;
 
-            rows: Vec::with_capacity(end_row - start_row),
+        // When capped, don't queue extras that could start on
+        // other free threads.
+        match self.max_threads {
+            Some(max) => cmp::min(jobs, max),
+            None => jobs,
         }
     }
 
-    fn is_full(&self) -> bool {
-        self.rows.len() == (self.end_row - self.start_row)
+    //
+    // Results coming back from the jobs are queued up to this limit.
+    //
+    // It's sized to hold everything the running jobs can send, so a worker
+    // never blocks on a full channel while holding a pool thread, which
+    // could deadlock with another encoder sharing the pool. In streaming
+    // mode that includes the pieces of output, up to OUTPUT_BUFFER_SIZE each.
+    //
+    fn channel_capacity(&self, header: &Header) -> usize {
+        let messages = if self.streaming {
+            let chunk_filtered = (header.stride() + 1) * self.chunk_rows(header);
+            let pieces = deflate::deflate_bound(chunk_filtered) / deflate::OUTPUT_BUFFER_SIZE + 1;
+            1 + pieces
+        } else {
+            1
+        };
+        self.max_jobs() * messages
     }
 
-    fn read_row(&mut self, row: &[u8])
-    {
-        let mut row_copy = Vec::with_capacity(self.stride);
-        row_copy.extend_from_slice(row);
+    fn channel(&self, header: &Header) -> (Sender<ThreadMessage>, Receiver<ThreadMessage>) {
+        crossbeam_channel::bounded(self.channel_capacity(header))
+    }
 
-        self.rows.push(row_copy);
+    //
+    // Maximum number of rows in a chunk; sizes differ by at most one.
+    //
+    fn chunk_rows(&self, header: &Header) -> usize {
+        (header.height as usize).div_ceil(self.chunks(header))
     }
 
-    fn get_row(&self, row: usize) -> &[u8] {
-        if row < self.start_row {
-            panic!("Tried to access row from earlier chunk: {} < {}", row, self.start_row);
-        } else if row >= self.end_row {
-            panic!("Tried to access row from later chunk: {} >= {}", row, self.end_row);
-        } else {
-            &self.rows[row - self.start_row]
+    //
+    // Number of chunks to divide the image data into.
+    //
+    fn chunks(&self, header: &Header) -> usize {
+        let stride = header.stride() + 1;
+        let height = header.height as usize;
+
+        // Rows aren't split between chunks, so very wide images
+        // get a chunk per row at most.
+        let chunks = stride * height / self.chunk_size;
+        chunks.clamp(1, height)
+    }
+
+    //
+    // Whether to skip the thread pool and run jobs on the calling thread.
+    //
+    fn serial(&self, header: &Header) -> bool {
+        let stride = header.stride() + 1;
+        let height = header.height as usize;
+
+        // Without shared-memory threads on wasm there's no pool to
+        // hand jobs to, so the whole pipeline runs inline.
+        if cfg!(all(target_arch="wasm32", not(target_feature="atomics"))) {
+            return true;
         }
+
+        self.chunks(header) == 1 || stride * height <= self.serial_threshold
     }
-}
 
-// Takes pixel chunks as input and accumulates filtered output.
-struct FilterChunk {
-    index: usize,
-    start_row: usize,
-    end_row: usize,
-    is_start: bool,
-    is_end: bool,
+    /// Estimate the peak memory usage in bytes of encoding an image with
+    /// the given header using these options, for working out chunk sizes
+    /// and concurrency that fit within a memory budget.
+    ///
+    /// This counts buffered input, filtered and compressed chunks in
+    /// flight, per-job filter and zlib state, and the compressed output
+    /// buffer when not streaming. The caller's own image buffers and
+    /// the Write sink are not included.
+    ///
+    /// This is an estimate and not a guarantee. It assumes rows are copied
+    /// in via write_image_rows(), and that data is written faster than
+    /// it can be compressed.
+    ///
+    /// Panics if the header's image data is too large to address on this
+    /// platform; Header::builder() checks for this.
+    pub fn estimate_memory(&self, header: &Header) -> usize {
+        let chunks = self.chunks(header);
+        let stride = header.stride();
+        let height = header.height as usize;
 
-    stride: usize,
-    filter_mode: Mode<Filter>,
+        let chunk_rows = self.chunk_rows(header);
+        let chunk_pixels = stride * chunk_rows;
+        let chunk_filtered = (stride + 1) * chunk_rows;
+        let chunk_deflated = deflate::deflate_bound(chunk_filtered);
+        let chunk_total = chunk_pixels + chunk_filtered + chunk_deflated;
+
+        // Each running job, plus the preceding chunk which is kept
+        // around for its last row and dictionary.
+        let jobs = cmp::min(self.max_jobs(), chunks);
+        let jobs = match self.max_inflight_chunks {
+            Some(max) => cmp::min(jobs, max),
+            None => jobs,
+        };
+        let in_flight = (jobs + 1) * chunk_total;
 
-    // The input pixels for chunk n-1
-    // Needed for its last row only.
-    prior_input: Option<Arc<PixelChunk>>,
+        // Five filter rows for adaptive filtering, and the zlib state.
+        let working = jobs * (5 * (stride + 1) + deflate::WORKING_MEMORY);
 
-    // The input pixels for chunk n
-    input: Arc<PixelChunk>,
+        let output = match (self.streaming, self.idat_size) {
+            (_, Some(size)) => size + deflate::OUTPUT_BUFFER_SIZE,
+            (true, None) => 0,
+            (false, None) => deflate::deflate_bound((stride + 1) * height),
+        };
 
-    // Filtered output bytes
-    data: Vec<u8>,
+        in_flight + working + output
+    }
 }
 
-impl FilterChunk {
-    fn new(prior_input: Option<Arc<PixelChunk>>,
-           input: Arc<PixelChunk>,
-           filter_mode: Mode<Filter>) -> FilterChunk
+/// Chainable builder for Options, as an alternative to the setters.
+///
+/// Each method takes the same value as the matching setter. Any invalid
+/// value, or invalid combination of values, is reported by build().
+///
+/// ```
+/// # fn main() -> mtpng::Result<()> {
+/// use mtpng::encoder::{Options, Preset};
+///
+/// let options = Options::builder()
+///     .preset(Preset::Smallest)
+///     .streaming(true)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct OptionsBuilder<'a> {
+    options: Options<'a>,
+    error: Option<Error>,
+}
+
+impl<'a> OptionsBuilder<'a> {
+    fn apply<F>(mut self, func: F) -> Self
+        where F: FnOnce(&mut Options<'a>) -> Result<()>
     {
-        // Prepend one byte for the filter selector.
-        let stride = input.stride + 1;
-        let nbytes = stride * (input.end_row - input.start_row);
+        if self.error.is_none() {
+            if let Err(e) = func(&mut self.options) {
+                self.error = Some(e);
+            }
+        }
+        self
+    }
 
-        FilterChunk {
-            index: input.index,
-            start_row: input.start_row,
-            end_row: input.end_row,
-            is_start: input.is_start,
-            is_end: input.is_end,
+    /// Reset all options to a preset. Call this first,
+    /// as it replaces any options set before it.
+    pub fn preset(self, preset: Preset) -> Self {
+        self.apply(|options| {
+            *options = Options::preset(preset);
+            Ok(())
+        })
+    }
 
-            stride,
-            filter_mode,
+    /// See Options::set_thread_pool().
+    pub fn thread_pool(self, thread_pool: &'a ThreadPool) -> Self {
+        self.apply(|options| options.set_thread_pool(thread_pool))
+    }
 
-            prior_input,
-            input,
-            data: Vec::with_capacity(nbytes),
-        }
+    /// See Options::set_worker_pool().
+    pub fn worker_pool(self, worker_pool: &'a WorkerPool) -> Self {
+        self.apply(|options| options.set_worker_pool(worker_pool))
     }
 
-    // Return the last up-to-32kib, used as an input dictionary
-    // for the next chunk's deflate job.
-    fn get_trailer(&self) -> &[u8] {
-        let trailer = 32768;
-        let len = self.data.len();
-        if len > trailer {
-            &self.data[len - trailer .. len]
-        } else {
-            &self.data[0 .. len]
-        }
+    /// See Options::set_chunk_size().
+    pub fn chunk_size(self, chunk_size: usize) -> Self {
+        self.apply(|options| options.set_chunk_size(chunk_size))
     }
 
-    //
-    // Run the filtering, on a background thread.
-    //
-    fn run(&mut self) -> IoResult {
-        let mut filter = AdaptiveFilter::new(self.input.header, self.filter_mode);
-        let zero = vec![0u8; self.stride - 1];
-        for i in self.start_row .. self.end_row {
-            let prior = if i == self.start_row {
-                match self.prior_input {
-                    Some(ref input) => input,
-                    None => &self.input, // Won't get used.
-                }
-            } else {
-                &self.input
-            };
-            let prev = if i == 0 {
-                &zero
-            } else {
-                prior.get_row(i - 1)
-            };
+    /// See Options::set_compression_level().
+    pub fn compression_level(self, level: CompressionLevel) -> Self {
+        self.apply(|options| options.set_compression_level(level))
+    }
 
-            let row = self.input.get_row(i);
+    /// See Options::set_filter_mode().
+    pub fn filter_mode(self, filter_mode: Mode<Filter>) -> Self {
+        self.apply(|options| options.set_filter_mode(filter_mode))
+    }
 
-            let output = filter.filter(prev, row);
+    /// See Options::set_strategy_mode().
+    pub fn strategy_mode(self, strategy_mode: Mode<Strategy>) -> Self {
+        self.apply(|options| options.set_strategy_mode(strategy_mode))
+    }
 
-            self.data.write_all(output)?
-        }
-        Ok(())
+    /// See Options::set_streaming().
+    pub fn streaming(self, streaming: bool) -> Self {
+        self.apply(|options| options.set_streaming(streaming))
     }
-}
 
-// Takes filter chunks as input and accumulates compressed output.
-struct DeflateChunk {
-    index: usize,
-    is_start: bool,
-    is_end: bool,
+    /// See Options::set_idat_size().
+    pub fn idat_size(self, idat_size: usize) -> Self {
+        self.apply(|options| options.set_idat_size(Some(idat_size)))
+    }
 
-    compression_level: CompressionLevel,
-    strategy: Strategy,
+    /// See Options::set_max_inflight_chunks().
+    pub fn max_inflight_chunks(self, max_inflight_chunks: usize) -> Self {
+        self.apply(|options| options.set_max_inflight_chunks(Some(max_inflight_chunks)))
+    }
 
-    // The filtered pixels for chunk n-1
-    // Empty on first chunk.
-    // Needed for its last row only.
-    prior_input: Option<Arc<FilterChunk>>,
+    /// See Options::set_serial_threshold().
+    pub fn serial_threshold(self, serial_threshold: usize) -> Self {
+        self.apply(|options| options.set_serial_threshold(serial_threshold))
+    }
 
-    // The filtered pixels for chunk n
-    input: Arc<FilterChunk>,
+    /// See Options::set_max_threads().
+    pub fn max_threads(self, max_threads: usize) -> Self {
+        self.apply(|options| options.set_max_threads(Some(max_threads)))
+    }
 
-    // Compressed output bytes
-    data: Vec<u8>,
+    /// See Options::set_deadline().
+    pub fn deadline(self, deadline: Duration) -> Self {
+        self.apply(|options| options.set_deadline(Some(deadline)))
+    }
 
-    // Checksum of this chunk
-    adler32: u32,
-}
+    /// See Options::set_deadline_action().
+    pub fn deadline_action(self, deadline_action: DeadlineAction) -> Self {
+        self.apply(|options| options.set_deadline_action(deadline_action))
+    }
 
-impl DeflateChunk {
-    fn new(compression_level: CompressionLevel,
-           strategy: Strategy,
-           prior_input: Option<Arc<FilterChunk>>,
-           input: Arc<FilterChunk>) -> DeflateChunk {
+    /// See Options::set_max_pixels().
+    pub fn max_pixels(self, max_pixels: u64) -> Self {
+        self.apply(|options| options.set_max_pixels(Some(max_pixels)))
+    }
 
-        DeflateChunk {
-            index: input.index,
-            is_start: input.is_start,
-            is_end: input.is_end,
+    /// See Options::set_verify().
+    pub fn verify(self, verify: Verify) -> Self {
+        self.apply(|options| options.set_verify(verify))
+    }
 
-            compression_level,
-            strategy,
+    /// See Options::set_validation().
+    pub fn validation(self, validation: Validation) -> Self {
+        self.apply(|options| options.set_validation(validation))
+    }
 
-            prior_input,
-            input,
-            data: Vec::new(),
-            adler32: deflate::adler32_initial(),
+    /// Check the options and return them, or the first error found.
+    pub fn build(self) -> Result<Options<'a>> {
+        if let Some(e) = self.error {
+            return Err(e);
         }
+        let options = self.options;
+        if options.deadline_action != DeadlineAction::Fail && options.deadline.is_none() {
+            return Err(Error::InvalidOptions("Deadline action set without a deadline"));
+        }
+        Ok(options)
     }
+}
 
-    fn run(&mut self) -> IoResult {
-        // Run the deflate!
-        // Todo: don't create an empty vector earlier, but reuse it sanely.
-        let data = Vec::<u8>::new();
-
-        let mut options = deflate::Options::new();
+impl<'a> Default for Options<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        options.set_window_bits(if self.is_start {
-            // 15 means 2^15 (32 KiB), the max supported.
-            15
-        } else {
-            // Negative forces raw stream output so we don't get
-            // a second header...
-            -15
-        });
+//
+// Options as deserialized, before they're checked.
+// Any that are missing take the defaults from Options::new().
+//
+#[cfg(feature="serde")]
+#[derive(serde::Deserialize)]
+#[serde(default)]
+struct OptionsFields {
+    chunk_size: usize,
+    compression_level: CompressionLevel,
+    strategy_mode: Mode<Strategy>,
+    filter_mode: Mode<Filter>,
+    streaming: bool,
+    idat_size: Option<usize>,
+    max_inflight_chunks: Option<usize>,
+    serial_threshold: usize,
+    max_threads: Option<usize>,
+    deadline: Option<Duration>,
+    deadline_action: DeadlineAction,
+    max_pixels: Option<u64>,
+    verify: Verify,
+    validation: Validation,
+}
 
-        match self.compression_level {
-            CompressionLevel::Default => {},
-            CompressionLevel::Fast => options.set_level(1),
-            CompressionLevel::High => options.set_level(9),
+#[cfg(feature="serde")]
+impl Default for OptionsFields {
+    fn default() -> Self {
+        let options = Options::new();
+        OptionsFields {
+            chunk_size: options.chunk_size,
+            compression_level: options.compression_level,
+            strategy_mode: options.strategy_mode,
+            filter_mode: options.filter_mode,
+            streaming: options.streaming,
+            idat_size: options.idat_size,
+            max_inflight_chunks: options.max_inflight_chunks,
+            serial_threshold: options.serial_threshold,
+            max_threads: options.max_threads,
+            deadline: options.deadline,
+            deadline_action: options.deadline_action,
+            max_pixels: options.max_pixels,
+            verify: options.verify,
+            validation: options.validation,
         }
-        options.set_strategy(self.strategy);
-
-        let mut encoder = Deflate::new(options, data);
+    }
+}
 
+#[cfg(feature="serde")]
+impl<'a> std::convert::TryFrom<OptionsFields> for Options<'a> {
+    type Error = Error;
+
+    fn try_from(fields: OptionsFields) -> Result<Options<'a>> {
+        Options::builder()
+            .chunk_size(fields.chunk_size)
+            .compression_level(fields.compression_level)
+            .strategy_mode(fields.strategy_mode)
+            .filter_mode(fields.filter_mode)
+            .streaming(fields.streaming)
+            .apply(|options| options.set_idat_size(fields.idat_size))
+            .apply(|options| options.set_max_inflight_chunks(fields.max_inflight_chunks))
+            .serial_threshold(fields.serial_threshold)
+            .apply(|options| options.set_max_threads(fields.max_threads))
+            .apply(|options| options.set_deadline(fields.deadline))
+            .deadline_action(fields.deadline_action)
+            .apply(|options| options.set_max_pixels(fields.max_pixels))
+            .verify(fields.verify)
+            .validation(fields.validation)
+            .build()
+    }
+}
 
-        if let Some(ref filter) = self.prior_input {
-            let trailer = filter.get_trailer();
-            encoder.set_dictionary(trailer)?;
-        }
+// Caller-provided image data that can be read directly by the
+// worker threads without copying.
+type SharedRows = Arc<dyn AsRef<[u8]> + Send + Sync>;
 
-        encoder.write(&self.input.data, if self.is_end {
-            Flush::Finish
-        } else {
-            Flush::SyncFlush
-        })?;
+//
+// Rows borrowed from the caller by write_image_rows_borrowed(),
+// with the lifetime erased so they can be sent to the jobs.
+//
+// That function doesn't return until every job has finished and
+// dropped its references, and copies out the one row the encoder
+// needs later, so nothing refers to them once it returns.
+//
+struct BorrowedRows {
+    ptr: *const u8,
+    len: usize,
+}
 
-        // In raw deflate mode we have to calculate the checksum ourselves.
-        self.adler32 = deflate::adler32(1, &self.input.data);
+unsafe impl Send for BorrowedRows {}
+unsafe impl Sync for BorrowedRows {}
 
-        match encoder.finish() {
-            Ok(data) => {
-                // This seems lame to move the vector back, but it's actually cheap.
-                self.data = data;
-                Ok(())
-            },
-            Err(e) => Err(e)
+impl AsRef<[u8]> for BorrowedRows {
+    fn as_ref(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self.ptr, self.len)
         }
     }
 }
 
 //
-// List of completed chunks, which may come in in any order
-// but are returned in original order, in pairs with the
-// prior chunk when available.
-//
-// The prior chunk is passed around because filtering and
-// deflating jobs need the end (last row, or last 32 KiB)
-// of the previous chunk's input as well as their own.
+// Number of jobs dispatched and not yet finished, which can be
+// waited on to reach zero.
 //
-struct ChunkMap<T> {
-    cursor_in: usize,
-    cursor_out: usize,
-    running: usize,
-
-    chunks: VecDeque<Option<Arc<T>>>,
-    prev: Option<Arc<T>>,
+#[derive(Default)]
+struct ActiveJobs {
+    count: Mutex<usize>,
+    done: Condvar,
 }
 
-impl<T> ChunkMap<T> {
-    fn new() -> ChunkMap<T> {
-        ChunkMap {
-            cursor_in: 0,
-            cursor_out: 0,
-            running: 0,
-            chunks: VecDeque::new(),
-            prev: None,
-        }
-    }
-
-    fn in_flight(&self) -> bool {
-        self.cursor_in > self.cursor_out
+impl ActiveJobs {
+    // Nothing panics while holding the lock, but waiting happens
+    // while unwinding, so don't add a panic there.
+    fn count(&self) -> MutexGuard<'_, usize> {
+        self.count.lock().unwrap_or_else(PoisonError::into_inner)
     }
 
-    fn running_jobs(&self) -> usize {
-        self.running
+    fn start(&self) {
+        *self.count() += 1;
     }
 
-    //
-    // Record that this job is now in-flight
-    //
-    fn advance(&mut self) {
-        self.cursor_in += 1;
-        self.running += 1;
+    fn finish(&self) {
+        let mut count = self.count();
+        *count -= 1;
+        if *count == 0 {
+            self.done.notify_all();
+        }
     }
 
     //
-    // Record that this job has landed and save its data.
+    // Block until every job has finished. On a rayon thread, run
+    // other work meanwhile, as the jobs may be queued behind us on
+    // the same pool; once there's none to run, they're all running
+    // elsewhere and we can sleep.
     //
-    fn land(&mut self, index: usize, chunk: Arc<T>) {
-        if index < self.cursor_out {
-            panic!("Tried to land an expired chunk");
-        }
-        if index > self.cursor_in {
-            panic!("Tried to land a future chunk");
-        }
-        self.running -= 1;
-        let offset = index - self.cursor_out;
-        while offset > self.chunks.len() {
-            self.chunks.push_back(None);
-        }
-        if offset == self.chunks.len() {
-            self.chunks.push_back(Some(chunk));
-        } else {
-            self.chunks[offset] = Some(chunk);
-        }
-    }
-
-    fn pop_front(&mut self) -> Option<(Option<Arc<T>>, Arc<T>)> {
-        match self.chunks.get(0) {
-            Some(Some(_)) => {
-                // Ok we're good we have something
-                self.cursor_out += 1;
-                match self.chunks.pop_front() {
-                    Some(Some(item)) => {
-                        let prev = std::mem::replace(&mut self.prev, Some(Arc::clone(&item)));
-                        Some((prev, item))
-                    },
-                    _ => {
-                        panic!("Bad job queue state")
-                    }
+    fn wait(&self) {
+        let on_pool = ::rayon::current_thread_index().is_some();
+        let mut count = self.count();
+        while *count > 0 {
+            if on_pool {
+                drop(count);
+                let executed = matches!(::rayon::yield_now(), Some(::rayon::Yield::Executed));
+                count = self.count();
+                if executed || *count == 0 {
+                    continue;
                 }
-            },
-            Some(None) => {
-                // Not ready yet but a later chunk landed.
-                None
-            },
-            None => {
-                // Nothing yet.
-                None
             }
+            count = self.done.wait(count).unwrap_or_else(PoisonError::into_inner);
         }
     }
 }
 
-enum ThreadMessage {
-    FilterDone(Arc<FilterChunk>),
-    DeflateDone(Arc<DeflateChunk>),
-    Error(io::Error),
+//
+// Waits for all running jobs to finish when dropped, including when
+// unwinding. Unless disarmed, it cancels the encode first so they
+// finish quickly and the encoder can't be used further.
+//
+struct JobScope {
+    active: Arc<ActiveJobs>,
+    cancel: CancelToken,
+    armed: bool,
 }
 
-#[derive(Copy, Clone)]
-enum DispatchMode {
-    Blocking,
-    NonBlocking,
+impl Drop for JobScope {
+    fn drop(&mut self) {
+        if self.armed {
+            self.cancel.cancel();
+        }
+        // Jobs never block on sending their results, so they
+        // will finish even if nobody's listening.
+        self.active.wait();
+    }
 }
 
-enum RowStatus {
-    Continue,
-    Done,
-}
+enum PixelData {
+    // Rows copied in one at a time.
+    Owned(Buffer),
 
-/// Parallel PNG encoder state.
-/// Takes an Options struct with initializer data and a Write struct
-/// to send output to.
-pub struct Encoder<'a, W: Write> {
-    writer: Writer<W>,
-    options: Options<'a>,
+    // The whole chunk's rows, at a byte offset into a shared buffer.
+    Shared(SharedRows, usize),
+}
 
+// Accumulates a set of pixels, then gets sent off as input
+// to the deflate jobs.
+struct PixelChunk {
     header: Header,
 
-    wrote_header: bool,
-    wrote_palette: bool,
-    palette_length: usize,
-    wrote_transparency: bool,
-    started_image: bool,
-
-    chunks_total: usize,
-    chunks_output: usize,
-
-    // Accumulates input rows until enough are ready to fire off a filter job.
-    pixel_accumulator: Arc<PixelChunk>,
-    pixel_index: usize,
-    current_row: u32,
-
-    // Accumulates completed output from pixel input, filter, and deflate jobs.
-    pixel_chunks: ChunkMap<PixelChunk>,
-    filter_chunks: ChunkMap<FilterChunk>,
-    deflate_chunks: ChunkMap<DeflateChunk>,
-
-    // Accumulates the checksum of all output chunks in turn.
-    adler32: u32,
+    index: usize,
+    start_row: usize,
+    end_row: usize,
+    is_start: bool,
+    is_end: bool,
 
-    // Accumulates IDAT output when not using streaming output mode
-    idat_buffer: Vec<u8>,
+    stride: usize,
 
-    // For messages from the thread pool.
-    tx: Sender<ThreadMessage>,
-    rx: Receiver<ThreadMessage>,
+    // Rows of pixel data, each with stride bytes per row,
+    // packed into a single buffer.
+    data: PixelData,
 }
 
-impl<'a, W: Write> Encoder<'a, W> {
-    /// Creates a new Encoder instance with the given Write output sink and options.
-    pub fn new(write: W, options: &Options<'a>) -> Encoder<'a, W> {
-        let (tx, rx) = mpsc::channel();
-        Encoder {
-            writer: Writer::new(write),
-
-            header: Header::new(),
-            options: *options,
-
-            wrote_header: false,
-            wrote_palette: false,
-            palette_length: 0,
-            wrote_transparency: false,
-            started_image: false,
+impl PixelChunk {
+    fn new(header: Header, index: usize, start_row: usize, end_row: usize, heap: &Heap) -> PixelChunk {
+        assert!(start_row <= end_row);
 
-            chunks_total: 0,
-            chunks_output: 0,
+        let height = header.height as usize;
+        assert!(end_row <= height);
 
-            // hack, clean this up later
-            pixel_accumulator: Arc::new(PixelChunk::new(Header::new(), 0, 0, 0)),
-            pixel_index: 0,
-            current_row: 0,
+        let stride = header.stride();
 
-            pixel_chunks: ChunkMap::new(),
-            filter_chunks: ChunkMap::new(),
-            deflate_chunks: ChunkMap::new(),
+        PixelChunk {
+            header,
 
-            adler32: deflate::adler32_initial(),
-            idat_buffer: Vec::new(),
+            index,
+            start_row,
+            end_row,
+            is_start: start_row == 0,
+            is_end: end_row == height,
 
-            tx,
-            rx,
-        }
-    }
+            stride,
 
-    /// Flush output and return the Write sink for further manipulation.
-    /// Consumes the encoder instance.
-    pub fn finish(mut self) -> io::Result<W> {
-        self.flush()?;
-        if self.is_finished() {
-            self.writer.write_end()?;
-            self.writer.finish()
-        } else {
-            Err(other("Incomplete image input"))
+            // Allocated on first row, as shared chunks won't need it.
+            data: PixelData::Owned(heap.buffer()),
         }
     }
 
-    fn running_jobs(&self) -> usize {
-        self.filter_chunks.running_jobs() + self.deflate_chunks.running_jobs()
+    fn len(&self) -> usize {
+        self.stride * (self.end_row - self.start_row)
     }
 
-    fn threads(&self) -> usize {
-        match self.options.thread_pool {
-            Some(pool) => pool.current_num_threads(),
-            None => ::rayon::current_num_threads()
+    fn is_empty(&self) -> bool {
+        match self.data {
+            PixelData::Owned(ref data) => data.is_empty(),
+            PixelData::Shared(..) => false,
         }
     }
 
-    fn max_threads(&self) -> usize {
-        // Keep the threads busy by queueing a couple extra jobs
-        // But not so busy that we don't interleave types
-        self.threads() + 2
+    fn is_full(&self) -> bool {
+        match self.data {
+            PixelData::Owned(ref data) => data.len() == self.len(),
+            PixelData::Shared(..) => true,
+        }
     }
 
-    fn dispatch_func<F>(&self, func: F)
-        where F: Fn(&Sender<ThreadMessage>) + Send + 'static
+    fn read_row(&mut self, row: &[u8]) -> io::Result<()>
+    {
+        let len = self.len();
+        match self.data {
+            PixelData::Owned(ref mut data) => {
+                if data.is_empty() {
+                    data.reserve_exact(len)?;
+                }
+                data.extend_from_slice(row)
+            },
+            PixelData::Shared(..) => panic!("Tried to add a row to a shared chunk"),
+        }
+    }
+
+    // Use all rows directly from the given buffer, starting at offset.
+    fn read_shared(&mut self, source: SharedRows, offset: usize)
+    {
+        assert!(self.is_empty());
+        assert!(offset + self.len() <= (*source).as_ref().len());
+        self.data = PixelData::Shared(source, offset);
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self.data {
+            PixelData::Owned(ref data) => data,
+            PixelData::Shared(ref source, offset) => {
+                &(**source).as_ref()[offset .. offset + self.len()]
+            },
+        }
+    }
+
+    //
+    // If this chunk's rows are shared, return a copy of just the last
+    // row, which is all that the next chunk's filter job reads. This
+    // lets go of a buffer that's about to be released.
+    //
+    fn last_row_only(chunk: Arc<PixelChunk>, heap: &Heap) -> io::Result<Arc<PixelChunk>> {
+        match chunk.data {
+            PixelData::Shared(..) => {
+                let mut copy = PixelChunk::new(chunk.header,
+                                               chunk.index,
+                                               chunk.end_row - 1,
+                                               chunk.end_row,
+                                               heap);
+                copy.read_row(chunk.get_row(chunk.end_row - 1))?;
+                Ok(Arc::new(copy))
+            },
+            PixelData::Owned(..) => Ok(chunk),
+        }
+    }
+
+    fn get_row(&self, row: usize) -> &[u8] {
+        if row < self.start_row {
+            panic!("Tried to access row from earlier chunk: {} < {}", row, self.start_row);
+        } else if row >= self.end_row {
+            panic!("Tried to access row from later chunk: {} >= {}", row, self.end_row);
+        } else {
+            let start = (row - self.start_row) * self.stride;
+            &self.bytes()[start .. start + self.stride]
+        }
+    }
+}
+
+// Takes pixel chunks as input and accumulates filtered output.
+struct FilterChunk {
+    index: usize,
+    start_row: usize,
+    end_row: usize,
+    is_start: bool,
+    is_end: bool,
+
+    stride: usize,
+    filter_mode: Mode<Filter>,
+
+    // Filtered output bytes
+    data: Buffer,
+
+    // Number of rows using each filter type, for stats.
+    filter_rows: [usize; 5],
+
+    // Time spent running the job.
+    elapsed: Duration,
+}
+
+impl FilterChunk {
+    fn new(input: &PixelChunk, filter_mode: Mode<Filter>, heap: &Heap) -> FilterChunk
+    {
+        // Prepend one byte for the filter selector.
+        let stride = input.stride + 1;
+
+        FilterChunk {
+            index: input.index,
+            start_row: input.start_row,
+            end_row: input.end_row,
+            is_start: input.is_start,
+            is_end: input.is_end,
+
+            stride,
+            filter_mode,
+
+            data: heap.buffer(),
+            filter_rows: [0; 5],
+            elapsed: Duration::default(),
+        }
+    }
+
+    // Return the last up-to-32kib, used as an input dictionary
+    // for the next chunk's deflate job.
+    fn get_trailer(&self) -> &[u8] {
+        let trailer = 32768;
+        let len = self.data.len();
+        if len > trailer {
+            &self.data[len - trailer .. len]
+        } else {
+            &self.data[0 .. len]
+        }
+    }
+
+    //
+    // Run the filtering, on a background thread, given the input
+    // pixels for chunk n and those for chunk n-1, which are needed
+    // for their last row only. They aren't kept, as they may be
+    // borrowed from the caller.
+    //
+    fn run(&mut self,
+           prior_input: Option<&PixelChunk>,
+           input: &PixelChunk,
+           cancel: &CancelToken) -> Result<()>
+    {
+        trace_span!("mtpng::filter", index = self.index, rows = self.end_row - self.start_row);
+        let start = Instant::now();
+        let mut filter = AdaptiveFilter::new(input.header, self.filter_mode);
+        self.data.reserve_exact(self.stride * (self.end_row - self.start_row))?;
+
+        // Only the first chunk needs an empty prior row.
+        let zero = if self.is_start {
+            vec![0u8; self.stride - 1]
+        } else {
+            Vec::new()
+        };
+        for i in self.start_row .. self.end_row {
+            let prior = if i == self.start_row {
+                match prior_input {
+                    Some(input) => input,
+                    None => input, // Won't get used.
+                }
+            } else {
+                input
+            };
+            let prev = if i == 0 {
+                &zero
+            } else {
+                prior.get_row(i - 1)
+            };
+
+            cancel.check()?;
+
+            let row = input.get_row(i);
+
+            let output = filter.filter(prev, row);
+            self.filter_rows[output[0] as usize] += 1;
+
+            self.data.write_all(output)?
+        }
+        self.elapsed = start.elapsed();
+        Ok(())
+    }
+}
+
+// A bounded piece of compressed output, sent back from a deflate
+// job as it's produced when in streaming mode.
+struct DeflatePiece {
+    index: usize,
+    data: Buffer,
+    crc32: u32,
+}
+
+// Write sink for the deflate jobs in streaming mode, which passes
+// each piece of output back to the encoder as soon as it's ready
+// instead of accumulating the whole chunk in memory.
+struct PieceWriter {
+    index: usize,
+    tx: Sender<ThreadMessage>,
+    heap: Heap,
+}
+
+impl Write for PieceWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !buf.is_empty() {
+            let mut data = self.heap.buffer();
+            data.reserve_exact(buf.len())?;
+            data.extend_from_slice(buf)?;
+            let piece = DeflatePiece {
+                index: self.index,
+                data,
+                crc32: deflate::crc32(deflate::crc32_initial(), buf),
+            };
+            self.tx.send(ThreadMessage::DeflatePiece(piece))
+                   .map_err(|_e| io::Error::other("Encoder went away"))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Input is fed to deflate in pieces of this size.
+const DEFLATE_SLICE_SIZE: usize = 64 * 1024;
+
+// Takes filter chunks as input and accumulates compressed output.
+struct DeflateChunk {
+    index: usize,
+    is_start: bool,
+    is_end: bool,
+
+    compression_level: CompressionLevel,
+    strategy: Strategy,
+
+    // If set, output is sent back in pieces instead of
+    // being accumulated in data.
+    streaming: bool,
+
+    // The filtered pixels for chunk n-1
+    // Empty on first chunk.
+    // Needed for its last row only.
+    prior_input: Option<Arc<FilterChunk>>,
+
+    // The filtered pixels for chunk n
+    input: Arc<FilterChunk>,
+
+    // Compressed output bytes
+    data: Buffer,
+
+    // Where the output buffers come from.
+    heap: Heap,
+
+    // Checksum of this chunk's input
+    adler32: u32,
+
+    // Checksum of this chunk's compressed output
+    crc32: u32,
+
+    // Time spent compressing and checksumming.
+    deflate_time: Duration,
+    checksum_time: Duration,
+}
+
+impl DeflateChunk {
+    fn new(compression_level: CompressionLevel,
+           strategy: Strategy,
+           streaming: bool,
+           prior_input: Option<Arc<FilterChunk>>,
+           input: Arc<FilterChunk>,
+           heap: Heap) -> DeflateChunk {
+
+        DeflateChunk {
+            index: input.index,
+            is_start: input.is_start,
+            is_end: input.is_end,
+
+            compression_level,
+            strategy,
+            streaming,
+
+            prior_input,
+            input,
+            data: heap.buffer(),
+            heap,
+            adler32: deflate::adler32_initial(),
+            crc32: deflate::crc32_initial(),
+            deflate_time: Duration::default(),
+            checksum_time: Duration::default(),
+        }
+    }
+
+    fn deflate_options(&self) -> deflate::Options {
+        let mut options = deflate::Options::new();
+
+        options.set_window_bits(if self.is_start {
+            // 15 means 2^15 (32 KiB), the max supported.
+            15
+        } else {
+            // Negative forces raw stream output so we don't get
+            // a second header...
+            -15
+        });
+
+        match self.compression_level {
+            CompressionLevel::Default => {},
+            CompressionLevel::Fast => options.set_level(1),
+            CompressionLevel::High => options.set_level(9),
+            CompressionLevel::Level(n) => options.set_level(i32::from(n)),
+        }
+        options.set_strategy(self.strategy);
+
+        options
+    }
+
+    fn deflate_into<O: Write>(&self, output: O, cancel: &CancelToken) -> Result<O> {
+        let mut encoder = Deflate::new(self.deflate_options(), output);
+
+        if let Some(ref filter) = self.prior_input {
+            let trailer = filter.get_trailer();
+            encoder.set_dictionary(trailer)?;
+        }
+
+        // Feed the input in slices, so cancellation can take effect
+        // partway through. This doesn't change the output.
+        let mut slices = self.input.data.chunks(DEFLATE_SLICE_SIZE).peekable();
+        while let Some(slice) = slices.next() {
+            cancel.check()?;
+            encoder.write(slice, if slices.peek().is_some() {
+                Flush::NoFlush
+            } else if self.is_end {
+                Flush::Finish
+            } else {
+                Flush::SyncFlush
+            })?;
+        }
+
+        Ok(encoder.finish()?)
+    }
+
+    fn run(&mut self, tx: &Sender<ThreadMessage>, cancel: &CancelToken) -> Result<()> {
+        trace_span!("mtpng::deflate", index = self.index, bytes = self.input.data.len());
+        let start = Instant::now();
+
+        // Run the deflate!
+        if self.streaming {
+            self.deflate_into(PieceWriter {
+                index: self.index,
+                tx: tx.clone(),
+                heap: self.heap.clone(),
+            }, cancel)?;
+        } else {
+            self.data = self.deflate_into(self.heap.buffer(), cancel)?;
+        }
+
+        let deflated = Instant::now();
+        self.deflate_time = deflated - start;
+
+        if !self.streaming {
+            // Checksum the output here too, so the PNG chunk CRCs
+            // don't have to be calculated serially on the writer.
+            self.crc32 = deflate::crc32(deflate::crc32_initial(), &self.data);
+        }
+
+        // In raw deflate mode we have to calculate the checksum ourselves.
+        self.adler32 = deflate::adler32(1, &self.input.data);
+
+        self.checksum_time = deflated.elapsed();
+        Ok(())
+    }
+}
+
+//
+// List of completed chunks, which may come in in any order
+// but are returned in original order, in pairs with the
+// prior chunk when available.
+//
+// The prior chunk is passed around because filtering and
+// deflating jobs need the end (last row, or last 32 KiB)
+// of the previous chunk's input as well as their own.
+//
+struct ChunkMap<T> {
+    cursor_in: usize,
+    cursor_out: usize,
+    running: usize,
+
+    chunks: VecDeque<Option<Arc<T>>>,
+    prev: Option<Arc<T>>,
+}
+
+impl<T> ChunkMap<T> {
+    fn new() -> ChunkMap<T> {
+        ChunkMap {
+            cursor_in: 0,
+            cursor_out: 0,
+            running: 0,
+            chunks: VecDeque::new(),
+            prev: None,
+        }
+    }
+
+    //
+    // Return to the initial state, keeping the queue's storage.
+    //
+    fn reset(&mut self) {
+        self.cursor_in = 0;
+        self.cursor_out = 0;
+        self.running = 0;
+        self.chunks.clear();
+        self.prev = None;
+    }
+
+    fn in_flight(&self) -> bool {
+        self.cursor_in > self.cursor_out
+    }
+
+    fn running_jobs(&self) -> usize {
+        self.running
+    }
+
+    //
+    // Record that this job is now in-flight
+    //
+    fn advance(&mut self) {
+        self.cursor_in += 1;
+        self.running += 1;
+    }
+
+    //
+    // Record that this job has landed and save its data.
+    //
+    fn land(&mut self, index: usize, chunk: Arc<T>) {
+        if index < self.cursor_out {
+            panic!("Tried to land an expired chunk");
+        }
+        if index > self.cursor_in {
+            panic!("Tried to land a future chunk");
+        }
+        self.running -= 1;
+        let offset = index - self.cursor_out;
+        while offset > self.chunks.len() {
+            self.chunks.push_back(None);
+        }
+        if offset == self.chunks.len() {
+            self.chunks.push_back(Some(chunk));
+        } else {
+            self.chunks[offset] = Some(chunk);
+        }
+    }
+
+    fn pop_front(&mut self) -> Option<(Option<Arc<T>>, Arc<T>)> {
+        match self.chunks.front() {
+            Some(Some(_)) => {
+                // Ok we're good we have something
+                self.cursor_out += 1;
+                match self.chunks.pop_front() {
+                    Some(Some(item)) => {
+                        let prev = self.prev.replace(Arc::clone(&item));
+                        Some((prev, item))
+                    },
+                    _ => {
+                        panic!("Bad job queue state")
+                    }
+                }
+            },
+            Some(None) => {
+                // Not ready yet but a later chunk landed.
+                None
+            },
+            None => {
+                // Nothing yet.
+                None
+            }
+        }
+    }
+}
+
+enum ThreadMessage {
+    FilterDone(Arc<FilterChunk>),
+    DeflatePiece(DeflatePiece),
+    DeflateDone(Arc<DeflateChunk>),
+    Error(Error),
+}
+
+#[derive(Copy, Clone)]
+enum DispatchMode {
+    Blocking,
+    NonBlocking,
+}
+
+enum RowStatus {
+    Continue,
+    Done,
+}
+
+/// A handle which can be used to cancel an encode, say from another
+/// thread when a request times out. Get one from Encoder::cancel_token().
+///
+/// Once cancelled, running filter and compression jobs stop at their
+/// next check, queued jobs are dropped, and the encoder returns an error
+/// from all further calls without writing anything more to output.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Create a new, uncancelled token.
+    pub fn new() -> CancelToken {
+        Self::default()
+    }
+
+    /// Cancel the encode.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Check whether the encode has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Progress of an encode, as passed to the callback set with
+/// Encoder::set_progress_callback(). More fields may be added.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Progress {
+    /// Image rows taken from input so far.
+    pub rows_consumed: u32,
+
+    /// Data chunks filtered, compressed, and passed on for output so far.
+    pub chunks_completed: usize,
+
+    /// Data chunks in the whole image, for working out the fraction done.
+    pub chunks_total: usize,
+
+    /// Bytes written to the output so far. When not in streaming mode,
+    /// compressed data is held back until the end.
+    pub bytes_written: u64,
+}
+
+type ProgressCallback<'a> = Box<dyn FnMut(Progress) + Send + 'a>;
+
+/// Statistics on a finished encode, as returned by
+/// Encoder::finish_with_stats(). More fields may be added.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Total bytes written to the output, including all chunks.
+    pub bytes_written: u64,
+
+    /// Size of the filtered image data before compression, which is
+    /// the raw pixel data plus a filter type byte per row.
+    pub uncompressed_bytes: u64,
+
+    /// Size of the compressed image data in the IDAT chunks.
+    pub compressed_bytes: u64,
+
+    /// Number of rows filtered with each filter type, indexed by
+    /// the Filter value: None, Sub, Up, Average, Paeth.
+    pub filter_rows: [usize; 5],
+
+    /// Number of data chunks the image was split into for
+    /// filtering and compression.
+    pub chunks: usize,
+
+    /// Time from writing the header to finishing the image.
+    pub wall_time: Duration,
+
+    /// Total time spent in filter and compression jobs, summed
+    /// over all threads. This is the sum of filter_time,
+    /// deflate_time, and checksum_time.
+    pub cpu_time: Duration,
+
+    /// Time spent filtering, summed over all threads.
+    pub filter_time: Duration,
+
+    /// Time spent compressing, summed over all threads.
+    pub deflate_time: Duration,
+
+    /// Time spent calculating checksums of the compressed data
+    /// on the worker threads. In streaming mode this is done as
+    /// part of compression instead.
+    pub checksum_time: Duration,
+
+    /// Time spent writing to the output sink.
+    pub write_time: Duration,
+
+    /// Time spent on the calling thread waiting for jobs to
+    /// finish, when no other work was ready.
+    pub wait_time: Duration,
+}
+
+//
+// Run a job, turning a panic into an error message for the encoder
+// in place of the job's result, instead of leaving it waiting forever
+// (or aborting the process, on Rayon's threads).
+//
+fn run_job<F>(func: &F, tx: &Sender<ThreadMessage>)
+    where F: Fn(&Sender<ThreadMessage>)
+{
+    if let Err(payload) = panic::catch_unwind(panic::AssertUnwindSafe(|| func(tx))) {
+        let message = if let Some(s) = payload.downcast_ref::<&str>() {
+            s
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.as_str()
+        } else {
+            "unknown error"
+        };
+        let e = Error::WorkerFailed(format!("Encoding job panicked: {}", message));
+        tx.send(ThreadMessage::Error(e)).ok();
+    }
+}
+
+// Called after each job finishes, to wake up an async
+// encoder waiting on results.
+pub(crate) type WakeFunc = Arc<dyn Fn() + Send + Sync>;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct QueuedJob {
+    priority: usize,
+    job: Job,
+}
+
+impl PartialEq for QueuedJob {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority
+    }
+}
+
+impl Eq for QueuedJob {}
+
+impl PartialOrd for QueuedJob {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedJob {
+    // Reversed, so the heap pops the lowest value first.
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        other.priority.cmp(&self.priority)
+    }
+}
+
+//
+// Jobs waiting for a thread in streaming mode, most urgent first.
+//
+#[derive(Default)]
+struct JobQueue {
+    jobs: Mutex<BinaryHeap<QueuedJob>>,
+}
+
+impl JobQueue {
+    fn push(&self, priority: usize, job: Job) {
+        self.jobs.lock().unwrap().push(QueuedJob {
+            priority,
+            job,
+        });
+    }
+
+    fn run_next(&self) {
+        let next = self.jobs.lock().unwrap().pop();
+        if let Some(queued) = next {
+            (queued.job)();
+        }
+    }
+}
+
+type SeekFunc<W> = fn(&mut W, SeekFrom) -> io::Result<u64>;
+
+fn seek_func<W: Seek>(w: &mut W, pos: SeekFrom) -> io::Result<u64> {
+    w.seek(pos)
+}
+
+/// Parallel PNG encoder state.
+/// Takes an Options struct with initializer data and a Write struct
+/// to send output to.
+///
+/// Output is byte-for-byte identical for the same input and options,
+/// regardless of the thread pool, how many threads it has, and the order
+/// in which jobs happen to run. The only exception is a deadline with
+/// DeadlineAction::Downgrade, which depends on timing by design.
+///
+/// The encoder is Send when the Write sink is, so it can be created on
+/// one thread and driven from another, such as moved into a worker task.
+/// It isn't Sync; use one from one thread at a time. To cancel from
+/// another thread, use a CancelToken.
+pub struct Encoder<'a, W: Write> {
+    writer: Writer<W>,
+    options: Options<'a>,
+
+    header: Header,
+
+    wrote_header: bool,
+    wrote_palette: bool,
+    palette_length: usize,
+    wrote_transparency: bool,
+    started_image: bool,
+
+    // Chunks written so far, for checking in strict mode.
+    chunk_order: ChunkOrder,
+
+    chunks_total: usize,
+    chunks_output: usize,
+
+    // Run jobs on the calling thread instead of the thread pool.
+    serial: bool,
+
+    // Accumulates input rows until enough are ready to fire off a filter job.
+    pixel_accumulator: Arc<PixelChunk>,
+    pixel_index: usize,
+    current_row: u32,
+
+    // Bytes of an incomplete row given through the Write trait.
+    partial_row: Buffer,
+
+    // Accumulates completed output from pixel input, filter, and deflate jobs.
+    pixel_chunks: ChunkMap<PixelChunk>,
+    filter_chunks: ChunkMap<FilterChunk>,
+    deflate_chunks: ChunkMap<DeflateChunk>,
+
+    // Accumulates the checksum of all output chunks in turn.
+    adler32: u32,
+
+    // Accumulates IDAT output when not using streaming output mode,
+    // or until a full chunk is ready when using a fixed IDAT size.
+    idat_buffer: Buffer,
+
+    // Accumulates the CRC-32 of the IDAT output buffer.
+    idat_crc32: u32,
+
+    // Streaming output pieces that arrived before their chunk's turn,
+    // indexed from the next chunk to be output.
+    pending_pieces: VecDeque<Vec<DeflatePiece>>,
+
+    // Seek function for the output, if it supports it.
+    seek: Option<SeekFunc<W>>,
+
+    // Position and running length of the IDAT chunk when writing
+    // it in place on seekable output.
+    idat_start: Option<u64>,
+    idat_len: usize,
+
+    // Checksum of the input rows, when verifying pixels.
+    input_crc32: u32,
+
+    // Shared with the jobs, to stop them early.
+    cancel: CancelToken,
+
+    progress_callback: Option<ProgressCallback<'a>>,
+
+    // Where the working buffers come from.
+    heap: Heap,
+
+    // Accumulated as chunks are output.
+    stats: Stats,
+    started: Option<Instant>,
+
+    // When the time limit runs out, if any.
+    deadline: Option<Instant>,
+
+    wake: Option<WakeFunc>,
+
+    // Number of jobs dispatched and not yet finished.
+    active_jobs: Arc<ActiveJobs>,
+
+    // Prioritized jobs for streaming mode.
+    job_queue: Arc<JobQueue>,
+
+    // For messages from the thread pool.
+    tx: Sender<ThreadMessage>,
+    rx: Receiver<ThreadMessage>,
+}
+
+impl<'a, W: Write> Encoder<'a, W> {
+    /// Creates a new Encoder instance with the given Write output sink and options.
+    pub fn new(write: W, options: &Options<'a>) -> Encoder<'a, W> {
+        Encoder::with_spare(write, options, Spare::new(options))
+    }
+
+    fn with_spare(write: W, options: &Options<'a>, spare: Spare) -> Encoder<'a, W> {
+        let Spare { idat_buffer, tx, rx } = spare;
+        Encoder {
+            writer: Writer::new(write),
+
+            header: Header::new(),
+            options: *options,
+
+            wrote_header: false,
+            wrote_palette: false,
+            palette_length: 0,
+            wrote_transparency: false,
+            started_image: false,
+            chunk_order: ChunkOrder::new(None),
+
+            chunks_total: 0,
+            chunks_output: 0,
+
+            serial: false,
+
+            // hack, clean this up later
+            pixel_accumulator: Arc::new(PixelChunk::new(Header::new(), 0, 0, 0, &Heap::default())),
+            pixel_index: 0,
+            current_row: 0,
+            partial_row: Buffer::default(),
+
+            pixel_chunks: ChunkMap::new(),
+            filter_chunks: ChunkMap::new(),
+            deflate_chunks: ChunkMap::new(),
+
+            adler32: deflate::adler32_initial(),
+            idat_buffer,
+            idat_crc32: deflate::crc32_initial(),
+            pending_pieces: VecDeque::new(),
+
+            seek: None,
+            idat_start: None,
+            idat_len: 0,
+
+            input_crc32: deflate::crc32_initial(),
+
+            cancel: CancelToken::new(),
+
+            progress_callback: None,
+
+            heap: Heap::default(),
+
+            stats: Stats::default(),
+            started: None,
+
+            deadline: None,
+
+            wake: None,
+
+            active_jobs: Arc::new(ActiveJobs::default()),
+
+            job_queue: Arc::new(JobQueue::default()),
+
+            tx,
+            rx,
+        }
+    }
+
+    /// Creates a new Encoder instance with the given seekable output sink
+    /// and options.
+    ///
+    /// When not in streaming mode, the image data is written to output as
+    /// it's compressed and the IDAT chunk's length is filled in at the end,
+    /// instead of buffering all the compressed data in memory.
+    pub fn new_seekable(write: W, options: &Options<'a>) -> Encoder<'a, W>
+        where W: Seek
+    {
+        let mut encoder = Encoder::new(write, options);
+        encoder.seek = Some(seek_func::<W>);
+        encoder
+    }
+
+    /// Flush output and return the Write sink for further manipulation.
+    /// Consumes the encoder instance.
+    ///
+    /// Returns Error::RowCountMismatch if fewer rows were written than
+    /// the header's height, or Error::PartialRow if bytes of an
+    /// incomplete row were left over from writes through the Write trait.
+    pub fn finish(mut self) -> Result<W> {
+        self.finish_image()?;
+        Ok(self.writer.finish()?)
+    }
+
+    /// Flush output and return the Write sink along with statistics
+    /// on the encode. Consumes the encoder instance.
+    pub fn finish_with_stats(mut self) -> Result<(W, Stats)> {
+        self.finish_image()?;
+        let stats = Stats {
+            bytes_written: self.writer.bytes_written(),
+            chunks: self.chunks_output,
+            wall_time: self.started.map(|started| started.elapsed()).unwrap_or_default(),
+            cpu_time: self.stats.filter_time + self.stats.deflate_time + self.stats.checksum_time,
+            write_time: self.writer.write_time(),
+            ..self.stats
+        };
+        Ok((self.writer.finish()?, stats))
+    }
+
+    /// Finish out the current image as with finish(), and prepare to
+    /// encode another one to the given Write sink with the given options.
+    /// Returns the previous Write sink.
+    ///
+    /// This reuses the encoder's internal channels and buffers, which
+    /// saves some setup overhead when encoding many small images.
+    ///
+    /// If the current image was incomplete, its in-flight work is
+    /// abandoned and an error is returned, but the encoder is still
+    /// reset and ready for the next image.
+    pub fn reset(&mut self, write: W, options: &Options<'a>) -> Result<W> {
+        let result = self.finish_image();
+        if result.is_err() {
+            // Abandoned jobs may still send messages; don't let
+            // them get mixed up with the next image's.
+            let (tx, rx) = options.channel(&Header::new());
+            self.tx = tx;
+            self.rx = rx;
+        }
+
+        let mut writer = std::mem::replace(&mut self.writer, Writer::new(write));
+
+        self.header = Header::new();
+        self.options = *options;
+
+        self.wrote_header = false;
+        self.wrote_palette = false;
+        self.palette_length = 0;
+        self.wrote_transparency = false;
+        self.started_image = false;
+        self.chunk_order = ChunkOrder::new(None);
+
+        self.chunks_total = 0;
+        self.chunks_output = 0;
+
+        self.serial = false;
+
+        self.pixel_accumulator = Arc::new(PixelChunk::new(Header::new(), 0, 0, 0, &Heap::default()));
+        self.pixel_index = 0;
+        self.current_row = 0;
+        self.partial_row.clear();
+
+        self.pixel_chunks.reset();
+        self.filter_chunks.reset();
+        self.deflate_chunks.reset();
+
+        self.adler32 = deflate::adler32_initial();
+        self.idat_buffer.clear();
+        self.idat_crc32 = deflate::crc32_initial();
+        self.pending_pieces.clear();
+        self.idat_start = None;
+        self.idat_len = 0;
+        self.input_crc32 = deflate::crc32_initial();
+
+        // Tokens handed out for the old image shouldn't affect the next.
+        self.cancel = CancelToken::new();
+        self.deadline = None;
+
+        self.stats = Stats::default();
+        self.started = None;
+
+        result?;
+        Ok(writer.finish()?)
+    }
+
+    /// Get a token which can be used to cancel this encode,
+    /// from this or another thread.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Cancel the encode, abandoning any in-flight work.
+    ///
+    /// Output is left truncated after the last complete PNG chunk
+    /// written, except that with new_seekable() a partial IDAT
+    /// chunk may be left. All further calls will return an error.
+    pub fn cancel(&mut self) {
+        self.cancel.cancel();
+    }
+
+    /// Set a function to be called with progress information as each
+    /// data chunk is completed, for updating a progress bar and such.
+    /// It's called on the thread that's writing to the encoder.
+    ///
+    /// The callback is kept when the encoder is reset().
+    pub fn set_progress_callback<F>(&mut self, callback: F)
+        where F: FnMut(Progress) + Send + 'a
+    {
+        self.progress_callback = Some(Box::new(callback));
+    }
+
+    /// Take the large working buffers -- copied rows, filtered and
+    /// compressed chunk data, and compressed output waiting to be
+    /// written -- from the given allocator instead of the global one.
+    /// Must be called before write_header().
+    ///
+    /// The allocator is kept when the encoder is reset().
+    pub fn set_allocator(&mut self, allocator: Arc<dyn Allocator>) -> Result<()> {
+        if self.wrote_header {
+            return Err(Error::InvalidState("Cannot change allocator after writing header."));
+        }
+        self.heap = Heap::new(allocator);
+        self.partial_row = self.heap.buffer();
+        self.idat_buffer = self.heap.buffer();
+        Ok(())
+    }
+
+    fn report_progress(&mut self) {
+        let progress = Progress {
+            rows_consumed: self.current_row,
+            chunks_completed: self.chunks_output,
+            chunks_total: self.chunks_total,
+            bytes_written: self.writer.bytes_written(),
+        };
+        if let Some(ref mut callback) = self.progress_callback {
+            callback(progress);
+        }
+    }
+
+    //
+    // Hand back the reusable parts of the encoder after finishing.
+    // The channel can only be reused if the image completed cleanly,
+    // otherwise abandoned jobs may still send messages on it.
+    //
+    fn take_spare(&mut self, clean: bool) -> Spare {
+        let mut idat_buffer = std::mem::take(&mut self.idat_buffer);
+        idat_buffer.clear();
+        if clean {
+            Spare {
+                idat_buffer,
+                tx: self.tx.clone(),
+                rx: self.rx.clone(),
+            }
+        } else {
+            Spare {
+                idat_buffer,
+                ..Spare::new(&self.options)
+            }
+        }
+    }
+
+    //
+    // When dropped without finish(), complete the file if all of the
+    // image was written, so it isn't left truncated. Otherwise, or if
+    // that fails, cancel any jobs still running so they stop early.
+    // Returns whether the image was finished.
+    //
+    fn finish_on_drop(&mut self) -> bool {
+        let complete = self.wrote_header
+            && self.current_row == self.header.height
+            && self.partial_row.is_empty()
+            && !thread::panicking();
+        if complete && self.finish_image().is_ok() && self.writer.flush().is_ok() {
+            true
+        } else {
+            self.cancel.cancel();
+            false
+        }
+    }
+
+    //
+    // Flush any remaining data and write the end chunk.
+    // On failure the encode is cancelled, so dropping the
+    // encoder afterwards doesn't try again.
+    //
+    fn finish_image(&mut self) -> Result<()> {
+        let result = self.end_image();
+        if result.is_err() {
+            self.cancel.cancel();
+        }
+        result
+    }
+
+    fn end_image(&mut self) -> Result<()> {
+        self.cancel.check()?;
+        self.flush()?;
+        if !self.partial_row.is_empty() {
+            Err(Error::PartialRow {
+                bytes: self.partial_row.len(),
+                stride: self.header.stride(),
+            })
+        } else if !self.is_finished() {
+            Err(Error::RowCountMismatch {
+                rows: self.current_row as usize,
+                height: self.header.height as usize,
+            })
+        } else {
+            self.writer.write_end()?;
+            match self.writer.take_verifier() {
+                Some(verifier) => verifier.finish(self.input_crc32),
+                None => Ok(()),
+            }
+        }
+    }
+
+    //
+    // Check that a buffer of image data holds whole rows, and no more
+    // of them than are left in the image, before any of it is used.
+    //
+    fn check_rows(&self, len: usize) -> Result<()> {
+        let stride = self.header.stride();
+        if len % stride != 0 {
+            return Err(Error::PartialRow {
+                bytes: len % stride,
+                stride,
+            });
+        }
+        let rows = self.current_row as usize + len / stride;
+        if rows > self.header.height as usize {
+            return Err(Error::RowCountMismatch {
+                rows,
+                height: self.header.height as usize,
+            });
+        }
+        Ok(())
+    }
+
+    fn running_jobs(&self) -> usize {
+        self.filter_chunks.running_jobs() + self.deflate_chunks.running_jobs()
+    }
+
+    fn max_threads(&self) -> usize {
+        self.options.max_jobs()
+    }
+
+    //
+    // Run a job on the thread pool, or inline in serial mode.
+    // Jobs with a lower priority value are more urgent.
+    //
+    fn dispatch_func<F>(&self, priority: usize, func: F)
+        where F: Fn(&Sender<ThreadMessage>) + Send + 'static
+    {
+        let tx = self.tx.clone();
+        let wake = self.wake.clone();
+        let active = Arc::clone(&self.active_jobs);
+        active.start();
+
+        // Keep the job's spans under the caller's, on whichever thread.
+        #[cfg(feature="tracing")]
+        let span = ::tracing::Span::current();
+
+        let job = move || {
+            #[cfg(feature="tracing")]
+            let _span = span.enter();
+
+            run_job(&func, &tx);
+            if let Some(wake) = wake {
+                wake();
+            }
+            // Let go of the job's data before it's counted as done,
+            // as it may refer to rows borrowed from the caller.
+            drop(func);
+            active.finish();
+        };
+
+        if self.serial {
+            // The channel has room for every running job's results,
+            // so this won't block.
+            job();
+        } else if self.options.streaming {
+            // Queue the job up, and send a runner to the pool which
+            // takes whichever job is most urgent once it gets a thread.
+            // This gets the earliest chunks out first, instead of in
+            // whatever order the pool picks.
+            self.job_queue.push(priority, Box::new(job));
+            let queue = Arc::clone(&self.job_queue);
+            self.spawn(move || queue.run_next());
+        } else {
+            self.spawn(job);
+        }
+    }
+
+    fn spawn<F>(&self, job: F)
+        where F: FnOnce() + Send + 'static
     {
-        let tx = self.tx.clone();
         match self.options.thread_pool {
-            Some(pool) => {
-                pool.spawn(move || {
-                    func(&tx);
-                });
+            Pool::Global => ::rayon::spawn(job),
+            Pool::Rayon(pool) => pool.spawn(job),
+            Pool::Workers(pool) => pool.spawn(job),
+        }
+    }
+
+    fn start_row(&self, index: usize) -> usize {
+        // Multiply in 64 bits, which can't overflow for 32-bit heights.
+        (index as u64 * u64::from(self.header.height()) / self.chunks_total as u64) as usize
+    }
+
+    fn end_row(&self, index: usize) -> usize {
+        self.start_row(index + 1)
+    }
+
+    fn receive(&mut self, blocking: DispatchMode) -> Option<ThreadMessage> {
+        match (blocking, self.deadline, self.options.deadline_action) {
+            // Wake up to fail on time.
+            #[cfg(not(all(target_arch="wasm32", target_os="unknown")))]
+            (DispatchMode::Blocking, Some(deadline), DeadlineAction::Fail) => {
+                let start = Instant::now();
+                let message = self.rx.recv_deadline(deadline).ok();
+                self.stats.wait_time += start.elapsed();
+                message
+            },
+            (DispatchMode::Blocking, _, _) => {
+                let start = Instant::now();
+                let message = self.rx.recv().ok();
+                self.stats.wait_time += start.elapsed();
+                message
+            },
+            (DispatchMode::NonBlocking, _, _) => self.rx.try_recv().ok(),
+        }
+    }
+
+    fn past_deadline(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        }
+    }
+
+    fn check_deadline(&mut self) -> Result<()> {
+        if self.options.deadline_action == DeadlineAction::Fail && self.past_deadline() {
+            // Stop the jobs, and any further output.
+            self.cancel();
+            return Err(Error::TimedOut);
+        }
+        Ok(())
+    }
+
+    fn compression_level(&self) -> CompressionLevel {
+        if self.options.deadline_action == DeadlineAction::Downgrade && self.past_deadline() {
+            log_debug!("Past deadline; downgrading compression to {:?}", CompressionLevel::Fast);
+            CompressionLevel::Fast
+        } else {
+            self.options.compression_level
+        }
+    }
+
+    fn filter_mode(&self) -> Mode<Filter> {
+        match self.options.filter_mode {
+            Fixed(s) => Fixed(s),
+            Adaptive => match self.header.color_type {
+                ColorType::IndexedColor => Fixed(Filter::None),
+                _                       => Adaptive,
+            }
+        }
+    }
+
+    fn compression_strategy(&self) -> Strategy {
+        match self.options.strategy_mode {
+            Fixed(s) => s,
+            Adaptive => match self.filter_mode() {
+                Fixed(Filter::None) => Strategy::Default,
+                _                   => Strategy::Filtered,
+            },
+        }
+    }
+
+    fn dispatch(&mut self, mode: DispatchMode) -> Result<()> {
+        trace_span!("mtpng::dispatch");
+        self.cancel.check()?;
+        self.check_deadline()?;
+
+        // See if anything interesting happened on the threads.
+        let mut blocking_mode = mode;
+        while self.filter_chunks.in_flight() || self.deflate_chunks.in_flight() {
+            match self.receive(blocking_mode) {
+                Some(ThreadMessage::FilterDone(filter)) => {
+                    self.filter_chunks.land(filter.index, filter);
+                }
+                Some(ThreadMessage::DeflatePiece(piece)) => {
+                    self.land_piece(piece)?;
+                },
+                Some(ThreadMessage::DeflateDone(deflate)) => {
+                    self.deflate_chunks.land(deflate.index, deflate);
+                },
+                Some(ThreadMessage::Error(e)) => {
+                    return Err(e);
+                }
+                None => {
+                    // No more output from the threads.
+                    break;
+                }
+            }
+            // After the first one, keep reading any if they're there
+            // but don't block further.
+            blocking_mode = DispatchMode::NonBlocking;
+        }
+
+        // If we have more deflate work to do, dispatch them!
+        while self.running_jobs() < self.max_threads() {
+            match self.filter_chunks.pop_front() {
+                Some((previous, current)) => {
+                    self.stats.uncompressed_bytes += current.data.len() as u64;
+                    self.stats.filter_time += current.elapsed;
+                    for (total, rows) in self.stats.filter_rows.iter_mut().zip(&current.filter_rows) {
+                        *total += rows;
+                    }
+                    log_debug!("Filtered chunk {}: rows using none/sub/up/average/paeth {:?}",
+                               current.index, current.filter_rows);
+
+                    // Prepare to dispatch the deflate job:
+                    let level = self.compression_level();
+                    let strategy = self.compression_strategy();
+                    let streaming = self.options.streaming;
+                    let cancel = self.cancel.clone();
+                    let heap = self.heap.clone();
+                    let priority = current.index * 2;
+                    self.deflate_chunks.advance();
+                    self.dispatch_func(priority, move |tx| {
+                        let mut deflate = DeflateChunk::new(level,
+                                                            strategy,
+                                                            streaming,
+                                                            previous.clone(),
+                                                            current.clone(),
+                                                            heap.clone());
+                        tx.send(match deflate.run(tx, &cancel) {
+                            Ok(()) => ThreadMessage::DeflateDone(Arc::new(deflate)),
+                            Err(e) => ThreadMessage::Error(e),
+                        }).ok();
+                    });
+                },
+                None => {
+                    break;
+                }
+            }
+        }
+
+        // If we have more filter work to do, dispatch them!
+        while self.running_jobs() < self.max_threads() {
+            match self.pixel_chunks.pop_front() {
+                Some((previous, current)) => {
+                    // Prepare to dispatch the filter job:
+                    self.filter_chunks.advance();
+                    let filter_mode = self.filter_mode();
+                    let cancel = self.cancel.clone();
+                    let heap = self.heap.clone();
+                    let priority = current.index * 2 + 1;
+                    self.dispatch_func(priority, move |tx| {
+                        let mut filter = FilterChunk::new(&current, filter_mode, &heap);
+                        tx.send(match filter.run(previous.as_deref(), &current, &cancel) {
+                            Ok(()) => ThreadMessage::FilterDone(Arc::new(filter)),
+                            Err(e) => ThreadMessage::Error(e),
+                        }).ok();
+                    });
+                },
+                None => {
+                    break;
+                }
+            }
+        }
+
+        // Don't write anything more once cancelled.
+        self.cancel.check()?;
+        self.check_deadline()?;
+
+        // If we have output to run, write it!
+        while let Some((_previous, current)) = self.deflate_chunks.pop_front() {
+            if self.chunks_output >= self.chunks_total {
+                panic!("Got extra output after end of file; should not happen.");
+            }
+
+            self.stats.deflate_time += current.deflate_time;
+            self.stats.checksum_time += current.checksum_time;
+
+            // Combine the checksums!
+            self.adler32 = deflate::adler32_combine(self.adler32,
+                                                    current.adler32,
+                                                    current.input.data.len());
+
+            // In streaming mode the compressed data was already
+            // written out in pieces.
+            if !self.options.streaming {
+                log_debug!("Compressed chunk {}: {} bytes to {}",
+                           current.index, current.input.data.len(), current.data.len());
+                self.write_idat(&current.data, current.crc32)?;
+            }
+
+            if current.is_end {
+                // A single chunk has a zlib header and checksum already.
+                let mut trailer = Vec::<u8>::new();
+                if !current.is_start {
+                    write_be32(&mut trailer, self.adler32)?;
+                }
+                self.finish_idat(&trailer)?;
+            }
+
+            self.chunks_output += 1;
+
+            // Any pieces of the next chunk that came in early can go out now.
+            self.pending_pieces.pop_front();
+            if let Some(pieces) = self.pending_pieces.front_mut() {
+                for piece in std::mem::take(pieces) {
+                    self.write_idat(&piece.data, piece.crc32)?;
+                }
+            }
+
+            self.report_progress();
+        }
+
+        Ok(())
+    }
+
+    //
+    // Send compressed data on its way to the output, as its own IDAT chunk
+    // in streaming mode or into the buffer otherwise.
+    //
+    fn write_idat(&mut self, data: &[u8], crc32: u32) -> Result<()> {
+        trace_span!("mtpng::write", bytes = data.len());
+        self.stats.compressed_bytes += data.len() as u64;
+        match self.options.idat_size {
+            Some(size) => {
+                // Re-block into fixed-size chunks. The CRCs have to be
+                // recalculated here as the boundaries don't line up.
+                self.idat_buffer.write_all(data)?;
+                let mut start = 0;
+                while self.idat_buffer.len() - start >= size {
+                    self.writer.write_chunk(b"IDAT", &self.idat_buffer[start .. start + size])?;
+                    start += size;
+                }
+                self.idat_buffer.drain_front(start);
+                Ok(())
+            },
+            None if self.options.streaming => {
+                Ok(self.writer.write_chunk_with_crc(b"IDAT", data, crc32)?)
+            },
+            None if self.in_place() => {
+                self.write_idat_in_place(data, crc32)
+            },
+            None => {
+                self.idat_buffer.write_all(data)?;
+                self.idat_crc32 = deflate::crc32_combine(self.idat_crc32,
+                                                         crc32,
+                                                         data.len());
+                Ok(())
+            },
+        }
+    }
+
+    //
+    // Write the stream's trailing checksum, if any, and
+    // anything left in the IDAT buffer.
+    //
+    fn finish_idat(&mut self, trailer: &[u8]) -> Result<()> {
+        trace_span!("mtpng::write", bytes = trailer.len());
+        self.stats.compressed_bytes += trailer.len() as u64;
+        match self.options.idat_size {
+            Some(_) => {
+                self.idat_buffer.write_all(trailer)?;
+                if self.idat_buffer.is_empty() {
+                    Ok(())
+                } else {
+                    Ok(self.writer.write_chunk(b"IDAT", &self.idat_buffer)?)
+                }
+            },
+            None if self.options.streaming => {
+                Ok(self.writer.write_chunk(b"IDAT", trailer)?)
+            },
+            None if self.in_place() => {
+                self.finish_idat_in_place(trailer)
             },
             None => {
-                ::rayon::spawn(move || {
-                    func(&tx);
-                });
+                self.idat_buffer.write_all(trailer)?;
+                self.idat_crc32 = deflate::crc32(self.idat_crc32, trailer);
+                Ok(self.writer.write_chunk_with_crc(b"IDAT", &self.idat_buffer, self.idat_crc32)?)
+            },
+        }
+    }
+
+    //
+    // The verifier can't see the IDAT length filled in after the
+    // fact, so verified output is buffered instead.
+    //
+    fn in_place(&self) -> bool {
+        self.seek.is_some() && self.options.verify == Verify::Off
+    }
+
+    //
+    // On seekable output, write compressed data directly into a single
+    // IDAT chunk whose length will be filled in at the end.
+    //
+    fn write_idat_in_place(&mut self, data: &[u8], crc32: u32) -> Result<()> {
+        if self.idat_start.is_none() {
+            let seek = self.seek.unwrap();
+            self.idat_start = Some(seek(self.writer.get_mut(), SeekFrom::Current(0))?);
+            log_debug!("Writing a single IDAT chunk in place on seekable output");
+            self.writer.begin_chunk(b"IDAT", 0)?;
+        }
+        if self.idat_len + data.len() > i32::MAX as usize {
+            return Err(Error::InvalidChunk("Compressed data too large for a single IDAT chunk"));
+        }
+        self.writer.write_chunk_data(data)?;
+        self.idat_crc32 = deflate::crc32_combine(self.idat_crc32, crc32, data.len());
+        self.idat_len += data.len();
+        Ok(())
+    }
+
+    fn finish_idat_in_place(&mut self, trailer: &[u8]) -> Result<()> {
+        self.write_idat_in_place(trailer, deflate::crc32(deflate::crc32_initial(), trailer))?;
+        self.writer.end_chunk(Writer::<W>::chunk_crc(b"IDAT", self.idat_crc32, self.idat_len))?;
+
+        // Go back and fill in the length.
+        let seek = self.seek.unwrap();
+        let output = self.writer.get_mut();
+        let end = seek(output, SeekFrom::Current(0))?;
+        seek(output, SeekFrom::Start(self.idat_start.unwrap()))?;
+        write_be32(output, self.idat_len as u32)?;
+        seek(output, SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    //
+    // In streaming mode, write out a piece of compressed data
+    // if it belongs to the chunk that's next in line, or hold on
+    // to it until that chunk's turn comes.
+    //
+    fn land_piece(&mut self, piece: DeflatePiece) -> Result<()> {
+        if piece.index < self.chunks_output {
+            panic!("Got a piece of an already-finished chunk; should not happen.");
+        }
+        let offset = piece.index - self.chunks_output;
+        if offset == 0 {
+            self.write_idat(&piece.data, piece.crc32)
+        } else {
+            while offset >= self.pending_pieces.len() {
+                self.pending_pieces.push_back(Vec::new());
+            }
+            self.pending_pieces[offset].push(piece);
+            Ok(())
+        }
+    }
+
+    /// Write the PNG signature and header chunk.
+    /// Must be done before anything else is output.
+    ///
+    /// Subsequent image data must match the given header data.
+    ///
+    /// Returns Error::InvalidHeader if the image data is too large to
+    /// address on this platform, or Error::ImageTooLarge if it has more
+    /// pixels than allowed by Options::set_max_pixels().
+    pub fn write_header(&mut self, header: &Header) -> Result<()> {
+        self.cancel.check()?;
+        if self.wrote_header {
+            return Err(Error::InvalidState("Cannot write header a second time."));
+        }
+
+        header.check_size()?;
+        if let Some(max) = self.options.max_pixels {
+            if header.pixels() > max {
+                return Err(Error::ImageTooLarge { pixels: header.pixels(), max });
+            }
+        }
+
+        self.header = *header;
+
+        self.chunks_total = self.options.chunks(&self.header);
+        self.serial = self.options.serial(&self.header);
+        self.deadline = self.options.deadline.map(|limit| Instant::now() + limit);
+        self.started = Some(Instant::now());
+
+        log_debug!("Encoding {}x{} {:?} image at depth {}",
+                   self.header.width(), self.header.height(),
+                   self.header.color_type(), self.header.depth());
+        log_debug!("Using {} chunks of up to {} rows, {}",
+                   self.chunks_total, self.options.chunk_rows(&self.header),
+                   if self.serial { "on the calling thread" } else { "on the thread pool" });
+        log_debug!("Using filter mode {:?}, compression strategy {:?}, level {:?}",
+                   self.filter_mode(), self.compression_strategy(),
+                   self.options.compression_level);
+
+        // Nothing's in flight yet, so it's safe to swap the channel
+        // if a different size is needed.
+        let capacity = self.options.channel_capacity(&self.header);
+        if self.rx.capacity() != Some(capacity) {
+            log_debug!("Resizing result channel to {} messages", capacity);
+            let (tx, rx) = self.options.channel(&self.header);
+            self.tx = tx;
+            self.rx = rx;
+        }
+
+        self.pixel_chunks.advance();
+        self.pixel_accumulator = Arc::new(PixelChunk::new(self.header,
+                                                          0, // index
+                                                          self.start_row(0),
+                                                          self.end_row(0),
+                                                          &self.heap));
+
+        self.wrote_header = true;
+        self.chunk_order = ChunkOrder::new(Some(self.header.color_type));
+        self.chunk_order.add(b"IHDR");
+
+        if self.options.verify != Verify::Off {
+            self.writer.set_verifier(Verifier::new(self.header, self.options.verify == Verify::Pixels));
+        }
+        self.writer.write_signature()?;
+        Ok(self.writer.write_header(self.header)?)
+    }
+
+    /// Write an indexed-color palette as a PLTE chunk.
+    ///
+    /// Data must be formatted per the spec matching the color mode:
+    /// https://www.w3.org/TR/2003/REC-PNG-20031110/#11PLTE
+    ///
+    /// Indexed-color palettes may have at most 2^depth entries. Note this
+    /// chunk is allowed on truecolor images, though sPLT is preferred,
+    /// but not on greyscale images.
+    pub fn write_palette(&mut self, palette: &[u8]) -> Result<()> {
+        self.cancel.check()?;
+        if !self.wrote_header {
+            return Err(Error::InvalidState("Cannot write palette before header."));
+        }
+        if self.wrote_palette {
+            return Err(Error::InvalidState("Cannot write palette a second time."));
+        }
+        if self.wrote_transparency {
+            return Err(Error::InvalidState("Cannot write palette after transparency."));
+        }
+        if self.started_image {
+            return Err(Error::InvalidState("Cannot write palette after image data."));
+        }
+        if palette.len() < 3 {
+            return Err(Error::InvalidPalette("Palette must have at least one entry."));
+        }
+        if palette.len() % 3 != 0 {
+            return Err(Error::InvalidPalette("Palette must have an integral number of entries."));
+        }
+        let max = match self.header.color_type {
+            ColorType::IndexedColor => 1 << self.header.depth,
+            ColorType::Truecolor | ColorType::TruecolorAlpha => 256,
+            ColorType::Greyscale | ColorType::GreyscaleAlpha => {
+                return Err(Error::InvalidPalette("Palette is not allowed for greyscale images."));
+            },
+        };
+        if palette.len() / 3 > max {
+            return Err(Error::PaletteTooLarge { entries: palette.len() / 3, max });
+        }
+
+        self.wrote_palette = true;
+        self.palette_length = palette.len() / 3;
+        self.chunk_order.add(b"PLTE");
+        Ok(self.writer.write_chunk(b"PLTE", palette)?)
+    }
+
+    /// Write a transparency info chunk.
+    ///
+    /// For indexed color, contains a single alpha value byte per palette
+    /// entry, up to but not exceeding the number of palette entries.
+    ///
+    /// Note this chunk is allowed on greyscale and truecolor images,
+    /// and there references a single color in 16-bit notation, whose
+    /// samples must fit in the image's bit depth. It's not allowed on
+    /// color types with an alpha channel.
+    ///
+    /// https://www.w3.org/TR/PNG/#11tRNS
+    pub fn write_transparency(&mut self, data: &[u8]) -> Result<()> {
+        self.cancel.check()?;
+        if !self.wrote_header {
+            return Err(Error::InvalidState("Cannot write transparency before header."));
+        }
+        if self.wrote_transparency {
+            return Err(Error::InvalidState("Cannot write transparency a second time."));
+        }
+        if self.started_image {
+            return Err(Error::InvalidState("Cannot write transparency after image data."));
+        }
+        match self.header.color_type {
+            ColorType::Greyscale => {
+                if data.len() != 2 {
+                    return Err(Error::InvalidTransparency("Greyscale transparency data must be exactly 2 bytes."));
+                }
+                self.check_transparent_color(data)?;
+            },
+            ColorType::Truecolor => {
+                if data.len() != 6 {
+                    return Err(Error::InvalidTransparency("Truecolor transparency data must be exactly 6 bytes."));
+                }
+                self.check_transparent_color(data)?;
+            },
+            ColorType::IndexedColor => {
+                if !self.wrote_palette {
+                    return Err(Error::InvalidState("Cannot write transparency before palette."));
+                }
+                if data.is_empty() {
+                    return Err(Error::InvalidTransparency("Transparency data too short."));
+                }
+                if data.len() > self.palette_length {
+                    return Err(Error::InvalidTransparency("Transparency data cannot contain more entries than palette."));
+                }
+            },
+            ColorType::GreyscaleAlpha | ColorType::TruecolorAlpha => {
+                return Err(Error::InvalidTransparency("Transparency chunk is invalid for color types with alpha"));
+            }
+        }
+        self.wrote_transparency = true;
+        self.chunk_order.add(b"tRNS");
+        Ok(self.writer.write_chunk(b"tRNS", data)?)
+    }
+
+    //
+    // Greyscale and truecolor transparency gives a color as 16-bit
+    // samples, which must fit in the image's bit depth.
+    //
+    fn check_transparent_color(&self, data: &[u8]) -> Result<()> {
+        let max = (1u32 << self.header.depth) - 1;
+        for sample in data.chunks(2) {
+            if u32::from(sample[0]) << 8 | u32::from(sample[1]) > max {
+                return Err(Error::InvalidTransparency("Transparency color is out of range for the bit depth."));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a text chunk with the given keyword and text.
+    ///
+    /// Text that can be represented in Latin-1 is written as a tEXt
+    /// chunk, which all decoders can read; other text is written as
+    /// UTF-8 in an iTXt chunk. Keywords must be 1-79 printable Latin-1
+    /// characters without leading, trailing, or consecutive spaces,
+    /// and text can't contain null characters.
+    ///
+    /// Text chunks may be written after the header, either before any
+    /// image data or after all of it.
+    ///
+    /// https://www.w3.org/TR/PNG/#11keywords
+    pub fn write_text(&mut self, keyword: &str, text: &str) -> Result<()> {
+        self.write_text_chunk(keyword, text, false)
+    }
+
+    /// Write a compressed text chunk with the given keyword and text,
+    /// as a zTXt chunk for Latin-1 text or a compressed iTXt chunk
+    /// otherwise. Requirements are the same as for write_text().
+    pub fn write_compressed_text(&mut self, keyword: &str, text: &str) -> Result<()> {
+        self.write_text_chunk(keyword, text, true)
+    }
+
+    /// Write an ICC color profile, compressed in an iCCP chunk. The
+    /// name follows the same rules as text keywords, and the profile
+    /// must be a whole ICC profile, such as read from an .icc file.
+    ///
+    /// The profile must be written after the header and before the
+    /// palette and image data, and replaces the sRGB chunk, so the two
+    /// shouldn't both be written.
+    ///
+    /// https://www.w3.org/TR/PNG/#11iCCP
+    pub fn write_icc_profile(&mut self, name: &str, profile: &[u8]) -> Result<()> {
+        self.cancel.check()?;
+        if !self.wrote_header {
+            return Err(Error::InvalidState("Cannot write ICC profile before header."));
+        }
+        if self.wrote_palette || self.started_image {
+            return Err(Error::InvalidState("Cannot write ICC profile after palette or image data."));
+        }
+        let data = text::icc_chunk(name, profile)?;
+        self.write_ancillary_chunk(b"iCCP", &data)
+    }
+
+    fn write_text_chunk(&mut self, keyword: &str, text: &str, compressed: bool) -> Result<()> {
+        self.cancel.check()?;
+        if !self.wrote_header {
+            return Err(Error::InvalidState("Cannot write text before header."));
+        }
+        if self.started_image && !self.is_finished() {
+            return Err(Error::InvalidState("Cannot write text in the middle of image data."));
+        }
+        let (tag, data) = text::text_chunk(keyword, text, compressed)?;
+        self.write_ancillary_chunk(tag, &data)
+    }
+
+    //
+    // Write a custom ancillary chunk to the output stream.
+    // The tag must be a 4-byte slice of ASCII letters. The data
+    // should be provided in the appropriate format for the tag.
+    //
+    // Critical chunks, with an uppercase first letter, are refused:
+    // the standard ones are written by the encoder itself, and any
+    // others would make the file unreadable by conforming decoders.
+    // The third letter must be uppercase, as lowercase is reserved.
+    //
+    // https://www.w3.org/TR/PNG/#5Chunk-naming-conventions
+    //
+    pub fn write_chunk(&mut self, tag: &[u8], data: &[u8]) -> Result<()> {
+        self.cancel.check()?;
+        if tag.first().is_some_and(u8::is_ascii_uppercase) {
+            return Err(Error::InvalidChunk("Cannot write critical chunks as custom chunks."));
+        }
+        if tag.get(2).is_some_and(u8::is_ascii_lowercase) {
+            return Err(Error::InvalidChunk("Chunk tags must have an uppercase third letter."));
+        }
+        self.write_ancillary_chunk(tag, data)
+    }
+
+    //
+    // Text and custom chunks are checked against the ordering rules
+    // and size limits in strict mode, before being written.
+    //
+    fn write_ancillary_chunk(&mut self, tag: &[u8], data: &[u8]) -> Result<()> {
+        let tag4 = <[u8; 4]>::try_from(tag).ok();
+        if self.options.validation == Validation::Strict {
+            if !self.wrote_header {
+                return Err(Error::InvalidChunk("Cannot write chunks before header in strict mode."));
+            }
+            if self.started_image {
+                return Err(Error::InvalidChunk("Cannot write chunks after image data has started in strict mode."));
+            }
+            if let Some(ref tag) = tag4 {
+                if let Some(&(_, message)) = self.chunk_order.check(tag, data.len()).first() {
+                    return Err(Error::InvalidChunk(message));
+                }
+            }
+        }
+        self.writer.write_chunk(tag, data)?;
+        if let Some(ref tag) = tag4 {
+            self.chunk_order.add(tag);
+        }
+        Ok(())
+    }
+
+    fn check_image_state(&mut self) -> Result<()> {
+        self.cancel.check()?;
+        if self.pixel_index >= self.chunks_total {
+            return Err(Error::InvalidState("invalid internal state"));
+        }
+        if !self.wrote_header {
+            return Err(Error::InvalidState("Cannot write image data before header."));
+        }
+        if let ColorType::IndexedColor = self.header.color_type {
+            if !self.wrote_palette {
+                return Err(Error::InvalidState("Cannot write indexed-color image data before palette."));
+            }
+        }
+        if !self.started_image {
+            self.started_image = true;
+        }
+        Ok(())
+    }
+
+    //
+    // Copy a row's pixel data into buffers for async compression.
+    // Returns immediately after copying.
+    //
+    fn process_row(&mut self, row: &[u8]) -> Result<RowStatus>
+    {
+        self.check_image_state()?;
+
+        Arc::get_mut(&mut self.pixel_accumulator).unwrap().read_row(row)?;
+        if self.options.verify == Verify::Pixels {
+            self.input_crc32 = deflate::crc32(self.input_crc32, row);
+        }
+
+        self.rows_added(1)
+    }
+
+    //
+    // Hand a whole chunk's worth of rows from a shared buffer
+    // off for async compression, without copying.
+    //
+    fn process_shared_chunk(&mut self, source: SharedRows, offset: usize) -> Result<RowStatus>
+    {
+        self.check_image_state()?;
+
+        let rows = self.end_row(self.pixel_index) - self.start_row(self.pixel_index);
+        if self.options.verify == Verify::Pixels {
+            let data = &(*source).as_ref()[offset .. offset + rows * self.header.stride()];
+            self.input_crc32 = deflate::crc32(self.input_crc32, data);
+        }
+        Arc::get_mut(&mut self.pixel_accumulator).unwrap().read_shared(source, offset);
+
+        self.rows_added(rows)
+    }
+
+    //
+    // Check if we're holding as many chunks of input
+    // as the options allow, and need to wait for output.
+    //
+    fn too_many_inflight(&self) -> bool {
+        match self.options.max_inflight_chunks {
+            Some(max) => self.pixel_index - self.chunks_output >= max,
+            None => false,
+        }
+    }
+
+    fn rows_added(&mut self, rows: usize) -> Result<RowStatus>
+    {
+        if self.pixel_accumulator.is_full() {
+            // Move the item off to the completed stack...
+            self.pixel_chunks.land(self.pixel_index, self.pixel_accumulator.clone());
+
+            // Make a nice new buffer to accumulate data into.
+            self.pixel_index += 1;
+            if self.pixel_index < self.chunks_total {
+                self.pixel_chunks.advance();
+                self.pixel_accumulator = Arc::new(PixelChunk::new(self.header,
+                                                                  self.pixel_index,
+                                                                  self.start_row(self.pixel_index),
+                                                                  self.end_row(self.pixel_index),
+                                                                  &self.heap));
+            }
+
+            // Dispatch any available async tasks and output.
+            while self.running_jobs() >= self.max_threads() || self.too_many_inflight() {
+                self.dispatch(DispatchMode::Blocking)?;
+            }
+            self.dispatch(DispatchMode::NonBlocking)?;
+        }
+
+        self.current_row += rows as u32;
+        if self.current_row == self.header.height {
+            Ok(RowStatus::Done)
+        } else {
+            Ok(RowStatus::Continue)
+        }
+    }
+
+    /// Encode and compress the given image data and write to output.
+    /// Input data must be packed in the correct format for the given
+    /// color type and depth, with no padding at the end of rows.
+    ///
+    /// An integral number of rows must be provided at once, and no more
+    /// than remain in the image; otherwise Error::PartialRow or
+    /// Error::RowCountMismatch is returned before any of the data is used.
+    ///
+    /// If not all of the image rows are provided, multiple calls are
+    /// required to finish out the data.
+    pub fn write_image_rows(&mut self, buf: &[u8]) -> Result<()> {
+        self.check_rows(buf.len())?;
+        for row in buf.chunks(self.header.stride()) {
+            self.process_row(row)?;
+        }
+        Ok(())
+    }
+
+    /// Encode and compress the given image data and write to output,
+    /// taking ownership of the buffer so worker threads can read from
+    /// it directly instead of copying it into internal buffers.
+    ///
+    /// Any owned, thread-safe byte container may be used, such as
+    /// `Vec<u8>` or `Arc<[u8]>`; pass an `Arc` clone to keep using the
+    /// data afterwards. The buffer is released once all of its rows
+    /// have been compressed.
+    ///
+    /// With the `bytes` feature enabled, `bytes::Bytes` buffers received
+    /// from network stacks work too, with the reference count keeping
+    /// the data alive across the worker threads.
+    ///
+    /// This saves a copy of the whole image when writing it in a single
+    /// call. Rows that straddle the boundaries of data chunks across
+    /// multiple calls are still copied.
+    ///
+    /// Format requirements are the same as for write_image_rows().
+    pub fn write_image_rows_shared<S>(&mut self, buf: S) -> Result<()>
+        where S: AsRef<[u8]> + Send + Sync + 'static
+    {
+        self.write_shared_rows(Arc::new(buf))
+    }
+
+    /// Load one or more rows of input data borrowed from the caller.
+    ///
+    /// Like write_image_rows_shared(), whole chunks are read in place by
+    /// the worker threads without copying. To allow this, it waits for
+    /// all the work on them to finish and be output before returning,
+    /// which limits parallelism to within each call; it's best used to
+    /// pass the whole image, or large spans of it, at once.
+    ///
+    /// If an error occurs, the encoder can't be used further.
+    pub fn write_image_rows_borrowed(&mut self, buf: &[u8]) -> Result<()> {
+        let source = Arc::new(BorrowedRows {
+            ptr: buf.as_ptr(),
+            len: buf.len(),
+        });
+
+        let mut scope = JobScope {
+            active: Arc::clone(&self.active_jobs),
+            cancel: self.cancel.clone(),
+            armed: true,
+        };
+
+        self.write_shared_rows(Arc::clone(&source) as SharedRows)?;
+        self.flush()?;
+
+        // The next chunk's filter job will need the last row,
+        // so copy it out of the borrowed buffer. The last chunk
+        // stays in the accumulator once the image is complete. If a
+        // copy fails, the borrowed chunk is dropped all the same.
+        let prev = self.pixel_chunks.prev.take()
+                       .map(|prev| PixelChunk::last_row_only(prev, &self.heap))
+                       .transpose();
+        let accumulator = std::mem::replace(&mut self.pixel_accumulator,
+                                            Arc::new(PixelChunk::new(Header::new(), 0, 0, 0, &Heap::default())));
+        let accumulator = PixelChunk::last_row_only(accumulator, &self.heap);
+        self.pixel_chunks.prev = prev?;
+        self.pixel_accumulator = accumulator?;
+
+        scope.armed = false;
+        drop(scope);
+        // Anything still holding the rows would read the caller's
+        // buffer after we return, so this can't be left to debug builds.
+        assert_eq!(Arc::strong_count(&source), 1, "borrowed rows still referenced");
+        Ok(())
+    }
+
+    fn write_shared_rows(&mut self, source: SharedRows) -> Result<()> {
+        let stride = self.header.stride();
+        let len = (*source).as_ref().len();
+        self.check_rows(len)?;
+
+        let mut offset = 0;
+        while offset < len {
+            self.check_image_state()?;
+            let chunk_len = self.pixel_accumulator.len();
+            if self.pixel_accumulator.is_empty() && len - offset >= chunk_len {
+                self.process_shared_chunk(Arc::clone(&source), offset)?;
+                offset += chunk_len;
+            } else {
+                self.process_row(&(*source).as_ref()[offset .. offset + stride])?;
+                offset += stride;
+            }
+        }
+        Ok(())
+    }
+
+    /// Return completion progress as a fraction of 1.0
+    ///
+    /// Currently progress is measured in chunks, so small files may
+    /// not report values between 0.0 and 1.0.
+    pub fn progress(&self) -> f64 {
+        self.chunks_output as f64 / self.chunks_total as f64
+    }
+
+    /// Return finished-ness state.
+    /// Is it finished? Yeah or no.
+    pub fn is_finished(&self) -> bool {
+        self.chunks_output == self.chunks_total
+    }
+
+    /// Flush all currently in-progress data to output
+    /// Warning: this may block.
+    pub fn flush(&mut self) -> Result<()> {
+        while self.chunks_output < self.pixel_index {
+            // Dispatch any available async tasks and output.
+            self.dispatch(DispatchMode::Blocking)?;
+        }
+        Ok(())
+    }
+}
+
+/// Image data can also be written through the Write trait, for use at
+/// the end of generic byte pipelines. Data may be split anywhere; bytes
+/// of incomplete rows are buffered until the rest of the row arrives.
+///
+/// Writes have the same requirements as write_image_rows(), and the
+/// image must still be completed with finish().
+impl<'a, W: Write> Write for Encoder<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_image_state()?;
+        let stride = self.header.stride();
+        let rows = self.current_row as usize + (self.partial_row.len() + buf.len()).div_ceil(stride);
+        if rows > self.header.height as usize {
+            return Err(Error::RowCountMismatch {
+                rows,
+                height: self.header.height as usize,
+            }.into());
+        }
+        let mut input = buf;
+
+        if !self.partial_row.is_empty() {
+            let needed = cmp::min(stride - self.partial_row.len(), input.len());
+            self.partial_row.extend_from_slice(&input[.. needed])?;
+            input = &input[needed ..];
+            if self.partial_row.len() < stride {
+                return Ok(buf.len());
+            }
+            let row = std::mem::take(&mut self.partial_row);
+            self.write_image_rows(&row)?;
+            self.partial_row = row;
+            self.partial_row.clear();
+        }
+
+        let whole = input.len() - input.len() % stride;
+        self.write_image_rows(&input[.. whole])?;
+        self.partial_row.extend_from_slice(&input[whole ..])?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(Encoder::flush(self)?)
+    }
+}
+
+/// If the encoder is dropped without calling finish(), the file is
+/// still completed if all of the image data was written; errors doing
+/// so can't be reported, so call finish() to check for them. If the
+/// image was incomplete, any jobs still running for it are cancelled.
+impl<'a, W: Write> Drop for Encoder<'a, W> {
+    fn drop(&mut self) {
+        if !self.writer.is_finished() {
+            self.finish_on_drop();
+        }
+    }
+}
+
+/// Encode a complete image in one call, returning the PNG file data.
+///
+/// Pixel data must be packed rows in the format described by the
+/// header, for the whole image. For indexed-color images, use
+/// encode_indexed_to_vec() instead.
+///
+/// ```
+/// # fn main() -> mtpng::Result<()> {
+/// use mtpng::{ColorType, Header, encode_to_vec};
+/// use mtpng::encoder::Options;
+///
+/// let mut header = Header::new();
+/// header.set_size(2, 2)?;
+/// header.set_color(ColorType::TruecolorAlpha, 8)?;
+///
+/// let pixels = [255u8; 2 * 2 * 4];
+/// let png = encode_to_vec(&header, &pixels, &Options::new())?;
+/// # assert!(png.starts_with(b"\x89PNG"));
+/// # Ok(())
+/// # }
+/// ```
+pub fn encode_to_vec(header: &Header, pixels: &[u8], options: &Options) -> Result<Vec<u8>> {
+    if let ColorType::IndexedColor = header.color_type {
+        return Err(Error::InvalidState("Indexed-color images need a palette; use encode_indexed_to_vec()"));
+    }
+    encode_image(header, None, None, pixels, options)
+}
+
+/// Encode a complete indexed-color image in one call, with the given
+/// palette and optional transparency data, returning the PNG file data.
+///
+/// Palette and transparency data are formatted as for
+/// Encoder::write_palette() and Encoder::write_transparency().
+pub fn encode_indexed_to_vec(header: &Header,
+                             palette: &[u8],
+                             transparency: Option<&[u8]>,
+                             pixels: &[u8],
+                             options: &Options) -> Result<Vec<u8>> {
+    encode_image(header, Some(palette), transparency, pixels, options)
+}
+
+fn encode_image(header: &Header,
+                palette: Option<&[u8]>,
+                transparency: Option<&[u8]>,
+                pixels: &[u8],
+                options: &Options) -> Result<Vec<u8>> {
+    header.check_size()?;
+    if pixels.len() != header.stride() * header.height as usize {
+        return Err(Error::RowDataMismatch("Pixel data size doesn't match the header"));
+    }
+
+    let mut encoder = Encoder::new(Vec::new(), options);
+    encoder.write_header(header)?;
+    if let Some(palette) = palette {
+        encoder.write_palette(palette)?;
+    }
+    if let Some(transparency) = transparency {
+        encoder.write_transparency(transparency)?;
+    }
+    encoder.write_image_rows_borrowed(pixels)?;
+    encoder.finish()
+}
+
+//
+// Hooks for driving the encoder without blocking, for the async wrappers.
+//
+#[cfg(any(feature="tokio", feature="futures"))]
+impl<'a, W: Write> Encoder<'a, W> {
+    pub(crate) fn set_wake(&mut self, wake: WakeFunc) {
+        self.wake = Some(wake);
+    }
+
+    pub(crate) fn stride(&self) -> usize {
+        self.header.stride()
+    }
+
+    // Would writing one more row have to wait on the jobs?
+    pub(crate) fn row_would_block(&self) -> bool {
+        let chunk_done = self.current_row as usize + 1 >= self.pixel_accumulator.end_row;
+        let too_many_inflight = match self.options.max_inflight_chunks {
+            Some(max) => self.pixel_index + 1 - self.chunks_output >= max,
+            None => false,
+        };
+        chunk_done && (self.running_jobs() >= self.max_threads() || too_many_inflight)
+    }
+
+    // Take in any finished work and write output, without blocking.
+    pub(crate) fn poll(&mut self) -> Result<()> {
+        self.dispatch(DispatchMode::NonBlocking)
+    }
+
+    pub(crate) fn is_flushed(&self) -> bool {
+        self.chunks_output >= self.pixel_index
+    }
+}
+
+#[cfg(any(feature="tokio", feature="futures", feature="capi"))]
+impl<'a, W: Write> Encoder<'a, W> {
+    pub(crate) fn output_mut(&mut self) -> &mut W {
+        self.writer.get_mut()
+    }
+}
+
+//
+// Reusable buffers and channel from a finished encoder.
+//
+struct Spare {
+    idat_buffer: Buffer,
+    tx: Sender<ThreadMessage>,
+    rx: Receiver<ThreadMessage>,
+}
+
+impl Spare {
+    fn new(options: &Options) -> Spare {
+        // Replaced with one sized for the image in write_header().
+        let (tx, rx) = options.channel(&Header::new());
+        Spare {
+            idat_buffer: Buffer::default(),
+            tx,
+            rx,
+        }
+    }
+}
+
+//
+// Don't hang on to more than this many sets of spare buffers.
+//
+const MAX_SPARES: usize = 64;
+
+/// A thread pool plus a stash of buffers recycled from finished encoders,
+/// for services encoding many small images where per-encode setup would
+/// otherwise dominate.
+///
+/// The pool is Sync, so it can be shared between request handlers; each
+/// gets a lightweight encoder from EncoderPool::encoder().
+pub struct EncoderPool {
+    thread_pool: ThreadPool,
+    spares: Mutex<Vec<Spare>>,
+}
+
+impl EncoderPool {
+    /// Create a new pool with its own thread pool of the given size.
+    /// Pass 0 to use Rayon's default, the number of CPUs.
+    pub fn new(threads: usize) -> Result<EncoderPool> {
+        let thread_pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(EncoderPool {
+            thread_pool,
+            spares: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Get an encoder writing to the given Write output sink with the
+    /// given options. The thread pool in the options is replaced with
+    /// the pool's own.
+    ///
+    /// Buffers are returned to the pool when the encoder is finished
+    /// or dropped.
+    pub fn encoder<'p, W: Write>(&'p self, write: W, options: &Options<'p>) -> PooledEncoder<'p, W> {
+        let mut options = *options;
+        options.thread_pool = Pool::Rayon(&self.thread_pool);
+
+        let spare = self.spares.lock().ok().and_then(|mut spares| spares.pop());
+        let spare = spare.unwrap_or_else(|| Spare::new(&options));
+
+        PooledEncoder {
+            pool: self,
+            encoder: Some(Encoder::with_spare(write, &options, spare)),
+        }
+    }
+
+    fn put(&self, spare: Spare) {
+        if let Ok(mut spares) = self.spares.lock() {
+            if spares.len() < MAX_SPARES {
+                spares.push(spare);
             }
         }
     }
+}
+
+/// An Encoder borrowed from an EncoderPool, which returns its buffers
+/// to the pool when finished. Dereferences to the Encoder.
+pub struct PooledEncoder<'p, W: Write> {
+    pool: &'p EncoderPool,
+    encoder: Option<Encoder<'p, W>>,
+}
+
+impl<'p, W: Write> PooledEncoder<'p, W> {
+    /// Flush output and return the Write sink for further manipulation.
+    /// Consumes the encoder instance.
+    pub fn finish(mut self) -> Result<W> {
+        let mut encoder = self.encoder.take().unwrap();
+        let result = encoder.finish_image();
+        self.pool.put(encoder.take_spare(result.is_ok()));
+        result?;
+        Ok(encoder.writer.finish()?)
+    }
+}
+
+impl<'p, W: Write> Deref for PooledEncoder<'p, W> {
+    type Target = Encoder<'p, W>;
+
+    fn deref(&self) -> &Encoder<'p, W> {
+        self.encoder.as_ref().unwrap()
+    }
+}
+
+impl<'p, W: Write> DerefMut for PooledEncoder<'p, W> {
+    fn deref_mut(&mut self) -> &mut Encoder<'p, W> {
+        self.encoder.as_mut().unwrap()
+    }
+}
+
+impl<'p, W: Write> Drop for PooledEncoder<'p, W> {
+    fn drop(&mut self) {
+        // Dropped without finishing.
+        if let Some(mut encoder) = self.encoder.take() {
+            let clean = encoder.finish_on_drop();
+            self.pool.put(encoder.take_spare(clean));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Header;
+    use super::super::ColorType;
+    use super::Encoder;
+    use super::Options;
+    use super::EncoderPool;
+    use super::Progress;
+    use super::Stats;
+    use super::DeadlineAction;
+    use super::DispatchMode;
+    use super::CancelToken;
+    use super::JobQueue;
+    use super::ActiveJobs;
+    use super::Preset;
+    use super::Verify;
+    use super::Validation;
+    use super::super::CompressionLevel;
+    use std::convert::TryFrom;
+    use super::super::Filter;
+    use super::super::Mode::Fixed;
+    use super::super::workers::WorkerPool;
+    use super::super::buffer::Allocator;
+    use super::super::{Error, Result};
+    use super::deflate;
+
+    use std::alloc;
+    use std::alloc::Layout;
+    use std::io::{Cursor, Write};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use crc::crc32;
+
+    fn test_encoder<F>(width: u32, height: u32, func: F)
+        where F: Fn(&mut Encoder<Vec<u8>>, &[u8]) -> Result<()>
+    {
+        test_encoder_with(width, height, &Options::new(), func);
+    }
+
+    fn test_row(width: u32) -> Vec<u8> {
+        let mut data = Vec::<u8>::with_capacity(width as usize * 3);
+        for i in 0 .. width as usize * 3 {
+            data.push((i % 255) as u8);
+        }
+        data
+    }
 
-    fn start_row(&self, index: usize) -> usize {
-        index * self.header.height() as usize / self.chunks_total
+    fn test_encoder_with<F>(width: u32, height: u32, options: &Options, func: F) -> Vec<u8>
+        where F: Fn(&mut Encoder<Vec<u8>>, &[u8]) -> Result<()>
+    {
+        let result = (|| -> Result<Vec<u8>> {
+            let data = test_row(width);
+
+            let writer = Vec::<u8>::new();
+            let mut encoder = Encoder::new(writer, options);
+
+            let mut header = Header::new();
+            header.set_size(width, height).unwrap();
+            header.set_color(ColorType::Truecolor, 8).unwrap();
+            encoder.write_header(&header)?;
+
+            func(&mut encoder, &data)?;
+            encoder.finish()
+        })();
+        match result {
+            Ok(writer) => writer,
+            Err(e) => panic!("Error {}", e),
+        }
     }
 
-    fn end_row(&self, index: usize) -> usize {
-        self.start_row(index + 1)
+    // Walk the chunks after the signature, checking each CRC.
+    // Returns the number of chunks seen.
+    fn check_chunks(output: &[u8]) -> usize {
+        let mut pos = 8;
+        let mut count = 0;
+        while pos < output.len() {
+            let len = u32::from_be_bytes([output[pos], output[pos + 1], output[pos + 2], output[pos + 3]]) as usize;
+            let body = &output[pos + 4 .. pos + 8 + len];
+            let crc = &output[pos + 8 + len .. pos + 12 + len];
+            let expected = crc32::checksum_ieee(body);
+            assert_eq!(crc, &expected.to_be_bytes()[..], "bad crc on chunk {}", count);
+            pos += 12 + len;
+            count += 1;
+        }
+        assert_eq!(pos, output.len());
+        count
     }
 
-    fn receive(&mut self, blocking: DispatchMode) -> Option<ThreadMessage> {
-        match blocking {
-            DispatchMode::Blocking => match self.rx.recv() {
-                Ok(msg) => Some(msg),
-                _ => None,
-            },
-            DispatchMode::NonBlocking => match self.rx.try_recv() {
-                Ok(msg) => Some(msg),
-                _ => None,
+    fn idat_sizes(output: &[u8]) -> Vec<usize> {
+        let mut pos = 8;
+        let mut sizes = Vec::new();
+        while pos < output.len() {
+            let len = u32::from_be_bytes([output[pos], output[pos + 1], output[pos + 2], output[pos + 3]]) as usize;
+            if &output[pos + 4 .. pos + 8] == b"IDAT" {
+                sizes.push(len);
             }
+            pos += 12 + len;
         }
+        sizes
     }
 
-    fn filter_mode(&self) -> Mode<Filter> {
-        match self.options.filter_mode {
-            Fixed(s) => Fixed(s),
-            Adaptive => match self.header.color_type {
-                ColorType::IndexedColor => Fixed(Filter::None),
-                _                       => Adaptive,
+    #[test]
+    fn create_and_state() {
+        test_encoder(1920, 1080, |encoder, data| {
+
+            assert!(!encoder.is_finished());
+            assert_eq!(encoder.progress(), 0.0);
+
+            // We must finish out the file or it'll whinge.
+            for _y in 0 .. 1080 {
+                encoder.write_image_rows(data)?;
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_rows() {
+        test_encoder(1920, 1080, |encoder, data| {
+            assert!(!encoder.is_finished());
+            assert_eq!(encoder.progress(), 0.0);
+
+            for _y in 0 .. 1080 {
+                encoder.write_image_rows(data)?;
+            }
+
+            // Should trigger all blocks!
+            encoder.flush()?;
+            assert!(encoder.is_finished());
+            assert_eq!(encoder.progress(), 1.0);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn crc_buffered() {
+        let options = Options::new();
+        let output = test_encoder_with(640, 480, &options, |encoder, data| {
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
+            }
+            Ok(())
+        });
+        // IHDR, IDAT, IEND
+        assert_eq!(check_chunks(&output), 3);
+    }
+
+    #[test]
+    fn crc_streaming() {
+        let mut options = Options::new();
+        options.set_streaming(true).unwrap();
+        let output = test_encoder_with(640, 480, &options, |encoder, data| {
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
             }
+            Ok(())
+        });
+        // IHDR, multiple IDATs, IEND
+        assert!(check_chunks(&output) > 3);
+    }
+
+    #[test]
+    fn streaming_pieces_are_bounded() {
+        let mut options = Options::new();
+        options.set_streaming(true).unwrap();
+        options.set_chunk_size(1024 * 1024).unwrap();
+
+        // Noise doesn't compress, so each chunk's output
+        // is bigger than a single piece.
+        let mut state = 1u32;
+        let noise: Vec<u8> = (0 .. 1024 * 1024 * 3).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect();
+
+        let output = test_encoder_with(1024, 1024, &options, |encoder, _data| {
+            encoder.write_image_rows(&noise)
+        });
+        check_chunks(&output);
+
+        let sizes = idat_sizes(&output);
+        assert!(sizes.iter().all(|&size| size <= deflate::OUTPUT_BUFFER_SIZE));
+        assert!(sizes.len() > 3 * 1024 * 1024 / deflate::OUTPUT_BUFFER_SIZE);
+    }
+
+    #[test]
+    fn fixed_idat_size() {
+        for streaming in &[false, true] {
+            let mut options = Options::new();
+            options.set_streaming(*streaming).unwrap();
+            options.set_idat_size(Some(1000)).unwrap();
+            let output = test_encoder_with(640, 480, &options, |encoder, data| {
+                for _y in 0 .. 480 {
+                    encoder.write_image_rows(data)?;
+                }
+                Ok(())
+            });
+            check_chunks(&output);
+
+            let sizes = idat_sizes(&output);
+            assert!(sizes.len() > 1);
+            let (last, rest) = sizes.split_last().unwrap();
+            assert!(rest.iter().all(|&size| size == 1000));
+            assert!(*last > 0 && *last <= 1000);
         }
     }
 
-    fn compression_strategy(&self) -> Strategy {
-        match self.options.strategy_mode {
-            Fixed(s) => s,
-            Adaptive => match self.filter_mode() {
-                Fixed(Filter::None) => Strategy::Default,
-                _                   => Strategy::Filtered,
-            },
+    #[test]
+    fn invalid_idat_size() {
+        let mut options = Options::new();
+        assert!(options.set_idat_size(Some(0)).is_err());
+        assert!(options.set_idat_size(None).is_ok());
+    }
+
+    #[test]
+    fn compression_levels() {
+        let encode = |level: CompressionLevel| {
+            let mut options = Options::new();
+            options.set_compression_level(level).unwrap();
+            test_encoder_with(640, 480, &options, |encoder, data| {
+                for _y in 0 .. 480 {
+                    encoder.write_image_rows(data)?;
+                }
+                Ok(())
+            })
+        };
+        assert!(encode(CompressionLevel::Level(6)) == encode(CompressionLevel::Default));
+        assert!(encode(CompressionLevel::Level(9)) == encode(CompressionLevel::High));
+        check_chunks(&encode(CompressionLevel::Level(4)));
+
+        assert!(matches!(CompressionLevel::try_from(4), Ok(CompressionLevel::Level(4))));
+        assert!(CompressionLevel::try_from(0).is_err());
+        let mut options = Options::new();
+        assert!(options.set_compression_level(CompressionLevel::Level(0)).is_err());
+        assert!(options.set_compression_level(CompressionLevel::Level(10)).is_err());
+    }
+
+    #[test]
+    fn shared_rows_match_copied() {
+        let mut options = Options::new();
+        options.set_chunk_size(32768).unwrap();
+
+        let expected = test_encoder_with(640, 480, &options, |encoder, data| {
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
+            }
+            Ok(())
+        });
+
+        let row = test_row(640);
+        let image: Arc<[u8]> = Arc::from(row.repeat(480));
+
+        // All at once.
+        let output = test_encoder_with(640, 480, &options, |encoder, _data| {
+            encoder.write_image_rows_shared(Arc::clone(&image))
+        });
+        assert!(output == expected);
+
+        // In pieces that don't line up with the data chunks.
+        let stride = row.len();
+        let output = test_encoder_with(640, 480, &options, |encoder, _data| {
+            encoder.write_image_rows_shared(image[0 .. stride * 7].to_vec())?;
+            encoder.write_image_rows(&image[stride * 7 .. stride * 100])?;
+            encoder.write_image_rows_shared(image[stride * 100 ..].to_vec())
+        });
+        assert!(output == expected);
+    }
+
+    #[cfg(feature="bytes")]
+    #[test]
+    fn shared_rows_bytes() {
+        use bytes::Bytes;
+
+        let options = Options::new();
+        let expected = test_encoder_with(640, 480, &options, |encoder, data| {
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
+            }
+            Ok(())
+        });
+
+        let image = Bytes::from(test_row(640).repeat(480));
+        let output = test_encoder_with(640, 480, &options, |encoder, _data| {
+            encoder.write_image_rows_shared(image.clone())
+        });
+        assert!(output == expected);
+    }
+
+    #[test]
+    fn reset_and_reuse() {
+        let options = Options::new();
+        let expected = test_encoder_with(640, 480, &options, |encoder, data| {
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
+            }
+            Ok(())
+        });
+
+        let data = test_row(640);
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+
+        // An abandoned image should error out, but leave us ready to go.
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows(&data).unwrap();
+        assert!(encoder.reset(Vec::<u8>::new(), &options).is_err());
+
+        for _i in 0 .. 3 {
+            encoder.write_header(&header).unwrap();
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(&data).unwrap();
+            }
+            let output = encoder.reset(Vec::<u8>::new(), &options).unwrap();
+            assert!(output == expected);
         }
     }
 
-    fn dispatch(&mut self, mode: DispatchMode) -> IoResult {
-        // See if anything interesting happened on the threads.
-        let mut blocking_mode = mode;
-        while self.filter_chunks.in_flight() || self.deflate_chunks.in_flight() {
-            match self.receive(blocking_mode) {
-                Some(ThreadMessage::FilterDone(filter)) => {
-                    self.filter_chunks.land(filter.index, filter);
+    #[test]
+    fn estimate_memory() {
+        let mut header = Header::new();
+        header.set_size(7680, 4320).unwrap();
+        header.set_color(ColorType::TruecolorAlpha, 8).unwrap();
+        let raw = header.stride() * 4320;
+
+        let mut options = Options::new();
+        let buffered = options.estimate_memory(&header);
+        assert!(buffered > raw);
+
+        options.set_streaming(true).unwrap();
+        let streaming = options.estimate_memory(&header);
+        assert!(streaming < buffered);
+
+        options.set_chunk_size(1024 * 1024).unwrap();
+        assert!(options.estimate_memory(&header) > streaming);
+
+        // Tiny images are mostly fixed overhead.
+        header.set_size(16, 16).unwrap();
+        assert!(options.estimate_memory(&header) < 1024 * 1024);
+    }
+
+    #[test]
+    fn max_inflight_chunks() {
+        let mut options = Options::new();
+        options.set_chunk_size(32768).unwrap();
+        let expected = test_encoder_with(640, 480, &options, |encoder, data| {
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
+            }
+            Ok(())
+        });
+
+        for max in 1 .. 4 {
+            options.set_max_inflight_chunks(Some(max)).unwrap();
+            let output = test_encoder_with(640, 480, &options, |encoder, data| {
+                for _y in 0 .. 480 {
+                    encoder.write_image_rows(data)?;
+                    assert!(encoder.pixel_index - encoder.chunks_output <= max);
                 }
-                Some(ThreadMessage::DeflateDone(deflate)) => {
-                    self.deflate_chunks.land(deflate.index, deflate);
-                },
-                Some(ThreadMessage::Error(e)) => {
-                    return Err(e);
+                Ok(())
+            });
+            assert!(output == expected);
+        }
+
+        assert!(options.set_max_inflight_chunks(Some(0)).is_err());
+    }
+
+    #[test]
+    fn seekable_output() {
+        let options = Options::new();
+        let expected = test_encoder_with(640, 480, &options, |encoder, data| {
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
+            }
+            Ok(())
+        });
+
+        let data = test_row(640);
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        // Start partway into the output, to be sure we track the position.
+        let mut cursor = Cursor::new(Vec::<u8>::new());
+        cursor.write_all(b"junk").unwrap();
+
+        let mut encoder = Encoder::new_seekable(cursor, &options);
+        encoder.write_header(&header).unwrap();
+        for _y in 0 .. 480 {
+            encoder.write_image_rows(&data).unwrap();
+        }
+        assert!(encoder.idat_buffer.is_empty());
+        let output = encoder.finish().unwrap().into_inner();
+        assert!(output[4 ..] == expected[..]);
+    }
+
+    #[test]
+    fn serial_fast_path() {
+        let encode = |options: &Options| {
+            test_encoder_with(640, 480, options, |encoder, data| {
+                assert!(encoder.serial == (options.serial_threshold > 0));
+                for _y in 0 .. 480 {
+                    encoder.write_image_rows(data)?;
                 }
-                None => {
-                    // No more output from the threads.
-                    break;
+                Ok(())
+            })
+        };
+        let expected = encode(&Options::new());
+
+        let mut options = Options::new();
+        options.set_serial_threshold(1024 * 1024).unwrap();
+        assert!(encode(&options) == expected);
+    }
+
+    #[test]
+    fn serial_single_chunk() {
+        let options = Options::new();
+        test_encoder_with(16, 16, &options, |encoder, data| {
+            assert!(encoder.serial);
+            for _y in 0 .. 16 {
+                encoder.write_image_rows(data)?;
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn worker_pool() {
+        let encode = |options: &Options| {
+            test_encoder_with(640, 480, options, |encoder, data| {
+                for _y in 0 .. 480 {
+                    encoder.write_image_rows(data)?;
                 }
+                Ok(())
+            })
+        };
+        let expected = encode(&Options::new());
+
+        let pool = WorkerPool::new(3).unwrap();
+        let mut options = Options::new();
+        options.set_worker_pool(&pool).unwrap();
+        assert!(encode(&options) == expected);
+        assert!(WorkerPool::new(0).is_err());
+    }
+
+    #[test]
+    fn max_threads() {
+        let pool = WorkerPool::new(4).unwrap();
+        let mut options = Options::new();
+        options.set_worker_pool(&pool).unwrap();
+        assert_eq!(options.max_jobs(), 6);
+
+        options.set_max_threads(Some(2)).unwrap();
+        assert_eq!(options.max_jobs(), 2);
+        assert!(options.set_max_threads(Some(0)).is_err());
+
+        let output = test_encoder_with(640, 480, &options, |encoder, data| {
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
+                assert!(encoder.running_jobs() <= 2);
             }
-            // After the first one, keep reading any if they're there
-            // but don't block further.
-            blocking_mode = DispatchMode::NonBlocking;
+            Ok(())
+        });
+        assert!(check_chunks(&output) > 0);
+    }
+
+    #[test]
+    fn encoder_pool() {
+        let options = Options::new();
+        let expected = test_encoder_with(64, 64, &options, |encoder, data| {
+            for _y in 0 .. 64 {
+                encoder.write_image_rows(data)?;
+            }
+            Ok(())
+        });
+
+        let pool = EncoderPool::new(2).unwrap();
+        let data = test_row(64);
+        let mut header = Header::new();
+        header.set_size(64, 64).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        for _i in 0 .. 3 {
+            let mut encoder = pool.encoder(Vec::<u8>::new(), &options);
+            encoder.write_header(&header).unwrap();
+            for _y in 0 .. 64 {
+                encoder.write_image_rows(&data).unwrap();
+            }
+            let output = encoder.finish().unwrap();
+            assert!(output == expected);
+            assert_eq!(pool.spares.lock().unwrap().len(), 1);
         }
 
-        // If we have more deflate work to do, dispatch them!
-        while self.running_jobs() < self.max_threads() {
-            match self.filter_chunks.pop_front() {
-                Some((previous, current)) => {
-                    // Prepare to dispatch the deflate job:
-                    let level = self.options.compression_level;
-                    let strategy = self.compression_strategy();
-                    self.deflate_chunks.advance();
-                    self.dispatch_func(move |tx| {
-                        let mut deflate = DeflateChunk::new(level, strategy, previous.clone(), current.clone());
-                        tx.send(match deflate.run() {
-                            Ok(()) => ThreadMessage::DeflateDone(Arc::new(deflate)),
-                            Err(e) => ThreadMessage::Error(e),
-                        }).ok();
-                    });
-                },
-                None => {
-                    break;
-                }
+        // Abandoned encoders give back their buffers too.
+        let mut encoder = pool.encoder(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        drop(encoder);
+        assert_eq!(pool.spares.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn cancel() {
+        let data = test_row(640);
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        let options = Options::new();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        for _y in 0 .. 240 {
+            encoder.write_image_rows(&data).unwrap();
+        }
+
+        let token = encoder.cancel_token();
+        std::thread::spawn(move || token.cancel()).join().unwrap();
+
+        assert!(matches!(encoder.write_image_rows(&data), Err(Error::Cancelled)));
+        assert!(encoder.write_chunk(b"tEXt", b"a\0b").is_err());
+
+        // The partial output still ends on a chunk boundary.
+        assert!(encoder.flush().is_err());
+        assert!(check_chunks(encoder.writer.get_mut()) > 0);
+        assert!(encoder.finish().is_err());
+    }
+
+    #[test]
+    fn progress_callback() {
+        let reports = Arc::new(Mutex::new(Vec::<Progress>::new()));
+        let mut options = Options::new();
+        options.set_chunk_size(65536).unwrap();
+        options.set_streaming(true).unwrap();
+        let output = test_encoder_with(640, 480, &options, |encoder, data| {
+            let reports = Arc::clone(&reports);
+            encoder.set_progress_callback(move |progress| {
+                reports.lock().unwrap().push(progress);
+            });
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
             }
+            Ok(())
+        });
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 14);
+        for (i, pair) in reports.windows(2).enumerate() {
+            assert_eq!(pair[0].chunks_completed, i + 1);
+            assert_eq!(pair[0].chunks_total, 14);
+            assert!(pair[0].rows_consumed <= pair[1].rows_consumed);
+            assert!(pair[0].bytes_written <= pair[1].bytes_written);
         }
+        let last = reports.last().unwrap();
+        assert_eq!(last.rows_consumed, 480);
+        assert_eq!(last.bytes_written as usize, output.len() - 12);
+    }
 
-        // If we have more filter work to do, dispatch them!
-        while self.running_jobs() < self.max_threads() {
-            match self.pixel_chunks.pop_front() {
-                Some((previous, current)) => {
-                    // Prepare to dispatch the filter job:
-                    self.filter_chunks.advance();
-                    let filter_mode = self.filter_mode();
-                    self.dispatch_func(move |tx| {
-                        let mut filter = FilterChunk::new(previous.clone(),
-                                                          current.clone(),
-                                                          filter_mode);
-                        tx.send(match filter.run() {
-                            Ok(()) => ThreadMessage::FilterDone(Arc::new(filter)),
-                            Err(e) => ThreadMessage::Error(e),
-                        }).ok();
-                    });
-                },
-                None => {
-                    break;
+    // Counts allocations, passing them on to the global allocator.
+    #[derive(Default)]
+    struct CountingAllocator {
+        live: AtomicUsize,
+        total: AtomicUsize,
+    }
+
+    unsafe impl Allocator for CountingAllocator {
+        fn alloc(&self, size: usize) -> *mut u8 {
+            self.live.fetch_add(1, Ordering::SeqCst);
+            self.total.fetch_add(1, Ordering::SeqCst);
+            unsafe {
+                alloc::alloc(Layout::from_size_align(size, 1).unwrap())
+            }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, old_size: usize, size: usize) -> *mut u8 {
+            alloc::realloc(ptr, Layout::from_size_align(old_size, 1).unwrap(), size)
+        }
+
+        unsafe fn free(&self, ptr: *mut u8, size: usize) {
+            self.live.fetch_sub(1, Ordering::SeqCst);
+            alloc::dealloc(ptr, Layout::from_size_align(size, 1).unwrap())
+        }
+    }
+
+    #[test]
+    fn custom_allocator() {
+        let mut options = Options::new();
+        options.set_chunk_size(65536).unwrap();
+        let data = test_row(640);
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        for streaming in [false, true] {
+            options.set_streaming(streaming).unwrap();
+            let expected = test_encoder_with(640, 480, &options, |encoder, data| {
+                for _y in 0 .. 480 {
+                    encoder.write_image_rows(data)?;
                 }
+                Ok(())
+            });
+
+            let counting = Arc::new(CountingAllocator::default());
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+            encoder.set_allocator(counting.clone()).unwrap();
+            encoder.write_header(&header).unwrap();
+            assert!(matches!(encoder.set_allocator(counting.clone()), Err(Error::InvalidState(_))));
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(&data).unwrap();
             }
+            let output = encoder.finish().unwrap();
+
+            assert_eq!(output, expected);
+            assert!(counting.total.load(Ordering::SeqCst) > 14);
+            assert_eq!(counting.live.load(Ordering::SeqCst), 0);
         }
+    }
 
-        // If we have output to run, write it!
-        while let Some((_previous, current)) = self.deflate_chunks.pop_front() {
-            if self.chunks_output >= self.chunks_total {
-                panic!("Got extra output after end of file; should not happen.");
+    #[test]
+    fn write_trait() {
+        let expected = test_encoder_with(640, 480, &Options::new(), |encoder, data| {
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
             }
+            Ok(())
+        });
 
-            // Combine the checksums!
-            self.adler32 = deflate::adler32_combine(self.adler32,
-                                                    current.adler32,
-                                                    current.input.data.len());
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let pixels: Vec<u8> = (0 .. 480).flat_map(|_y| test_row(640)).collect();
+
+        // Feed it through in pieces that don't line up with the rows.
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        assert!(encoder.write(&pixels[.. 100]).is_err());
+        encoder.write_header(&header).unwrap();
+        for piece in pixels.chunks(1000) {
+            encoder.write_all(piece).unwrap();
+        }
+        Write::flush(&mut encoder).unwrap();
+        assert_eq!(encoder.finish().unwrap(), expected);
+
+        // A partial row at the end leaves the image incomplete.
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        encoder.write_all(&pixels[.. pixels.len() - 1]).unwrap();
+        assert!(matches!(encoder.finish(), Err(Error::PartialRow { bytes: 1919, stride: 1920 })));
+    }
 
-            // if not streaming, append to an in-memory buffer
-            // and output a giant tag later.
-            if self.options.streaming {
-                self.writer.write_chunk(b"IDAT", &current.data)?;
+    #[test]
+    fn verify_output() {
+        let options = Options::new();
+        let expected = test_encoder_with(640, 480, &options, |encoder, data| {
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
+            }
+            Ok(())
+        });
 
-                if current.is_end {
-                    let mut chunk = Vec::<u8>::new();
-                    if !current.is_start {
-                        write_be32(&mut chunk, self.adler32)?;
-                    }
-                    self.writer.write_chunk(b"IDAT", &chunk)?;
+        let data = test_row(640);
+        let pixels: Vec<u8> = (0 .. 480).flat_map(|_y| test_row(640)).collect();
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        for verify in &[Verify::Chunks, Verify::Pixels] {
+            let options = Options::builder()
+                .chunk_size(32768)
+                .verify(*verify)
+                .build()
+                .unwrap();
+            test_encoder_with(640, 480, &options, |encoder, data| {
+                for _y in 0 .. 480 {
+                    encoder.write_image_rows(data)?;
                 }
-            } else {
-                self.idat_buffer.write_all(&current.data)?;
+                Ok(())
+            });
+
+            let streaming = Options::builder().streaming(true).verify(*verify).build().unwrap();
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &streaming);
+            encoder.write_header(&header).unwrap();
+            encoder.write_image_rows_shared(pixels.clone()).unwrap();
+            encoder.finish().unwrap();
+
+            // Seekable output is buffered, but comes out the same.
+            let options = Options::builder().verify(*verify).build().unwrap();
+            let mut encoder = Encoder::new_seekable(Cursor::new(Vec::<u8>::new()), &options);
+            encoder.write_header(&header).unwrap();
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(&data).unwrap();
+            }
+            assert_eq!(encoder.finish().unwrap().into_inner(), expected);
+        }
+    }
 
-                if current.is_end {
-                    if !current.is_start {
-                        write_be32(&mut self.idat_buffer, self.adler32)?;
-                    }
-                    self.writer.write_chunk(b"IDAT", &self.idat_buffer)?;
-                }
+    #[test]
+    fn finish_on_drop() {
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let data = test_row(640);
+        let options = Options::builder().chunk_size(32768).build().unwrap();
+
+        let expected = test_encoder_with(640, 480, &options, |encoder, data| {
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
             }
+            Ok(())
+        });
 
-            self.chunks_output += 1;
+        let mut output = Vec::<u8>::new();
+        {
+            let mut encoder = Encoder::new(&mut output, &options);
+            encoder.write_header(&header).unwrap();
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(&data).unwrap();
+            }
         }
-
-        Ok(())
+        assert_eq!(output, expected);
+
+        // An incomplete image is abandoned, with its jobs cancelled.
+        let mut output = Vec::<u8>::new();
+        let token = {
+            let mut encoder = Encoder::new(&mut output, &options);
+            encoder.write_header(&header).unwrap();
+            for _y in 0 .. 240 {
+                encoder.write_image_rows(&data).unwrap();
+            }
+            encoder.cancel_token()
+        };
+        assert!(token.is_cancelled());
+        assert!(!output.ends_with(&expected[expected.len() - 12 ..]));
     }
 
-    /// Write the PNG signature and header chunk.
-    /// Must be done before anything else is output.
-    ///
-    /// Subsequent image data must match the given header data.
-    pub fn write_header(&mut self, header: &Header) -> IoResult {
-        if self.wrote_header {
-            return Err(invalid_input("Cannot write header a second time."));
+    #[test]
+    fn finish_with_stats() {
+        let data = test_row(640);
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        let mut options = Options::new();
+        options.set_filter_mode(Fixed(Filter::Sub)).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        for _y in 0 .. 480 {
+            encoder.write_image_rows(&data).unwrap();
         }
+        let (output, stats): (Vec<u8>, Stats) = encoder.finish_with_stats().unwrap();
+
+        assert_eq!(stats.bytes_written, output.len() as u64);
+        assert_eq!(stats.uncompressed_bytes, (640 * 3 + 1) * 480);
+        assert!(stats.compressed_bytes > 0);
+        assert!(stats.compressed_bytes < stats.uncompressed_bytes);
+        assert_eq!(stats.filter_rows, [0, 480, 0, 0, 0]);
+        assert_eq!(stats.chunks, options.chunks(&header));
+        assert!(stats.wall_time > Duration::default());
+        assert!(stats.filter_time > Duration::default());
+        assert!(stats.deflate_time > Duration::default());
+        assert_eq!(stats.cpu_time, stats.filter_time + stats.deflate_time + stats.checksum_time);
+    }
 
-        self.header = *header;
-
-        let stride = self.header.stride() + 1;
-        let height = self.header.height as usize;
-
-        let chunks = stride * height / self.options.chunk_size;
-        self.chunks_total = if chunks < 1 {
-            1
-        } else {
-            chunks
+    #[test]
+    fn deadline() {
+        let encode = |options: &Options| {
+            let data = test_row(640);
+            let mut header = Header::new();
+            header.set_size(640, 480).unwrap();
+            header.set_color(ColorType::Truecolor, 8).unwrap();
+
+            let mut encoder = Encoder::new(Vec::<u8>::new(), options);
+            encoder.write_header(&header)?;
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(&data)?;
+            }
+            encoder.finish()
         };
 
-        self.pixel_chunks.advance();
-        self.pixel_accumulator = Arc::new(PixelChunk::new(self.header,
-                                                          0, // index
-                                                          self.start_row(0),
-                                                          self.end_row(0)));
+        let mut options = Options::new();
+        options.set_deadline(Some(Duration::from_secs(0))).unwrap();
+        match encode(&options) {
+            Ok(_) => panic!("Expected deadline to pass"),
+            Err(Error::TimedOut) => {},
+            Err(e) => panic!("Unexpected error {}", e),
+        }
 
-        self.wrote_header = true;
+        // Downgrading right away is the same as the Fast level.
+        options.set_deadline_action(DeadlineAction::Downgrade).unwrap();
+        let downgraded = encode(&options).unwrap();
 
-        self.writer.write_signature()?;
-        self.writer.write_header(self.header)
+        let mut fast = Options::new();
+        fast.set_compression_level(CompressionLevel::Fast).unwrap();
+        assert!(downgraded == encode(&fast).unwrap());
+        assert!(downgraded != encode(&Options::new()).unwrap());
     }
 
-    /// Write an indexed-color palette as a PLTE chunk.
-    ///
-    /// Data must be formatted per the spec matching the color mode:
-    /// https://www.w3.org/TR/2003/REC-PNG-20031110/#11PLTE
-    ///
-    /// Note this chunk is allowed on truecolor images, though sPLT is preferred.
-    pub fn write_palette(&mut self, palette: &[u8]) -> io::Result<()> {
-        if !self.wrote_header {
-            return Err(invalid_input("Cannot write palette before header."));
-        }
-        if self.wrote_palette {
-            return Err(invalid_input("Cannot write palette a second time."));
-        }
-        if self.wrote_transparency {
-            return Err(invalid_input("Cannot write palette after transparency."));
-        }
-        if self.started_image {
-            return Err(invalid_input("Cannot write palette after image data."));
-        }
-        if palette.len() < 3 {
-            return Err(invalid_input("Palette must have at least one entry."));
-        }
-        if palette.len() % 3 != 0 {
-            return Err(invalid_input("Palette must have an integral number of entries."));
+    #[test]
+    fn job_panic() {
+        let data = test_row(640);
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        let options = Options::new();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows(&data).unwrap();
+
+        // Stand in for a filter job that blows up.
+        encoder.filter_chunks.advance();
+        encoder.dispatch_func(0, |_tx| panic!("boom"));
+
+        match encoder.dispatch(DispatchMode::Blocking) {
+            Ok(()) => panic!("Expected an error"),
+            Err(Error::WorkerFailed(e)) => assert!(e.contains("boom")),
+            Err(e) => panic!("Unexpected error {}", e),
         }
-
-        self.wrote_palette = true;
-        self.palette_length = palette.len() / 3;
-        self.writer.write_chunk(b"PLTE", palette)
     }
 
-    /// Write a transparency info chunk.
-    ///
-    /// For indexed color, contains a single alpha value byte per palette
-    /// entry, up to but not exceeding the number of palette entries.
-    ///
-    /// Note this chunk is allowed on greyscale and truecolor images,
-    /// and there references a single color in 16-bit notation.
-    ///
-    /// https://www.w3.org/TR/PNG/#11tRNS
-    pub fn write_transparency(&mut self, data: &[u8]) -> io::Result<()> {
-        if !self.wrote_header {
-            return Err(invalid_input("Cannot write transparency before header."));
-        }
-        if self.started_image {
-            return Err(invalid_input("Cannot write transparency after image data."));
-        }
-        match self.header.color_type {
-            ColorType::Greyscale => {
-                if data.len() != 2 {
-                    return Err(invalid_input("Greyscale transparency data must be exactly 2 bytes."));
-                }
-            },
-            ColorType::Truecolor => {
-                if data.len() != 6 {
-                    return Err(invalid_input("Truecolor transparency data must be exactly 6 bytes."));
-                }
-            },
-            ColorType::IndexedColor => {
-                if !self.wrote_palette {
-                    return Err(invalid_input("Cannot write transparency before palette."));
-                }
-                if data.is_empty() {
-                    return Err(invalid_input("Transparency data too short."));
-                }
-                if data.len() > self.palette_length {
-                    return Err(invalid_input("Transparency data cannot contain more entries than palette."));
-                }
-            },
-            _ => {
-                return Err(invalid_input("Transparency chunk is invalid for color types with alpha"));
+    #[test]
+    fn identical_across_threads() {
+        // Mix of compressible and incompressible rows.
+        let mut state = 1u32;
+        let rows: Vec<u8> = (0 .. 480).flat_map(|y| {
+            if y % 3 == 0 {
+                (0 .. 640 * 3).map(|_| {
+                    state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                    (state >> 16) as u8
+                }).collect()
+            } else {
+                test_row(640)
+            }
+        }).collect();
+
+        for &streaming in &[false, true] {
+            let mut base = Options::new();
+            base.set_chunk_size(65536).unwrap();
+            base.set_streaming(streaming).unwrap();
+
+            let encode = |options: &Options| {
+                test_encoder_with(640, 480, options, |encoder, _data| {
+                    encoder.write_image_rows(&rows)
+                })
+            };
+            let expected = encode(&base);
+
+            for &threads in &[1, 2, 5] {
+                let pool = WorkerPool::new(threads).unwrap();
+                let mut options = base;
+                options.set_worker_pool(&pool).unwrap();
+                assert!(encode(&options) == expected, "{} threads", threads);
+
+                options.set_max_threads(Some(1)).unwrap();
+                assert!(encode(&options) == expected, "{} threads capped", threads);
             }
 
+            let mut options = base;
+            options.set_serial_threshold(usize::MAX).unwrap();
+            assert!(encode(&options) == expected, "serial");
         }
-        self.wrote_transparency = true;
-        self.writer.write_chunk(b"tRNS", data)
     }
 
-    //
-    // Write a custom ancillary chunk to the output stream.
-    // The tag must be a 4-byte slice. The data should be provided
-    // in the appropriate format for the tag.
-    //
-    pub fn write_chunk(&mut self, tag: &[u8], data: &[u8]) -> io::Result<()> {
-        self.writer.write_chunk(tag, data)
+    #[test]
+    fn borrowed_rows() {
+        let mut options = Options::new();
+        options.set_chunk_size(65536).unwrap();
+
+        let rows: Vec<u8> = (0 .. 480).flat_map(|_y| test_row(640)).collect();
+        let expected = test_encoder_with(640, 480, &options, |encoder, _data| {
+            encoder.write_image_rows(&rows)
+        });
+
+        let stride = 640 * 3;
+        let output = test_encoder_with(640, 480, &options, |encoder, _data| {
+            // Split at a point that isn't on a chunk boundary, and
+            // free the first buffer before writing the rest.
+            let first = rows[.. 201 * stride].to_vec();
+            encoder.write_image_rows_borrowed(&first)?;
+            drop(first);
+            encoder.write_image_rows_borrowed(&rows[201 * stride ..])
+        });
+        assert!(output == expected);
     }
 
-    //
-    // Copy a row's pixel data into buffers for async compression.
-    // Returns immediately after copying.
-    //
-    fn process_row(&mut self, row: &[u8]) -> io::Result<RowStatus>
-    {
-        if self.pixel_index >= self.chunks_total {
-            return Err(other("invalid internal state"));
-        }
-        if !self.wrote_header {
-            return Err(invalid_input("Cannot write image data before header."));
-        }
-        if let ColorType::IndexedColor = self.header.color_type {
-            if !self.wrote_palette {
-                return Err(invalid_input("Cannot write indexed-color image data before palette."));
-            }
-        }
-        if !self.started_image {
-            self.started_image = true;
+    #[test]
+    fn active_jobs_wait() {
+        // Jobs finishing on other threads wake the waiter.
+        let active = Arc::new(ActiveJobs::default());
+        let threads: Vec<_> = (0 .. 4).map(|_| {
+            active.start();
+            let active = Arc::clone(&active);
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                active.finish();
+            })
+        }).collect();
+        active.wait();
+        assert_eq!(*active.count(), 0);
+        for thread in threads {
+            thread.join().unwrap();
         }
 
-        Arc::get_mut(&mut self.pixel_accumulator).unwrap().read_row(row);
-
-        if self.pixel_accumulator.is_full() {
-            // Move the item off to the completed stack...
-            self.pixel_chunks.land(self.pixel_index, self.pixel_accumulator.clone());
+        // On a pool's only thread, a job queued behind the waiter
+        // is run rather than waited on forever.
+        let pool = ::rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        pool.install(|| {
+            let active = Arc::new(ActiveJobs::default());
+            active.start();
+            let job = Arc::clone(&active);
+            ::rayon::spawn(move || job.finish());
+            active.wait();
+        });
+    }
 
-            // Make a nice new buffer to accumulate data into.
-            self.pixel_index += 1;
-            if self.pixel_index < self.chunks_total {
-                self.pixel_chunks.advance();
-                self.pixel_accumulator = Arc::new(PixelChunk::new(self.header,
-                                                                  self.pixel_index,
-                                                                  self.start_row(self.pixel_index),
-                                                                  self.end_row(self.pixel_index)));
+    #[test]
+    fn thread_safety() {
+        fn assert_send<T: Send>() {}
+        fn assert_sync<T: Sync>() {}
+
+        assert_send::<Encoder<Vec<u8>>>();
+        assert_send::<Encoder<std::fs::File>>();
+        assert_send::<Options>();
+        assert_sync::<Options>();
+        assert_send::<CancelToken>();
+        assert_sync::<CancelToken>();
+        assert_sync::<EncoderPool>();
+        assert_sync::<WorkerPool>();
+
+        // Create on one thread, finish on another.
+        let options = Options::new();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        let mut header = Header::new();
+        header.set_size(64, 64).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        encoder.write_header(&header).unwrap();
+        let output = std::thread::spawn(move || {
+            for _y in 0 .. 64 {
+                encoder.write_image_rows(&test_row(64))?;
             }
+            encoder.finish()
+        }).join().unwrap().unwrap();
+        assert!(check_chunks(&output) > 0);
+    }
 
-            // Dispatch any available async tasks and output.
-            while self.running_jobs() >= self.max_threads() {
-                self.dispatch(DispatchMode::Blocking)?;
-            }
-            self.dispatch(DispatchMode::NonBlocking)?;
+    #[test]
+    fn job_queue_order() {
+        let queue = JobQueue::default();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for &priority in &[5, 1, 4, 0, 3] {
+            let order = Arc::clone(&order);
+            queue.push(priority, Box::new(move || {
+                order.lock().unwrap().push(priority);
+            }));
         }
-
-        self.current_row += 1;
-        if self.current_row == self.header.height {
-            Ok(RowStatus::Done)
-        } else {
-            Ok(RowStatus::Continue)
+        for _i in 0 .. 5 {
+            queue.run_next();
         }
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 3, 4, 5]);
     }
 
-    /// Encode and compress the given image data and write to output.
-    /// Input data must be packed in the correct format for the given
-    /// color type and depth, with no padding at the end of rows.
-    ///
-    /// An integral number of rows must be provided at once.
-    ///
-    /// If not all of the image rows are provided, multiple calls are
-    /// required to finish out the data.
-    pub fn write_image_rows(&mut self, buf: &[u8]) -> IoResult {
-        let stride = self.header.stride();
-        if buf.len() % stride != 0 {
-            Err(invalid_input("Buffer must be an integral number of rows"))
-        } else {
-            for row in buf.chunks(stride) {
-                self.process_row(row)?;
+    #[test]
+    fn encode_to_vec() {
+        let options = Options::new();
+        let expected = test_encoder_with(640, 480, &options, |encoder, data| {
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
             }
             Ok(())
-        }
-    }
+        });
 
-    /// Return completion progress as a fraction of 1.0
-    ///
-    /// Currently progress is measured in chunks, so small files may
-    /// not report values between 0.0 and 1.0.
-    pub fn progress(&self) -> f64 {
-        self.chunks_output as f64 / self.chunks_total as f64
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let pixels: Vec<u8> = (0 .. 480).flat_map(|_y| test_row(640)).collect();
+        assert!(super::encode_to_vec(&header, &pixels, &options).unwrap() == expected);
+        assert!(matches!(super::encode_to_vec(&header, &pixels[1 ..], &options),
+                         Err(Error::RowDataMismatch(_))));
+
+        header.set_color(ColorType::IndexedColor, 8).unwrap();
+        let pixels = vec![0u8; 640 * 480];
+        assert!(super::encode_to_vec(&header, &pixels, &options).is_err());
+        let output = super::encode_indexed_to_vec(&header, &[0, 0, 0], Some(&[128]), &pixels, &options).unwrap();
+        assert_eq!(check_chunks(&output), 5);
     }
 
-    /// Return finished-ness state.
-    /// Is it finished? Yeah or no.
-    pub fn is_finished(&self) -> bool {
-        self.chunks_output == self.chunks_total
-    }
+    #[test]
+    fn error_variants() {
+        let mut header = Header::new();
+        assert!(matches!(header.set_size(0, 1), Err(Error::InvalidHeader(_))));
+        header.set_color(ColorType::IndexedColor, 8).unwrap();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        assert!(matches!(encoder.write_palette(&[0; 3]), Err(Error::InvalidState(_))));
+        encoder.write_header(&header).unwrap();
+        match encoder.write_palette(&[0; 257 * 3]) {
+            Err(Error::PaletteTooLarge { entries: 257, max: 256 }) => {},
+            _ => panic!("Expected PaletteTooLarge"),
+        }
 
-    /// Flush all currently in-progress data to output
-    /// Warning: this may block.
-    pub fn flush(&mut self) -> IoResult {
-        while self.chunks_output < self.pixel_index {
-            // Dispatch any available async tasks and output.
-            self.dispatch(DispatchMode::Blocking)?;
+        header.set_color(ColorType::IndexedColor, 2).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        match encoder.write_palette(&[0; 5 * 3]) {
+            Err(Error::PaletteTooLarge { entries: 5, max: 4 }) => {},
+            _ => panic!("Expected PaletteTooLarge"),
         }
-        Ok(())
-    }
-}
+        assert!(matches!(encoder.write_palette(&[0; 4]), Err(Error::InvalidPalette(_))));
+        encoder.write_palette(&[0; 4 * 3]).unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::super::Header;
-    use super::super::ColorType;
-    use super::Encoder;
-    use super::Options;
-    use super::IoResult;
+        header.set_color(ColorType::Greyscale, 8).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        assert!(matches!(encoder.write_palette(&[0; 3]), Err(Error::InvalidPalette(_))));
+    }
 
-    use std::io;
+    #[test]
+    fn transparency_validation() {
+        let mut header = Header::new();
+        header.set_size(16, 16).unwrap();
+
+        header.set_color(ColorType::Greyscale, 4).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        assert!(matches!(encoder.write_transparency(&[0, 15, 0]), Err(Error::InvalidTransparency(_))));
+        assert!(matches!(encoder.write_transparency(&[0, 16]), Err(Error::InvalidTransparency(_))));
+        encoder.write_transparency(&[0, 15]).unwrap();
+        assert!(matches!(encoder.write_transparency(&[0, 15]), Err(Error::InvalidState(_))));
+
+        header.set_color(ColorType::Truecolor, 16).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        assert!(matches!(encoder.write_transparency(&[0, 0]), Err(Error::InvalidTransparency(_))));
+        encoder.write_transparency(&[255; 6]).unwrap();
+
+        header.set_color(ColorType::IndexedColor, 8).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        assert!(matches!(encoder.write_transparency(&[255]), Err(Error::InvalidState(_))));
+        encoder.write_palette(&[0; 2 * 3]).unwrap();
+        assert!(matches!(encoder.write_transparency(&[255; 3]), Err(Error::InvalidTransparency(_))));
+        encoder.write_transparency(&[255; 2]).unwrap();
+
+        header.set_color(ColorType::TruecolorAlpha, 8).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        assert!(matches!(encoder.write_transparency(&[0; 6]), Err(Error::InvalidTransparency(_))));
+    }
 
-    fn test_encoder<F>(width: u32, height: u32, func: F)
-        where F: Fn(&mut Encoder<Vec<u8>>, &[u8]) -> IoResult
-    {
-        match {
-            || -> io::Result<Vec<u8>> {
-                let mut data = Vec::<u8>::with_capacity(width as usize * 3);
-                for i in 0 .. width as usize * 3 {
-                    data.push((i % 255) as u8);
-                }
+    #[test]
+    fn custom_chunks() {
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        encoder.write_chunk(b"prVt", b"data").unwrap();
+        for tag in &[&b"PLTE"[..], b"ABCD", b"abcd", b"ab1d", b"abc"] {
+            assert!(matches!(encoder.write_chunk(tag, b""), Err(Error::InvalidChunk(_))),
+                    "tag {:?}", tag);
+        }
+    }
 
-                let writer = Vec::<u8>::new();
-                let options = Options::new();
-                let mut encoder = Encoder::new(writer, &options);
+    #[test]
+    fn strict_validation() {
+        let mut header = Header::new();
+        header.set_size(640, 2).unwrap();
+        header.set_color(ColorType::IndexedColor, 8).unwrap();
+        let strict = Options::builder().validation(Validation::Strict).build().unwrap();
+        let invalid = |result: Result<()>| matches!(result, Err(Error::InvalidChunk(_)));
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &strict);
+        assert!(invalid(encoder.write_chunk(b"gAMA", &[0; 4])));
+        encoder.write_header(&header).unwrap();
+        encoder.write_chunk(b"gAMA", &[0; 4]).unwrap();
+        assert!(invalid(encoder.write_chunk(b"gAMA", &[0; 4])));
+        encoder.write_palette(&[0; 6]).unwrap();
+        assert!(invalid(encoder.write_chunk(b"sRGB", &[0])));
+        assert!(invalid(encoder.write_text("Comment", &"a".repeat(8_000_001))));
+        encoder.write_text("Comment", "Test").unwrap();
+        encoder.write_chunk(b"pHYs", &[0; 9]).unwrap();
+        encoder.write_image_rows(&[0; 640 * 2]).unwrap();
+        encoder.flush().unwrap();
+        assert!(invalid(encoder.write_text("Comment", "Test")));
+        encoder.finish().unwrap();
+
+        // The same chunks are allowed in permissive mode.
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        encoder.write_chunk(b"gAMA", &[0; 4]).unwrap();
+        encoder.write_chunk(b"gAMA", &[0; 4]).unwrap();
+        encoder.write_palette(&[0; 6]).unwrap();
+        encoder.write_chunk(b"sRGB", &[0]).unwrap();
+        encoder.write_image_rows(&[0; 640 * 2]).unwrap();
+        encoder.flush().unwrap();
+        encoder.write_text("Comment", "Test").unwrap();
+        encoder.finish().unwrap();
+    }
 
-                let mut header = Header::new();
-                header.set_size(width, height).unwrap();
-                header.set_color(ColorType::Truecolor, 8).unwrap();
-                encoder.write_header(&header)?;
+    #[test]
+    fn text_chunks() {
+        let data = test_row(640);
+        let mut header = Header::new();
+        header.set_size(640, 2).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        assert!(matches!(encoder.write_text("Title", "Test"), Err(Error::InvalidState(_))));
+        encoder.write_header(&header).unwrap();
+        encoder.write_text("Title", "Test").unwrap();
+        assert!(matches!(encoder.write_text("", "Test"), Err(Error::InvalidText(_))));
+        encoder.write_image_rows(&data).unwrap();
+        assert!(matches!(encoder.write_text("Comment", "Test"), Err(Error::InvalidState(_))));
+        encoder.write_image_rows(&data).unwrap();
+        encoder.flush().unwrap();
+        encoder.write_compressed_text("Comment", "Test").unwrap();
+        let output = encoder.finish().unwrap();
+
+        assert_eq!(check_chunks(&output), 5);
+        assert_eq!(&output[37 .. 41], b"tEXt");
+    }
 
-                func(&mut encoder, &data)?;
-                encoder.finish()
-            }()
-        } {
-            Ok(_writer) => {},
-            Err(e) => assert!(false, "Error {}", e),
-        }
+    #[test]
+    fn icc_profile() {
+        let mut profile = vec![0u8; 200];
+        profile[0 .. 4].copy_from_slice(&200u32.to_be_bytes());
+        profile[36 .. 40].copy_from_slice(b"acsp");
+        let mut header = Header::new();
+        header.set_size(640, 2).unwrap();
+        header.set_color(ColorType::IndexedColor, 8).unwrap();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        assert!(matches!(encoder.write_icc_profile("Profile", &profile), Err(Error::InvalidState(_))));
+        encoder.write_header(&header).unwrap();
+        encoder.write_icc_profile("Profile", &profile).unwrap();
+        encoder.write_palette(&[0; 6]).unwrap();
+        assert!(matches!(encoder.write_icc_profile("Profile", &profile), Err(Error::InvalidState(_))));
+        encoder.write_image_rows(&[0; 640 * 2]).unwrap();
+        let output = encoder.finish().unwrap();
+
+        assert_eq!(check_chunks(&output), 5);
+        assert_eq!(&output[37 .. 41], b"iCCP");
     }
 
     #[test]
-    fn create_and_state() {
-        test_encoder(1920, 1080, |encoder, data| {
+    fn image_limits() {
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        let options = Options::builder().max_pixels(640 * 479).build().unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        match encoder.write_header(&header) {
+            Err(Error::ImageTooLarge { pixels: 307200, max: 306560 }) => {},
+            e => panic!("Unexpected result: {:?}", e),
+        }
+        assert!(Options::builder().max_pixels(0).build().is_err());
 
-            assert_eq!(encoder.is_finished(), false);
-            assert_eq!(encoder.progress(), 0.0);
+        let options = Options::builder().max_pixels(640 * 480).build().unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
 
-            // We must finish out the file or it'll whinge.
-            for _y in 0 .. 1080 {
+        // Rows wider than a chunk get a chunk each, with none left empty.
+        let options = Options::builder().chunk_size(32768).build().unwrap();
+        let output = test_encoder_with(40000, 3, &options, |encoder, data| {
+            assert_eq!(encoder.chunks_total, 3);
+            for _y in 0 .. 3 {
                 encoder.write_image_rows(data)?;
             }
-
             Ok(())
         });
+        check_chunks(&output);
     }
 
     #[test]
-    fn test_rows() {
-        test_encoder(1920, 1080, |encoder, data| {
-            assert_eq!(encoder.is_finished(), false);
-            assert_eq!(encoder.progress(), 0.0);
+    fn row_count_mismatch() {
+        let mut header = Header::new();
+        header.set_size(640, 4).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let pixels: Vec<u8> = (0 .. 4).flat_map(|_y| test_row(640)).collect();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        match encoder.write_image_rows(&pixels[.. 1000]) {
+            Err(Error::PartialRow { bytes: 1000, stride: 1920 }) => {},
+            e => panic!("Unexpected result: {:?}", e),
+        }
+        encoder.write_image_rows(&pixels[.. 1920 * 3]).unwrap();
+        match encoder.write_image_rows(&pixels) {
+            Err(Error::RowCountMismatch { rows: 7, height: 4 }) => {},
+            e => panic!("Unexpected result: {:?}", e),
+        }
+        match encoder.finish() {
+            Err(Error::RowCountMismatch { rows: 3, height: 4 }) => {},
+            e => panic!("Unexpected result: {:?}", e.map(|_| ())),
+        }
 
-            for _y in 0 .. 1080 {
-                encoder.write_image_rows(data)?;
-            }
+        // Extra bytes through the Write trait are caught before buffering.
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        encoder.write_all(&pixels).unwrap();
+        assert!(encoder.write(&[0]).is_err());
+        encoder.finish().unwrap();
+    }
 
-            // Should trigger all blocks!
-            encoder.flush()?;
-            assert_eq!(encoder.is_finished(), true);
-            assert_eq!(encoder.progress(), 1.0);
+    #[cfg(feature="serde")]
+    #[test]
+    fn serde_options() {
+        let options = Options::builder()
+            .preset(Preset::Smallest)
+            .streaming(true)
+            .deadline(Duration::from_millis(1500))
+            .build()
+            .unwrap();
+        let json = serde_json::to_string(&options).unwrap();
+        let parsed: Options = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+
+        // Missing fields get the defaults, and values are checked.
+        let parsed: Options = serde_json::from_str(r#"{"compression_level": "High"}"#).unwrap();
+        assert!(matches!(parsed.compression_level, CompressionLevel::High));
+        assert_eq!(parsed.chunk_size, Options::new().chunk_size);
+        assert!(serde_json::from_str::<Options>(r#"{"chunk_size": 1}"#).is_err());
+
+        let header: Header = serde_json::from_str(
+            r#"{"width": 640, "height": 480, "color_type": "IndexedColor", "depth": 4}"#).unwrap();
+        assert_eq!(header.stride(), 320);
+        assert!(serde_json::from_str::<Header>(r#"{"color_type": "IndexedColor", "depth": 16}"#).is_err());
+    }
 
-            Ok(())
-        });
+    #[test]
+    fn options_builder() {
+        let options = Options::builder()
+            .preset(Preset::Smallest)
+            .streaming(true)
+            .idat_size(8192)
+            .build()
+            .unwrap();
+        assert!(options.streaming);
+        assert_eq!(options.idat_size, Some(8192));
+        assert_eq!(options.chunk_size, Options::preset(Preset::Smallest).chunk_size);
+
+        // The first bad value is reported.
+        match Options::builder().chunk_size(1).idat_size(0).build() {
+            Ok(_) => panic!("Expected an error"),
+            Err(e) => assert!(e.to_string().contains("chunk size")),
+        }
+
+        assert!(Options::builder()
+            .deadline_action(DeadlineAction::Downgrade)
+            .build()
+            .is_err());
+        assert!(Options::builder()
+            .deadline(Duration::from_secs(1))
+            .deadline_action(DeadlineAction::Downgrade)
+            .build()
+            .is_ok());
+
+        // Presets all produce valid images.
+        for &preset in &[Preset::Fastest, Preset::Balanced, Preset::Smallest] {
+            let output = test_encoder_with(64, 64, &Options::preset(preset), |encoder, data| {
+                for _y in 0 .. 64 {
+                    encoder.write_image_rows(data)?;
+                }
+                Ok(())
+            });
+            assert!(check_chunks(&output) > 0);
+        }
     }
 }
@@ -0,0 +1,223 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// error.rs - error type returned from the public API
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use alloc::string::String;
+
+use core::fmt;
+use core::result;
+
+#[cfg(feature="std")]
+use std::error;
+#[cfg(feature="std")]
+use std::io;
+
+/// Errors returned by mtpng.
+///
+/// Most variants carry a short description of what was wrong, for
+/// display; match on the variant to handle a class of error. Which
+/// variants exist depends on features, and more may be added, so a
+/// match needs a wildcard arm.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Image header fields are invalid or inconsistent.
+    InvalidHeader(&'static str),
+
+    /// Image has more pixels than the limit set in the options.
+    ImageTooLarge {
+        /// Number of pixels in the image.
+        pixels: u64,
+        /// Maximum number of pixels allowed.
+        max: u64,
+    },
+
+    /// An encoder option is out of range or inconsistent.
+    InvalidOptions(&'static str),
+
+    /// Palette data is malformed.
+    InvalidPalette(&'static str),
+
+    /// Palette has more entries than the image's bit depth can index.
+    PaletteTooLarge {
+        /// Number of entries in the palette given.
+        entries: usize,
+        /// Maximum number of entries allowed.
+        max: usize,
+    },
+
+    /// Transparency data is malformed or doesn't fit the color type.
+    InvalidTransparency(&'static str),
+
+    /// A text chunk's keyword or text breaks the encoding rules
+    /// for PNG text.
+    InvalidText(&'static str),
+
+    /// A chunk tag or payload can't be written.
+    InvalidChunk(&'static str),
+
+    /// Calls were made out of order, such as writing image data
+    /// before the header.
+    InvalidState(&'static str),
+
+    /// Image data doesn't match the size given in the header.
+    RowDataMismatch(&'static str),
+
+    /// Image data ended partway through a row.
+    PartialRow {
+        /// Number of bytes past the last whole row.
+        bytes: usize,
+        /// Number of bytes in a row.
+        stride: usize,
+    },
+
+    /// More or fewer rows were written than the header's height.
+    RowCountMismatch {
+        /// Number of rows written, or that would have been.
+        rows: usize,
+        /// Number of rows in the image.
+        height: usize,
+    },
+
+    /// Output didn't pass the checks enabled with Options::set_verify().
+    VerificationFailed(&'static str),
+
+    /// A filter or compression job failed on a worker thread, or
+    /// inline without the `std` feature.
+    WorkerFailed(String),
+
+    /// The encode was cancelled through its CancelToken.
+    Cancelled,
+
+    /// The encode ran past its deadline.
+    TimedOut,
+
+    /// I/O error from the output, or other system failure.
+    #[cfg(feature="std")]
+    Io(io::Error),
+}
+
+/// Result type returned by mtpng.
+pub type Result<T, E = Error> = result::Result<T, E>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidHeader(msg) |
+            Error::InvalidOptions(msg) |
+            Error::InvalidPalette(msg) |
+            Error::InvalidTransparency(msg) |
+            Error::InvalidText(msg) |
+            Error::InvalidChunk(msg) |
+            Error::InvalidState(msg) |
+            Error::RowDataMismatch(msg) |
+            Error::VerificationFailed(msg) => f.write_str(msg),
+            Error::WorkerFailed(msg) => f.write_str(msg),
+            Error::PaletteTooLarge { entries, max } =>
+                write!(f, "Palette has {} entries, but at most {} are allowed", entries, max),
+            Error::ImageTooLarge { pixels, max } =>
+                write!(f, "Image has {} pixels, but at most {} are allowed", pixels, max),
+            Error::PartialRow { bytes, stride } =>
+                write!(f, "Image data ends {} bytes into a {}-byte row", bytes, stride),
+            Error::RowCountMismatch { rows, height } =>
+                write!(f, "Image has {} rows, but {} were written", height, rows),
+            Error::Cancelled => f.write_str("Encoding cancelled"),
+            Error::TimedOut => f.write_str("Encoding deadline exceeded"),
+            #[cfg(feature="std")]
+            Error::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature="std")]
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature="std")]
+impl Error {
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::WorkerFailed(_) | Error::VerificationFailed(_) | Error::Cancelled => io::ErrorKind::Other,
+            Error::TimedOut => io::ErrorKind::TimedOut,
+            Error::Io(e) => e.kind(),
+            _ => io::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+//
+// Errors pass through io::Error where they have to go through
+// Write implementations and the like; they're wrapped whole so
+// they come back out as the same variant.
+//
+#[cfg(feature="std")]
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            *e.into_inner().unwrap().downcast::<Error>().unwrap()
+        } else {
+            Error::Io(e)
+        }
+    }
+}
+
+#[cfg(feature="std")]
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+#[cfg(all(test, feature="std"))]
+mod tests {
+    use std::io;
+
+    use super::Error;
+
+    #[test]
+    fn round_trip() {
+        let e = io::Error::from(Error::InvalidHeader("bad"));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        match Error::from(e) {
+            Error::InvalidHeader("bad") => {},
+            e => panic!("Unexpected error: {:?}", e),
+        }
+
+        let e = Error::from(io::Error::from(io::ErrorKind::BrokenPipe));
+        match e {
+            Error::Io(ref e) => assert_eq!(e.kind(), io::ErrorKind::BrokenPipe),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::BrokenPipe);
+    }
+}
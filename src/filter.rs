@@ -23,18 +23,26 @@
 // THE SOFTWARE.
 //
 
-use std::cmp;
-use std::convert::TryFrom;
-use std::io;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use core::cmp;
+use core::convert::TryFrom;
 
 use super::Header;
 use super::Mode;
 use super::Mode::{Adaptive, Fixed};
 
-use super::utils::invalid_input;
+use super::Error;
 
+/// PNG filter type, applied to each row before compression.
+///
+/// Matches on it need a wildcard arm, in case more filters
+/// are added in future versions.
 #[repr(u8)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum Filter {
     None = 0,
     Sub = 1,
@@ -44,7 +52,7 @@ pub enum Filter {
 }
 
 impl TryFrom<u8> for Filter {
-    type Error = io::Error;
+    type Error = Error;
 
     fn try_from(val: u8) -> Result<Self, Self::Error> {
         match val {
@@ -53,7 +61,7 @@ impl TryFrom<u8> for Filter {
             2 => Ok(Filter::Up),
             3 => Ok(Filter::Average),
             4 => Ok(Filter::Paeth),
-            _ => Err(invalid_input("Invalid type constant")),
+            _ => Err(Error::InvalidOptions("Invalid type constant")),
         }
     }
 }
@@ -215,7 +223,7 @@ fn filter_complexity_delta(val: u8) -> u32 {
 // without overflow.
 //
 fn complexity_max() -> u32 {
-    u32::max_value() - 256
+    u32::MAX - 256
 }
 
 //
@@ -297,38 +305,42 @@ impl Filterator {
         &self.data
     }
 
-    #[cfg(target_arch = "x86")]
+    #[cfg(all(feature = "std", target_arch = "x86"))]
     #[target_feature(enable = "sse2")]
     unsafe fn do_filter_sse2(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
         self.do_filter(prev, src)
     }
 
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
     #[target_feature(enable = "sse4.1")]
     unsafe fn do_filter_sse41(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
         self.do_filter(prev, src)
     }
 
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
     #[target_feature(enable = "sse4.2")]
     unsafe fn do_filter_sse42(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
         self.do_filter(prev, src)
     }
 
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
     #[target_feature(enable = "avx")]
     unsafe fn do_filter_avx(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
         self.do_filter(prev, src)
     }
 
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
     #[target_feature(enable = "avx2")]
     unsafe fn do_filter_avx2(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
         self.do_filter(prev, src)
     }
 
+    //
+    // Picking the best instruction set at runtime needs std; without
+    // it, only what the target enables at compile time is used.
+    //
     fn filter(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
-        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
         {
             if is_x86_feature_detected!("avx2") {
                 return unsafe {
@@ -351,7 +363,7 @@ impl Filterator {
                 };
             }
         }
-        #[cfg(target_arch = "x86")]
+        #[cfg(all(feature = "std", target_arch = "x86"))]
         {
             // SSE2 is guaranteed on x86_64
             // but may not be present on x86
@@ -447,6 +459,8 @@ impl AdaptiveFilter {
 
 #[cfg(test)]
 mod tests {
+    use alloc::vec;
+
     use super::AdaptiveFilter;
     use super::Mode;
     use super::super::Header;
@@ -0,0 +1,240 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// java.rs - JNI bindings for Android and the JVM
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//
+// Native methods for the org.mtpng.Mtpng class in java/, with the
+// `java` feature. The Java side unpacks the options, so these only
+// take primitives and strings.
+//
+
+use jni::JNIEnv;
+use jni::objects::{JByteBuffer, JClass, JIntArray, JObject, JString};
+use jni::sys::{jboolean, jbyteArray, jint, JNI_TRUE};
+
+use std::convert::TryFrom;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+
+use super::ColorType;
+use super::Error;
+use super::bindings;
+use super::encoder::{encode_to_vec, Options};
+
+enum Failure {
+    Mtpng(Error),
+    Jni(jni::errors::Error),
+}
+
+impl From<Error> for Failure {
+    fn from(err: Error) -> Failure {
+        Failure::Mtpng(err)
+    }
+}
+
+impl From<jni::errors::Error> for Failure {
+    fn from(err: jni::errors::Error) -> Failure {
+        Failure::Jni(err)
+    }
+}
+
+type Result<T> = std::result::Result<T, Failure>;
+
+//
+// Run a native method's body, turning errors and panics into Java
+// exceptions, and returning null for them.
+//
+fn call<F>(env: &mut JNIEnv, body: F) -> jbyteArray
+    where F: FnOnce(&mut JNIEnv) -> Result<Vec<u8>>
+{
+    let result = panic::catch_unwind(AssertUnwindSafe(|| -> Result<jbyteArray> {
+        let data = body(env)?;
+        Ok(env.byte_array_from_slice(&data)?.into_raw())
+    }));
+    let (class, message) = match result {
+        Ok(Ok(array)) => return array,
+        Ok(Err(Failure::Mtpng(err))) => {
+            let class = match err {
+                Error::Io(_) | Error::Cancelled | Error::TimedOut |
+                Error::WorkerFailed(_) | Error::VerificationFailed(_) => "java/lang/RuntimeException",
+                _ => "java/lang/IllegalArgumentException",
+            };
+            (class, err.to_string())
+        },
+        Ok(Err(Failure::Jni(err))) => ("java/lang/RuntimeException", err.to_string()),
+        Err(_) => ("java/lang/RuntimeException", "mtpng panicked".to_string()),
+    };
+
+    // A failed JNI call has usually thrown already.
+    if !env.exception_check().unwrap_or(false) {
+        let _ = env.throw_new(class, message);
+    }
+    JObject::null().into_raw()
+}
+
+fn string(env: &mut JNIEnv, s: &JString) -> Result<Option<String>> {
+    if s.is_null() {
+        Ok(None)
+    } else {
+        Ok(Some(env.get_string(s)?.into()))
+    }
+}
+
+// Zero or less for any of the numbers means the default.
+#[allow(clippy::too_many_arguments)]
+fn options(env: &mut JNIEnv,
+           level: jint,
+           level_name: &JString,
+           filter: &JString,
+           strategy: &JString,
+           chunk_size: jint,
+           threads: jint,
+           streaming: jboolean)
+    -> Result<Options<'static>>
+{
+    let level_name = string(env, level_name)?;
+    let level = match (level, &level_name) {
+        (_, Some(name)) => Some(bindings::Level::Name(name)),
+        (n, None) if n > 0 => Some(bindings::Level::Number(i64::from(n))),
+        _ => None,
+    };
+    let filter = string(env, filter)?;
+    let strategy = string(env, strategy)?;
+    let positive = |n: jint| if n > 0 { Some(n as usize) } else { None };
+    Ok(bindings::options(level,
+                         filter.as_deref(),
+                         strategy.as_deref(),
+                         positive(chunk_size),
+                         positive(threads),
+                         streaming == JNI_TRUE)?)
+}
+
+//
+// Unpack Java's ARGB ints, as from Bitmap.getPixels() or
+// BufferedImage.getRGB(), into RGBA bytes, or RGB if they're
+// all opaque.
+//
+fn unpack_argb(argb: &[jint]) -> (ColorType, Vec<u8>) {
+    if argb.iter().all(|&p| (p as u32) >> 24 == 0xff) {
+        let rgb = argb.iter().flat_map(|&p| {
+            let [_, r, g, b] = (p as u32).to_be_bytes();
+            [r, g, b]
+        }).collect();
+        (ColorType::Truecolor, rgb)
+    } else {
+        let rgba = argb.iter().flat_map(|&p| {
+            let [a, r, g, b] = (p as u32).to_be_bytes();
+            [r, g, b, a]
+        }).collect();
+        (ColorType::TruecolorAlpha, rgba)
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "system"
+fn Java_org_mtpng_Mtpng_nativeEncode<'local>(mut env: JNIEnv<'local>,
+                                             _class: JClass<'local>,
+                                             pixels: JByteBuffer<'local>,
+                                             offset: jint,
+                                             length: jint,
+                                             width: jint,
+                                             height: jint,
+                                             color_type: jint,
+                                             depth: jint,
+                                             level: jint,
+                                             level_name: JString<'local>,
+                                             filter: JString<'local>,
+                                             strategy: JString<'local>,
+                                             chunk_size: jint,
+                                             threads: jint,
+                                             streaming: jboolean)
+    -> jbyteArray
+{
+    call(&mut env, |env| {
+        let color_type = u8::try_from(color_type).ok()
+            .and_then(|n| ColorType::try_from(n).ok())
+            .ok_or(Error::InvalidHeader("Invalid color type"))?;
+        let header = bindings::header(width, height, bindings::Color::Type(color_type), depth)?;
+        let options = options(env, level, &level_name, &filter, &strategy, chunk_size, threads, streaming)?;
+
+        let address = env.get_direct_buffer_address(&pixels)?;
+        let capacity = env.get_direct_buffer_capacity(&pixels)?;
+        let (offset, length) = match (usize::try_from(offset), usize::try_from(length)) {
+            (Ok(offset), Ok(length)) if offset.checked_add(length).is_some_and(|end| end <= capacity) => (offset, length),
+            _ => return Err(Error::RowDataMismatch("Pixel data is outside the buffer").into()),
+        };
+        // The Java side holds on to the buffer until this returns.
+        let data = unsafe {
+            std::slice::from_raw_parts(address.add(offset), length)
+        };
+        Ok(encode_to_vec(&header, data, &options)?)
+    })
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "system"
+fn Java_org_mtpng_Mtpng_nativeEncodeArgb<'local>(mut env: JNIEnv<'local>,
+                                                 _class: JClass<'local>,
+                                                 argb: JIntArray<'local>,
+                                                 width: jint,
+                                                 height: jint,
+                                                 level: jint,
+                                                 level_name: JString<'local>,
+                                                 filter: JString<'local>,
+                                                 strategy: JString<'local>,
+                                                 chunk_size: jint,
+                                                 threads: jint,
+                                                 streaming: jboolean)
+    -> jbyteArray
+{
+    call(&mut env, |env| {
+        let options = options(env, level, &level_name, &filter, &strategy, chunk_size, threads, streaming)?;
+
+        let length = env.get_array_length(&argb)?;
+        let mut pixels = vec![0; length as usize];
+        env.get_int_array_region(&argb, 0, &mut pixels)?;
+        let (color_type, data) = unpack_argb(&pixels);
+
+        let header = bindings::header(width, height, bindings::Color::Type(color_type), 8)?;
+        Ok(encode_to_vec(&header, &data, &options)?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unpack_argb;
+
+    #[test]
+    fn argb() {
+        let (color_type, data) = unpack_argb(&[0xff112233u32 as i32, 0xff445566u32 as i32]);
+        assert_eq!(color_type.channels(), 3);
+        assert_eq!(data, [0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+
+        let (color_type, data) = unpack_argb(&[0x80112233u32 as i32]);
+        assert_eq!(color_type.channels(), 4);
+        assert_eq!(data, [0x11, 0x22, 0x33, 0x80]);
+    }
+}
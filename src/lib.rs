@@ -24,33 +24,124 @@
 //
 
 //! mtpng - a multithreaded parallel PNG encoder in Rust
+//!
+//! Without the default `std` feature, the crate is `no_std` and
+//! needs only `alloc`; the serial module then encodes on the calling
+//! thread, with the `zlib-rs` feature for a pure-Rust deflate.
 
+#![cfg_attr(not(feature="std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature="std")]
 extern crate rayon;
+#[cfg(feature="std")]
+extern crate crossbeam_channel;
 extern crate crc;
+#[cfg(all(feature="zlib", not(any(feature="zlib-rs", target_arch="wasm32"))))]
 extern crate libz_sys;
+#[cfg(any(feature="zlib-rs", target_arch="wasm32"))]
+extern crate libz_rs_sys as libz_sys;
 #[macro_use] extern crate itertools;
 
+#[cfg(not(any(feature="zlib", feature="zlib-rs", target_arch="wasm32")))]
+compile_error!("mtpng needs a deflate backend; enable the zlib or zlib-rs feature");
+
+#[cfg(feature="bytes")]
+extern crate bytes;
+
+#[cfg(feature="tokio")]
+extern crate tokio;
+
+#[cfg(feature="futures")]
+extern crate futures_io;
+
+#[cfg(feature="serde")]
+extern crate serde;
+
+#[cfg(feature="tracing")]
+extern crate tracing;
+
+#[cfg(feature="log")]
+extern crate log;
+
 #[cfg(feature="capi")]
 extern crate libc;
 #[cfg(feature="capi")]
 pub mod capi;
 
+#[cfg(feature="python")]
+extern crate pyo3;
+#[cfg(feature="java")]
+extern crate jni;
+#[cfg(feature="wasm")]
+extern crate wasm_bindgen;
+#[cfg(any(feature="python", feature="java", feature="wasm", all(feature="node", not(test))))]
+mod bindings;
+#[cfg(feature="python")]
+mod python;
+// The Node addon's symbols only resolve once loaded into node,
+// so it's left out of test builds.
+#[cfg(all(feature="node", not(test)))]
+mod node;
+#[cfg(feature="java")]
+mod java;
+#[cfg(feature="wasm")]
+mod wasm;
+
+#[cfg(all(feature="wasm-threads", target_arch="wasm32"))]
+extern crate wasm_bindgen_rayon;
+
+#[macro_use] mod utils;
 mod deflate;
+mod error;
 mod filter;
+mod text;
+#[cfg(feature="std")]
 pub mod encoder;
-mod utils;
 mod writer;
+#[cfg(feature="std")]
+mod verify;
+pub mod serial;
+pub mod validate;
+pub mod convert;
+#[cfg(feature="std")]
+pub mod workers;
+#[cfg(feature="std")]
+pub mod buffer;
+#[cfg(feature="std")]
+pub mod simple;
+#[cfg(feature="std")]
+pub mod png_compat;
+#[cfg(all(feature="std", any(feature="tokio", feature="futures")))]
+pub mod async_encoder;
 
 pub type Strategy = deflate::Strategy;
 pub type Filter = filter::Filter;
 
-use std::convert::TryFrom;
-use std::io;
+pub use error::{Error, Result};
+#[cfg(feature="std")]
+pub use encoder::{encode_to_vec, encode_indexed_to_vec};
+pub use text::read_text_chunk;
 
-use utils::invalid_input;
+/// With the `wasm-threads` feature, start the global rayon pool on
+/// Web Workers sharing the module's memory. Call `initThreadPool(n)`
+/// from JavaScript and await it before encoding; the pool is then
+/// used as with Options::new(). Encoding blocks waiting on the
+/// workers, so run it in a worker itself rather than on the page's
+/// main thread.
+#[cfg(all(feature="wasm-threads", target_arch="wasm32"))]
+pub use wasm_bindgen_rayon::init_thread_pool;
+
+use core::convert::TryFrom;
 
 /// Wrapper for filter and compression modes.
-#[derive(Copy, Clone)]
+///
+/// More modes, such as other heuristics, may be added in future
+/// versions; matches on it need a wildcard arm.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum Mode<T> {
     /// Automatic selection based on file contents
     Adaptive,
@@ -59,7 +150,8 @@ pub enum Mode<T> {
 }
 
 /// PNG color types.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum ColorType {
     /// Single brightness channel.
@@ -76,7 +168,7 @@ pub enum ColorType {
 use ColorType::*;
 
 impl TryFrom<u8> for ColorType {
-    type Error = io::Error;
+    type Error = Error;
 
     /// Validate and produce a ColorType from one of the PNG header constants.
     ///
@@ -88,7 +180,7 @@ impl TryFrom<u8> for ColorType {
             3 => Ok(IndexedColor),
             4 => Ok(GreyscaleAlpha),
             6 => Ok(TruecolorAlpha),
-            _ => Err(invalid_input("Invalid color type")),
+            _ => Err(Error::InvalidHeader("Invalid color type")),
         }
     }
 }
@@ -105,6 +197,17 @@ impl ColorType {
         }
     }
 
+    // Explain which depths are valid, for error messages.
+    fn depth_error(self) -> &'static str {
+        match self {
+            Greyscale => "Greyscale images must have a bit depth of 1, 2, 4, 8, or 16",
+            Truecolor => "Truecolor images must have a bit depth of 8 or 16",
+            IndexedColor => "Indexed-color images must have a bit depth of 1, 2, 4, or 8",
+            GreyscaleAlpha => "Greyscale with alpha images must have a bit depth of 8 or 16",
+            TruecolorAlpha => "Truecolor with alpha images must have a bit depth of 8 or 16",
+        }
+    }
+
     /// Calculate the number of channels per pixel.
     pub fn channels(self) -> usize {
         match self {
@@ -121,6 +224,7 @@ impl ColorType {
 ///
 /// There is only one method defined, which is Deflate.
 #[derive(Copy, Clone)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum CompressionMethod {
     /// Use zlib deflate compression, the default.
@@ -131,6 +235,7 @@ pub enum CompressionMethod {
 ///
 /// Currently only Standard is supported. This may be expanded to support APNG in future.
 #[derive(Copy, Clone)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum FilterMethod {
     /// Use PNG standard filter types.
@@ -141,6 +246,7 @@ pub enum FilterMethod {
 ///
 /// Currently only Standard is supported; Adam7 interlacing will throw an error if used.
 #[derive(Copy, Clone)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum InterlaceMethod {
     /// No interlacing.
@@ -157,7 +263,12 @@ pub enum InterlaceMethod {
 ///
 /// You must create one of these with image metadata when encoding,
 /// and can reuse the header for multiple encodings if desired.
+///
+/// With the `serde` feature, headers can be serialized, and are
+/// validated as by Header::builder() when deserialized.
 #[derive(Copy, Clone)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature="serde", serde(try_from="HeaderFields"))]
 pub struct Header {
     width: u32,
     height: u32,
@@ -234,37 +345,58 @@ impl Header {
 
     /// Calculate the stride in bytes for the encoded pixel rows.
     ///
-    /// Will panic on arithmetic overflow if given pathologically long rows.
+    /// Will panic on arithmetic overflow if given pathologically long rows;
+    /// Encoder::write_header() and Header::builder() check for these.
     pub fn stride(&self) -> usize {
+        self.checked_stride().expect("Image rows are too long for this platform")
+    }
+
+    //
+    // Stride in bytes, rounded up to the nearest byte, or None if the
+    // row's bit count overflows usize, which can happen on 32-bit.
+    //
+    fn checked_stride(&self) -> Option<usize> {
         let bits_per_pixel = self.color_type.channels() * self.depth as usize;
+        let stride_bits = bits_per_pixel.checked_mul(self.width as usize)?;
+        Some(stride_bits.div_ceil(8))
+    }
 
-        // Very long line lengths can overflow usize on 32-bit.
-        // If we got this far, let it panic in the unwrap().
-        let stride_bits = bits_per_pixel.checked_mul(self.width as usize)
-                                        .unwrap();
+    //
+    // Check that the image's rows, and all of its filtered data with
+    // a filter byte per row, can be addressed on this platform.
+    // Everything else sized from the header fits within that.
+    //
+    pub(crate) fn check_size(&self) -> Result<()> {
+        let stride = self.checked_stride()
+            .ok_or(Error::InvalidHeader("Image rows are too long for this platform"))?;
+        stride.checked_add(1)
+            .and_then(|filtered| filtered.checked_mul(self.height as usize))
+            .ok_or(Error::InvalidHeader("Image data is too large for this platform"))?;
+        Ok(())
+    }
 
-        // And round up to nearest byte.
-        let stride_bytes = stride_bits >> 3;
-        let remainder = stride_bits & 3;
-        if remainder > 0 {
-            stride_bytes + 1
-        } else {
-            stride_bytes
-        }
+    #[cfg(feature="std")]
+    pub(crate) fn pixels(&self) -> u64 {
+        u64::from(self.width) * u64::from(self.height)
     }
 
     /// Set the pixel dimensions of the image.
     ///
-    /// Returns error if width or height are 0.
+    /// Returns error if width or height are 0, or above the PNG limit
+    /// of 2^31-1.
     ///
     /// Warning: it's possible to make combinations of width and color type
     /// that cannot fit in memory on 32-bit systems. These are not detected
-    /// here, but will panic when stride() is called.
-    pub fn set_size(&mut self, width: u32, height: u32) -> io::Result<()> {
+    /// here, but are reported by Encoder::write_header().
+    pub fn set_size(&mut self, width: u32, height: u32) -> Result<()> {
         if width == 0 {
-            Err(invalid_input("width cannot be 0"))
+            Err(Error::InvalidHeader("width cannot be 0"))
         } else if height == 0 {
-            Err(invalid_input("height canno tbe 0"))
+            Err(Error::InvalidHeader("height cannot be 0"))
+        } else if width > i32::MAX as u32 {
+            Err(Error::InvalidHeader("width cannot be above 2^31-1"))
+        } else if height > i32::MAX as u32 {
+            Err(Error::InvalidHeader("height cannot be above 2^31-1"))
         } else {
             self.width = width;
             self.height = height;
@@ -278,10 +410,10 @@ impl Header {
     ///
     /// Warning: it's possible to make combinations of width and color type
     /// that cannot fit in memory on 32-bit systems. These are not detected
-    /// here, but will panic when stride() is called.
-    pub fn set_color(&mut self, color_type: ColorType, depth: u8) -> io::Result<()> {
+    /// here, but are reported by Encoder::write_header().
+    pub fn set_color(&mut self, color_type: ColorType, depth: u8) -> Result<()> {
         if !color_type.is_depth_valid(depth) {
-            Err(invalid_input("invalid color depth for this color type"))
+            Err(Error::InvalidHeader(color_type.depth_error()))
         } else {
             self.color_type = color_type;
             self.depth = depth;
@@ -292,7 +424,7 @@ impl Header {
     /// Set the compression method.
     ///
     /// This is not very useful, as only deflate is supported.
-    pub fn set_compression_method(&mut self, compression_method: CompressionMethod) -> io::Result<()> {
+    pub fn set_compression_method(&mut self, compression_method: CompressionMethod) -> Result<()> {
         self.compression_method = compression_method;
         Ok(())
     }
@@ -300,7 +432,7 @@ impl Header {
     /// Set the filter method.
     ///
     /// Currently only Standard is supported.
-    pub fn set_filter_method(&mut self, filter_method: FilterMethod) -> io::Result<()> {
+    pub fn set_filter_method(&mut self, filter_method: FilterMethod) -> Result<()> {
         self.filter_method = filter_method;
         Ok(())
     }
@@ -308,10 +440,10 @@ impl Header {
     /// Set the interlace method.
     ///
     /// Currently only Standard is supported; requesting Adam7 will return an error.
-    pub fn set_interlace_method(&mut self, interlace_method: InterlaceMethod) -> io::Result<()> {
+    pub fn set_interlace_method(&mut self, interlace_method: InterlaceMethod) -> Result<()> {
         match interlace_method {
             InterlaceMethod::Standard => {},
-            InterlaceMethod::Adam7 => return Err(invalid_input("Adam7 interlacing not yet")),
+            InterlaceMethod::Adam7 => return Err(Error::InvalidHeader("Adam7 interlacing not yet")),
         }
         self.interlace_method = interlace_method;
         Ok(())
@@ -324,19 +456,164 @@ impl Default for Header {
     }
 }
 
-/// Representation of deflate compression level.
+impl Header {
+    /// Create a builder for a Header, starting from the same
+    /// defaults as Header::new().
+    pub fn builder() -> HeaderBuilder {
+        HeaderBuilder {
+            header: Header::new(),
+        }
+    }
+}
+
+//
+// Header fields as deserialized, before they're checked.
+// Any that are missing take the defaults from Header::new().
+//
+#[cfg(feature="serde")]
+#[derive(serde::Deserialize)]
+#[serde(default)]
+struct HeaderFields {
+    width: u32,
+    height: u32,
+    depth: u8,
+    color_type: ColorType,
+    compression_method: CompressionMethod,
+    filter_method: FilterMethod,
+    interlace_method: InterlaceMethod,
+}
+
+#[cfg(feature="serde")]
+impl Default for HeaderFields {
+    fn default() -> Self {
+        let header = Header::new();
+        HeaderFields {
+            width: header.width,
+            height: header.height,
+            depth: header.depth,
+            color_type: header.color_type,
+            compression_method: header.compression_method,
+            filter_method: header.filter_method,
+            interlace_method: header.interlace_method,
+        }
+    }
+}
+
+#[cfg(feature="serde")]
+impl TryFrom<HeaderFields> for Header {
+    type Error = Error;
+
+    fn try_from(fields: HeaderFields) -> Result<Header> {
+        Header::builder()
+            .size(fields.width, fields.height)
+            .color_type(fields.color_type)
+            .depth(fields.depth)
+            .compression_method(fields.compression_method)
+            .filter_method(fields.filter_method)
+            .interlace_method(fields.interlace_method)
+            .build()
+    }
+}
+
+/// Chainable builder for Header, as an alternative to the setters.
+///
+/// Values are only checked in build(), so the color type and depth
+/// can be given in either order. All combinations are validated
+/// against the PNG standard before any encoding starts.
+///
+/// ```
+/// # fn main() -> mtpng::Result<()> {
+/// use mtpng::{ColorType, Header};
+///
+/// let header = Header::builder()
+///     .size(640, 480)
+///     .color_type(ColorType::IndexedColor)
+///     .depth(4)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
 #[derive(Copy, Clone)]
+pub struct HeaderBuilder {
+    header: Header,
+}
+
+impl HeaderBuilder {
+    /// Set the pixel dimensions of the image.
+    pub fn size(mut self, width: u32, height: u32) -> Self {
+        self.header.width = width;
+        self.header.height = height;
+        self
+    }
+
+    /// Set the color type of the image.
+    pub fn color_type(mut self, color_type: ColorType) -> Self {
+        self.header.color_type = color_type;
+        self
+    }
+
+    /// Set the color depth of the image in bits.
+    pub fn depth(mut self, depth: u8) -> Self {
+        self.header.depth = depth;
+        self
+    }
+
+    /// Set the compression method.
+    pub fn compression_method(mut self, compression_method: CompressionMethod) -> Self {
+        self.header.compression_method = compression_method;
+        self
+    }
+
+    /// Set the filter method.
+    pub fn filter_method(mut self, filter_method: FilterMethod) -> Self {
+        self.header.filter_method = filter_method;
+        self
+    }
+
+    /// Set the interlace method.
+    pub fn interlace_method(mut self, interlace_method: InterlaceMethod) -> Self {
+        self.header.interlace_method = interlace_method;
+        self
+    }
+
+    /// Check the header and return it, or the first error found.
+    pub fn build(self) -> Result<Header> {
+        let fields = self.header;
+        let mut header = Header::new();
+        header.set_size(fields.width, fields.height)?;
+        header.set_color(fields.color_type, fields.depth)?;
+        header.set_compression_method(fields.compression_method)?;
+        header.set_filter_method(fields.filter_method)?;
+        header.set_interlace_method(fields.interlace_method)?;
+
+        // Catch images too large to address up front, instead of
+        // panicking in stride() later.
+        header.check_size()?;
+
+        Ok(header)
+    }
+}
+
+/// Representation of deflate compression level.
+///
+/// More levels may be added in future versions; matches on it
+/// need a wildcard arm.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum CompressionLevel {
     /// Fast but poor compression (zlib level 1).
     Fast,
     /// Good balance of speed and compression (zlib level 6).
     Default,
     /// Best compression but slow (zlib level 9).
-    High
+    High,
+    /// Any zlib level from 1 to 9, for tuning between the others.
+    Level(u8),
 }
 
 impl TryFrom<u8> for CompressionLevel {
-    type Error = io::Error;
+    type Error = Error;
 
     /// Validate and convert u8 to CompressionLevel.
     ///
@@ -346,7 +623,67 @@ impl TryFrom<u8> for CompressionLevel {
             1 => Ok(CompressionLevel::Fast),
             6 => Ok(CompressionLevel::Default),
             9 => Ok(CompressionLevel::High),
-            _ => Err(invalid_input("Compression level not supported")),
+            2 ..= 8 => Ok(CompressionLevel::Level(val)),
+            _ => Err(Error::InvalidOptions("Compression level not supported")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ColorType, Error, Header, InterlaceMethod};
+
+    #[test]
+    fn header_builder() {
+        let header = Header::builder()
+            .depth(16)
+            .color_type(ColorType::Greyscale)
+            .size(640, 480)
+            .build()
+            .unwrap();
+        assert_eq!(header.width(), 640);
+        assert_eq!(header.depth(), 16);
+
+        let valid = [
+            (ColorType::Greyscale, &[1, 2, 4, 8, 16][..]),
+            (ColorType::Truecolor, &[8, 16][..]),
+            (ColorType::IndexedColor, &[1, 2, 4, 8][..]),
+            (ColorType::GreyscaleAlpha, &[8, 16][..]),
+            (ColorType::TruecolorAlpha, &[8, 16][..]),
+        ];
+        for &(color_type, depths) in valid.iter() {
+            for depth in 0 ..= 32 {
+                let result = Header::builder()
+                    .color_type(color_type)
+                    .depth(depth)
+                    .build();
+                match result {
+                    Ok(_) => assert!(depths.contains(&depth)),
+                    Err(Error::InvalidHeader(_)) => assert!(!depths.contains(&depth)),
+                    Err(e) => panic!("Unexpected error {}", e),
+                }
+            }
+        }
+
+        assert!(Header::builder().size(0, 1).build().is_err());
+        assert!(Header::builder().interlace_method(InterlaceMethod::Adam7).build().is_err());
+        assert!(Header::builder().size(1 << 31, 1).build().is_err());
+        assert!(Header::builder().size(1, 1 << 31).build().is_err());
+    }
+
+    #[test]
+    fn stride() {
+        // Partial bytes at the end of rows are rounded up.
+        for &(width, depth, stride) in &[(1, 1, 1), (8, 1, 1), (9, 1, 2),
+                                        (1, 4, 1), (3, 4, 2), (4, 4, 2),
+                                        (1, 16, 2), (3, 8, 3)] {
+            let header = Header::builder()
+                .size(width, 1)
+                .color_type(ColorType::Greyscale)
+                .depth(depth)
+                .build()
+                .unwrap();
+            assert_eq!(header.stride(), stride, "width {} depth {}", width, depth);
         }
     }
 }
@@ -0,0 +1,134 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// node.rs - Node.js bindings
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Node.js bindings, as a native addon built by napi-rs with the
+//! `node` feature.
+//!
+//! ```js
+//! const mtpng = require('mtpng');
+//!
+//! const pixels = Buffer.alloc(640 * 480 * 4);
+//! const png = await mtpng.encode(pixels, { width: 640, height: 480, level: 9 });
+//! ```
+
+use napi::bindgen_prelude::{AsyncTask, Buffer, Either};
+use napi::{Env, Status, Task};
+use napi_derive::napi;
+
+use super::Header;
+use super::{Error, Result};
+use super::bindings;
+use super::encoder::{encode_to_vec, Options};
+
+fn napi_error(err: Error) -> napi::Error {
+    let status = match err {
+        Error::Io(_) | Error::Cancelled | Error::TimedOut |
+        Error::WorkerFailed(_) | Error::VerificationFailed(_) => Status::GenericFailure,
+        _ => Status::InvalidArg,
+    };
+    napi::Error::new(status, err.to_string())
+}
+
+/// Image layout and encoder options for encode().
+#[napi(object)]
+pub struct EncodeOptions {
+    pub width: u32,
+    pub height: u32,
+    /// "gray", "graya", "rgb", or "rgba"; defaults to "rgba".
+    pub color_type: Option<String>,
+    /// 8 or 16, or less for gray; defaults to 8.
+    pub depth: Option<u32>,
+    /// 1-9, "fast", "default", or "high".
+    pub level: Option<Either<u32, String>>,
+    /// "adaptive", "none", "sub", "up", "average", or "paeth".
+    pub filter: Option<String>,
+    /// "auto", "default", "filtered", "huffman", "rle", or "fixed".
+    pub strategy: Option<String>,
+    pub chunk_size: Option<u32>,
+    /// Most threads of the shared pool for one encode to use.
+    pub threads: Option<u32>,
+    pub streaming: Option<bool>,
+}
+
+fn header(options: &EncodeOptions) -> Result<Header> {
+    bindings::header(options.width,
+                     options.height,
+                     bindings::Color::Name(options.color_type.as_deref()),
+                     options.depth.unwrap_or(8))
+}
+
+fn encoder_options(options: &EncodeOptions) -> Result<Options<'static>> {
+    let level = match &options.level {
+        Some(Either::A(n)) => Some(bindings::Level::Number(i64::from(*n))),
+        Some(Either::B(name)) => Some(bindings::Level::Name(name)),
+        None => None,
+    };
+    bindings::options(level,
+                      options.filter.as_deref(),
+                      options.strategy.as_deref(),
+                      options.chunk_size.map(|n| n as usize),
+                      options.threads.map(|n| n as usize),
+                      options.streaming.unwrap_or(false))
+}
+
+//
+// Encodes on a libuv worker thread, which hands the chunks out to
+// the rayon pool and waits for them, keeping the event loop free.
+// The encoder mustn't wait on a rayon thread itself, as jobs queued
+// behind it could starve.
+//
+pub struct EncodeTask {
+    pixels: Buffer,
+    header: Header,
+    options: Options<'static>,
+}
+
+impl Task for EncodeTask {
+    type Output = Vec<u8>;
+    type JsValue = Buffer;
+
+    fn compute(&mut self) -> napi::Result<Vec<u8>> {
+        encode_to_vec(&self.header, &self.pixels, &self.options).map_err(napi_error)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Vec<u8>) -> napi::Result<Buffer> {
+        Ok(output.into())
+    }
+}
+
+/// Encode raw pixels to PNG file data, resolving to a Buffer.
+///
+/// Pixels are packed rows of the given width, height, color type,
+/// and depth, with 16-bit samples big-endian as in PNG.
+#[napi(ts_return_type = "Promise<Buffer>")]
+pub fn encode(pixels: Buffer, options: EncodeOptions) -> napi::Result<AsyncTask<EncodeTask>> {
+    let header = header(&options).map_err(napi_error)?;
+    let options = encoder_options(&options).map_err(napi_error)?;
+    Ok(AsyncTask::new(EncodeTask {
+        pixels,
+        header,
+        options,
+    }))
+}
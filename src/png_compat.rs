@@ -0,0 +1,326 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// png_compat.rs - encoder API shim compatible with the png crate
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! An encoder with the same method names and behavior as the
+//! [png](https://crates.io/crates/png) crate's `Encoder` and `Writer`,
+//! so existing code can switch to parallel encoding by changing
+//! its import:
+//!
+//! ```
+//! use mtpng::png_compat as png;
+//!
+//! # fn main() -> mtpng::Result<()> {
+//! # let pixels = vec![0u8; 640 * 480 * 4];
+//! let mut output = Vec::<u8>::new();
+//! let mut encoder = png::Encoder::new(&mut output, 640, 480);
+//! encoder.set_color(png::ColorType::Rgba);
+//! encoder.set_depth(png::BitDepth::Eight);
+//! let mut writer = encoder.write_header()?;
+//! writer.write_image_data(&pixels)?;
+//! writer.finish()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Filter and compression settings map onto mtpng's Options; unless
+//! set, mtpng's defaults are used, including adaptive filtering.
+
+use std::borrow::Cow;
+use std::io::Write;
+
+use super::{Error, Result};
+use super::Header;
+use super::Mode::{Adaptive, Fixed};
+use super::encoder;
+use super::encoder::Options;
+
+/// Color type of the image, as in png::ColorType.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorType {
+    /// Single brightness channel.
+    Grayscale,
+    /// Red, green, and blue channels.
+    Rgb,
+    /// Single channel of palette indices.
+    Indexed,
+    /// Brightness and alpha channels.
+    GrayscaleAlpha,
+    /// Red, green, blue, and alpha channels.
+    Rgba,
+}
+
+impl From<ColorType> for super::ColorType {
+    fn from(color_type: ColorType) -> super::ColorType {
+        match color_type {
+            ColorType::Grayscale => super::ColorType::Greyscale,
+            ColorType::Rgb => super::ColorType::Truecolor,
+            ColorType::Indexed => super::ColorType::IndexedColor,
+            ColorType::GrayscaleAlpha => super::ColorType::GreyscaleAlpha,
+            ColorType::Rgba => super::ColorType::TruecolorAlpha,
+        }
+    }
+}
+
+/// Bits per channel, as in png::BitDepth.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BitDepth {
+    One = 1,
+    Two = 2,
+    Four = 4,
+    Eight = 8,
+    Sixteen = 16,
+}
+
+/// Compression level, as in png::Compression.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// The default level.
+    Default,
+    /// Fast but poor compression.
+    Fast,
+    /// Best compression but slow.
+    Best,
+}
+
+impl From<Compression> for super::CompressionLevel {
+    fn from(compression: Compression) -> super::CompressionLevel {
+        match compression {
+            Compression::Default => super::CompressionLevel::Default,
+            Compression::Fast => super::CompressionLevel::Fast,
+            Compression::Best => super::CompressionLevel::High,
+        }
+    }
+}
+
+/// Filter type, as in png::FilterType. Used for every row
+/// unless adaptive filtering is on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FilterType {
+    NoFilter,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+}
+
+impl From<FilterType> for super::Filter {
+    fn from(filter: FilterType) -> super::Filter {
+        match filter {
+            FilterType::NoFilter => super::Filter::None,
+            FilterType::Sub => super::Filter::Sub,
+            FilterType::Up => super::Filter::Up,
+            FilterType::Avg => super::Filter::Average,
+            FilterType::Paeth => super::Filter::Paeth,
+        }
+    }
+}
+
+/// Whether to pick a filter per row, as in png::AdaptiveFilterType.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AdaptiveFilterType {
+    Adaptive,
+    NonAdaptive,
+}
+
+/// A chunk tag, as in png::chunk::ChunkType.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkType(pub [u8; 4]);
+
+/// Sets up the image before writing, as png::Encoder does.
+pub struct Encoder<'a, W: Write> {
+    write: W,
+    width: u32,
+    height: u32,
+    color_type: ColorType,
+    depth: BitDepth,
+    compression: Option<Compression>,
+    filter: Option<FilterType>,
+    adaptive_filter: Option<AdaptiveFilterType>,
+    palette: Option<Cow<'a, [u8]>>,
+    trns: Option<Cow<'a, [u8]>>,
+    options: Options<'a>,
+}
+
+impl<'a, W: Write> Encoder<'a, W> {
+    /// Create an encoder for an image of the given size, defaulting
+    /// to 8-bit greyscale like png::Encoder.
+    pub fn new(write: W, width: u32, height: u32) -> Encoder<'a, W> {
+        Encoder {
+            write,
+            width,
+            height,
+            color_type: ColorType::Grayscale,
+            depth: BitDepth::Eight,
+            compression: None,
+            filter: None,
+            adaptive_filter: None,
+            palette: None,
+            trns: None,
+            options: Options::new(),
+        }
+    }
+
+    /// Set the color type.
+    pub fn set_color(&mut self, color_type: ColorType) {
+        self.color_type = color_type;
+    }
+
+    /// Set the bit depth.
+    pub fn set_depth(&mut self, depth: BitDepth) {
+        self.depth = depth;
+    }
+
+    /// Set the compression level.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = Some(compression);
+    }
+
+    /// Set the filter type to use, when not filtering adaptively.
+    pub fn set_filter(&mut self, filter: FilterType) {
+        self.filter = Some(filter);
+    }
+
+    /// Set whether to pick the filter adaptively for each row.
+    pub fn set_adaptive_filter(&mut self, adaptive_filter: AdaptiveFilterType) {
+        self.adaptive_filter = Some(adaptive_filter);
+    }
+
+    /// Set the palette, as packed RGB triples.
+    pub fn set_palette<T: Into<Cow<'a, [u8]>>>(&mut self, palette: T) {
+        self.palette = Some(palette.into());
+    }
+
+    /// Set the transparency data, which for indexed color is an
+    /// alpha value per palette entry.
+    pub fn set_trns<T: Into<Cow<'a, [u8]>>>(&mut self, trns: T) {
+        self.trns = Some(trns.into());
+    }
+
+    /// Use the given mtpng Options, for settings such as the thread
+    /// pool and chunk size that png::Encoder doesn't have. Filter and
+    /// compression settings made on this encoder take precedence.
+    pub fn set_options(&mut self, options: &Options<'a>) {
+        self.options = *options;
+    }
+
+    /// Write the PNG signature, header, and any palette and
+    /// transparency chunks, returning a Writer for the image data.
+    pub fn write_header(self) -> Result<Writer<'a, W>> {
+        let mut header = Header::new();
+        header.set_size(self.width, self.height)?;
+        header.set_color(self.color_type.into(), self.depth as u8)?;
+
+        let mut options = self.options;
+        if let Some(compression) = self.compression {
+            options.set_compression_level(compression.into())?;
+        }
+        let filter_mode = match (self.adaptive_filter, self.filter) {
+            (Some(AdaptiveFilterType::Adaptive), _) => Some(Adaptive),
+            (_, Some(filter)) => Some(Fixed(filter.into())),
+            (Some(AdaptiveFilterType::NonAdaptive), None) => Some(Fixed(FilterType::Sub.into())),
+            (None, None) => None,
+        };
+        if let Some(filter_mode) = filter_mode {
+            options.set_filter_mode(filter_mode)?;
+        }
+
+        let mut encoder = encoder::Encoder::new(self.write, &options);
+        encoder.write_header(&header)?;
+        if let Some(palette) = self.palette {
+            encoder.write_palette(&palette)?;
+        }
+        if let Some(trns) = self.trns {
+            encoder.write_transparency(&trns)?;
+        }
+
+        Ok(Writer {
+            header,
+            encoder,
+        })
+    }
+}
+
+/// Writes image data, as png::Writer does. The image is finished
+/// when the writer is dropped, if finish() isn't called.
+pub struct Writer<'a, W: Write> {
+    header: Header,
+    encoder: encoder::Encoder<'a, W>,
+}
+
+impl<'a, W: Write> Writer<'a, W> {
+    /// Write a custom chunk.
+    pub fn write_chunk(&mut self, name: ChunkType, data: &[u8]) -> Result<()> {
+        self.encoder.write_chunk(&name.0, data)
+    }
+
+    /// Write the whole image's data, as packed rows.
+    pub fn write_image_data(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() != self.header.stride() * self.header.height() as usize {
+            return Err(Error::RowDataMismatch("Image data size doesn't match the header"));
+        }
+        self.encoder.write_image_rows_borrowed(data)
+    }
+
+    /// Finish the image, writing the end chunk.
+    pub fn finish(self) -> Result<()> {
+        self.encoder.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::encode_to_vec;
+
+    #[test]
+    fn matches_encoder() {
+        let pixels: Vec<u8> = (0 .. 64 * 32 * 3).map(|i| (i % 251) as u8).collect();
+
+        let mut header = Header::new();
+        header.set_size(64, 32).unwrap();
+        header.set_color(super::super::ColorType::Truecolor, 8).unwrap();
+        let expected = encode_to_vec(&header, &pixels, &Options::new()).unwrap();
+
+        let mut output = Vec::<u8>::new();
+        let mut encoder = Encoder::new(&mut output, 64, 32);
+        encoder.set_color(ColorType::Rgb);
+        encoder.set_depth(BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        assert!(writer.write_image_data(&pixels[1 ..]).is_err());
+        writer.write_image_data(&pixels).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(output, expected);
+
+        // Finishes on drop, too.
+        let mut output = Vec::<u8>::new();
+        let mut encoder = Encoder::new(&mut output, 64, 32);
+        encoder.set_color(ColorType::Rgb);
+        encoder.write_header().unwrap().write_image_data(&pixels).unwrap();
+        assert_eq!(output, expected);
+    }
+}
@@ -0,0 +1,166 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// python.rs - Python bindings
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Python bindings, as the `mtpng` extension module built by maturin
+//! with the `python` feature.
+//!
+//! ```python
+//! import numpy, mtpng
+//!
+//! pixels = numpy.zeros((480, 640, 4), dtype=numpy.uint8)
+//! png = mtpng.encode(pixels, level=9)
+//! ```
+//!
+//! Anything with the buffer protocol works, not only numpy arrays.
+
+// The pyfunction macro's generated code converts errors into PyErr
+// even when they already are one.
+#![allow(clippy::useless_conversion)]
+
+use pyo3::prelude::*;
+use pyo3::buffer::PyBuffer;
+use pyo3::exceptions::{PyOSError, PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::types::PyBytes;
+
+use super::Header;
+use super::{Error, Result};
+use super::bindings;
+use super::encoder::{encode_to_vec, Options};
+
+impl From<Error> for PyErr {
+    fn from(err: Error) -> PyErr {
+        match err {
+            Error::Io(e) => PyOSError::new_err(e.to_string()),
+            Error::Cancelled | Error::TimedOut |
+            Error::WorkerFailed(_) | Error::VerificationFailed(_) => PyRuntimeError::new_err(err.to_string()),
+            _ => PyValueError::new_err(err.to_string()),
+        }
+    }
+}
+
+/// A compression level, as 1-9 or a name.
+#[derive(FromPyObject)]
+enum Level {
+    Number(i64),
+    Name(String),
+}
+
+//
+// Work out the header from an array's shape: rows and columns, then
+// optionally 1 to 4 channels.
+//
+fn header(shape: &[usize], depth: u8) -> PyResult<Header> {
+    let (height, width, channels) = match *shape {
+        [height, width] => (height, width, 1),
+        [height, width, channels] => (height, width, channels),
+        _ => return Err(PyValueError::new_err("Image arrays must have 2 or 3 dimensions")),
+    };
+    Ok(bindings::header(width, height, bindings::Color::Channels(channels), depth)?)
+}
+
+fn encode_u8(py: Python, buffer: PyBuffer<u8>, options: Options<'static>) -> PyResult<Vec<u8>> {
+    let header = header(buffer.shape(), 8)?;
+    // Other Python threads may write to the array once the GIL is
+    // released, so encode from a copy taken while it's still held.
+    let pixels = buffer.to_vec(py)?;
+    Ok(py.allow_threads(|| encode_to_vec(&header, &pixels, &options))?)
+}
+
+fn encode_u16(py: Python, buffer: PyBuffer<u16>, options: Options<'static>) -> PyResult<Vec<u8>> {
+    let header = header(buffer.shape(), 16)?;
+    let samples = buffer.to_vec(py)?;
+    Ok(py.allow_threads(|| -> Result<Vec<u8>> {
+        // PNG wants 16-bit samples big-endian.
+        let pixels: Vec<u8> = samples.iter().flat_map(|s| s.to_be_bytes()).collect();
+        encode_to_vec(&header, &pixels, &options)
+    })?)
+}
+
+/// Encode an image array to PNG file data, returned as bytes.
+///
+/// The array has shape (height, width) for greyscale, or
+/// (height, width, channels) for greyscale, greyscale with alpha,
+/// RGB, or RGBA with 1 to 4 channels. Samples are uint8, or uint16
+/// for 16-bit images.
+///
+/// Options are as for the command-line tool: level is 1-9, "fast",
+/// "default", or "high"; filter is "adaptive", "none", "sub", "up",
+/// "average", or "paeth"; strategy is "auto", "default", "filtered",
+/// "huffman", "rle", or "fixed". threads limits how many threads of
+/// the shared pool the encode uses.
+///
+/// The GIL is released while encoding, so other Python threads can
+/// run, or encode more images at once. The pixels are copied first,
+/// so the array may be changed while the encode runs.
+#[pyfunction]
+#[pyo3(signature = (array, *, level=None, filter=None, strategy=None, chunk_size=None, threads=None, streaming=false))]
+#[allow(clippy::too_many_arguments)]
+fn encode<'py>(py: Python<'py>,
+               array: &Bound<'py, PyAny>,
+               level: Option<Level>,
+               filter: Option<&str>,
+               strategy: Option<&str>,
+               chunk_size: Option<usize>,
+               threads: Option<usize>,
+               streaming: bool)
+    -> PyResult<Bound<'py, PyBytes>>
+{
+    let level = match &level {
+        Some(Level::Number(n)) => Some(bindings::Level::Number(*n)),
+        Some(Level::Name(name)) => Some(bindings::Level::Name(name)),
+        None => None,
+    };
+    let options = bindings::options(level, filter, strategy, chunk_size, threads, streaming)?;
+    let data = if let Ok(buffer) = PyBuffer::<u8>::get_bound(array) {
+        encode_u8(py, buffer, options)?
+    } else if let Ok(buffer) = PyBuffer::<u16>::get_bound(array) {
+        encode_u16(py, buffer, options)?
+    } else {
+        return Err(PyTypeError::new_err("Expected an array of uint8 or uint16 samples"));
+    };
+    Ok(PyBytes::new_bound(py, &data))
+}
+
+#[pymodule]
+fn mtpng(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    m.add_function(wrap_pyfunction!(encode, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::header;
+
+    #[test]
+    fn shapes() {
+        let grey = header(&[3, 2], 8).unwrap();
+        assert_eq!((grey.width(), grey.height()), (2, 3));
+        assert_eq!(grey.color_type().channels(), 1);
+        assert_eq!(header(&[3, 2, 4], 16).unwrap().color_type().channels(), 4);
+        assert!(header(&[3, 2, 5], 8).is_err());
+        assert!(header(&[3], 8).is_err());
+    }
+}
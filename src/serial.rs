@@ -0,0 +1,247 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// serial.rs - single-threaded encoding without std
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Encoding on the calling thread, with no threads or I/O.
+//!
+//! This filters and deflates the whole image in memory, with the same
+//! filters and compression as encoder::Encoder, and is all that's
+//! available without the `std` feature: it only needs `alloc`, so it
+//! works in kernels, bootloaders, and other `no_std` environments.
+//! Build with `default-features = false, features = ["zlib-rs"]` there
+//! for a pure-Rust deflate.
+//!
+//! ```
+//! # fn main() -> mtpng::Result<()> {
+//! let mut header = mtpng::Header::new();
+//! header.set_size(640, 480)?;
+//! header.set_color(mtpng::ColorType::TruecolorAlpha, 8)?;
+//!
+//! let pixels = vec![0u8; 640 * 480 * 4];
+//! let png = mtpng::serial::encode_to_vec(&header, &pixels, &mtpng::serial::Options::new())?;
+//! # Ok(())
+//! # }
+//! ```
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::ColorType;
+use super::CompressionLevel;
+use super::Header;
+use super::Mode;
+use super::Mode::{Adaptive, Fixed};
+use super::Strategy;
+use super::{Error, Result};
+
+use super::deflate;
+use super::deflate::{Deflate, Flush};
+use super::filter::{AdaptiveFilter, Filter};
+use super::writer::Writer;
+
+/// Compression options for encode_to_vec(), as for the
+/// multithreaded encoder's options of the same names.
+#[derive(Copy, Clone, Debug)]
+pub struct Options {
+    compression_level: CompressionLevel,
+    filter_mode: Mode<Filter>,
+    strategy_mode: Mode<Strategy>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Options {
+    /// Return a new set of options with defaults:
+    ///
+    /// * compression_level: Default
+    /// * filter_mode: Adaptive
+    /// * strategy_mode: Adaptive
+    pub fn new() -> Options {
+        Options {
+            compression_level: CompressionLevel::Default,
+            filter_mode: Adaptive,
+            strategy_mode: Adaptive,
+        }
+    }
+
+    /// Set the deflate compression level.
+    pub fn set_compression_level(&mut self, level: CompressionLevel) -> Result<()> {
+        self.compression_level = level;
+        Ok(())
+    }
+
+    /// Set the filter mode: a fixed filter, or adaptive choice per row.
+    pub fn set_filter_mode(&mut self, filter_mode: Mode<Filter>) -> Result<()> {
+        self.filter_mode = filter_mode;
+        Ok(())
+    }
+
+    /// Set the deflate strategy, or let it follow the filter mode.
+    pub fn set_strategy_mode(&mut self, strategy_mode: Mode<Strategy>) -> Result<()> {
+        self.strategy_mode = strategy_mode;
+        Ok(())
+    }
+
+    //
+    // Adaptive modes resolve as in the multithreaded encoder:
+    // palette indices don't filter well, and the filtered strategy
+    // suits anything else that's filtered.
+    //
+    fn filter_mode(&self, header: &Header) -> Mode<Filter> {
+        match (self.filter_mode, header.color_type) {
+            (Adaptive, ColorType::IndexedColor) => Fixed(Filter::None),
+            (mode, _) => mode,
+        }
+    }
+
+    fn deflate_options(&self, header: &Header) -> deflate::Options {
+        let mut options = deflate::Options::new();
+        match self.compression_level {
+            CompressionLevel::Default => {},
+            CompressionLevel::Fast => options.set_level(1),
+            CompressionLevel::High => options.set_level(9),
+            CompressionLevel::Level(n) => options.set_level(i32::from(n)),
+        }
+        options.set_strategy(match (self.strategy_mode, self.filter_mode(header)) {
+            (Fixed(s), _) => s,
+            (_, Fixed(Filter::None)) => Strategy::Default,
+            _ => Strategy::Filtered,
+        });
+        options
+    }
+}
+
+/// Encode a complete image in one call on the calling thread,
+/// returning the PNG file data.
+///
+/// Pixels are packed rows as for Encoder::write_image_rows().
+/// Indexed-color images aren't supported here, as they need a
+/// palette; use the multithreaded encoder for those.
+pub fn encode_to_vec(header: &Header, pixels: &[u8], options: &Options) -> Result<Vec<u8>> {
+    trace_span!("mtpng::serial", width = header.width, height = header.height);
+    if let ColorType::IndexedColor = header.color_type {
+        return Err(Error::InvalidState("Indexed-color images need a palette"));
+    }
+    header.check_size()?;
+    let stride = header.stride();
+    if pixels.len() != stride * header.height as usize {
+        return Err(Error::RowDataMismatch("Pixel data size doesn't match the header"));
+    }
+
+    let mut filter = AdaptiveFilter::new(*header, options.filter_mode(header));
+    let mut deflate = Deflate::new(options.deflate_options(header), Vec::new());
+    let zero = vec![0u8; stride];
+    let mut prev = &zero[..];
+    for row in pixels.chunks(stride) {
+        deflate.write(filter.filter(prev, row), Flush::NoFlush)?;
+        prev = row;
+    }
+    deflate.write(&[], Flush::Finish)?;
+    let data = deflate.finish()?;
+
+    let mut writer = Writer::new(Vec::new());
+    writer.write_signature()?;
+    writer.write_header(*header)?;
+    writer.write_chunk(b"IDAT", &data)?;
+    writer.write_end()?;
+    let png = writer.finish()?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::{encode_to_vec, Options};
+    use super::super::{ColorType, Header, Error};
+    use super::super::validate::validate;
+    #[cfg(feature="std")]
+    use super::super::{CompressionLevel, Mode::Fixed};
+    #[cfg(feature="std")]
+    use super::super::deflate;
+    #[cfg(feature="std")]
+    use super::super::encoder;
+    #[cfg(feature="std")]
+    use super::super::filter::Filter;
+    #[cfg(feature="std")]
+    use super::super::verify::Verifier;
+
+    #[test]
+    fn round_trip() {
+        for &(color_type, depth) in &[(ColorType::Greyscale, 1),
+                                      (ColorType::Truecolor, 8),
+                                      (ColorType::TruecolorAlpha, 16)] {
+            let mut header = Header::new();
+            header.set_size(33, 17).unwrap();
+            header.set_color(color_type, depth).unwrap();
+            let pixels: Vec<u8> = (0 .. header.stride() * 17).map(|i| (i * 7 % 251) as u8).collect();
+
+            let png = encode_to_vec(&header, &pixels, &Options::new()).unwrap();
+            assert_eq!(validate(&png), vec![]);
+            #[cfg(feature="std")]
+            {
+                let mut verifier = Verifier::new(header, true);
+                verifier.write(&png).unwrap();
+                verifier.finish(deflate::crc32(deflate::crc32_initial(), &pixels)).unwrap();
+            }
+        }
+    }
+
+    #[cfg(feature="std")]
+    #[test]
+    fn matches_encoder() {
+        let mut header = Header::new();
+        header.set_size(64, 8).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let pixels: Vec<u8> = (0 .. header.stride() * 8).map(|i| (i * 13 % 241) as u8).collect();
+
+        let mut options = Options::new();
+        options.set_compression_level(CompressionLevel::High).unwrap();
+        options.set_filter_mode(Fixed(Filter::Paeth)).unwrap();
+        let mut threaded = encoder::Options::new();
+        threaded.set_compression_level(CompressionLevel::High).unwrap();
+        threaded.set_filter_mode(Fixed(Filter::Paeth)).unwrap();
+
+        assert_eq!(encode_to_vec(&header, &pixels, &options).unwrap(),
+                   encoder::encode_to_vec(&header, &pixels, &threaded).unwrap());
+    }
+
+    #[test]
+    fn errors() {
+        let mut header = Header::new();
+        header.set_size(4, 4).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        match encode_to_vec(&header, &[0; 47], &Options::new()) {
+            Err(Error::RowDataMismatch(_)) => {},
+            e => panic!("Unexpected result: {:?}", e),
+        }
+        header.set_color(ColorType::IndexedColor, 8).unwrap();
+        assert!(encode_to_vec(&header, &[0; 16], &Options::new()).is_err());
+    }
+}
@@ -0,0 +1,104 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// simple.rs - one-call helpers with default options
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Simple functions for the most common cases: saving an 8-bit
+//! RGBA, RGB, or greyscale image from memory, with default options
+//! and the global thread pool.
+//!
+//! For anything more involved, use encoder::Encoder directly.
+//!
+//! ```no_run
+//! # fn main() -> mtpng::Result<()> {
+//! let pixels = vec![0u8; 640 * 480 * 4];
+//! mtpng::simple::write_rgba8("out.png", 640, 480, &pixels)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fs;
+use std::path::Path;
+
+use super::ColorType;
+use super::Result;
+use super::Header;
+use super::encoder::{encode_to_vec, Options};
+
+fn encode(color_type: ColorType, width: u32, height: u32, pixels: &[u8]) -> Result<Vec<u8>> {
+    let mut header = Header::new();
+    header.set_size(width, height)?;
+    header.set_color(color_type, 8)?;
+    encode_to_vec(&header, pixels, &Options::new())
+}
+
+/// Encode 8-bit RGBA pixels, 4 bytes per pixel, to PNG file data.
+pub fn encode_rgba8(width: u32, height: u32, pixels: &[u8]) -> Result<Vec<u8>> {
+    encode(ColorType::TruecolorAlpha, width, height, pixels)
+}
+
+/// Encode 8-bit RGB pixels, 3 bytes per pixel, to PNG file data.
+pub fn encode_rgb8(width: u32, height: u32, pixels: &[u8]) -> Result<Vec<u8>> {
+    encode(ColorType::Truecolor, width, height, pixels)
+}
+
+/// Encode 8-bit greyscale pixels, 1 byte per pixel, to PNG file data.
+pub fn encode_grey8(width: u32, height: u32, pixels: &[u8]) -> Result<Vec<u8>> {
+    encode(ColorType::Greyscale, width, height, pixels)
+}
+
+/// Save 8-bit RGBA pixels, 4 bytes per pixel, to a PNG file.
+pub fn write_rgba8<P: AsRef<Path>>(path: P, width: u32, height: u32, pixels: &[u8]) -> Result<()> {
+    Ok(fs::write(path, encode_rgba8(width, height, pixels)?)?)
+}
+
+/// Save 8-bit RGB pixels, 3 bytes per pixel, to a PNG file.
+pub fn write_rgb8<P: AsRef<Path>>(path: P, width: u32, height: u32, pixels: &[u8]) -> Result<()> {
+    Ok(fs::write(path, encode_rgb8(width, height, pixels)?)?)
+}
+
+/// Save 8-bit greyscale pixels, 1 byte per pixel, to a PNG file.
+pub fn write_grey8<P: AsRef<Path>>(path: P, width: u32, height: u32, pixels: &[u8]) -> Result<()> {
+    Ok(fs::write(path, encode_grey8(width, height, pixels)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn simple_encode() {
+        let pixels = vec![128u8; 16 * 8 * 4];
+        let png = super::encode_rgba8(16, 8, &pixels).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+
+        assert!(super::encode_rgb8(16, 8, &pixels).is_err());
+        assert!(super::encode_grey8(16, 32, &pixels).is_ok());
+
+        let path = env::temp_dir().join(format!("mtpng-simple-{}.png", std::process::id()));
+        super::write_rgba8(&path, 16, 8, &pixels).unwrap();
+        assert!(fs::read(&path).unwrap() == png);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// text.rs - encoding of tEXt, zTXt, iTXt, and iCCP chunk payloads
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{Error, Result};
+
+#[cfg(feature="std")]
+use super::deflate::{Deflate, Flush, Options};
+use super::deflate::Inflate;
+
+//
+// Build a text chunk for the keyword and text, returning its tag and
+// payload. Text that fits in Latin-1 goes in tEXt, or zTXt when
+// compressed, which every decoder reads; anything else goes in iTXt
+// as UTF-8, with no language tag or translated keyword.
+//
+// https://www.w3.org/TR/PNG/#11textinfo
+//
+#[cfg(feature="std")]
+pub fn text_chunk(keyword: &str, text: &str, compressed: bool) -> Result<(&'static [u8], Vec<u8>)> {
+    let mut data = check_keyword(keyword)?;
+    data.push(0);
+
+    if text.contains('\0') {
+        return Err(Error::InvalidText("Text cannot contain null characters."));
+    }
+
+    match (latin1(text), compressed) {
+        (Some(text), false) => {
+            data.extend_from_slice(&text);
+            Ok((b"tEXt", data))
+        },
+        (Some(text), true) => {
+            data.push(0); // compression method
+            data.extend_from_slice(&compress(&text)?);
+            Ok((b"zTXt", data))
+        },
+        (None, _) => {
+            data.push(compressed as u8);
+            data.push(0); // compression method
+            data.push(0); // empty language tag
+            data.push(0); // empty translated keyword
+            if compressed {
+                data.extend_from_slice(&compress(text.as_bytes())?);
+            } else {
+                data.extend_from_slice(text.as_bytes());
+            }
+            Ok((b"iTXt", data))
+        },
+    }
+}
+
+//
+// Build an iCCP chunk payload for an ICC profile, whose name follows
+// the same rules as text keywords. The profile is only checked for
+// the size and signature in its header, so other data isn't embedded
+// by mistake.
+//
+// https://www.w3.org/TR/PNG/#11iCCP
+//
+#[cfg(feature="std")]
+pub fn icc_chunk(name: &str, profile: &[u8]) -> Result<Vec<u8>> {
+    let mut data = check_keyword(name)?;
+    data.push(0);
+
+    let size = profile.get(0 .. 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    if profile.len() < 128 || size != Some(profile.len() as u32) {
+        return Err(Error::InvalidChunk("ICC profile size doesn't match its header."));
+    }
+    if &profile[36 .. 40] != b"acsp" {
+        return Err(Error::InvalidChunk("ICC profile signature is missing."));
+    }
+
+    data.push(0); // compression method
+    data.extend_from_slice(&compress(profile)?);
+    Ok(data)
+}
+
+/// Read the keyword and text from a tEXt, zTXt, or iTXt chunk's
+/// payload, decompressing it if needed. An iTXt chunk's language tag
+/// and translated keyword are skipped.
+///
+/// ```
+/// let data = b"Title\0Caf\xe9";
+/// let (keyword, text) = mtpng::read_text_chunk(b"tEXt", data).unwrap();
+/// assert_eq!((&keyword[..], &text[..]), ("Title", "Caf\u{e9}"));
+/// ```
+pub fn read_text_chunk(tag: &[u8; 4], data: &[u8]) -> Result<(String, String)> {
+    let malformed = || Error::InvalidChunk("Text chunk is malformed.");
+    let end = data.iter().position(|&b| b == 0).ok_or_else(malformed)?;
+    let keyword = from_latin1(&data[.. end]);
+    let rest = &data[end + 1 ..];
+
+    let text = match tag {
+        b"tEXt" => from_latin1(rest),
+        b"zTXt" => match rest.split_first() {
+            Some((0, compressed)) => from_latin1(&decompress(compressed)?),
+            _ => return Err(malformed()),
+        },
+        b"iTXt" => {
+            if rest.len() < 2 {
+                return Err(malformed());
+            }
+            let mut fields = rest[2 ..].splitn(3, |&b| b == 0).skip(2);
+            let text = fields.next().ok_or_else(malformed)?;
+            let text = match (rest[0], rest[1]) {
+                (0, _) => text.to_vec(),
+                (1, 0) => decompress(text)?,
+                _ => return Err(malformed()),
+            };
+            String::from_utf8(text).map_err(|_| Error::InvalidChunk("iTXt text isn't UTF-8."))?
+        },
+        _ => return Err(Error::InvalidChunk("Not a text chunk.")),
+    };
+    Ok((keyword, text))
+}
+
+//
+// Keywords are 1-79 printable Latin-1 characters, with no leading,
+// trailing, or consecutive spaces. Returns the Latin-1 bytes.
+//
+#[cfg(feature="std")]
+fn check_keyword(keyword: &str) -> Result<Vec<u8>> {
+    let bytes = latin1(keyword)
+        .ok_or(Error::InvalidText("Keyword must be Latin-1 text."))?;
+    if bytes.is_empty() || bytes.len() > 79 {
+        return Err(Error::InvalidText("Keyword must be 1-79 characters long."));
+    }
+    if !bytes.iter().all(|&b| (32 ..= 126).contains(&b) || b >= 161) {
+        return Err(Error::InvalidText("Keyword must contain only printable characters."));
+    }
+    if bytes[0] == b' ' || bytes[bytes.len() - 1] == b' ' || keyword.contains("  ") {
+        return Err(Error::InvalidText("Keyword cannot have leading, trailing, or consecutive spaces."));
+    }
+    Ok(bytes)
+}
+
+//
+// Latin-1 is the first 256 code points, so those map directly to bytes.
+//
+#[cfg(feature="std")]
+fn latin1(text: &str) -> Option<Vec<u8>> {
+    text.chars()
+        .map(|c| if (c as u32) < 256 { Some(c as u8) } else { None })
+        .collect()
+}
+
+fn from_latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+#[cfg(feature="std")]
+fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut deflate = Deflate::new(Options::new(), Vec::new());
+    deflate.write(data, Flush::Finish)?;
+    Ok(deflate.finish()?)
+}
+
+fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let corrupt = Error::InvalidChunk("Compressed text is corrupt.");
+    let mut inflate = Inflate::new();
+    let mut output = Vec::new();
+    if inflate.write(data, &mut output).is_err() || !inflate.is_finished() {
+        return Err(corrupt);
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_text_chunk;
+    #[cfg(feature="std")]
+    use super::{icc_chunk, text_chunk};
+    use super::super::Error;
+
+    #[cfg(feature="std")]
+    #[test]
+    fn chunk_types() {
+        let (tag, data) = text_chunk("Title", "Caf\u{e9}", false).unwrap();
+        assert_eq!(tag, b"tEXt");
+        assert_eq!(data, b"Title\0Caf\xe9");
+
+        let (tag, data) = text_chunk("Title", "\u{732b}", false).unwrap();
+        assert_eq!(tag, b"iTXt");
+        assert_eq!(data, "Title\0\0\0\0\0\u{732b}".as_bytes());
+
+        let (tag, data) = text_chunk("Comment", &"a".repeat(1000), true).unwrap();
+        assert_eq!(tag, b"zTXt");
+        assert!(data.starts_with(b"Comment\0\0") && data.len() < 100);
+
+        let (tag, data) = text_chunk("Comment", &"\u{732b}".repeat(1000), true).unwrap();
+        assert_eq!(tag, b"iTXt");
+        assert!(data.starts_with(b"Comment\0\x01\0\0\0") && data.len() < 100);
+    }
+
+    #[cfg(feature="std")]
+    #[test]
+    fn read_back() {
+        for &(text, compressed) in &[("Caf\u{e9}", false), ("Caf\u{e9}", true),
+                                     ("\u{732b}", false), ("\u{732b}", true)] {
+            let (tag, data) = text_chunk("Title", text, compressed).unwrap();
+            let tag = [tag[0], tag[1], tag[2], tag[3]];
+            let (keyword, read) = read_text_chunk(&tag, &data).unwrap();
+            assert_eq!((&keyword[..], &read[..]), ("Title", text));
+        }
+    }
+
+    #[test]
+    fn read_malformed() {
+        assert!(matches!(read_text_chunk(b"tEXt", b"Title"), Err(Error::InvalidChunk(_))));
+        assert!(matches!(read_text_chunk(b"zTXt", b"Title\0\0garbage"), Err(Error::InvalidChunk(_))));
+        assert!(matches!(read_text_chunk(b"iTXt", b"Title\0\0\0\0\0\xff"), Err(Error::InvalidChunk(_))));
+        assert!(matches!(read_text_chunk(b"IDAT", b"Title\0"), Err(Error::InvalidChunk(_))));
+    }
+
+    #[cfg(feature="std")]
+    #[test]
+    fn invalid_text() {
+        for keyword in &["", " Title", "Title ", "Two  spaces", "Tab\t", "\u{732b}", &"a".repeat(80)] {
+            assert!(matches!(text_chunk(keyword, "text", false), Err(Error::InvalidText(_))),
+                    "keyword {:?}", keyword);
+        }
+        assert!(text_chunk("Two spaces", "text", false).is_ok());
+        assert!(text_chunk(&"a".repeat(79), "text", false).is_ok());
+        assert!(matches!(text_chunk("Title", "a\0b", false), Err(Error::InvalidText(_))));
+    }
+
+    #[cfg(feature="std")]
+    #[test]
+    fn icc_profiles() {
+        let mut profile = vec![0u8; 200];
+        profile[0 .. 4].copy_from_slice(&200u32.to_be_bytes());
+        profile[36 .. 40].copy_from_slice(b"acsp");
+        let data = icc_chunk("sRGB", &profile).unwrap();
+        assert!(data.starts_with(b"sRGB\0\0") && data.len() < 100);
+
+        assert!(matches!(icc_chunk("", &profile), Err(Error::InvalidText(_))));
+        assert!(matches!(icc_chunk("sRGB", &profile[.. 199]), Err(Error::InvalidChunk(_))));
+        profile[36] = 0;
+        assert!(matches!(icc_chunk("sRGB", &profile), Err(Error::InvalidChunk(_))));
+    }
+}
@@ -23,21 +23,142 @@
 // THE SOFTWARE.
 //
 
-use ::std::io;
-use ::std::io::{Error, ErrorKind, Write};
+#[cfg(not(feature="std"))]
+use alloc::vec::Vec;
 
-pub type IoResult = io::Result<()>;
+#[cfg(feature="std")]
+pub use ::std::io::{Error as IoError, ErrorKind, Write};
 
-pub fn invalid_input(payload: &str) -> Error
+pub type IoResult<T = ()> = Result<T, IoError>;
+
+//
+// Without std, output goes to a stand-in for io::Write that's
+// enough for writing into a Vec, and errors come out as ours.
+//
+#[cfg(not(feature="std"))]
+pub type IoError = super::Error;
+
+#[cfg(not(feature="std"))]
+pub trait Write {
+    fn write_all(&mut self, buf: &[u8]) -> IoResult;
+
+    fn flush(&mut self) -> IoResult {
+        Ok(())
+    }
+}
+
+#[cfg(not(feature="std"))]
+impl Write for Vec<u8> {
+    fn write_all(&mut self, buf: &[u8]) -> IoResult {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[cfg(not(feature="std"))]
+impl<W: Write + ?Sized> Write for &mut W {
+    fn write_all(&mut self, buf: &[u8]) -> IoResult {
+        (**self).write_all(buf)
+    }
+
+    fn flush(&mut self) -> IoResult {
+        (**self).flush()
+    }
+}
+
+//
+// wasm32-unknown-unknown has no clock, and std's Instant::now()
+// panics there. Stand in with one that never moves, so timings
+// all come out as zero; deadlines are refused up front. Without
+// std there's no clock to ask either.
+//
+#[cfg(all(feature="std", not(all(target_arch="wasm32", target_os="unknown"))))]
+pub use ::std::time::Instant;
+
+#[cfg(any(not(feature="std"), all(target_arch="wasm32", target_os="unknown")))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant;
+
+#[cfg(any(not(feature="std"), all(target_arch="wasm32", target_os="unknown")))]
+impl Instant {
+    pub fn now() -> Instant {
+        Instant
+    }
+
+    pub fn elapsed(&self) -> ::core::time::Duration {
+        ::core::time::Duration::ZERO
+    }
+}
+
+#[cfg(any(not(feature="std"), all(target_arch="wasm32", target_os="unknown")))]
+impl ::core::ops::Add<::core::time::Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, _other: ::core::time::Duration) -> Instant {
+        Instant
+    }
+}
+
+#[cfg(any(not(feature="std"), all(target_arch="wasm32", target_os="unknown")))]
+impl ::core::ops::Sub for Instant {
+    type Output = ::core::time::Duration;
+
+    fn sub(self, _other: Instant) -> ::core::time::Duration {
+        ::core::time::Duration::ZERO
+    }
+}
+
+//
+// Enter a tracing span, at debug level, until the end of the
+// enclosing scope. Arguments are as for tracing::debug_span!().
+// Compiles to nothing without the tracing feature.
+//
+macro_rules! trace_span {
+    ($($args:tt)*) => {
+        #[cfg(feature="tracing")]
+        let _span = ::tracing::debug_span!($($args)*).entered();
+    }
+}
+
+//
+// Log a debug-level message through the log facade. Arguments
+// are as for log::debug!(). Compiles to nothing without the
+// log feature. Only the encoder logs, so it goes unused
+// without std.
+//
+#[cfg_attr(not(feature="std"), allow(unused_macros))]
+macro_rules! log_debug {
+    ($($args:tt)*) => {
+        #[cfg(feature="log")]
+        ::log::debug!($($args)*);
+    }
+}
+
+#[cfg(feature="std")]
+pub fn invalid_input(payload: &str) -> IoError
+{
+    IoError::new(ErrorKind::InvalidInput, payload)
+}
+
+#[cfg(feature="std")]
+pub fn other(payload: &str) -> IoError
+{
+    IoError::other(payload)
+}
+
+#[cfg(not(feature="std"))]
+pub fn invalid_input(payload: &'static str) -> IoError
 {
-    Error::new(ErrorKind::InvalidInput, payload)
+    super::Error::InvalidOptions(payload)
 }
 
-pub fn other(payload: &str) -> Error
+#[cfg(not(feature="std"))]
+pub fn other(payload: &str) -> IoError
 {
-    Error::new(ErrorKind::Other, payload)
+    super::Error::WorkerFailed(payload.into())
 }
 
+#[cfg(feature="std")]
 pub fn write_be32<W: Write>(w: &mut W, val: u32) -> IoResult {
     let bytes = [
         (val >> 24 & 0xff) as u8,
@@ -47,8 +168,3 @@ pub fn write_be32<W: Write>(w: &mut W, val: u32) -> IoResult {
     ];
     w.write_all(&bytes)
 }
-
-pub fn write_byte<W: Write>(w: &mut W, val: u8) -> IoResult {
-    let bytes = [val];
-    w.write_all(&bytes)
-}
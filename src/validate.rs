@@ -0,0 +1,554 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// validate.rs - structural checks for PNG files
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Structural checks for PNG files.
+//!
+//! This checks the signature, chunk lengths, tags, and CRCs, the order
+//! of chunks, and the contents of IHDR, PLTE, and tRNS, without
+//! decompressing the image data. It's useful for checking output that
+//! includes custom chunks.
+//!
+//! ```
+//! # fn main() -> mtpng::Result<()> {
+//! let header = mtpng::Header::builder()
+//!     .size(4, 4)
+//!     .color_type(mtpng::ColorType::Truecolor)
+//!     .depth(8)
+//!     .build()?;
+//! let pixels = vec![0u8; 4 * 4 * 3];
+//! let png = mtpng::encode_to_vec(&header, &pixels, &mtpng::encoder::Options::new())?;
+//! assert!(mtpng::validate::validate(&png).is_empty());
+//! # Ok(())
+//! # }
+//! ```
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use core::convert::TryFrom;
+use core::fmt;
+
+use super::ColorType;
+use super::deflate;
+
+pub(crate) const SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+//
+// libpng refuses ancillary chunks larger than this by default,
+// as a defence against memory exhaustion (PNG_USER_CHUNK_MALLOC_MAX).
+//
+const MAX_CHUNK_SIZE: usize = 8_000_000;
+
+/// How serious a finding is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The file breaks the PNG spec, and decoders may reject it.
+    Error,
+    /// The file is allowed, but unusual enough to be worth a look.
+    Warning,
+}
+
+/// A problem found in a PNG file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finding {
+    /// Byte offset into the file of the chunk, or of the problem
+    /// if it's not in a chunk.
+    pub offset: usize,
+    /// Tag of the chunk concerned, if any.
+    pub chunk: Option<[u8; 4]>,
+    /// How serious the problem is.
+    pub severity: Severity,
+    /// Description of the problem.
+    pub message: &'static str,
+}
+
+impl Finding {
+    fn new(offset: usize, chunk: Option<[u8; 4]>, severity: Severity, message: &'static str) -> Finding {
+        Finding {
+            offset,
+            chunk,
+            severity,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{} at byte {}", severity, self.offset)?;
+        if let Some(ref tag) = self.chunk {
+            write!(f, " in {}", String::from_utf8_lossy(tag))?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Check a complete PNG file, returning any problems found in the order
+/// they occur. An empty list means the file is structurally valid.
+///
+/// Checking stops early if the signature is wrong, or a chunk is
+/// truncated or too long to read the rest of the file.
+pub fn validate(data: &[u8]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    if data.len() < SIGNATURE.len() || data[.. SIGNATURE.len()] != SIGNATURE {
+        findings.push(Finding::new(0, None, Severity::Error, "Bad PNG signature"));
+        return findings;
+    }
+
+    let mut order = ChunkOrder::new(None);
+    let mut header: Option<(ColorType, u8)> = None;
+    let mut palette_entries = 0;
+    let mut offset = SIGNATURE.len();
+    while offset < data.len() {
+        if order.ended() {
+            findings.push(Finding::new(offset, None, Severity::Warning, "Data after IEND chunk"));
+            break;
+        }
+        if data.len() - offset < 12 {
+            findings.push(Finding::new(offset, None, Severity::Error, "Chunk is truncated"));
+            return findings;
+        }
+        let len = u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+        let mut tag = [0; 4];
+        tag.copy_from_slice(&data[offset + 4 .. offset + 8]);
+        let mut report = |severity, message| {
+            findings.push(Finding::new(offset, Some(tag), severity, message));
+        };
+        if len > i32::MAX as u32 {
+            report(Severity::Error, "Chunk length is above 2^31-1");
+            return findings;
+        }
+        let len = len as usize;
+        if data.len() - offset - 12 < len {
+            report(Severity::Error, "Chunk is truncated");
+            return findings;
+        }
+
+        let body = &data[offset + 8 .. offset + 8 + len];
+        let crc = &data[offset + 8 + len .. offset + 12 + len];
+        let expected = deflate::crc32(deflate::crc32(deflate::crc32_initial(), &tag), body);
+        if crc != expected.to_be_bytes() {
+            report(Severity::Error, "Bad chunk CRC");
+        }
+
+        for (severity, message) in order.chunk(&tag, len) {
+            report(severity, message);
+        }
+
+        match &tag {
+            b"IHDR" => {
+                header = check_header(body, &mut report);
+                order.set_color_type(header.map(|(color_type, _)| color_type));
+            },
+            b"PLTE" => {
+                palette_entries = body.len() / 3;
+                check_palette(header, body, &mut report);
+            },
+            b"tRNS" => check_transparency(header, palette_entries, body, &mut report),
+            _ => {},
+        }
+
+        offset += 12 + len;
+    }
+
+    for (severity, message) in order.end() {
+        findings.push(Finding::new(data.len(), None, severity, message));
+    }
+    findings
+}
+
+//
+// IHDR fields, which the writer produces with writer::header_data().
+// Returns the color type and depth if they're usable for checking
+// later chunks.
+//
+// https://www.w3.org/TR/PNG/#11IHDR
+//
+fn check_header<F>(body: &[u8], report: &mut F) -> Option<(ColorType, u8)>
+    where F: FnMut(Severity, &'static str)
+{
+    if body.len() != 13 {
+        report(Severity::Error, "IHDR must be 13 bytes long");
+        return None;
+    }
+    let width = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+    let height = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+    if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+        report(Severity::Error, "Image size must be 1 to 2^31-1 pixels in each direction");
+    }
+    if body[10] != 0 {
+        report(Severity::Error, "Unknown compression method");
+    }
+    if body[11] != 0 {
+        report(Severity::Error, "Unknown filter method");
+    }
+    if body[12] > 1 {
+        report(Severity::Error, "Unknown interlace method");
+    }
+
+    let depth = body[8];
+    match ColorType::try_from(body[9]) {
+        Ok(color_type) if color_type.is_depth_valid(depth) => Some((color_type, depth)),
+        Ok(_) => {
+            report(Severity::Error, "Invalid bit depth for the color type");
+            None
+        },
+        Err(_) => {
+            report(Severity::Error, "Unknown color type");
+            None
+        },
+    }
+}
+
+//
+// https://www.w3.org/TR/PNG/#11PLTE
+//
+fn check_palette<F>(header: Option<(ColorType, u8)>, body: &[u8], report: &mut F)
+    where F: FnMut(Severity, &'static str)
+{
+    if body.is_empty() || body.len() % 3 != 0 || body.len() > 256 * 3 {
+        report(Severity::Error, "PLTE must have 1-256 three-byte entries");
+    } else if let Some((ColorType::IndexedColor, depth)) = header {
+        if body.len() / 3 > 1 << depth {
+            report(Severity::Error, "PLTE has more entries than the bit depth allows");
+        }
+    }
+}
+
+//
+// https://www.w3.org/TR/PNG/#11tRNS
+//
+fn check_transparency<F>(header: Option<(ColorType, u8)>, palette_entries: usize, body: &[u8], report: &mut F)
+    where F: FnMut(Severity, &'static str)
+{
+    let (color_type, depth) = match header {
+        Some(header) => header,
+        None => return,
+    };
+    let samples = match color_type {
+        ColorType::Greyscale => 1,
+        ColorType::Truecolor => 3,
+        ColorType::IndexedColor => {
+            if body.is_empty() || body.len() > palette_entries {
+                report(Severity::Error, "tRNS must have 1 to as many entries as PLTE");
+            }
+            return;
+        },
+        // ChunkOrder reports these.
+        _ => return,
+    };
+    if body.len() != samples * 2 {
+        report(Severity::Error, "tRNS has the wrong length for the color type");
+    } else if depth < 16 {
+        let max = (1u16 << depth) - 1;
+        if body.chunks(2).any(|sample| u16::from_be_bytes([sample[0], sample[1]]) > max) {
+            report(Severity::Warning, "tRNS color is out of range for the bit depth");
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum ImageData {
+    NotStarted,
+    Started,
+    Done,
+}
+
+// Chunks that may appear at most once.
+const ONCE: [&[u8; 4]; 14] = [
+    b"IHDR", b"PLTE", b"IEND", b"tRNS", b"cHRM", b"gAMA", b"iCCP",
+    b"sBIT", b"sRGB", b"cICP", b"bKGD", b"hIST", b"pHYs", b"tIME",
+];
+
+// Chunks that must come before PLTE and IDAT.
+const BEFORE_PALETTE: [&[u8; 4]; 6] = [
+    b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB", b"cICP",
+];
+
+// Chunks that must come before IDAT.
+const BEFORE_IMAGE_DATA: [&[u8; 4]; 9] = [
+    b"PLTE", b"tRNS", b"bKGD", b"hIST", b"pHYs", b"sPLT", b"eXIf",
+    b"oFFs", b"sCAL",
+];
+
+//
+// Tracks chunk ordering rules as chunks go past, given their tags and
+// lengths. This is shared with the verifier, which checks the encoder's
+// output as it's written.
+//
+// https://www.w3.org/TR/PNG/#5ChunkOrdering
+//
+pub(crate) struct ChunkOrder {
+    color_type: Option<ColorType>,
+    seen: Vec<[u8; 4]>,
+    image_data: ImageData,
+}
+
+impl ChunkOrder {
+    pub fn new(color_type: Option<ColorType>) -> ChunkOrder {
+        ChunkOrder {
+            color_type,
+            seen: Vec::new(),
+            image_data: ImageData::NotStarted,
+        }
+    }
+
+    pub fn set_color_type(&mut self, color_type: Option<ColorType>) {
+        self.color_type = color_type;
+    }
+
+    pub fn ended(&self) -> bool {
+        self.has(b"IEND")
+    }
+
+    fn has(&self, tag: &[u8; 4]) -> bool {
+        self.seen.iter().any(|seen| seen == tag)
+    }
+
+    //
+    // Returns any problems with the next chunk, and records it.
+    //
+    pub fn chunk(&mut self, tag: &[u8; 4], len: usize) -> Vec<(Severity, &'static str)> {
+        let findings = self.check(tag, len);
+        self.add(tag);
+        findings
+    }
+
+    //
+    // Returns any problems with the next chunk, without recording it.
+    //
+    pub fn check(&self, tag: &[u8; 4], len: usize) -> Vec<(Severity, &'static str)> {
+        let mut findings = Vec::new();
+        let mut error = |message| findings.push((Severity::Error, message));
+
+        if !tag.iter().all(u8::is_ascii_alphabetic) {
+            error("Chunk tag isn't ASCII letters");
+            return findings;
+        }
+        if self.seen.is_empty() != (tag == b"IHDR") {
+            error("IHDR must be the first chunk");
+        }
+        if tag[2].is_ascii_lowercase() {
+            error("Chunk tag has the reserved bit set");
+        }
+        if tag[0].is_ascii_uppercase() && ![b"IHDR", b"PLTE", b"IDAT", b"IEND"].contains(&tag) {
+            error("Unknown critical chunk");
+        }
+        if ONCE.contains(&tag) && self.has(tag) {
+            error("Chunk may only appear once");
+        }
+        if BEFORE_PALETTE.contains(&tag) && (self.has(b"PLTE") || self.image_data != ImageData::NotStarted) {
+            error("Chunk must come before PLTE and IDAT");
+        }
+        if BEFORE_IMAGE_DATA.contains(&tag) && self.image_data != ImageData::NotStarted {
+            error("Chunk must come before IDAT");
+        }
+
+        let indexed = matches!(self.color_type, Some(ColorType::IndexedColor));
+        match tag {
+            b"PLTE" => match self.color_type {
+                Some(ColorType::Greyscale) | Some(ColorType::GreyscaleAlpha) => {
+                    error("Greyscale images can't have a PLTE chunk");
+                },
+                _ => {},
+            },
+            b"tRNS" => match self.color_type {
+                Some(ColorType::GreyscaleAlpha) | Some(ColorType::TruecolorAlpha) => {
+                    error("Images with an alpha channel can't have a tRNS chunk");
+                },
+                _ => {},
+            },
+            b"hIST" if !self.has(b"PLTE") => error("hIST must come after PLTE"),
+            b"bKGD" if indexed && !self.has(b"PLTE") => error("bKGD must come after PLTE"),
+            b"IDAT" => {
+                if self.image_data == ImageData::Done {
+                    error("IDAT chunks must be consecutive");
+                }
+                if indexed && !self.has(b"PLTE") {
+                    error("Indexed color images need a PLTE chunk before IDAT");
+                }
+            },
+            b"IEND" => {
+                if len != 0 {
+                    error("IEND must be empty");
+                }
+                if self.image_data == ImageData::NotStarted {
+                    error("Image has no IDAT chunks");
+                }
+            },
+            _ => {},
+        }
+        if tag == b"tRNS" && indexed && !self.has(b"PLTE") {
+            error("tRNS must come after PLTE");
+        }
+        if (tag == b"iCCP" && self.has(b"sRGB")) || (tag == b"sRGB" && self.has(b"iCCP")) {
+            findings.push((Severity::Warning, "Image has both iCCP and sRGB chunks"));
+        }
+        if tag != b"IDAT" && len > MAX_CHUNK_SIZE {
+            findings.push((Severity::Warning, "Chunk is larger than libpng accepts by default"));
+        }
+        findings
+    }
+
+    //
+    // Records the next chunk as written.
+    //
+    pub fn add(&mut self, tag: &[u8; 4]) {
+        if tag == b"IDAT" {
+            self.image_data = ImageData::Started;
+        } else if self.image_data == ImageData::Started {
+            self.image_data = ImageData::Done;
+        }
+        if !self.has(tag) {
+            self.seen.push(*tag);
+        }
+    }
+
+    //
+    // Returns any problems at the end of the file.
+    //
+    pub fn end(&self) -> Vec<(Severity, &'static str)> {
+        if self.ended() {
+            Vec::new()
+        } else {
+            vec![(Severity::Error, "File ends before IEND chunk")]
+        }
+    }
+}
+
+#[cfg(all(test, feature="std"))]
+mod tests {
+    use super::{validate, Severity};
+    use super::super::{ColorType, Header};
+    use super::super::deflate;
+    use super::super::encoder::{Encoder, Options};
+
+    fn encode<F>(color_type: ColorType, func: F) -> Vec<u8>
+        where F: FnOnce(&mut Encoder<Vec<u8>>)
+    {
+        let mut header = Header::new();
+        header.set_size(4, 4).unwrap();
+        header.set_color(color_type, 8).unwrap();
+        let mut encoder = Encoder::new(Vec::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        func(&mut encoder);
+        encoder.write_image_rows(&vec![0; header.stride() * 4]).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn chunk(tag: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = (body.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(tag);
+        data.extend_from_slice(body);
+        let crc = deflate::crc32(deflate::crc32(deflate::crc32_initial(), tag), body);
+        data.extend_from_slice(&crc.to_be_bytes());
+        data
+    }
+
+    // Insert a chunk before the first chunk with the given tag.
+    fn insert(png: &[u8], before: &[u8; 4], new_chunk: &[u8]) -> Vec<u8> {
+        let mut offset = 8;
+        while &png[offset + 4 .. offset + 8] != before {
+            let len = u32::from_be_bytes([png[offset], png[offset + 1], png[offset + 2], png[offset + 3]]);
+            offset += 12 + len as usize;
+        }
+        [&png[.. offset], new_chunk, &png[offset ..]].concat()
+    }
+
+    fn messages(png: &[u8]) -> Vec<&'static str> {
+        validate(png).iter().map(|finding| finding.message).collect()
+    }
+
+    #[test]
+    fn valid_files() {
+        assert_eq!(validate(&encode(ColorType::Truecolor, |_| {})), vec![]);
+        let png = encode(ColorType::IndexedColor, |encoder| {
+            encoder.write_palette(&[0; 12]).unwrap();
+            encoder.write_transparency(&[0; 3]).unwrap();
+            encoder.write_text("Title", "Test").unwrap();
+        });
+        assert_eq!(validate(&png), vec![]);
+    }
+
+    #[test]
+    fn invalid_files() {
+        let png = encode(ColorType::Truecolor, |_| {});
+
+        assert_eq!(messages(b"GIF89a"), vec!["Bad PNG signature"]);
+        assert_eq!(messages(&png[.. png.len() - 12]), vec!["File ends before IEND chunk"]);
+        assert_eq!(messages(&png[.. png.len() - 4]), vec!["Chunk is truncated"]);
+        assert_eq!(messages(&[&png[..], b"\0"].concat()), vec!["Data after IEND chunk"]);
+
+        let mut bad = png.clone();
+        bad[20] ^= 1;
+        let findings = validate(&bad);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].offset, 8);
+        assert_eq!(findings[0].chunk, Some(*b"IHDR"));
+        assert_eq!(findings[0].severity, Severity::Error);
+        assert_eq!(findings[0].message, "Bad chunk CRC");
+
+        assert_eq!(messages(&insert(&png, b"IHDR", &chunk(b"teSt", b""))),
+                   vec!["IHDR must be the first chunk", "IHDR must be the first chunk"]);
+        assert_eq!(messages(&insert(&png, b"IDAT", &chunk(b"tesT", b""))),
+                   vec!["Chunk tag has the reserved bit set"]);
+        assert_eq!(messages(&insert(&png, b"IDAT", &chunk(b"TeSt", b""))),
+                   vec!["Unknown critical chunk"]);
+        assert_eq!(messages(&insert(&png, b"IDAT", &chunk(b"te5t", b""))),
+                   vec!["Chunk tag isn't ASCII letters"]);
+        assert_eq!(messages(&insert(&png, b"IEND", &chunk(b"gAMA", &[0; 4]))),
+                   vec!["Chunk must come before PLTE and IDAT"]);
+        let split = insert(&png, b"IEND", &chunk(b"teSt", b""));
+        assert_eq!(messages(&insert(&split, b"IEND", &chunk(b"IDAT", b""))),
+                   vec!["IDAT chunks must be consecutive"]);
+        assert_eq!(messages(&insert(&png, b"IDAT", &chunk(b"PLTE", &[0; 4]))),
+                   vec!["PLTE must have 1-256 three-byte entries"]);
+        assert_eq!(messages(&insert(&png, b"IDAT", &chunk(b"tRNS", &[0, 0, 1, 0, 0, 0]))),
+                   vec!["tRNS color is out of range for the bit depth"]);
+        assert_eq!(messages(&insert(&png, b"IDAT", &[chunk(b"sRGB", &[0]), chunk(b"sRGB", &[0])].concat())),
+                   vec!["Chunk may only appear once"]);
+        assert_eq!(messages(&insert(&png, b"IDAT", &chunk(b"teSt", &vec![0; 8_000_001]))),
+                   vec!["Chunk is larger than libpng accepts by default"]);
+
+        let mut bad_header = png.clone();
+        bad_header[8 + 8 + 8] = 3; // depth
+        let crc = deflate::crc32(deflate::crc32_initial(), &bad_header[12 .. 29]);
+        bad_header[29 .. 33].copy_from_slice(&crc.to_be_bytes());
+        assert_eq!(messages(&bad_header), vec!["Invalid bit depth for the color type"]);
+
+        let indexed = encode(ColorType::IndexedColor, |encoder| {
+            encoder.write_palette(&[0; 6]).unwrap();
+        });
+        assert_eq!(messages(&insert(&indexed, b"PLTE", &chunk(b"tRNS", &[0]))),
+                   vec!["tRNS must come after PLTE", "tRNS must have 1 to as many entries as PLTE"]);
+        assert_eq!(messages(&insert(&indexed, b"IDAT", &chunk(b"tRNS", &[0; 3]))),
+                   vec!["tRNS must have 1 to as many entries as PLTE"]);
+    }
+}
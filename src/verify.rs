@@ -0,0 +1,353 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// verify.rs - checks the encoder's output as it's written
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use core::cmp;
+
+use super::{Error, Result};
+use super::Header;
+
+use super::deflate;
+use super::deflate::Inflate;
+use super::writer;
+use super::validate::{ChunkOrder, Severity, SIGNATURE};
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Part {
+    Signature,
+    ChunkHeader,
+    ChunkData,
+    ChunkCrc,
+    End,
+}
+
+//
+// Parses the PNG file as it's written, checking the chunk structure
+// and CRCs against the spec and the header being encoded. When
+// checking pixels, the image data is also decompressed and unfiltered,
+// and a checksum of the decoded rows compared against the input's.
+//
+// This is separate from the code that produced the output, so it can
+// catch bugs there as well as corruption along the way.
+//
+pub struct Verifier {
+    header: Header,
+    part: Part,
+
+    // Fixed-size fields are gathered here until complete.
+    field: Vec<u8>,
+
+    tag: [u8; 4],
+    remaining: usize,
+    crc32: u32,
+    chunk_data: Vec<u8>,
+
+    order: ChunkOrder,
+
+    // Only when checking pixels.
+    inflate: Option<Inflate>,
+    inflated: Vec<u8>,
+    prev_row: Vec<u8>,
+    row: Vec<u8>,
+    rows: usize,
+    rows_crc32: u32,
+}
+
+impl Verifier {
+    pub fn new(header: Header, pixels: bool) -> Verifier {
+        Verifier {
+            header,
+            part: Part::Signature,
+
+            field: Vec::new(),
+
+            tag: [0; 4],
+            remaining: 0,
+            crc32: deflate::crc32_initial(),
+            chunk_data: Vec::new(),
+
+            order: ChunkOrder::new(Some(header.color_type())),
+
+            inflate: if pixels { Some(Inflate::new()) } else { None },
+            inflated: Vec::new(),
+            prev_row: vec![0; header.stride()],
+            row: vec![0; header.stride()],
+            rows: 0,
+            rows_crc32: deflate::crc32_initial(),
+        }
+    }
+
+    //
+    // Check the next bytes of output.
+    //
+    pub fn write(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            if self.part == Part::ChunkData {
+                let len = cmp::min(self.remaining, data.len());
+                self.chunk_data_in(&data[.. len])?;
+                data = &data[len ..];
+                self.remaining -= len;
+                if self.remaining == 0 {
+                    self.chunk_data_end()?;
+                }
+                continue;
+            }
+
+            let size = match self.part {
+                Part::Signature => 8,
+                Part::ChunkHeader => 8,
+                Part::ChunkCrc => 4,
+                _ => return Err(Error::VerificationFailed("Data after IEND chunk")),
+            };
+            let len = cmp::min(size - self.field.len(), data.len());
+            self.field.extend_from_slice(&data[.. len]);
+            data = &data[len ..];
+            if self.field.len() == size {
+                let field = core::mem::take(&mut self.field);
+                match self.part {
+                    Part::Signature => self.signature(&field)?,
+                    Part::ChunkHeader => self.chunk_header(&field)?,
+                    _ => self.chunk_crc(&field)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //
+    // Check that the output was complete, and that the decoded rows
+    // match the checksum of the input rows if checking pixels.
+    //
+    pub fn finish(self, input_crc32: u32) -> Result<()> {
+        if self.part != Part::End {
+            return Err(Error::VerificationFailed("Output ends before IEND chunk"));
+        }
+        if let Some(ref inflate) = self.inflate {
+            if !inflate.is_finished() || !self.inflated.is_empty() {
+                return Err(Error::VerificationFailed("Image data is incomplete"));
+            }
+            if self.rows != self.header.height() as usize {
+                return Err(Error::VerificationFailed("Image data has the wrong number of rows"));
+            }
+            if self.rows_crc32 != input_crc32 {
+                return Err(Error::VerificationFailed("Decoded image doesn't match the input"));
+            }
+        }
+        Ok(())
+    }
+
+    fn signature(&mut self, field: &[u8]) -> Result<()> {
+        if field != SIGNATURE {
+            return Err(Error::VerificationFailed("Bad PNG signature"));
+        }
+        self.part = Part::ChunkHeader;
+        Ok(())
+    }
+
+    fn chunk_header(&mut self, field: &[u8]) -> Result<()> {
+        let len = u32::from_be_bytes([field[0], field[1], field[2], field[3]]);
+        self.tag.copy_from_slice(&field[4 ..]);
+        if len > i32::MAX as u32 {
+            return Err(Error::VerificationFailed("Chunk length is above 2^31-1"));
+        }
+        for (severity, message) in self.order.chunk(&self.tag, len as usize) {
+            if severity == Severity::Error {
+                return Err(Error::VerificationFailed(message));
+            }
+        }
+
+        self.crc32 = deflate::crc32(deflate::crc32_initial(), &self.tag);
+        self.remaining = len as usize;
+        self.part = Part::ChunkData;
+        if self.remaining == 0 {
+            self.chunk_data_end()?;
+        }
+        Ok(())
+    }
+
+    fn chunk_data_in(&mut self, data: &[u8]) -> Result<()> {
+        self.crc32 = deflate::crc32(self.crc32, data);
+        match &self.tag {
+            b"IHDR" => self.chunk_data.extend_from_slice(data),
+            b"IDAT" => self.image_data_in(data)?,
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn chunk_data_end(&mut self) -> Result<()> {
+        if &self.tag == b"IHDR" && self.chunk_data != writer::header_data(&self.header) {
+            return Err(Error::VerificationFailed("IHDR doesn't match the header"));
+        }
+        self.part = Part::ChunkCrc;
+        Ok(())
+    }
+
+    fn chunk_crc(&mut self, field: &[u8]) -> Result<()> {
+        if field != self.crc32.to_be_bytes() {
+            return Err(Error::VerificationFailed("Bad chunk CRC"));
+        }
+        self.part = if &self.tag == b"IEND" {
+            Part::End
+        } else {
+            Part::ChunkHeader
+        };
+        Ok(())
+    }
+
+    fn image_data_in(&mut self, data: &[u8]) -> Result<()> {
+        let inflate = match self.inflate {
+            Some(ref mut inflate) => inflate,
+            None => return Ok(()),
+        };
+        inflate.write(data, &mut self.inflated)
+            .map_err(|_| Error::VerificationFailed("Compressed image data is corrupt"))?;
+
+        let stride = self.header.stride();
+        let mut offset = 0;
+        while self.inflated.len() - offset > stride {
+            if self.rows == self.header.height() as usize {
+                return Err(Error::VerificationFailed("Image data has the wrong number of rows"));
+            }
+            let filter = self.inflated[offset];
+            self.row.copy_from_slice(&self.inflated[offset + 1 .. offset + stride + 1]);
+            unfilter(filter, self.header.bytes_per_pixel(), &self.prev_row, &mut self.row)?;
+            self.rows_crc32 = deflate::crc32(self.rows_crc32, &self.row);
+            core::mem::swap(&mut self.prev_row, &mut self.row);
+            self.rows += 1;
+            offset += stride + 1;
+        }
+        self.inflated.drain(.. offset);
+        Ok(())
+    }
+}
+
+//
+// Reverse the filter on a row in place, given the decoded previous row.
+//
+// https://www.w3.org/TR/PNG/#9Filter-types
+//
+fn unfilter(filter: u8, bpp: usize, prev: &[u8], row: &mut [u8]) -> Result<()> {
+    if filter > 4 {
+        return Err(Error::VerificationFailed("Invalid filter type"));
+    }
+    for i in 0 .. row.len() {
+        let a = if i >= bpp { row[i - bpp] } else { 0 };
+        let b = prev[i];
+        let c = if i >= bpp { prev[i - bpp] } else { 0 };
+        let predicted = match filter {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((u16::from(a) + u16::from(b)) / 2) as u8,
+            _ => {
+                let p = i16::from(a) + i16::from(b) - i16::from(c);
+                let (pa, pb, pc) = ((p - i16::from(a)).abs(),
+                                    (p - i16::from(b)).abs(),
+                                    (p - i16::from(c)).abs());
+                if pa <= pb && pa <= pc {
+                    a
+                } else if pb <= pc {
+                    b
+                } else {
+                    c
+                }
+            },
+        };
+        row[i] = row[i].wrapping_add(predicted);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Verifier;
+    use super::super::{ColorType, Header, Error};
+    use super::super::deflate;
+    use super::super::encoder::{Encoder, Options};
+
+    fn encode(header: &Header, pixels: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::new(Vec::new(), &Options::new());
+        encoder.write_header(header).unwrap();
+        encoder.write_text("Comment", "Test").unwrap();
+        encoder.write_image_rows(pixels).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn verify(header: &Header, output: &[u8], pixels: &[u8]) -> Result<(), Error> {
+        let mut verifier = Verifier::new(*header, true);
+        // Feed it in uneven pieces.
+        for piece in output.chunks(7) {
+            verifier.write(piece)?;
+        }
+        verifier.finish(deflate::crc32(deflate::crc32_initial(), pixels))
+    }
+
+    #[test]
+    fn good_output() {
+        for &(color_type, depth) in &[(ColorType::Truecolor, 8), (ColorType::Greyscale, 1),
+                                     (ColorType::GreyscaleAlpha, 16)] {
+            let mut header = Header::new();
+            header.set_size(33, 17).unwrap();
+            header.set_color(color_type, depth).unwrap();
+            let pixels: Vec<u8> = (0 .. header.stride() * 17).map(|i| (i * 7 % 251) as u8).collect();
+            let output = encode(&header, &pixels);
+            verify(&header, &output, &pixels).unwrap();
+        }
+    }
+
+    #[test]
+    fn bad_output() {
+        let mut header = Header::new();
+        header.set_size(33, 17).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let pixels: Vec<u8> = (0 .. 33 * 3 * 17).map(|i| (i * 7 % 251) as u8).collect();
+        let output = encode(&header, &pixels);
+
+        let fail = |header: &Header, output: &[u8], pixels: &[u8]| {
+            assert!(matches!(verify(header, output, pixels), Err(Error::VerificationFailed(_))));
+        };
+
+        // Flipped bits, truncation, and trailing data.
+        for pos in &[0, 12, 20, 40, output.len() - 30, output.len() - 1] {
+            let mut bad = output.clone();
+            bad[*pos] ^= 1;
+            fail(&header, &bad, &pixels);
+        }
+        fail(&header, &output[.. output.len() - 12], &pixels);
+        fail(&header, &[&output[..], b"\0"].concat(), &pixels);
+
+        // Pixels that don't match the input.
+        let mut other = pixels.clone();
+        other[100] ^= 1;
+        fail(&header, &output, &other);
+
+        // A header that doesn't match.
+        header.set_size(33, 16).unwrap();
+        fail(&header, &output, &pixels[.. 33 * 3 * 16]);
+    }
+}
@@ -0,0 +1,134 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// wasm.rs - JavaScript bindings for WebAssembly
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! JavaScript bindings for WebAssembly, built by wasm-bindgen with the
+//! `wasm` feature.
+//!
+//! ```js
+//! import init, { encode } from './pkg/mtpng.js';
+//!
+//! await init();
+//! const pixels = new Uint8Array(640 * 480 * 4);
+//! const png = encode(pixels, { width: 640, height: 480, level: 9 });
+//! ```
+
+use wasm_bindgen::prelude::*;
+
+use super::Header;
+use super::{Error, Result};
+use super::bindings;
+use super::encoder::{encode_to_vec, Options};
+
+#[wasm_bindgen(typescript_custom_section)]
+const ENCODE_OPTIONS: &str = r#"
+/** Image layout and encoder options for encode(). */
+export interface EncodeOptions {
+    width: number;
+    height: number;
+    /** Defaults to "rgba". */
+    colorType?: "gray" | "graya" | "rgb" | "rgba";
+    /** 8 or 16, or less for gray; defaults to 8. */
+    depth?: number;
+    level?: number | "fast" | "default" | "high";
+    filter?: "adaptive" | "none" | "sub" | "up" | "average" | "paeth";
+    strategy?: "auto" | "default" | "filtered" | "huffman" | "rle" | "fixed";
+    chunkSize?: number;
+    /** Most threads of the pool for one encode to use, with wasm-threads. */
+    threads?: number;
+    streaming?: boolean;
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "EncodeOptions")]
+    pub type EncodeOptions;
+
+    #[wasm_bindgen(method, getter)]
+    fn width(this: &EncodeOptions) -> u32;
+
+    #[wasm_bindgen(method, getter)]
+    fn height(this: &EncodeOptions) -> u32;
+
+    #[wasm_bindgen(method, getter, js_name = colorType)]
+    fn color_type(this: &EncodeOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    fn depth(this: &EncodeOptions) -> Option<u32>;
+
+    #[wasm_bindgen(method, getter)]
+    fn level(this: &EncodeOptions) -> JsValue;
+
+    #[wasm_bindgen(method, getter)]
+    fn filter(this: &EncodeOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    fn strategy(this: &EncodeOptions) -> Option<String>;
+
+    #[wasm_bindgen(method, getter, js_name = chunkSize)]
+    fn chunk_size(this: &EncodeOptions) -> Option<u32>;
+
+    #[wasm_bindgen(method, getter)]
+    fn threads(this: &EncodeOptions) -> Option<u32>;
+
+    #[wasm_bindgen(method, getter)]
+    fn streaming(this: &EncodeOptions) -> Option<bool>;
+}
+
+fn header(options: &EncodeOptions) -> Result<Header> {
+    bindings::header(options.width(),
+                     options.height(),
+                     bindings::Color::Name(options.color_type().as_deref()),
+                     options.depth().unwrap_or(8))
+}
+
+fn encoder_options(options: &EncodeOptions) -> Result<Options<'static>> {
+    let level = options.level();
+    let name = level.as_string();
+    let level = match (level.as_f64(), &name) {
+        (Some(n), _) if n.fract() == 0.0 => Some(bindings::Level::Number(n as i64)),
+        (_, Some(name)) => Some(bindings::Level::Name(name)),
+        _ if level.is_undefined() => None,
+        _ => return Err(Error::InvalidOptions("Compression level must be from 1 to 9")),
+    };
+    bindings::options(level,
+                      options.filter().as_deref(),
+                      options.strategy().as_deref(),
+                      options.chunk_size().map(|n| n as usize),
+                      options.threads().map(|n| n as usize),
+                      options.streaming().unwrap_or(false))
+}
+
+/// Encode raw pixels to PNG file data.
+///
+/// Pixels are packed rows of the given width, height, color type,
+/// and depth, with 16-bit samples big-endian as in PNG. Invalid
+/// images or options throw an Error.
+#[wasm_bindgen]
+pub fn encode(pixels: &[u8], options: &EncodeOptions) -> std::result::Result<Vec<u8>, JsError> {
+    let header = header(options)?;
+    let options = encoder_options(options)?;
+    Ok(encode_to_vec(&header, pixels, &options)?)
+}
@@ -0,0 +1,106 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// workers.rs - plain std::thread worker pool
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use crossbeam_channel;
+use crossbeam_channel::Sender;
+
+use std::panic;
+use std::thread;
+use std::thread::JoinHandle;
+
+use super::{Error, Result};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A small fixed set of plain std::thread workers, fed from a bounded
+/// queue, which can be used to run the encoder's jobs instead of Rayon.
+///
+/// Pass a reference to Options::set_worker_pool(). The pool may be shared
+/// between encoders; the worker threads are shut down when it is dropped.
+pub struct WorkerPool {
+    tx: Option<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Start a new pool with the given number of worker threads.
+    pub fn new(threads: usize) -> Result<WorkerPool> {
+        if threads < 1 {
+            return Err(Error::InvalidOptions("Worker pool needs at least one thread"));
+        }
+
+        // Queue up to one job per worker beyond the ones running;
+        // submitting more will wait for a worker to free up.
+        let (tx, rx) = crossbeam_channel::bounded::<Job>(threads);
+
+        let mut pool = WorkerPool {
+            tx: Some(tx),
+            threads: Vec::with_capacity(threads),
+        };
+        for i in 0 .. threads {
+            let rx = rx.clone();
+            let handle = thread::Builder::new()
+                .name(format!("mtpng-worker-{}", i))
+                .spawn(move || {
+                    for job in rx.iter() {
+                        // Keep the worker alive if a job panics, so
+                        // one bad job doesn't take down the pool.
+                        panic::catch_unwind(panic::AssertUnwindSafe(job)).ok();
+                    }
+                })?;
+            pool.threads.push(handle);
+        }
+        Ok(pool)
+    }
+
+    /// Number of worker threads in the pool.
+    pub fn current_num_threads(&self) -> usize {
+        self.threads.len()
+    }
+
+    //
+    // Queue a job to run on a worker thread.
+    // Blocks if the queue is full.
+    //
+    pub(crate) fn spawn<F>(&self, func: F)
+        where F: FnOnce() + Send + 'static
+    {
+        if let Some(ref tx) = self.tx {
+            // Can only fail once the workers are gone, which
+            // doesn't happen until the pool is dropped.
+            tx.send(Box::new(func)).ok();
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Closing the queue lets the workers run out.
+        self.tx = None;
+        for handle in self.threads.drain(..) {
+            handle.join().ok();
+        }
+    }
+}
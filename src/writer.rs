@@ -26,39 +26,84 @@
 use crc::crc32;
 use crc::Hasher32;
 
-use std::io;
-use std::io::Write;
+use alloc::vec::Vec;
 
+use core::time::Duration;
+
+use super::Error;
 use super::Header;
 
+#[cfg(feature="std")]
+use super::deflate;
+#[cfg(feature="std")]
+use super::verify::Verifier;
+
 use super::utils::*;
 
 pub struct Writer<W: Write> {
-    output: W,
+    // Taken back out by finish().
+    output: Option<W>,
+    bytes_written: u64,
+    write_time: Duration,
+
+    // Checks everything written, when verifying output.
+    #[cfg(feature="std")]
+    verifier: Option<Verifier>,
 }
 
 impl<W: Write> Writer<W> {
     //
     // Creates a new PNG chunk stream writer.
     // Consumes the output Write object, but will
-    // give it back to you via Writer::close().
+    // give it back to you via Writer::finish().
     //
     pub fn new(output: W) -> Writer<W> {
         Writer {
-            output,
+            output: Some(output),
+            bytes_written: 0,
+            write_time: Duration::default(),
+            #[cfg(feature="std")]
+            verifier: None,
         }
     }
 
+    //
+    // Check all output from here on with the given verifier,
+    // which is handed back by take_verifier() at the end.
+    //
+    #[cfg(feature="std")]
+    pub fn set_verifier(&mut self, verifier: Verifier) {
+        self.verifier = Some(verifier);
+    }
+
+    #[cfg(feature="std")]
+    pub fn take_verifier(&mut self) -> Option<Verifier> {
+        self.verifier.take()
+    }
+
     //
     // Close out the writer and return the Write
     // passed in originally so it can be used for
     // further output if necessary.
     //
-    // Consumes the writer.
+    // Nothing more can be written afterwards; this lets an owner
+    // that implements Drop hand back the output.
     //
-    pub fn finish(mut self: Writer<W>) -> io::Result<W> {
+    pub fn finish(&mut self) -> IoResult<W> {
         self.flush()?;
-        Ok(self.output)
+        Ok(self.output.take().unwrap())
+    }
+
+    //
+    // Whether finish() has returned the output.
+    //
+    #[cfg(feature="std")]
+    pub fn is_finished(&self) -> bool {
+        self.output.is_none()
+    }
+
+    fn output(&mut self) -> &mut W {
+        self.output.as_mut().expect("Writer used after finish()")
     }
 
     //
@@ -76,15 +121,44 @@ impl<W: Write> Writer<W> {
             26u8,  // SUB
             10u8   // \n
         ];
-        self.write_bytes(&bytes)
+        self.write_parts(&[&bytes])
     }
 
-    fn write_be32(&mut self, val: u32) -> IoResult {
-        write_be32(&mut self.output, val)
+    //
+    // Write some byte slices to the output in turn, then pass them
+    // on to the verifier. The clock is checked once around the lot,
+    // rather than for each small write, to keep the overhead down.
+    //
+    fn write_parts(&mut self, parts: &[&[u8]]) -> IoResult {
+        let start = Instant::now();
+        for part in parts {
+            self.output().write_all(part)?;
+            self.bytes_written += part.len() as u64;
+        }
+        self.write_time += start.elapsed();
+        #[cfg(feature="std")]
+        if let Some(ref mut verifier) = self.verifier {
+            for part in parts {
+                verifier.write(part)?;
+            }
+        }
+        Ok(())
     }
 
-    fn write_bytes(&mut self, data: &[u8]) -> IoResult {
-        self.output.write_all(data)
+    //
+    // Total bytes written to the output so far.
+    //
+    #[cfg(feature="std")]
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    //
+    // Total time spent writing and flushing the output so far.
+    //
+    #[cfg(feature="std")]
+    pub fn write_time(&self) -> Duration {
+        self.write_time
     }
 
     //
@@ -94,12 +168,7 @@ impl<W: Write> Writer<W> {
     // https://www.w3.org/TR/PNG/#5CRC-algorithm
     //
     pub fn write_chunk(&mut self, tag: &[u8], data: &[u8]) -> IoResult {
-        if tag.len() != 4 {
-            return Err(invalid_input("Chunk tags must be 4 bytes"));
-        }
-        if data.len() > u32::max_value() as usize {
-            return Err(invalid_input("Data chunks cannot exceed 4 GiB - 1 byte"));
-        }
+        Self::check_chunk(tag, data)?;
 
         // CRC covers both tag and data.
         let mut digest = crc32::Digest::new(crc32::IEEE);
@@ -107,11 +176,82 @@ impl<W: Write> Writer<W> {
         digest.write(data);
         let checksum = digest.sum32();
 
-        // Write data...
-        self.write_be32(data.len() as u32)?;
-        self.write_bytes(tag)?;
-        self.write_bytes(data)?;
-        self.write_be32(checksum)
+        self.write_chunk_raw(tag, data, checksum)
+    }
+
+    //
+    // Write a chunk to the output stream, where the CRC-32 of
+    // the data payload has already been calculated elsewhere
+    // (say, on a worker thread). Only the tag is checksummed
+    // here, and combined with the data's checksum.
+    //
+    #[cfg(feature="std")]
+    pub fn write_chunk_with_crc(&mut self, tag: &[u8], data: &[u8], data_crc: u32) -> IoResult {
+        Self::check_chunk(tag, data)?;
+
+        let checksum = Self::chunk_crc(tag, data_crc, data.len());
+
+        self.write_chunk_raw(tag, data, checksum)
+    }
+
+    //
+    // Calculate a chunk's full CRC-32 from its tag and the
+    // separately calculated CRC-32 of its data payload.
+    //
+    #[cfg(feature="std")]
+    pub fn chunk_crc(tag: &[u8], data_crc: u32, data_len: usize) -> u32 {
+        let tag_crc = deflate::crc32(deflate::crc32_initial(), tag);
+        deflate::crc32_combine(tag_crc, data_crc, data_len)
+    }
+
+    //
+    // Write a chunk a piece at a time: begin_chunk() writes the
+    // length and tag, write_chunk_data() any amount of the payload,
+    // and end_chunk() the CRC-32.
+    //
+    // This is used to write data in place when the output is seekable,
+    // so the length can be patched up after the fact.
+    //
+    #[cfg(feature="std")]
+    pub fn begin_chunk(&mut self, tag: &[u8], len: u32) -> IoResult {
+        Self::check_chunk(tag, &[])?;
+        self.write_parts(&[&len.to_be_bytes(), tag])
+    }
+
+    #[cfg(feature="std")]
+    pub fn write_chunk_data(&mut self, data: &[u8]) -> IoResult {
+        self.write_parts(&[data])
+    }
+
+    #[cfg(feature="std")]
+    pub fn end_chunk(&mut self, checksum: u32) -> IoResult {
+        self.write_parts(&[&checksum.to_be_bytes()])
+    }
+
+    //
+    // Access the underlying output, to seek around in it.
+    //
+    #[cfg(feature="std")]
+    pub fn get_mut(&mut self) -> &mut W {
+        self.output()
+    }
+
+    fn check_chunk(tag: &[u8], data: &[u8]) -> Result<(), Error> {
+        if tag.len() != 4 {
+            return Err(Error::InvalidChunk("Chunk tags must be 4 bytes"));
+        }
+        if !tag.iter().all(u8::is_ascii_alphabetic) {
+            return Err(Error::InvalidChunk("Chunk tags must be ASCII letters"));
+        }
+        if data.len() > u32::MAX as usize {
+            return Err(Error::InvalidChunk("Data chunks cannot exceed 4 GiB - 1 byte"));
+        }
+        Ok(())
+    }
+
+    fn write_chunk_raw(&mut self, tag: &[u8], data: &[u8], checksum: u32) -> IoResult {
+        let len = data.len() as u32;
+        self.write_parts(&[&len.to_be_bytes(), tag, data, &checksum.to_be_bytes()])
     }
 
     //
@@ -119,16 +259,7 @@ impl<W: Write> Writer<W> {
     // https://www.w3.org/TR/PNG/#11IHDR
     //
     pub fn write_header(&mut self, header: Header) -> IoResult {
-        let mut data = Vec::<u8>::new();
-        write_be32(&mut data, header.width)?;
-        write_be32(&mut data, header.height)?;
-        write_byte(&mut data, header.depth)?;
-        write_byte(&mut data, header.color_type as u8)?;
-        write_byte(&mut data, header.compression_method as u8)?;
-        write_byte(&mut data, header.filter_method as u8)?;
-        write_byte(&mut data, header.interlace_method as u8)?;
-
-        self.write_chunk(b"IHDR", &data)
+        self.write_chunk(b"IHDR", &header_data(&header))
     }
 
     //
@@ -143,16 +274,35 @@ impl<W: Write> Writer<W> {
     // Flush output.
     //
     pub fn flush(&mut self) -> IoResult {
-        self.output.flush()
+        let start = Instant::now();
+        self.output().flush()?;
+        self.write_time += start.elapsed();
+        Ok(())
     }
 }
 
-#[cfg(test)]
+//
+// Payload of the IHDR chunk for the header.
+//
+pub fn header_data(header: &Header) -> Vec<u8> {
+    let mut data = Vec::<u8>::with_capacity(13);
+    data.extend_from_slice(&header.width.to_be_bytes());
+    data.extend_from_slice(&header.height.to_be_bytes());
+    data.push(header.depth);
+    data.push(header.color_type as u8);
+    data.push(header.compression_method as u8);
+    data.push(header.filter_method as u8);
+    data.push(header.interlace_method as u8);
+    data
+}
+
+#[cfg(all(test, feature="std"))]
 mod tests {
     use std::io;
 
     use super::Writer;
     use super::IoResult;
+    use super::deflate;
 
     fn test_writer<F, G>(test_func: F, assert_func: G)
         where F: Fn(&mut Writer<Vec<u8>>) -> IoResult,
@@ -166,7 +316,7 @@ mod tests {
         })();
         match result {
             Ok(output) => assert_func(&output),
-            Err(e) => assert!(false, "Error: {}", e),
+            Err(e) => panic!("Error: {}", e),
         }
     }
 
@@ -188,6 +338,14 @@ mod tests {
         })
     }
 
+    #[test]
+    fn bytes_written_works() {
+        let mut writer = Writer::new(Vec::<u8>::new());
+        writer.write_signature().unwrap();
+        writer.write_chunk(b"IDAT", b"0123").unwrap();
+        assert_eq!(writer.bytes_written(), 8 + 16);
+    }
+
     #[test]
     fn empty_chunk_works() {
         test_writer(|writer| {
@@ -213,6 +371,15 @@ mod tests {
         })
     }
 
+    #[test]
+    fn invalid_tag() {
+        let mut writer = Writer::new(Vec::<u8>::new());
+        assert!(writer.write_chunk(b"IDA", b"").is_err());
+        assert!(writer.write_chunk(b"ID1T", b"").is_err());
+        assert!(writer.write_chunk(b"ID T", b"").is_err());
+        assert_eq!(writer.bytes_written(), 0);
+    }
+
     #[test]
     fn crc_works() {
         // From a 1x1 truecolor black pixel made with gd
@@ -226,4 +393,18 @@ mod tests {
             assert_eq!(output[20..24], b"\xa3\x0a\x15\xe3"[..], "expected crc32");
         })
     }
+
+    #[test]
+    fn precomputed_crc_works() {
+        let one_pixel = b"\x08\x99\x63\x60\x60\x60\x00\x00\x00\x04\x00\x01";
+        test_writer(|writer| {
+            let (head, tail) = one_pixel.split_at(5);
+            let head_crc = deflate::crc32(deflate::crc32_initial(), head);
+            let tail_crc = deflate::crc32(deflate::crc32_initial(), tail);
+            let data_crc = deflate::crc32_combine(head_crc, tail_crc, tail.len());
+            writer.write_chunk_with_crc(b"IDAT", one_pixel, data_crc)
+        }, |output| {
+            assert_eq!(output[20..24], b"\xa3\x0a\x15\xe3"[..], "expected crc32");
+        })
+    }
 }
@@ -0,0 +1,276 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// cli.rs - tests of the CLI tool's exit codes, file handling, and metadata
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+const SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+//
+// A scratch directory for one test, removed when done.
+//
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Scratch {
+        let dir = std::env::temp_dir().join(format!("mtpng-cli-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+
+    // Copy a file from the pngsuite directory in.
+    fn sample(&self, name: &str) -> PathBuf {
+        let path = self.path(name);
+        fs::copy(Path::new(env!("CARGO_MANIFEST_DIR")).join("pngsuite").join(name), &path).unwrap();
+        path
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+//
+// The CLI, kept away from any config file or MTPNG_* variables
+// that are set where the tests run.
+//
+fn mtpng(scratch: &Scratch) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_mtpng"));
+    command.env("XDG_CONFIG_HOME", &scratch.0)
+           .env_remove("MTPNG_CONFIG")
+           .env_remove("MTPNG_THREADS")
+           .env_remove("MTPNG_LEVEL")
+           .env_remove("MTPNG_FILTER")
+           .env_remove("MTPNG_STRIP")
+           .arg("--quiet");
+    command
+}
+
+fn run(command: &mut Command) -> Output {
+    command.stdin(Stdio::null()).output().unwrap()
+}
+
+fn code(output: &Output) -> i32 {
+    output.status.code().unwrap()
+}
+
+#[test]
+fn converts() {
+    let scratch = Scratch::new("converts");
+    let input = scratch.sample("basn2c08.png");
+    let output = scratch.path("out.png");
+    let result = run(mtpng(&scratch).arg("--level").arg("9").arg(&input).arg(&output));
+    assert_eq!(code(&result), 0, "{}", String::from_utf8_lossy(&result.stderr));
+    assert!(fs::read(&output).unwrap().starts_with(&SIGNATURE));
+
+    // Recompressed image data differs, but the pixels don't.
+    let result = run(mtpng(&scratch).arg("diff").arg("--json").arg(&input).arg(&output));
+    assert_eq!(code(&result), 0);
+    let json = String::from_utf8_lossy(&result.stdout);
+    assert!(json.contains("{\"tag\":\"IDAT\",\"status\":\"differs\""), "{}", json);
+    assert!(json.contains("\"identical\":true"), "{}", json);
+}
+
+#[test]
+fn stdin_to_stdout() {
+    let scratch = Scratch::new("stdio");
+    let input = fs::read(scratch.sample("basn6a08.png")).unwrap();
+    let mut child = mtpng(&scratch).arg("-").arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&input).unwrap();
+    let result = child.wait_with_output().unwrap();
+    assert_eq!(code(&result), 0);
+    assert!(result.stdout.starts_with(&SIGNATURE));
+
+    let output = scratch.path("out.png");
+    fs::write(&output, &result.stdout).unwrap();
+    let result = run(mtpng(&scratch).arg("diff").arg(scratch.path("basn6a08.png")).arg(&output));
+    assert_eq!(code(&result), 0);
+}
+
+#[test]
+fn exit_codes() {
+    let scratch = Scratch::new("exit-codes");
+    let input = scratch.sample("basn0g08.png");
+    let output = scratch.path("out.png");
+
+    // Usage errors, from clap and from us.
+    assert_eq!(code(&run(mtpng(&scratch).arg("--no-such-option").arg(&input).arg(&output))), 2);
+    assert_eq!(code(&run(mtpng(&scratch).arg("--level").arg("10").arg(&input).arg(&output))), 2);
+    assert_eq!(code(&run(mtpng(&scratch).arg(&input))), 2);
+
+    // Input that isn't an image.
+    let text = scratch.path("text.png");
+    fs::write(&text, "not an image").unwrap();
+    assert_eq!(code(&run(mtpng(&scratch).arg(&text).arg(&output))), 3);
+
+    // Files that can't be read.
+    assert_eq!(code(&run(mtpng(&scratch).arg(scratch.path("missing.png")).arg(&output))), 4);
+    assert!(!output.exists());
+
+    // Images whose pixels differ.
+    let other = scratch.sample("basn0g16.png");
+    assert_eq!(code(&run(mtpng(&scratch).arg("diff").arg(&input).arg(&other))), 5);
+
+    // Errors are listed on stderr with --quiet.
+    let result = run(mtpng(&scratch).arg(&text).arg(&output));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.starts_with("error\tinput\t"), "{}", stderr);
+}
+
+#[test]
+fn force() {
+    let scratch = Scratch::new("force");
+    let input = scratch.sample("basn0g08.png");
+    let output = scratch.path("out.png");
+    fs::write(&output, "keep me").unwrap();
+
+    assert_eq!(code(&run(mtpng(&scratch).arg(&input).arg(&output))), 4);
+    assert_eq!(fs::read(&output).unwrap(), b"keep me");
+
+    // Writing over the input needs --in-place, even with --force.
+    assert_eq!(code(&run(mtpng(&scratch).arg("--force").arg(&input).arg(&input))), 2);
+
+    assert_eq!(code(&run(mtpng(&scratch).arg("--force").arg(&input).arg(&output))), 0);
+    assert!(fs::read(&output).unwrap().starts_with(&SIGNATURE));
+}
+
+#[test]
+fn in_place() {
+    let scratch = Scratch::new("in-place");
+    let first = scratch.sample("basn2c16.png");
+    let second = scratch.sample("basn3p08.png");
+    let original = fs::read(&first).unwrap();
+    let reference = scratch.path("reference.png");
+    fs::write(&reference, &original).unwrap();
+
+    let result = run(mtpng(&scratch).arg("--in-place").arg("--level").arg("1").arg(&first).arg(&second));
+    assert_eq!(code(&result), 0, "{}", String::from_utf8_lossy(&result.stderr));
+    assert_ne!(fs::read(&first).unwrap(), original);
+    assert_eq!(code(&run(mtpng(&scratch).arg("diff").arg(&reference).arg(&first))), 0);
+
+    // No temporary files are left behind.
+    let mut names: Vec<_> = fs::read_dir(&scratch.0).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["basn2c16.png", "basn3p08.png", "reference.png"]);
+
+    // A failure leaves the original alone.
+    let text = scratch.path("text.png");
+    fs::write(&text, "not an image").unwrap();
+    assert_eq!(code(&run(mtpng(&scratch).arg("--in-place").arg(&text))), 3);
+    assert_eq!(fs::read(&text).unwrap(), b"not an image");
+}
+
+//
+// The chunk tags in order from `info --json`, along with the JSON.
+//
+fn chunk_tags(scratch: &Scratch, path: &Path) -> (Vec<String>, String) {
+    let result = run(mtpng(scratch).arg("info").arg("--json").arg(path));
+    assert_eq!(code(&result), 0, "{}", String::from_utf8_lossy(&result.stderr));
+    let json = String::from_utf8(result.stdout).unwrap();
+    let start = json.find("\"chunks\":[").unwrap();
+    let end = start + json[start ..].find(']').unwrap();
+    let tags = json[start .. end].split("{\"tag\":\"")
+        .skip(1)
+        .map(|chunk| chunk[.. 4].to_string())
+        .collect();
+    (tags, json)
+}
+
+// A minimal ICC profile: the header's size and signature, then filler.
+fn icc_profile(len: usize) -> Vec<u8> {
+    let mut profile: Vec<u8> = (0 .. len).map(|i| (i * 7 % 251) as u8).collect();
+    profile[.. 4].copy_from_slice(&(len as u32).to_be_bytes());
+    profile[36 .. 40].copy_from_slice(b"acsp");
+    profile
+}
+
+#[test]
+fn keep_metadata() {
+    let scratch = Scratch::new("keep-metadata");
+    let input = scratch.sample("basn3p02.png");
+    let output = scratch.path("out.png");
+
+    // Copied chunks keep their order, with those that must come first
+    // before the palette.
+    let result = run(mtpng(&scratch).arg("--keep-metadata").arg(&input).arg(&output));
+    assert_eq!(code(&result), 0, "{}", String::from_utf8_lossy(&result.stderr));
+    let (tags, _) = chunk_tags(&scratch, &output);
+    assert_eq!(tags, ["IHDR", "gAMA", "sBIT", "PLTE", "IDAT", "IEND"]);
+
+    // Give it a profile, resolution and time to copy in turn.
+    let small = scratch.path("small.icc");
+    fs::write(&small, icc_profile(128)).unwrap();
+    let source = scratch.path("source.png");
+    let result = run(mtpng(&scratch).env("SOURCE_DATE_EPOCH", "0")
+        .arg("--keep-metadata").arg("--icc").arg(&small).arg("--dpi").arg("72").arg("--timestamp")
+        .arg(&input).arg(&source));
+    assert_eq!(code(&result), 0, "{}", String::from_utf8_lossy(&result.stderr));
+
+    let copied = scratch.path("copied.png");
+    let result = run(mtpng(&scratch).arg("--keep-metadata").arg(&source).arg(&copied));
+    assert_eq!(code(&result), 0, "{}", String::from_utf8_lossy(&result.stderr));
+    let (tags, json) = chunk_tags(&scratch, &copied);
+    assert_eq!(tags, ["IHDR", "iCCP", "gAMA", "sBIT", "PLTE", "pHYs", "tIME", "IDAT", "IEND"]);
+    assert!(json.contains("(72x72 dpi)"), "{}", json);
+    assert!(json.contains("1970-01-01 00:00:00 UTC"), "{}", json);
+
+    // Options replace the copied chunks rather than adding to them.
+    let large = scratch.path("large.icc");
+    fs::write(&large, icc_profile(4096)).unwrap();
+    let result = run(mtpng(&scratch).env("SOURCE_DATE_EPOCH", "1000000000").arg("--force")
+        .arg("--keep-metadata").arg("--icc").arg(&large).arg("--dpi").arg("300").arg("--timestamp")
+        .arg(&source).arg(&output));
+    assert_eq!(code(&result), 0, "{}", String::from_utf8_lossy(&result.stderr));
+    let (tags, json) = chunk_tags(&scratch, &output);
+    assert_eq!(tags, ["IHDR", "iCCP", "gAMA", "sBIT", "PLTE", "pHYs", "tIME", "IDAT", "IEND"]);
+    let (_, original) = chunk_tags(&scratch, &copied);
+    let profile = |json: &str| json.split("ICC profile (").nth(1).unwrap().split(' ').next().unwrap().to_string();
+    assert_ne!(profile(&json), profile(&original));
+    assert!(json.contains("(300x300 dpi)"), "{}", json);
+    assert!(json.contains("2001-09-09 01:46:40 UTC"), "{}", json);
+
+    // And --srgb replaces the profile and gamma, with its own.
+    let result = run(mtpng(&scratch).arg("--force").arg("--keep-metadata").arg("--srgb")
+        .arg(&source).arg(&output));
+    assert_eq!(code(&result), 0, "{}", String::from_utf8_lossy(&result.stderr));
+    let (tags, json) = chunk_tags(&scratch, &output);
+    assert_eq!(tags, ["IHDR", "sRGB", "gAMA", "cHRM", "sBIT", "PLTE", "pHYs", "tIME", "IDAT", "IEND"]);
+    assert!(json.contains("{\"tag\":\"gAMA\",\"keyword\":null,\"value\":\"0.45455\"}"), "{}", json);
+}